@@ -0,0 +1,49 @@
+#!/bin/sh
+# Rebuild the ext4 test images in this directory. Needs mkfs.ext4, e2fsck and zstd.
+#
+# The images are checked in so the ext4 tests run without e2fsprogs; a fixed UUID,
+# hash seed and clock keep them reproducible.
+set -e
+cd "$(dirname "$0")"
+export E2FSPROGS_FAKE_TIME=1700000000
+UUID=2b8e5a3c-6c1d-4f7e-9a0b-1c2d3e4f5a6b
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+# name, size in MiB, block size; the source tree is $work/<name>
+image() {
+    mkfs.ext4 -q -F -b "$3" -U "$UUID" -E "root_owner=0:0,hash_seed=$UUID" -d "$work/$1" "$work/$1.img" "$2M"
+}
+
+mkdir -p "$work/write/etc"
+printf 'revyos-lpi4a\n' > "$work/write/etc/hostname"
+ln -s etc "$work/write/config"
+image write 32 4096
+
+mkdir -p "$work/htree/etc"
+for i in $(seq -w 0 399); do printf x > "$work/htree/etc/existing-config-file-0$i.conf"; done
+image htree 32 4096
+# mke2fs -d links entries linearly; let e2fsck build the index
+e2fsck -fyD "$work/htree.img" > /dev/null || [ $? -eq 1 ]
+
+mkdir -p "$work/grow-1024/etc" "$work/grow-4096/etc"
+printf 'revyos-lpi4a\n' > "$work/grow-1024/etc/hostname"
+printf 'revyos-lpi4a\n' > "$work/grow-4096/etc/hostname"
+# 1K blocks: 2 groups. 4K blocks: 1 group
+image grow-1024 16 1024
+image grow-4096 32 4096
+
+mkdir -p "$work/firstboot/etc"
+printf 'revyos-lpi4a\n' > "$work/firstboot/etc/hostname"
+printf '127.0.0.1\tlocalhost\n127.0.1.1\trevyos-lpi4a\n' > "$work/firstboot/etc/hosts"
+printf 'root:x:0:0:root:/root:/bin/bash\ndebian:x:1000:1000:Debian:/home/debian:/bin/bash\n' > "$work/firstboot/etc/passwd"
+printf 'root:*:19000:0:99999:7:::\ndebian:$6$old$hash:19000:0:99999:7:::\n' > "$work/firstboot/etc/shadow"
+image firstboot 16 4096
+
+mkdir -p "$work/bootconf/extlinux"
+cp bootconf-extlinux.conf "$work/bootconf/extlinux/extlinux.conf"
+image bootconf 8 4096
+
+for name in write htree grow-1024 grow-4096 firstboot bootconf; do
+    zstd -q -19 -f "$work/$name.img" -o "$name.img.zst"
+done
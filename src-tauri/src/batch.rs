@@ -0,0 +1,756 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use nusb::MaybeFuture;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::error::{Error, Result};
+use crate::fastboot::{FastBoot, NusbTransport, Transport};
+use crate::flash::{flash_file, Reconnect, RetryPolicy, UploadProgressEvent};
+use crate::history::{HistoryEntry, HistoryStore, JobKind};
+use crate::reboot::{self, RebootTarget};
+use crate::settings::{Settings, SettingsStore};
+use crate::tunables::Tuning;
+use crate::usb::{self, USBDevice, FASTBOOT_INTERFACE};
+
+/// How long a board gets to come back after a reboot step.
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// One step of a flash plan, run in order on every board of the batch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "action")]
+pub enum PlanStep {
+    /// `force` flashes even an image that does not look like it belongs on `partition`;
+    /// `grow` grows an ext4 image to the size of the partition first.
+    #[serde(rename_all = "camelCase")]
+    Flash {
+        partition: String,
+        file_path: String,
+        #[serde(default)]
+        force: bool,
+        #[serde(default)]
+        grow: bool,
+    },
+    /// Reboot and wait for the board to come back before the next step.
+    Reboot { target: RebootTarget },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlashPlan {
+    pub name: String,
+    pub steps: Vec<PlanStep>,
+    /// Recorded in the flash history of every board.
+    #[serde(default)]
+    pub image_version: Option<String>,
+    #[serde(default)]
+    pub variant: Option<String>,
+    /// Board id the images are for; selects its download and flash overrides.
+    #[serde(default)]
+    pub board: Option<String>,
+    /// How often a failed split of a flash step is sent again.
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+/// Which attached devices take part in a batch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceFilter {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl DeviceFilter {
+    fn matches(&self, info: &nusb::DeviceInfo) -> bool {
+        info.vendor_id() == self.vendor_id
+            && info.product_id() == self.product_id
+            && usb::has_interface(info, FASTBOOT_INTERFACE)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "state")]
+pub enum JobState {
+    Pending,
+    Running { step: usize },
+    Done,
+    Failed { step: usize, error: Error },
+}
+
+/// Progress of the plan on a single board.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchJob {
+    pub key: String,
+    pub device: USBDevice,
+    pub state: JobState,
+    pub attempts: u32,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchProgressPayload {
+    key: String,
+    step: usize,
+    total_steps: usize,
+    current: u64,
+    total: u64,
+    state: JobState,
+}
+
+/// Everything a batch needs from outside: the boards on the bus, a way to talk to them,
+/// and somewhere to report to. [`UsbHost`] is the real one.
+pub trait BatchHost: Clone + Send + Sync + 'static {
+    type Transport: Transport + 'static;
+
+    /// Boards attached right now that match `filter`.
+    fn list(&self, filter: &DeviceFilter) -> Result<Vec<USBDevice>>;
+    /// Boards matching `filter` as they are plugged in.
+    fn watch(&self, filter: &DeviceFilter) -> Result<BoxStream<'static, USBDevice>>;
+    /// `device` as it is attached now; a failed board may have re-enumerated.
+    fn find(&self, device: &USBDevice) -> Option<USBDevice>;
+    fn open(&self, device: &USBDevice) -> Result<FastBoot<Self::Transport>>;
+    /// Open `device` again after it dropped off the bus, following it to its new address.
+    fn reconnect(&self, device: &mut USBDevice) -> impl Future<Output = Result<FastBoot<Self::Transport>>> + Send;
+    /// Wait for `device` to come back in `target`, as [`reboot::wait_for_target`].
+    fn wait_for_target(
+        &self,
+        device: &USBDevice,
+        target: RebootTarget,
+    ) -> impl Future<Output = Result<Option<USBDevice>>> + Send;
+    fn settings(&self) -> Settings;
+    fn history(&self) -> &HistoryStore;
+    fn emit(&self, payload: BatchProgressPayload);
+}
+
+/// Boards on the USB bus, reporting to the Tauri app.
+#[derive(Clone)]
+pub struct UsbHost {
+    app: AppHandle,
+}
+
+impl UsbHost {
+    pub fn new(app: AppHandle) -> Self {
+        Self { app }
+    }
+}
+
+impl BatchHost for UsbHost {
+    type Transport = NusbTransport;
+
+    fn list(&self, filter: &DeviceFilter) -> Result<Vec<USBDevice>> {
+        Ok(nusb::list_devices()
+            .wait()
+            .map_err(|e| Error::usb(e.to_string()))?
+            .filter(|info| filter.matches(info))
+            .map(USBDevice::from)
+            .collect())
+    }
+
+    fn watch(&self, filter: &DeviceFilter) -> Result<BoxStream<'static, USBDevice>> {
+        let filter = filter.clone();
+        let watch = nusb::watch_devices().map_err(|e| Error::usb(e.to_string()))?;
+        Ok(watch
+            .filter_map(move |event| {
+                futures::future::ready(match event {
+                    nusb::hotplug::HotplugEvent::Connected(info) if filter.matches(&info) => {
+                        Some(USBDevice::from(info))
+                    }
+                    _ => None,
+                })
+            })
+            .boxed())
+    }
+
+    fn find(&self, device: &USBDevice) -> Option<USBDevice> {
+        nusb::list_devices()
+            .wait()
+            .ok()?
+            .find(|info| device.is_same_board(info) && usb::has_interface(info, FASTBOOT_INTERFACE))
+            .map(USBDevice::from)
+    }
+
+    fn open(&self, device: &USBDevice) -> Result<FastBoot<NusbTransport>> {
+        FastBoot::from_info(&device.clone().try_into()?)
+    }
+
+    async fn reconnect(&self, device: &mut USBDevice) -> Result<FastBoot<NusbTransport>> {
+        device.reconnect().await
+    }
+
+    async fn wait_for_target(&self, device: &USBDevice, target: RebootTarget) -> Result<Option<USBDevice>> {
+        reboot::wait_for_target(device, target, REBOOT_TIMEOUT).await
+    }
+
+    fn settings(&self) -> Settings {
+        self.app.state::<SettingsStore>().get()
+    }
+
+    fn history(&self) -> &HistoryStore {
+        self.app.state::<HistoryStore>().inner()
+    }
+
+    fn emit(&self, payload: BatchProgressPayload) {
+        let _ = self.app.emit("batch-progress", payload);
+    }
+}
+
+/// A board of the batch, reconnected through its host when a split has to be retried.
+struct HostedDevice<'a, H> {
+    host: &'a H,
+    device: &'a mut USBDevice,
+}
+
+impl<H: BatchHost> Reconnect<H::Transport> for HostedDevice<'_, H> {
+    fn reconnect(&mut self) -> impl Future<Output = Result<FastBoot<H::Transport>>> + Send {
+        self.host.reconnect(self.device)
+    }
+}
+
+struct Batch {
+    plan: FlashPlan,
+    jobs: HashMap<String, BatchJob>,
+    watcher: Option<tauri::async_runtime::JoinHandle<()>>,
+}
+
+/// Runs one flash plan concurrently on every matching board.
+///
+/// Each board gets its own task, so a failure on one board never stops the others.
+/// Progress is emitted as `batch-progress` events keyed by board.
+#[derive(Default)]
+pub struct BatchManager {
+    batch: Arc<Mutex<Option<Batch>>>,
+}
+
+/// Stable key for a board; the serial and the port survive reboots, the bus address does not.
+fn board_key(device: &USBDevice) -> String {
+    match (&device.serial_number, &device.port_path) {
+        (Some(serial), _) => serial.clone(),
+        (None, Some(path)) => format!("{:04x}:{:04x}@{path}", device.vendor_id, device.product_id),
+        (None, None) => format!(
+            "{:04x}:{:04x}@{}",
+            device.vendor_id, device.product_id, device.device_address
+        ),
+    }
+}
+
+impl BatchManager {
+    pub fn start<H: BatchHost>(
+        &self,
+        host: H,
+        plan: FlashPlan,
+        filter: DeviceFilter,
+        auto_join: bool,
+    ) -> Result<Vec<BatchJob>> {
+        if plan.steps.is_empty() {
+            return Err(Error::invalid_input("Flash plan has no steps"));
+        }
+        let devices = host.list(&filter)?;
+        {
+            let mut guard = self.batch.lock().unwrap();
+            if guard.as_ref().is_some_and(|b| {
+                b.jobs.values().any(|j| matches!(j.state, JobState::Pending | JobState::Running { .. }))
+            }) {
+                return Err(Error::invalid_input("A batch is already running"));
+            }
+            if let Some(old) = guard.take() {
+                if let Some(watcher) = old.watcher {
+                    watcher.abort();
+                }
+            }
+            *guard = Some(Batch {
+                plan,
+                jobs: HashMap::new(),
+                watcher: None,
+            });
+        }
+        for device in devices {
+            spawn_job(&host, &self.batch, device);
+        }
+        if auto_join {
+            match host.watch(&filter) {
+                Ok(boards) => {
+                    let watcher = tauri::async_runtime::spawn(watch_for_boards(host, self.batch.clone(), boards));
+                    if let Some(batch) = self.batch.lock().unwrap().as_mut() {
+                        batch.watcher = Some(watcher);
+                    }
+                }
+                Err(e) => eprintln!("Cannot watch for new devices: {e}"),
+            }
+        }
+        Ok(self.jobs())
+    }
+
+    pub fn jobs(&self) -> Vec<BatchJob> {
+        let guard = self.batch.lock().unwrap();
+        let mut jobs: Vec<_> = guard
+            .as_ref()
+            .map(|b| b.jobs.values().cloned().collect())
+            .unwrap_or_default();
+        jobs.sort_by(|a, b| a.key.cmp(&b.key));
+        jobs
+    }
+
+    /// Run the plan again, from the start, on every board that failed.
+    pub fn retry_failed<H: BatchHost>(&self, host: H) -> Result<usize> {
+        let failed: Vec<USBDevice> = {
+            let guard = self.batch.lock().unwrap();
+            let batch = guard
+                .as_ref()
+                .ok_or_else(|| Error::invalid_input("No batch has been started"))?;
+            batch
+                .jobs
+                .values()
+                .filter(|j| matches!(j.state, JobState::Failed { .. }))
+                .map(|j| j.device.clone())
+                .collect()
+        };
+        for device in &failed {
+            let device = host.find(device).unwrap_or_else(|| device.clone());
+            spawn_job(&host, &self.batch, device);
+        }
+        Ok(failed.len())
+    }
+
+    /// Stop admitting new boards. Jobs already flashing run to completion, since
+    /// interrupting a partition write halfway would leave the board unbootable.
+    pub fn stop(&self) {
+        if let Some(batch) = self.batch.lock().unwrap().as_mut() {
+            if let Some(watcher) = batch.watcher.take() {
+                watcher.abort();
+            }
+        }
+    }
+}
+
+fn spawn_job<H: BatchHost>(host: &H, batch: &Arc<Mutex<Option<Batch>>>, device: USBDevice) {
+    let key = board_key(&device);
+    let plan = {
+        let mut guard = batch.lock().unwrap();
+        let Some(batch) = guard.as_mut() else { return };
+        let attempts = batch.jobs.get(&key).map_or(0, |j| j.attempts);
+        batch.jobs.insert(
+            key.clone(),
+            BatchJob {
+                key: key.clone(),
+                device: device.clone(),
+                state: JobState::Pending,
+                attempts: attempts + 1,
+            },
+        );
+        batch.plan.clone()
+    };
+    tauri::async_runtime::spawn(run_job(host.clone(), batch.clone(), key, device, plan));
+}
+
+/// Add boards that appear on the bus while the batch is running.
+async fn watch_for_boards<H: BatchHost>(
+    host: H,
+    batch: Arc<Mutex<Option<Batch>>>,
+    mut boards: BoxStream<'static, USBDevice>,
+) {
+    while let Some(device) = boards.next().await {
+        {
+            let guard = batch.lock().unwrap();
+            let Some(batch) = guard.as_ref() else { return };
+            // Without a serial we cannot tell a new board from one that is rebooting
+            // in the middle of its plan, so only serialised boards may join.
+            if device.serial_number.is_none() {
+                println!("Ignoring new device without a serial number");
+                continue;
+            }
+            if batch.jobs.contains_key(&board_key(&device)) {
+                continue;
+            }
+        }
+        println!("Board {} joined the batch", board_key(&device));
+        spawn_job(&host, &batch, device);
+    }
+}
+
+fn set_state(batch: &Arc<Mutex<Option<Batch>>>, key: &str, state: JobState, device: Option<&USBDevice>) {
+    if let Some(job) = batch.lock().unwrap().as_mut().and_then(|b| b.jobs.get_mut(key)) {
+        job.state = state;
+        if let Some(device) = device {
+            job.device = device.clone();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_step<H: BatchHost>(
+    host: &H,
+    key: &str,
+    device: &mut USBDevice,
+    step: &PlanStep,
+    index: usize,
+    total_steps: usize,
+    retry: RetryPolicy,
+    tuning: &Tuning,
+) -> Result<()> {
+    match step {
+        PlanStep::Flash { partition, file_path, force, grow } => {
+            let fb = host.open(device)?;
+            let reporter = host.clone();
+            let key = key.to_string();
+            let file = std::path::Path::new(file_path);
+            let cache_dir = host.settings().image_cache_dir();
+            let mut device = HostedDevice { host, device };
+            flash_file(fb, &mut device, partition, file, *force, *grow, retry, tuning, &cache_dir, move |event| {
+                // Only the upload is reported per board
+                let UploadProgressEvent::Progress { current, total } = event else {
+                    return;
+                };
+                reporter.emit(BatchProgressPayload {
+                    key: key.clone(),
+                    step: index,
+                    total_steps,
+                    current,
+                    total,
+                    state: JobState::Running { step: index },
+                });
+            })
+            .await
+        }
+        PlanStep::Reboot { target } => {
+            {
+                let mut fb = host.open(device)?;
+                reboot::reboot(&mut fb, *target).await?;
+            }
+            if let Some(new_device) = host.wait_for_target(device, *target).await? {
+                *device = new_device;
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn run_job<H: BatchHost>(
+    host: H,
+    batch: Arc<Mutex<Option<Batch>>>,
+    key: String,
+    mut device: USBDevice,
+    plan: FlashPlan,
+) {
+    let total_steps = plan.steps.len();
+    let emit = |state: &JobState, step: usize| {
+        host.emit(BatchProgressPayload {
+            key: key.clone(),
+            step,
+            total_steps,
+            current: 0,
+            total: 0,
+            state: state.clone(),
+        });
+    };
+    let history = host.history();
+    let mut entry = HistoryEntry::start(JobKind::Flash);
+    entry.device_serial = device.serial_number.clone();
+    entry.device = Some(device.product_string.clone());
+    entry.image_version = plan.image_version.clone();
+    entry.variant = plan.variant.clone();
+    // Reboots between steps keep the device key, so one resolution covers the whole job
+    let settings = host.settings();
+    let board = plan.board.as_deref().or(settings.default_board.as_deref());
+    let tuning = settings.tunables.resolve(board, Some(&device));
+    entry.tuning = tuning.describe();
+    for (index, step) in plan.steps.iter().enumerate() {
+        let state = JobState::Running { step: index };
+        set_state(&batch, &key, state.clone(), None);
+        emit(&state, index);
+        if let PlanStep::Flash { partition, file_path, .. } = step {
+            entry.partitions.push(partition.clone());
+            entry.binaries.push(history.binary_record(std::path::Path::new(file_path), Some(partition)));
+        }
+        if let Err(error) = run_step(&host, &key, &mut device, step, index, total_steps, plan.retry, &tuning).await {
+            let error = error.with_context(format!("Step {index}"));
+            eprintln!("Board {key} failed: {error}");
+            entry.finish::<(), _>(&Err(error.clone()));
+            history.record_hashed(entry).await;
+            let state = JobState::Failed { step: index, error };
+            set_state(&batch, &key, state.clone(), Some(&device));
+            emit(&state, index);
+            return;
+        }
+        set_state(&batch, &key, JobState::Running { step: index }, Some(&device));
+    }
+    entry.finish::<(), String>(&Ok(()));
+    history.record_hashed(entry).await;
+    set_state(&batch, &key, JobState::Done, Some(&device));
+    emit(&JobState::Done, total_steps);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use futures::channel::mpsc;
+    use crate::error::ErrorKind;
+    use crate::history::Outcome;
+    use crate::fastboot::sim::SimTransport;
+
+    /// Simulated boards, each answering with the next of its queued transports every
+    /// time it is opened.
+    #[derive(Clone)]
+    struct SimHost {
+        attached: Vec<USBDevice>,
+        boards: Arc<Mutex<HashMap<String, VecDeque<SimTransport>>>>,
+        plugged: Arc<Mutex<Option<mpsc::UnboundedReceiver<USBDevice>>>>,
+        history: Arc<HistoryStore>,
+        cache_dir: std::path::PathBuf,
+    }
+
+    impl SimHost {
+        fn new(name: &str) -> (Self, mpsc::UnboundedSender<USBDevice>) {
+            let path = std::env::temp_dir().join(format!("revyos-imager-batch-{name}.jsonl"));
+            let _ = std::fs::remove_file(&path);
+            let (sender, receiver) = mpsc::unbounded();
+            let host = Self {
+                attached: vec![],
+                boards: Default::default(),
+                plugged: Arc::new(Mutex::new(Some(receiver))),
+                history: Arc::new(HistoryStore::new(path)),
+                cache_dir: std::env::temp_dir().join(format!("revyos-imager-batch-{name}-cache")),
+            };
+            (host, sender)
+        }
+
+        /// A board attached from the start.
+        fn board(mut self, serial: &str, transports: Vec<SimTransport>) -> Self {
+            self.attached.push(device(Some(serial)));
+            self.unplugged(serial, transports)
+        }
+
+        /// A board that is only plugged in later.
+        fn unplugged(self, serial: &str, transports: Vec<SimTransport>) -> Self {
+            self.boards.lock().unwrap().insert(serial.to_string(), transports.into());
+            self
+        }
+    }
+
+    impl BatchHost for SimHost {
+        type Transport = SimTransport;
+
+        fn list(&self, _filter: &DeviceFilter) -> Result<Vec<USBDevice>> {
+            Ok(self.attached.clone())
+        }
+
+        fn watch(&self, _filter: &DeviceFilter) -> Result<BoxStream<'static, USBDevice>> {
+            Ok(self.plugged.lock().unwrap().take().expect("watched once").boxed())
+        }
+
+        fn find(&self, device: &USBDevice) -> Option<USBDevice> {
+            Some(device.clone())
+        }
+
+        fn open(&self, device: &USBDevice) -> Result<FastBoot<SimTransport>> {
+            let serial = device.serial_number.clone().unwrap_or_default();
+            let mut boards = self.boards.lock().unwrap();
+            let transport = boards.get_mut(&serial).and_then(VecDeque::pop_front);
+            transport.map(FastBoot::new).ok_or_else(Error::device_not_found)
+        }
+
+        async fn reconnect(&self, device: &mut USBDevice) -> Result<FastBoot<SimTransport>> {
+            self.open(device)
+        }
+
+        async fn wait_for_target(&self, device: &USBDevice, _target: RebootTarget) -> Result<Option<USBDevice>> {
+            Ok(Some(device.clone()))
+        }
+
+        fn settings(&self) -> Settings {
+            Settings { cache_dir: Some(self.cache_dir.clone()), ..Settings::default() }
+        }
+
+        fn history(&self) -> &HistoryStore {
+            &self.history
+        }
+
+        fn emit(&self, _payload: BatchProgressPayload) {}
+    }
+
+    fn device(serial: Option<&str>) -> USBDevice {
+        USBDevice {
+            vendor_id: 0x2345,
+            product_id: 0x7654,
+            product_string: "USB download gadget".to_string(),
+            device_address: 1,
+            serial_number: serial.map(str::to_string),
+            port_path: None,
+        }
+    }
+
+    /// A board that takes the image, or one that refuses to write it.
+    fn board(accepts: bool) -> SimTransport {
+        let transport = SimTransport::new().accept_downloads().reply("getvar:max-download-size", &["OKAY00100000"]);
+        if accepts {
+            transport.reply("flash:boot", &["OKAY"])
+        } else {
+            transport.reply("flash:boot", &["FAILpartition is locked"])
+        }
+    }
+
+    fn plan(name: &str) -> FlashPlan {
+        let file = std::env::temp_dir().join(format!("revyos-imager-batch-{name}.img"));
+        std::fs::write(&file, vec![0x5a; 4096]).unwrap();
+        FlashPlan {
+            name: name.to_string(),
+            steps: vec![PlanStep::Flash {
+                partition: "boot".to_string(),
+                file_path: file.to_string_lossy().to_string(),
+                force: true,
+                grow: false,
+            }],
+            image_version: None,
+            variant: None,
+            board: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    const FILTER: DeviceFilter = DeviceFilter { vendor_id: 0x2345, product_id: 0x7654 };
+
+    /// The jobs, once none is pending or running any more.
+    async fn settled(manager: &BatchManager) -> HashMap<String, BatchJob> {
+        for _ in 0..500 {
+            let jobs = manager.jobs();
+            if jobs.iter().all(|j| matches!(j.state, JobState::Done | JobState::Failed { .. })) {
+                return jobs.into_iter().map(|j| (j.key.clone(), j)).collect();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("batch did not finish: {:?}", manager.jobs().iter().map(|j| &j.state).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_failure_is_isolated() {
+        let (host, _plugged) = SimHost::new("isolated");
+        let host = host
+            .board("A", vec![board(true)])
+            .board("B", vec![board(false)])
+            .board("C", vec![board(true)]);
+        let manager = BatchManager::default();
+        assert_eq!(manager.start(host.clone(), plan("isolated"), FILTER, false).unwrap().len(), 3);
+
+        let jobs = settled(&manager).await;
+        assert_eq!(jobs["A"].state, JobState::Done);
+        assert_eq!(jobs["C"].state, JobState::Done);
+        assert!(matches!(&jobs["B"].state, JobState::Failed { step: 0, error } if error.kind == ErrorKind::Fastboot));
+        let history = host.history.list().unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.iter().filter(|e| e.outcome == Outcome::Success).count(), 2);
+        assert!(history.iter().all(|e| e.tuning == Tuning::default().describe()));
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed() {
+        let (host, _plugged) = SimHost::new("retry");
+        let host = host.board("A", vec![board(true)]).board("B", vec![board(false), board(true)]);
+        let manager = BatchManager::default();
+        manager.start(host.clone(), plan("retry"), FILTER, false).unwrap();
+        assert!(matches!(settled(&manager).await["B"].state, JobState::Failed { .. }));
+
+        // Only the failed board runs the plan again
+        assert_eq!(manager.retry_failed(host).unwrap(), 1);
+        let jobs = settled(&manager).await;
+        assert_eq!((&jobs["A"].state, jobs["A"].attempts), (&JobState::Done, 1));
+        assert_eq!((&jobs["B"].state, jobs["B"].attempts), (&JobState::Done, 2));
+    }
+
+    #[tokio::test]
+    async fn test_auto_join() {
+        let (host, plugged) = SimHost::new("join");
+        let host = host.board("A", vec![board(true), board(true)]).unplugged("D", vec![board(true)]);
+        let manager = BatchManager::default();
+        manager.start(host.clone(), plan("join"), FILTER, true).unwrap();
+        settled(&manager).await;
+
+        // A board without a serial, and one already in the batch, do not join
+        plugged.unbounded_send(device(None)).unwrap();
+        plugged.unbounded_send(device(Some("A"))).unwrap();
+        plugged.unbounded_send(device(Some("D"))).unwrap();
+        while manager.jobs().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let jobs = settled(&manager).await;
+        assert_eq!(jobs.keys().map(String::as_str).collect::<std::collections::BTreeSet<_>>(), ["A", "D"].into());
+        assert_eq!(jobs["D"].state, JobState::Done);
+        assert_eq!(jobs["A"].attempts, 1);
+        manager.stop();
+    }
+
+    #[tokio::test]
+    async fn test_compressed_image() {
+        let data = vec![0x5a; 4096];
+        let file = std::env::temp_dir().join("revyos-imager-batch-compressed.img.zst");
+        std::fs::write(&file, crate::compression::tests::compress(crate::compression::Compression::Zstd, &data).await)
+            .unwrap();
+        let mut plan = plan("compressed");
+        plan.steps = vec![PlanStep::Flash {
+            partition: "boot".to_string(),
+            file_path: file.to_string_lossy().to_string(),
+            force: true,
+            grow: false,
+        }];
+        let transport = board(true);
+        let downloads = transport.downloads.clone();
+        let (host, _plugged) = SimHost::new("compressed");
+        let host = host.board("A", vec![transport]);
+        let manager = BatchManager::default();
+        manager.start(host, plan, FILTER, false).unwrap();
+
+        // The board gets the extracted image, not the archive
+        assert_eq!(settled(&manager).await["A"].state, JobState::Done);
+        assert_eq!(*downloads.lock().unwrap(), [data]);
+    }
+
+    #[test]
+    fn test_board_key() {
+        let mut device = USBDevice {
+            vendor_id: 0x1234,
+            product_id: 0x8888,
+            product_string: "USB download gadget".to_string(),
+            device_address: 7,
+            serial_number: None,
+            port_path: None,
+        };
+        assert_eq!(board_key(&device), "1234:8888@7");
+        device.port_path = Some("1-2.4".to_string());
+        assert_eq!(board_key(&device), "1234:8888@1-2.4");
+        device.serial_number = Some("0123456789ABCDEF".to_string());
+        assert_eq!(board_key(&device), "0123456789ABCDEF");
+    }
+
+    #[test]
+    fn test_plan_deserialize() {
+        let plan: FlashPlan = serde_json::from_str(
+            r#"{
+                "name": "lpi4a-16g",
+                "steps": [
+                    { "action": "flash", "partition": "ram", "filePath": "/tmp/u-boot.bin" },
+                    { "action": "reboot", "target": "bootloader" },
+                    { "action": "flash", "partition": "root", "filePath": "/tmp/root.ext4" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(plan.steps.len(), 3);
+        assert!(matches!(plan.steps[1], PlanStep::Reboot { target: RebootTarget::Bootloader }));
+        assert_eq!(plan.retry, RetryPolicy::default());
+
+        let plan: FlashPlan = serde_json::from_str(
+            r#"{
+                "name": "flaky-hub",
+                "steps": [],
+                "retry": { "retries": 6, "initialBackoffMs": 500, "maxBackoffMs": 4000 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(plan.retry, RetryPolicy { retries: 6, initial_backoff_ms: 500, max_backoff_ms: 4000 });
+    }
+}
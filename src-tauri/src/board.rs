@@ -0,0 +1,371 @@
+use std::borrow::Cow;
+use std::path::PathBuf;
+use chrono::NaiveDateTime;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::image::{BinaryMetadata, ImageBinary, ImageBinaryType};
+
+/// How an image for a board ends up on its storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FlashMethod {
+    /// root, boot and u-boot are flashed to eMMC over fastboot.
+    Fastboot,
+    /// A single disk image is written to an SD card or NVMe drive on the host.
+    SdCard,
+}
+
+/// One entry of a board's rule table: files whose name matches `pattern` are of
+/// `binary_type`. The first matching rule wins.
+///
+/// Patterns may capture `ram`, `revision` and `timestamp` (`YYYYMMDD_HHMMSS`), which
+/// end up in the binary's [`BinaryMetadata`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileRule {
+    pub pattern: Cow<'static, str>,
+    pub binary_type: ImageBinaryType,
+}
+
+const fn rule(pattern: &'static str, binary_type: ImageBinaryType) -> FileRule {
+    FileRule { pattern: Cow::Borrowed(pattern), binary_type }
+}
+
+/// The rules for RevyOS's naming on all thead boards so far, e.g.
+/// `u-boot-with-spl-lpi4a-16g.bin`, `root-lpi4a-20250323_154524.ext4.zst` and
+/// `sdcard-pioneer-20250323_154524.img.zst`. Each type ends with a looser rule without
+/// metadata, for files named differently.
+pub const DEFAULT_FILE_RULES: &[FileRule] = &[
+    rule(
+        r"^u-boot(?:-with-spl)?-[a-z0-9]+(?:-(?P<ram>\d+g))?(?:-(?P<revision>[a-z0-9]+))?\.bin$",
+        ImageBinaryType::UBoot,
+    ),
+    rule(r"^u-boot", ImageBinaryType::UBoot),
+    // An SD card image may be named after the rootfs it holds, so it goes before root
+    rule(r"^sdcard-[a-z0-9-]+?-(?P<timestamp>\d{8}_\d{6})\.img", ImageBinaryType::Sdcard),
+    rule(r"(?:^|[-_.])sdcard(?:[-_.]|$)", ImageBinaryType::Sdcard),
+    rule(r"^boot-[a-z0-9-]+?-(?P<timestamp>\d{8}_\d{6})\.ext4", ImageBinaryType::Boot),
+    rule(r"^boot[-_.]", ImageBinaryType::Boot),
+    rule(r"^root(?:fs)?-[a-z0-9-]+?-(?P<timestamp>\d{8}_\d{6})\.ext4", ImageBinaryType::Root),
+    rule(r"^root(?:fs)?[-_.]", ImageBinaryType::Root),
+];
+
+/// Checksum files published next to an image, by suffix, with the hash they hold.
+const CHECKSUM_SUFFIXES: &[(&str, &str)] = &[
+    (".sha256", "sha256"),
+    (".sha256sum", "sha256"),
+    (".sha512", "sha512"),
+    (".md5", "md5"),
+];
+
+/// A hash the downloader and the import can verify an image against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// The algorithm named `name` (`sha256`, `SHA512`), if it is one we can check.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha512" => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    /// The name used for it in sidecar suffixes and [`ImageBinary::hash_type`].
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    pub fn hasher(self) -> Box<dyn sha2::digest::DynDigest + Send> {
+        match self {
+            HashAlgorithm::Sha256 => Box::new(sha2::Sha256::default()),
+            HashAlgorithm::Sha512 => Box::new(sha2::Sha512::default()),
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "SHA-256"),
+            HashAlgorithm::Sha512 => write!(f, "SHA-512"),
+        }
+    }
+}
+
+/// For a checksum sidecar like `root.ext4.zst.sha256`, the file it is for and the hash.
+/// Sidecars of hashes [`HashAlgorithm`] cannot check are recognised too, so they are
+/// not taken for images.
+pub fn checksum_sidecar(file_name: &str) -> Option<(&str, &'static str)> {
+    CHECKSUM_SUFFIXES.iter().find_map(|(suffix, algorithm)| {
+        file_name
+            .strip_suffix(suffix)
+            .filter(|target| !target.is_empty())
+            .map(|target| (target, *algorithm))
+    })
+}
+
+/// The hash for `file_name` in the content of a checksum file: either a bare hash, or
+/// `sha256sum` output listing one or more files.
+pub fn parse_checksum_file(content: &str, file_name: &str) -> Option<String> {
+    let mut lines = content.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let hash = fields.next()?;
+        // sha256sum marks binary mode with a leading '*'
+        let name = fields.next().map(|name| name.trim_start_matches('*'));
+        Some((hash, name))
+    });
+    let first = lines.clone().next();
+    lines
+        .find(|(_, name)| name.is_some_and(|name| name.rsplit('/').next() == Some(file_name)))
+        .or(first.filter(|(_, name)| name.is_none()))
+        .map(|(hash, _)| hash.to_ascii_lowercase())
+}
+
+/// A compiled rule table.
+#[derive(Debug, Clone)]
+pub struct RuleSet {
+    rules: Vec<(Regex, ImageBinaryType)>,
+}
+
+impl RuleSet {
+    pub fn compile(rules: &[FileRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                RegexBuilder::new(&rule.pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map(|regex| (regex, rule.binary_type.clone()))
+                    .map_err(|e| Error::invalid_input(format!("Bad file rule {}: {e}", rule.pattern)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Classify a file name; files no rule matches become `Other`.
+    pub fn classify(&self, file_name: &str) -> (ImageBinaryType, BinaryMetadata) {
+        for (regex, binary_type) in &self.rules {
+            if let Some(captures) = regex.captures(file_name) {
+                let group = |name| captures.name(name).map(|m| m.as_str().to_string());
+                let metadata = BinaryMetadata {
+                    ram: group("ram"),
+                    revision: group("revision"),
+                    built_at: group("timestamp")
+                        .and_then(|t| NaiveDateTime::parse_from_str(&t, "%Y%m%d_%H%M%S").ok())
+                        .map(|t| t.and_utc()),
+                };
+                return (binary_type.clone(), metadata);
+            }
+        }
+        (ImageBinaryType::Other(file_name.to_string()), BinaryMetadata::default())
+    }
+}
+
+/// Per-board rule tables that replace the built-in ones, read from `<dir>/<board id>.json`.
+pub struct RuleStore {
+    dir: PathBuf,
+}
+
+impl RuleStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The rules for `board`: its override file if there is one, else the built-in table.
+    pub fn rules(&self, board: &Board) -> Result<RuleSet> {
+        let path = self.dir.join(format!("{}.json", board.id));
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                let rules: Vec<FileRule> = serde_json::from_str(&content)
+                    .map_err(|e| Error::invalid_input(format!("Bad rule file {}: {e}", path.display())))?;
+                println!("Using file rules for {} from {}", board.id, path.display());
+                RuleSet::compile(&rules)
+            }
+            Err(_) => Ok(board.rules()),
+        }
+    }
+}
+
+/// A board RevyOS publishes images for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Board {
+    /// Stable id used by the frontend and in flash plans.
+    pub id: &'static str,
+    pub name: &'static str,
+    /// Subdirectory of a mirror's image tree holding one directory per image version.
+    pub mirror_path: &'static str,
+    pub file_rules: &'static [FileRule],
+    /// Binaries every variant of a version shares; a version missing one is skipped.
+    pub required: &'static [ImageBinaryType],
+    /// Each binary of this type makes one variant, together with the `required` ones.
+    pub variant_by: ImageBinaryType,
+    pub flash_method: FlashMethod,
+}
+
+impl Board {
+    /// The board's built-in rule table, compiled.
+    pub fn rules(&self) -> RuleSet {
+        RuleSet::compile(self.file_rules).expect("built-in file rules compile")
+    }
+
+    /// The board's directory on `mirror`, the root of a RevyOS image tree.
+    pub fn url(&self, mirror: &str) -> String {
+        format!("{}{}/", mirror, self.mirror_path)
+    }
+
+    /// Group classified binaries into variants, named after their `variant_by` binary.
+    /// Fails with the first required type none of the binaries has.
+    pub fn group_variants(
+        &self,
+        binaries: &[ImageBinary],
+    ) -> std::result::Result<Vec<(String, Vec<ImageBinary>)>, ImageBinaryType> {
+        let required = self
+            .required
+            .iter()
+            .map(|binary_type| {
+                binaries
+                    .iter()
+                    .find(|binary| &binary.binary_type == binary_type)
+                    .cloned()
+                    .ok_or_else(|| binary_type.clone())
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(binaries
+            .iter()
+            .filter(|binary| binary.binary_type == self.variant_by)
+            .map(|binary| {
+                let mut variant = required.clone();
+                variant.push(binary.clone());
+                (binary.name.clone(), variant)
+            })
+            .collect())
+    }
+}
+
+const FASTBOOT_REQUIRED: &[ImageBinaryType] = &[ImageBinaryType::Root, ImageBinaryType::Boot];
+
+const fn fastboot_board(id: &'static str, name: &'static str, mirror_path: &'static str) -> Board {
+    Board {
+        id,
+        name,
+        mirror_path,
+        file_rules: DEFAULT_FILE_RULES,
+        required: FASTBOOT_REQUIRED,
+        variant_by: ImageBinaryType::UBoot,
+        flash_method: FlashMethod::Fastboot,
+    }
+}
+
+pub const LPI4A: Board = fastboot_board("lpi4a", "Sipeed LicheePi 4A", "lpi4a");
+
+pub const BOARDS: &[Board] = &[
+    LPI4A,
+    fastboot_board("lpi4a-console", "Sipeed LicheePi 4A Console", "lcon4a"),
+    fastboot_board("lpi4a-laptop", "Sipeed LicheePi 4A Laptop", "laptop4a"),
+    fastboot_board("lc4a", "Sipeed LC4A", "lc4a"),
+    fastboot_board("meles", "Milk-V Meles", "meles"),
+    Board {
+        id: "pioneer",
+        name: "Milk-V Pioneer",
+        mirror_path: "pioneer",
+        file_rules: DEFAULT_FILE_RULES,
+        required: &[],
+        variant_by: ImageBinaryType::Sdcard,
+        flash_method: FlashMethod::SdCard,
+    },
+];
+
+pub fn find(id: &str) -> Option<&'static Board> {
+    BOARDS.iter().find(|board| board.id == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let rules = find("lpi4a").unwrap().rules();
+        let classify = |name| rules.classify(name).0;
+        assert_eq!(classify("u-boot-with-spl-lpi4a-16g.bin"), ImageBinaryType::UBoot);
+        assert_eq!(classify("u-boot-with-spl-lpi4a-16g-bootfs.bin"), ImageBinaryType::UBoot);
+        assert_eq!(classify("boot-lpi4a-20250323_154524.ext4.zst"), ImageBinaryType::Boot);
+        assert_eq!(classify("root-lpi4a-20250323_154524.ext4.zst"), ImageBinaryType::Root);
+        assert_eq!(classify("rootfs-sdcard.img"), ImageBinaryType::Sdcard);
+        assert_eq!(classify("sdcard-pioneer-20250323_154524.img.zst"), ImageBinaryType::Sdcard);
+        // Only the prefix counts, not any "root" or "boot" in the middle
+        assert_eq!(classify("chroot-notes.txt"), ImageBinaryType::Other("chroot-notes.txt".to_string()));
+        assert_eq!(classify("README.md"), ImageBinaryType::Other("README.md".to_string()));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let rules = LPI4A.rules();
+        let (_, metadata) = rules.classify("u-boot-with-spl-lpi4a-16g-bootfs.bin");
+        assert_eq!(metadata.ram.as_deref(), Some("16g"));
+        assert_eq!(metadata.revision.as_deref(), Some("bootfs"));
+        let (_, metadata) = rules.classify("u-boot-with-spl-lpi4a-main.bin");
+        assert_eq!((metadata.ram, metadata.revision.as_deref()), (None, Some("main")));
+        let (_, metadata) = rules.classify("root-lpi4a-20250323_154524.ext4.zst");
+        assert_eq!(
+            metadata.built_at,
+            chrono::NaiveDate::from_ymd_opt(2025, 3, 23)
+                .and_then(|d| d.and_hms_opt(15, 45, 24))
+                .map(|t| t.and_utc())
+        );
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(checksum_sidecar("root.ext4.zst.sha256"), Some(("root.ext4.zst", "sha256")));
+        assert_eq!(checksum_sidecar("root.ext4.zst"), None);
+        assert_eq!(HashAlgorithm::from_name("SHA512"), Some(HashAlgorithm::Sha512));
+        assert_eq!(HashAlgorithm::from_name("md5"), None);
+        let mut hasher = HashAlgorithm::Sha256.hasher();
+        hasher.update(b"abc");
+        assert!(hex::encode(hasher.finalize()).starts_with("ba7816bf"));
+        assert_eq!(parse_checksum_file("ABCD\n", "root.ext4.zst").as_deref(), Some("abcd"));
+        let sums = "1111  boot.ext4.zst\n2222 *images/root.ext4.zst\n";
+        assert_eq!(parse_checksum_file(sums, "root.ext4.zst").as_deref(), Some("2222"));
+        assert_eq!(parse_checksum_file(sums, "u-boot.bin"), None);
+    }
+
+    #[test]
+    fn test_rule_store() {
+        let dir = std::env::temp_dir().join("revyos-imager-rules");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = RuleStore::new(dir.clone());
+        assert_eq!(store.rules(&LPI4A).unwrap().classify("boot.ext4").0, ImageBinaryType::Boot);
+
+        let rules = [FileRule { pattern: "^kernel".into(), binary_type: ImageBinaryType::Boot }];
+        std::fs::write(dir.join("lpi4a.json"), serde_json::to_string(&rules).unwrap()).unwrap();
+        let loaded = store.rules(&LPI4A).unwrap();
+        assert_eq!(loaded.classify("kernel.ext4").0, ImageBinaryType::Boot);
+        assert!(matches!(loaded.classify("boot.ext4").0, ImageBinaryType::Other(_)));
+
+        std::fs::write(dir.join("lpi4a.json"), r#"[{"pattern": "(", "binaryType": "Boot"}]"#).unwrap();
+        assert!(store.rules(&LPI4A).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_board_ids_unique() {
+        for (i, board) in BOARDS.iter().enumerate() {
+            assert!(BOARDS[i + 1..].iter().all(|b| b.id != board.id), "{} twice", board.id);
+        }
+        assert_eq!(find("pioneer").unwrap().url("http://mirror/images/"), "http://mirror/images/pioneer/");
+        assert!(find("unknown").is_none());
+    }
+}
@@ -0,0 +1,473 @@
+//! U-Boot boot configuration inside a boot image: `extlinux.conf` entries and
+//! `uEnv.txt` variables, read and rewritten without mounting. Rewrites touch only
+//! the lines that changed, so comments and directives we do not model survive.
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::ext4::{Attributes, Ext4};
+
+/// Where the files live on a separate boot partition, or on a root filesystem.
+const EXTLINUX_PATHS: [&str; 2] = ["/extlinux/extlinux.conf", "/boot/extlinux/extlinux.conf"];
+const UENV_PATHS: [&str; 2] = ["/uEnv.txt", "/boot/uEnv.txt"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtlinuxEntry {
+    pub label: String,
+    pub menu_label: Option<String>,
+    /// `linux` or `kernel`.
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    /// A single device tree; takes precedence over `fdtdir` in U-Boot.
+    pub fdt: Option<String>,
+    pub fdtdir: Option<String>,
+    /// The kernel command line.
+    pub append: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtlinuxConfig {
+    pub default: Option<String>,
+    /// In tenths of a second.
+    pub timeout: Option<u32>,
+    pub entries: Vec<ExtlinuxEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UEnvVar {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UEnv {
+    pub vars: Vec<UEnvVar>,
+}
+
+/// The boot configuration found in an image, with the paths it came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootConfig {
+    pub extlinux_path: Option<String>,
+    pub extlinux: Option<ExtlinuxConfig>,
+    pub uenv_path: Option<String>,
+    pub uenv: Option<UEnv>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Label,
+    Default,
+    Timeout,
+    MenuLabel,
+    Kernel,
+    Initrd,
+    Fdt,
+    FdtDir,
+    Append,
+}
+
+impl Key {
+    /// The keyword written for a directive that was not in the file yet.
+    fn keyword(self) -> &'static str {
+        match self {
+            Key::Label => "label",
+            Key::Default => "default",
+            Key::Timeout => "timeout",
+            Key::MenuLabel => "menu label",
+            Key::Kernel => "linux",
+            Key::Initrd => "initrd",
+            Key::Fdt => "fdt",
+            Key::FdtDir => "fdtdir",
+            Key::Append => "append",
+        }
+    }
+}
+
+/// The directive on `line` and where its value starts.
+fn directive(line: &str) -> Option<(Key, usize)> {
+    let indent = line.len() - line.trim_start().len();
+    let mut words = line[indent..].split_whitespace();
+    let first = words.next()?.to_ascii_lowercase();
+    let (key, keyword_words) = match first.as_str() {
+        "label" => (Key::Label, 1),
+        "default" => (Key::Default, 1),
+        "timeout" => (Key::Timeout, 1),
+        "linux" | "kernel" => (Key::Kernel, 1),
+        "initrd" => (Key::Initrd, 1),
+        "fdt" | "devicetree" => (Key::Fdt, 1),
+        "fdtdir" | "devicetreedir" => (Key::FdtDir, 1),
+        "append" => (Key::Append, 1),
+        "menu" if words.next().is_some_and(|w| w.eq_ignore_ascii_case("label")) => (Key::MenuLabel, 2),
+        _ => return None,
+    };
+    // Skip the keyword words and the whitespace after them
+    let mut rest = &line[indent..];
+    for _ in 0..keyword_words {
+        rest = rest.trim_start();
+        rest = &rest[rest.find(char::is_whitespace).unwrap_or(rest.len())..];
+    }
+    let value_start = line.len() - rest.trim_start().len();
+    Some((key, value_start))
+}
+
+fn value(line: &str, start: usize) -> String {
+    line[start..].trim_end().to_string()
+}
+
+/// The global lines and then one block per `label`, each starting with its label line.
+fn blocks(text: &str) -> (Vec<&str>, Vec<Vec<&str>>) {
+    let mut preamble = Vec::new();
+    let mut entries: Vec<Vec<&str>> = Vec::new();
+    for line in text.lines() {
+        match (directive(line), entries.last_mut()) {
+            (Some((Key::Label, _)), _) => entries.push(vec![line]),
+            (_, Some(entry)) => entry.push(line),
+            (_, None) => preamble.push(line),
+        }
+    }
+    (preamble, entries)
+}
+
+impl ExtlinuxConfig {
+    pub fn parse(text: &str) -> Self {
+        let (preamble, blocks) = blocks(text);
+        let mut config = ExtlinuxConfig::default();
+        for line in preamble {
+            match directive(line) {
+                Some((Key::Default, at)) => config.default = Some(value(line, at)),
+                Some((Key::Timeout, at)) => config.timeout = value(line, at).parse().ok(),
+                _ => {}
+            }
+        }
+        for block in blocks {
+            let (_, at) = directive(block[0]).unwrap();
+            let mut entry = ExtlinuxEntry {
+                label: value(block[0], at),
+                menu_label: None,
+                kernel: None,
+                initrd: None,
+                fdt: None,
+                fdtdir: None,
+                append: None,
+            };
+            for line in &block[1..] {
+                let Some((key, at)) = directive(line) else {
+                    continue;
+                };
+                let field = match key {
+                    Key::MenuLabel => &mut entry.menu_label,
+                    Key::Kernel => &mut entry.kernel,
+                    Key::Initrd => &mut entry.initrd,
+                    Key::Fdt => &mut entry.fdt,
+                    Key::FdtDir => &mut entry.fdtdir,
+                    Key::Append => &mut entry.append,
+                    _ => continue,
+                };
+                field.get_or_insert_with(|| value(line, at));
+            }
+            config.entries.push(entry);
+        }
+        config
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let mut labels = std::collections::HashSet::new();
+        for entry in &self.entries {
+            if entry.label.is_empty() || entry.label.contains(char::is_whitespace) {
+                return Err(Error::invalid_input(format!("Invalid extlinux label: {:?}", entry.label)));
+            }
+            if !labels.insert(entry.label.as_str()) {
+                return Err(Error::invalid_input(format!("Duplicate extlinux label: {}", entry.label)));
+            }
+            let values = [&entry.menu_label, &entry.kernel, &entry.initrd, &entry.fdt, &entry.fdtdir, &entry.append];
+            if values.iter().any(|v| v.as_deref().is_some_and(|v| v.contains(['\n', '\r']))) {
+                return Err(Error::invalid_input(format!("Entry {} has a value spanning lines", entry.label)));
+            }
+        }
+        if let Some(default) = &self.default {
+            if !labels.contains(default.as_str()) {
+                return Err(Error::invalid_input(format!("Default entry {default} does not exist")));
+            }
+        }
+        Ok(())
+    }
+
+    /// `original` rewritten to hold this configuration. Entries are matched to the
+    /// original ones by position; surplus original entries are dropped.
+    pub fn render(&self, original: &str) -> String {
+        let (preamble, blocks) = blocks(original);
+        let timeout = self.timeout.map(|t| t.to_string());
+        let mut out = Vec::new();
+        let globals = [(Key::Default, self.default.as_deref()), (Key::Timeout, timeout.as_deref())];
+        rewrite_block(&mut out, &preamble, &globals, "");
+        for (i, entry) in self.entries.iter().enumerate() {
+            let label_line = format!("label {}", entry.label);
+            let block = match blocks.get(i) {
+                Some(block) => {
+                    let (_, at) = directive(block[0]).unwrap();
+                    let mut block = block.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+                    block[0] = format!("{}{}", &block[0][..at], entry.label);
+                    block
+                }
+                None => vec![label_line],
+            };
+            let block: Vec<&str> = block.iter().map(String::as_str).collect();
+            let fields = [
+                (Key::MenuLabel, entry.menu_label.as_deref()),
+                (Key::Kernel, entry.kernel.as_deref()),
+                (Key::Initrd, entry.initrd.as_deref()),
+                (Key::Fdt, entry.fdt.as_deref()),
+                (Key::FdtDir, entry.fdtdir.as_deref()),
+                (Key::Append, entry.append.as_deref()),
+            ];
+            if i >= blocks.len() && out.last().is_some_and(|l: &String| !l.trim().is_empty()) {
+                out.push(String::new());
+            }
+            rewrite_block(&mut out, &block, &fields, "\t");
+        }
+        out.join("\n") + "\n"
+    }
+}
+
+/// Copy `lines` to `out` with the directives in `fields` set to their values:
+/// replaced in place, dropped when `None`, or added after the last directive.
+fn rewrite_block(out: &mut Vec<String>, lines: &[&str], fields: &[(Key, Option<&str>)], indent: &str) {
+    let mut written = Vec::new();
+    // Where to add missing directives: after the last one kept
+    let mut insert_at = None;
+    let start = out.len();
+    for line in lines {
+        let Some((key, at)) = directive(line) else {
+            out.push(line.to_string());
+            continue;
+        };
+        match fields.iter().find(|(k, _)| *k == key) {
+            None => out.push(line.to_string()),
+            Some((_, Some(value))) if !written.contains(&key) => {
+                out.push(format!("{}{value}", &line[..at]));
+                written.push(key);
+            }
+            // Cleared, or a later duplicate U-Boot ignored anyway
+            Some(_) => continue,
+        }
+        insert_at = Some(out.len());
+    }
+    let missing: Vec<String> = fields
+        .iter()
+        .filter(|(key, value)| value.is_some() && !written.contains(key))
+        .map(|(key, value)| format!("{indent}{} {}", key.keyword(), value.unwrap()))
+        .collect();
+    let at = insert_at.unwrap_or_else(|| {
+        start + lines.iter().take_while(|l| l.trim().is_empty() || l.trim_start().starts_with('#')).count()
+    });
+    out.splice(at..at, missing);
+}
+
+impl UEnv {
+    pub fn parse(text: &str) -> Self {
+        let vars = text
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| UEnvVar { name: name.trim().to_string(), value: value.to_string() })
+            .collect();
+        UEnv { vars }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for var in &self.vars {
+            if var.name.is_empty() || var.name.contains(|c: char| c == '=' || c.is_whitespace()) {
+                return Err(Error::invalid_input(format!("Invalid uEnv variable name: {:?}", var.name)));
+            }
+            if var.value.contains(['\n', '\r']) {
+                return Err(Error::invalid_input(format!("uEnv variable {} spans lines", var.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// `original` with variables updated in place, removed ones dropped and new
+    /// ones appended.
+    pub fn render(&self, original: &str) -> String {
+        let mut written = Vec::new();
+        let mut out: Vec<String> = Vec::new();
+        for line in original.lines() {
+            let name = match line.split_once('=') {
+                Some((name, _)) if !line.trim_start().starts_with('#') => name.trim(),
+                _ => {
+                    out.push(line.to_string());
+                    continue;
+                }
+            };
+            if let Some(var) = self.vars.iter().find(|v| v.name == name).filter(|_| !written.contains(&name)) {
+                out.push(format!("{}={}", var.name, var.value));
+                written.push(name);
+            }
+        }
+        for var in self.vars.iter().filter(|v| !written.contains(&v.name.as_str())) {
+            out.push(format!("{}={}", var.name, var.value));
+        }
+        out.join("\n") + "\n"
+    }
+}
+
+fn read_first(fs: &mut Ext4, paths: &[&str]) -> Result<Option<(String, String)>> {
+    for path in paths {
+        if let Some(data) = fs.read_file(path)? {
+            return Ok(Some((path.to_string(), String::from_utf8_lossy(&data).into_owned())));
+        }
+    }
+    Ok(None)
+}
+
+/// The extlinux and uEnv configuration of the boot image at `image`.
+pub fn read_boot_config(image: &Path) -> Result<BootConfig> {
+    let mut fs = Ext4::open_read_only(image)?;
+    let mut config = BootConfig::default();
+    if let Some((path, text)) = read_first(&mut fs, &EXTLINUX_PATHS)? {
+        config.extlinux = Some(ExtlinuxConfig::parse(&text));
+        config.extlinux_path = Some(path);
+    }
+    if let Some((path, text)) = read_first(&mut fs, &UENV_PATHS)? {
+        config.uenv = Some(UEnv::parse(&text));
+        config.uenv_path = Some(path);
+    }
+    Ok(config)
+}
+
+fn write_config(fs: &mut Ext4, path: &str, render: impl FnOnce(&str) -> String) -> Result<()> {
+    let original = fs.read_file(path)?.map(|data| String::from_utf8_lossy(&data).into_owned());
+    if original.is_none() {
+        if let Some((dir, _)) = path.rsplit_once('/').filter(|(dir, _)| !dir.is_empty()) {
+            fs.create_dir_all(dir, Attributes::root(0o755))?;
+        }
+    }
+    let text = render(original.as_deref().unwrap_or_default());
+    fs.write_file(path, text.as_bytes(), Attributes::root(0o644))
+}
+
+/// Write `config` into `output`, a copy of the boot image `source`. Parts set to
+/// `None` are left alone; files that do not exist yet go to the first usual path.
+pub fn write_boot_config(source: &Path, output: &Path, config: &BootConfig) -> Result<()> {
+    if let Some(extlinux) = &config.extlinux {
+        extlinux.validate()?;
+    }
+    if let Some(uenv) = &config.uenv {
+        uenv.validate()?;
+    }
+    crate::ext4::edit_copy(source, output, |fs| {
+        if let Some(extlinux) = &config.extlinux {
+            let path = config.extlinux_path.as_deref().unwrap_or(EXTLINUX_PATHS[0]);
+            write_config(fs, path, |original| extlinux.render(original))?;
+        }
+        if let Some(uenv) = &config.uenv {
+            let path = config.uenv_path.as_deref().unwrap_or(UENV_PATHS[0]);
+            write_config(fs, path, |original| uenv.render(original))?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4::tests::{fixture, fsck};
+
+    /// The extlinux.conf of `fixtures/ext4/bootconf.img.zst`.
+    const EXTLINUX: &str = include_str!("../fixtures/ext4/bootconf-extlinux.conf");
+
+    #[test]
+    fn test_parse_extlinux() {
+        let config = ExtlinuxConfig::parse(EXTLINUX);
+        assert_eq!(config.default.as_deref(), Some("l0"));
+        assert_eq!(config.timeout, Some(50));
+        assert_eq!(config.entries.len(), 2);
+        let entry = &config.entries[0];
+        assert_eq!(entry.label, "l0");
+        assert_eq!(entry.menu_label.as_deref(), Some("Debian GNU/Linux trixie/sid 6.6.73-th1520"));
+        assert_eq!(entry.kernel.as_deref(), Some("/vmlinuz-6.6.73-th1520"));
+        assert_eq!(entry.fdtdir.as_deref(), Some("/dtbs/linux-image-6.6.73-th1520/"));
+        assert_eq!(entry.fdt, None);
+        assert_eq!(entry.append.as_deref(), Some("root=/dev/mmcblk0p3 console=ttyS0,115200 rootwait rw loglevel=7"));
+        assert_eq!(config.entries[1].initrd, None);
+        // Unchanged configuration renders back byte for byte
+        assert_eq!(config.render(EXTLINUX), EXTLINUX);
+    }
+
+    #[test]
+    fn test_render_extlinux() {
+        let mut config = ExtlinuxConfig::parse(EXTLINUX);
+        config.timeout = None;
+        let entry = &mut config.entries[0];
+        entry.append = Some("root=/dev/mmcblk0p3 console=ttyS0,115200 loglevel=8".into());
+        entry.fdt = Some("/dtbs/linux-image-6.6.73-th1520/thead/th1520-lichee-pi-4a.dtb".into());
+        entry.initrd = None;
+        config.entries.truncate(1);
+        config.entries.push(ExtlinuxEntry {
+            label: "debug".into(),
+            menu_label: None,
+            kernel: Some("/vmlinuz-debug".into()),
+            initrd: None,
+            fdt: None,
+            fdtdir: None,
+            append: Some("console=ttyS0,115200 earlycon".into()),
+        });
+        let text = config.render(EXTLINUX);
+        assert!(text.contains("## Do not edit this file manually"));
+        assert!(text.contains("menu title U-Boot menu\nprompt 0\n"));
+        assert!(!text.contains("timeout"));
+        assert!(text.contains("\tappend   root=/dev/mmcblk0p3 console=ttyS0,115200 loglevel=8\n"));
+        assert!(!text.contains("initrd"));
+        assert!(!text.contains("l0r"));
+        assert!(text.contains("loglevel=8\n\tfdt /dtbs/linux-image-6.6.73-th1520/thead/"));
+        assert!(text.ends_with("\nlabel debug\n\tlinux /vmlinuz-debug\n\tappend console=ttyS0,115200 earlycon\n"));
+        assert_eq!(ExtlinuxConfig::parse(&text), config);
+
+        config.default = Some("missing".into());
+        assert!(config.validate().is_err());
+        config.default = Some("debug".into());
+        config.entries[1].label = "l0".into();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_uenv() {
+        let original = "# board overrides\nfdtfile=th1520-lichee-pi-4a.dtb\nconsole=ttyS0,115200\n";
+        let mut uenv = UEnv::parse(original);
+        assert_eq!(uenv.vars.len(), 2);
+        assert_eq!(uenv.render(original), original);
+        uenv.vars.retain(|v| v.name != "console");
+        uenv.vars[0].value = "th1520-lichee-pi-4a-16g.dtb".into();
+        uenv.vars.push(UEnvVar { name: "loglevel".into(), value: "8".into() });
+        assert_eq!(uenv.render(original), "# board overrides\nfdtfile=th1520-lichee-pi-4a-16g.dtb\nloglevel=8\n");
+        uenv.vars.push(UEnvVar { name: "bad name".into(), value: String::new() });
+        assert!(uenv.validate().is_err());
+    }
+
+    #[test]
+    fn test_boot_image_round_trip() {
+        let image = fixture("bootconf");
+        let mut config = read_boot_config(&image).unwrap();
+        assert_eq!(config.extlinux_path.as_deref(), Some("/extlinux/extlinux.conf"));
+        assert!(config.uenv.is_none());
+        config.extlinux.as_mut().unwrap().entries[0].append = Some("console=ttyS0,115200 loglevel=8".into());
+        config.uenv = Some(UEnv { vars: vec![UEnvVar { name: "fdtfile".into(), value: "a.dtb".into() }] });
+        let output = crate::ext4::copy_path(&image);
+        write_boot_config(&image, &output, &config).unwrap();
+        fsck(&output);
+
+        let written = read_boot_config(&output).unwrap();
+        assert_eq!(written.uenv_path.as_deref(), Some("/uEnv.txt"));
+        assert_eq!(written.extlinux, config.extlinux);
+        assert_eq!(written.uenv, config.uenv);
+        let original = read_boot_config(&image).unwrap().extlinux.unwrap();
+        assert_eq!(original.entries[0].append.as_deref(), Some("root=/dev/mmcblk0p3 console=ttyS0,115200 rootwait rw loglevel=7"));
+        let _ = std::fs::remove_file(image);
+        let _ = std::fs::remove_file(output);
+    }
+}
@@ -1,15 +1,35 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use serde::Serialize;
-use tauri::Emitter;
-use tauri::{command, ipc::Channel};
+use tauri::{Emitter, Manager};
+use tauri::{command, ipc::Channel, State};
+use crate::board::RuleStore;
+use crate::bootconf::BootConfig;
+use crate::batch::{BatchJob, BatchManager, DeviceFilter, FlashPlan, UsbHost};
+use crate::error::{Error, ErrorKind, Result, ResultExt};
+use crate::console::{ConsoleLine, ConsoleTranscript};
+use crate::fastboot::FastBoot;
+use crate::firstboot::FirstBootProfile;
+use crate::history::{ExportFormat, HistoryEntry, HistoryStore, JobKind};
+use crate::html_parser::ListingFetcher;
+use crate::http_cache::HttpCache;
+use crate::mirror::{Mirror, MirrorProbe, MirrorRegistry};
+use crate::reboot::{self, RebootTarget};
+use crate::sparse::{SparseHeader, SparseMap};
+use crate::settings::{Settings, SettingsStore};
+use crate::tunables::{TunableSettings, Tuning};
+use crate::ubootenv::{DecodedEnv, EnvLayout};
 use crate::usb::{USBDevice, list_devices};
-use crate::flash::flash;
-use crate::image::ProgressType;
+use crate::flash::{RetryPolicy, UploadProgressEvent};
+use crate::image::{ImageVersion, ProgressType};
+use crate::import::LocalImages;
 
-#[derive(Clone, Serialize)]
-#[serde(rename_all = "camelCase", tag = "event", content = "data")]
-pub enum UploadProgressEvent {
-    #[serde(rename_all = "camelCase")]
-    Progress { current: u64, total: u64 },
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageInspection {
+    info: crate::inspect::ImageInfo,
+    /// Present when a partition was given.
+    fit: Option<crate::inspect::Fit>,
 }
 
 #[derive(Clone, Serialize)]
@@ -22,7 +42,7 @@ pub struct DownloadProgressPayload {
 }
 
 #[command]
-pub fn connect_to_device(device: USBDevice) -> Result<String, String> {
+pub fn connect_to_device(device: USBDevice) -> Result<String> {
     let device_info: nusb::DeviceInfo = device.try_into()?;
     // Perform connection logic here (stubbed for now)
     Ok(format!(
@@ -32,34 +52,278 @@ pub fn connect_to_device(device: USBDevice) -> Result<String, String> {
 }
 
 #[command]
-pub async fn reboot_device(device: USBDevice) -> Result<String, String> {
-    // Stub implementation
-    let device_info: nusb::DeviceInfo = device.try_into()?;
-    let mut fb = fastboot_protocol::nusb::NusbFastBoot::from_info(&device_info).unwrap();
-    println!("Fastboot version: {}", fb.get_var("version").await.unwrap());
-    fb.reboot().await.map_err(|e| e.to_string())?;
-    Ok("Rebooted device.".to_string())
+pub async fn reboot_device(
+    device: USBDevice,
+    target: Option<RebootTarget>,
+    wait: Option<bool>,
+    timeout_secs: Option<u64>,
+) -> Result<String> {
+    let target = target.unwrap_or(RebootTarget::System);
+    let wait = wait.unwrap_or(false);
+    // Refuse before rebooting a board that could not be found again afterwards
+    if wait {
+        device.ensure_identifiable()?;
+    }
+    {
+        // 限定 fb 的作用域，以便在等待设备重新枚举前释放 USB 接口
+        let device_info: nusb::DeviceInfo = device.clone().try_into()?;
+        let mut fb = FastBoot::from_info(&device_info)?;
+        reboot::reboot(&mut fb, target).await?;
+    }
+    if !wait {
+        return Ok(format!("Sent {} to device.", target.command()));
+    }
+    let timeout = std::time::Duration::from_secs(timeout_secs.unwrap_or(60));
+    match reboot::wait_for_target(&device, target, timeout).await? {
+        Some(new_device) => Ok(format!(
+            "Device rebooted into {:?} at address {}.",
+            target, new_device.device_address
+        )),
+        None => Ok(format!("Device left fastboot after {}.", target.command())),
+    }
 }
 
+/// force 为 true 时，即使镜像内容与目标分区不符也照常刷写；
+/// grow 为 true 时，先将 ext4 镜像离线扩展到设备报告的分区大小；
+/// retry 控制某个分段失败后重新连接设备并重试的次数与间隔；
+/// board 为镜像所属开发板，用于查找该开发板的下载与刷写参数覆盖
 #[command]
+#[allow(clippy::too_many_arguments)]
 pub async fn flash_to_partition(
     file_path: String,
     partition: String,
     device: USBDevice,
+    board: Option<String>,
+    image_version: Option<String>,
+    variant: Option<String>,
+    force: Option<bool>,
+    grow: Option<bool>,
+    retry: Option<RetryPolicy>,
     on_event: Channel<UploadProgressEvent>,
-) -> Result<String, String> {
+    app: tauri::AppHandle,
+    settings: State<'_, SettingsStore>,
+) -> Result<String> {
     // Validate file path
     if !std::path::Path::new(&file_path).exists() {
-        return Err(format!("File not found: {}", file_path));
+        return Err(Error::new(ErrorKind::NotFound, format!("File not found: {}", file_path)));
     }
+    let mut entry = HistoryEntry::start(JobKind::Flash);
+    entry.device_serial = device.serial_number.clone();
+    entry.device = Some(device.product_string.clone());
+    entry.image_version = image_version;
+    entry.variant = variant;
+    entry.partitions.push(partition.clone());
+    entry
+        .binaries
+        .push(app.state::<HistoryStore>().binary_record(std::path::Path::new(&file_path), Some(&partition)));
+
+    let (force, grow) = (force.unwrap_or(false), grow.unwrap_or(false));
+    let retry = retry.unwrap_or_default();
+    let settings = settings.get();
+    let board = board.or(settings.default_board);
+    let tuning = settings.tunables.resolve(board.as_deref(), Some(&device));
+    entry.tuning = tuning.describe();
+    let result = flash_file(&file_path, &partition, device, force, grow, retry, tuning, on_event).await;
+    entry.finish(&result);
+    // The image is hashed for the history once the flash is reported
+    tauri::async_runtime::spawn(async move { app.state::<HistoryStore>().record_hashed(entry).await });
+    result
+}
+
+/// 刷写前检查镜像内容（ext4、FAT、U-Boot、Android boot、GPT），并判断是否适合目标分区
+#[command]
+pub fn inspect_image(file_path: String, partition: Option<String>) -> Result<ImageInspection> {
+    let info = crate::inspect::inspect(std::path::Path::new(&file_path))?;
+    let fit = partition.map(|partition| crate::inspect::fit(&info, &partition));
+    Ok(ImageInspection { info, fit })
+}
+
+/// 将首次启动配置（主机名、SSH 公钥、Wi-Fi、密码）写入 root 镜像的副本，返回副本路径
+#[command]
+pub async fn customize_root_image(file_path: String, profile: FirstBootProfile) -> Result<String> {
+    let source = std::path::PathBuf::from(&file_path);
+    let output = crate::ext4::copy_path(&source);
+    let output_str = output.to_string_lossy().to_string();
+    tokio::task::spawn_blocking(move || crate::firstboot::customize_image(&source, &output, &profile))
+        .await
+        .map_err(|e| Error::internal(e.to_string()))?
+        .with_context(|| format!("Customizing {file_path}"))?;
+    Ok(output_str)
+}
+
+/// 不挂载镜像，读取 boot 镜像中的 extlinux.conf 与 uEnv.txt
+#[command]
+pub fn read_boot_config(file_path: String) -> Result<BootConfig> {
+    crate::bootconf::read_boot_config(std::path::Path::new(&file_path))
+        .with_context(|| format!("Reading boot configuration from {file_path}"))
+}
+
+/// 将修改后的启动配置写入 boot 镜像的副本，返回副本路径
+#[command]
+pub async fn write_boot_config(file_path: String, config: BootConfig) -> Result<String> {
+    let source = std::path::PathBuf::from(&file_path);
+    let output = crate::ext4::copy_path(&source);
+    let output_str = output.to_string_lossy().to_string();
+    tokio::task::spawn_blocking(move || crate::bootconf::write_boot_config(&source, &output, &config))
+        .await
+        .map_err(|e| Error::internal(e.to_string()))?
+        .with_context(|| format!("Writing boot configuration to {output_str}"))?;
+    Ok(output_str)
+}
+
+static NEXT_ENV_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// 生成 u-boot 环境变量镜像（CRC32，可选冗余标志）并刷写到 env 分区；
+/// retry 控制失败后重新连接设备并重试的次数与间隔；
+/// board 为目标开发板，用于查找该开发板的刷写参数覆盖，未指定时使用默认开发板
+#[command]
+#[allow(clippy::too_many_arguments)]
+pub async fn flash_uboot_env(
+    vars: BTreeMap<String, String>,
+    layout: Option<EnvLayout>,
+    partition: Option<String>,
+    device: USBDevice,
+    board: Option<String>,
+    retry: Option<RetryPolicy>,
+    on_event: Channel<UploadProgressEvent>,
+    history: State<'_, HistoryStore>,
+    settings: State<'_, SettingsStore>,
+) -> Result<String> {
+    let layout = layout.unwrap_or_default();
+    let partition = partition.unwrap_or_else(|| "env".to_string());
+    let image = crate::ubootenv::build(&vars, &layout)?;
+    // One file per call, as several boards may be flashed at once
+    let id = NEXT_ENV_FILE.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("uboot-env-{}-{id}.bin", std::process::id()));
+    std::fs::write(&path, &image).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    let mut entry = HistoryEntry::start(JobKind::Flash);
+    entry.device_serial = device.serial_number.clone();
+    entry.device = Some(device.product_string.clone());
+    entry.partitions.push(partition.clone());
+    entry.binaries.push(history.binary_record(&path, Some(&partition)));
+    let settings = settings.get();
+    let board = board.or(settings.default_board);
+    let tuning = settings.tunables.resolve(board.as_deref(), Some(&device));
+    entry.tuning = tuning.describe();
+    let retry = retry.unwrap_or_default();
+    let result = flash_file(&path.to_string_lossy(), &partition, device, false, false, retry, tuning, on_event).await;
+    entry.finish(&result);
+    history.record_hashed(entry).await;
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+/// 通过 fastboot fetch 读取设备上的 env 分区并解码
+#[command]
+pub async fn read_uboot_env(
+    device: USBDevice,
+    layout: Option<EnvLayout>,
+    partition: Option<String>,
+) -> Result<DecodedEnv> {
+    let layout = layout.unwrap_or_default();
+    let partition = partition.unwrap_or_else(|| "env".to_string());
     let device_info: nusb::DeviceInfo = device.try_into()?;
-    let mut fb = fastboot_protocol::nusb::NusbFastBoot::from_info(&device_info)
-        .map_err(|e| e.to_string())?;
-    println!(
-        "Fastboot version: {}",
-        fb.get_var("version").await.map_err(|e| e.to_string())?
-    );
-    flash(&mut fb, &partition, std::path::Path::new(&file_path), move |c, t| on_event.send(UploadProgressEvent::Progress { current: c, total: t }).unwrap()).await.map_err(|e| e.to_string())?;
+    let mut fb = FastBoot::from_info(&device_info)?;
+    let image = fb.fetch(&partition, 0, layout.image_len() as u64).await?;
+    crate::ubootenv::parse(&image, &layout).with_context(|| format!("Decoding the {partition} partition"))
+}
+
+/// 列出 sparse 镜像的文件头与所有 chunk
+#[command]
+pub fn sparse_chunk_map(file_path: String) -> Result<SparseMap> {
+    crate::sparse::chunk_map(std::path::Path::new(&file_path))
+}
+
+/// 将 sparse 镜像展开为 raw 镜像，返回输出路径
+#[command]
+pub async fn unsparse_image(file_path: String, output_path: Option<String>) -> Result<String> {
+    let source = std::path::PathBuf::from(&file_path);
+    let output = output_path.map_or_else(|| crate::sparse::sibling_path(&source, "raw"), Into::into);
+    let output_str = output.to_string_lossy().to_string();
+    tokio::task::spawn_blocking(move || crate::sparse::unsparse(&source, &output))
+        .await
+        .map_err(|e| Error::internal(e.to_string()))?
+        .with_context(|| format!("Unsparsing {file_path}"))?;
+    Ok(output_str)
+}
+
+/// 将 raw 镜像转换为 sparse 镜像，block_size 默认为 4096，返回输出路径与文件头
+#[command]
+pub async fn resparse_image(
+    file_path: String,
+    block_size: Option<u32>,
+    output_path: Option<String>,
+) -> Result<(String, SparseHeader)> {
+    let source = std::path::PathBuf::from(&file_path);
+    let output = output_path.map_or_else(|| crate::sparse::sibling_path(&source, "sparse"), Into::into);
+    let output_str = output.to_string_lossy().to_string();
+    let block_size = block_size.unwrap_or(crate::sparse::DEFAULT_BLOCK_SIZE);
+    let header = tokio::task::spawn_blocking(move || crate::sparse::resparse(&source, &output, block_size))
+        .await
+        .map_err(|e| Error::internal(e.to_string()))?
+        .with_context(|| format!("Converting {file_path} to a sparse image"))?;
+    Ok((output_str, header))
+}
+
+/// 按设备的 max-download-size 将 sparse 镜像拆分为多个文件，默认输出到镜像所在目录
+#[command]
+pub async fn split_sparse_image(file_path: String, max_size: u64, output_dir: Option<String>) -> Result<Vec<String>> {
+    let source = std::path::PathBuf::from(&file_path);
+    let output_dir = match output_dir {
+        Some(dir) => dir.into(),
+        None => source.parent().map(std::path::Path::to_path_buf).unwrap_or_default(),
+    };
+    let parts = tokio::task::spawn_blocking(move || crate::sparse::split(&source, max_size, &output_dir))
+        .await
+        .map_err(|e| Error::internal(e.to_string()))?
+        .with_context(|| format!("Splitting {file_path}"))?;
+    Ok(parts.iter().map(|p| p.to_string_lossy().to_string()).collect())
+}
+
+/// 将拆分后的多个 sparse 镜像合并为一个
+#[command]
+pub async fn merge_sparse_images(parts: Vec<String>, output_path: String) -> Result<SparseHeader> {
+    let sources: Vec<std::path::PathBuf> = parts.iter().map(Into::into).collect();
+    let output = std::path::PathBuf::from(&output_path);
+    tokio::task::spawn_blocking(move || crate::sparse::merge(&sources, &output))
+        .await
+        .map_err(|e| Error::internal(e.to_string()))?
+        .with_context(|| format!("Merging into {output_path}"))
+}
+
+/// 计算镜像展开后的 SHA-256（DONT_CARE 按全零计算），可与 raw 镜像的校验和比较
+#[command]
+pub async fn sparse_logical_hash(file_path: String) -> Result<String> {
+    let source = std::path::PathBuf::from(&file_path);
+    tokio::task::spawn_blocking(move || crate::sparse::logical_hash(&source))
+        .await
+        .map_err(|e| Error::internal(e.to_string()))?
+        .with_context(|| format!("Hashing {file_path}"))
+}
+
+/// 压缩的本地镜像先解压到缓存目录，grow 时再扩展，然后刷写
+#[allow(clippy::too_many_arguments)]
+async fn flash_file(
+    file_path: &str,
+    partition: &str,
+    mut device: USBDevice,
+    force: bool,
+    grow: bool,
+    retry: RetryPolicy,
+    tuning: Tuning,
+    on_event: Channel<UploadProgressEvent>,
+) -> Result<String> {
+    let device_info: nusb::DeviceInfo = device.clone().try_into()?;
+    let mut fb = FastBoot::from_info(&device_info)?;
+    println!("Fastboot version: {}", fb.get_var("version").await?);
+    let file = std::path::Path::new(file_path);
+    let cache_dir = crate::space::image_cache_dir();
+    crate::flash::flash_file(fb, &mut device, partition, file, force, grow, retry, &tuning, &cache_dir, move |event| {
+        // 前端关闭通道不应中断刷写
+        let _ = on_event.send(event);
+    })
+    .await?;
     Ok(format!(
         "Flashed file to partition {} on device: {}",
         partition,
@@ -69,23 +333,183 @@ pub async fn flash_to_partition(
 }
 
 #[command]
-pub fn list_usb_devices() -> Result<Vec<USBDevice>, String> {
+pub fn list_usb_devices() -> Result<Vec<USBDevice>> {
     list_devices()
 }
 
 // 增加一个新的命令，用于获取LPi4A镜像版本列表
 #[tauri::command]
-pub async fn fetch_lpi4a_image_versions() -> Result<Vec<crate::image::ImageVersion>, String> {
-    crate::html_parser::fetch_and_parse_lpi4a_image_all(None)
-        .await
-        .map_err(|e| e.to_string())
+pub async fn fetch_lpi4a_image_versions(
+    refresh: Option<bool>,
+    mirrors: State<'_, MirrorRegistry>,
+    cache: State<'_, HttpCache>,
+    rules: State<'_, RuleStore>,
+    settings: State<'_, SettingsStore>,
+    local_images: State<'_, LocalImages>,
+) -> Result<Vec<crate::image::ImageVersion>> {
+    let mirrors = mirrors.ranked(&settings.get().mirrors);
+    image_catalog(&crate::board::LPI4A, refresh.unwrap_or(false), &mirrors, &cache, &rules, &local_images).await
+}
+
+#[command]
+pub fn list_boards() -> Vec<&'static crate::board::Board> {
+    crate::board::BOARDS.iter().collect()
+}
+
+/// 获取任意受支持开发板的镜像版本列表；默认使用缓存并向镜像重新验证，refresh 为 true 时强制重新获取
+#[command]
+pub async fn fetch_image_catalog(
+    board: String,
+    refresh: Option<bool>,
+    mirrors: State<'_, MirrorRegistry>,
+    cache: State<'_, HttpCache>,
+    rules: State<'_, RuleStore>,
+    settings: State<'_, SettingsStore>,
+    local_images: State<'_, LocalImages>,
+) -> Result<Vec<crate::image::ImageVersion>> {
+    let board = crate::board::find(&board)
+        .ok_or_else(|| Error::invalid_input(format!("Unsupported board: {board}")))?;
+    let mirrors = mirrors.ranked(&settings.get().mirrors);
+    image_catalog(board, refresh.unwrap_or(false), &mirrors, &cache, &rules, &local_images).await
+}
+
+/// The board's versions on the mirrors, followed by those imported for it. Imports are
+/// still offered when no mirror can be reached.
+async fn image_catalog(
+    board: &crate::board::Board,
+    refresh: bool,
+    mirrors: &[String],
+    cache: &HttpCache,
+    rules: &RuleStore,
+    local_images: &LocalImages,
+) -> Result<Vec<crate::image::ImageVersion>> {
+    let fetcher = &ListingFetcher::cached(cache, refresh);
+    let rules = &rules.rules(board)?;
+    let local = local_images.list(board.id);
+    let catalog = crate::mirror::with_failover(mirrors, move |mirror| {
+        crate::html_parser::fetch_image_catalog(fetcher, board, rules, Some(board.url(&mirror)))
+    })
+    .await;
+    match catalog {
+        Ok(mut versions) => {
+            versions.extend(local);
+            Ok(versions)
+        }
+        Err(e) if !local.is_empty() => {
+            eprintln!("Offering only imported images for {}: {e}", board.name);
+            Ok(local)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The board named `board`, or the default board of the settings.
+fn find_board(board: Option<String>, settings: &SettingsStore) -> Result<&'static crate::board::Board> {
+    match board {
+        Some(id) => crate::board::find(&id).ok_or_else(|| Error::invalid_input(format!("Unknown board: {id}"))),
+        None => Ok(settings.get().board()),
+    }
+}
+
+#[command]
+pub fn get_settings(settings: State<'_, SettingsStore>) -> Settings {
+    settings.get()
+}
+
+/// 校验并保存全部设置（缓存目录、代理、默认开发板、镜像列表与刷写参数覆盖），成功后发出 settings-changed 事件
+#[command]
+pub fn update_settings(
+    new_settings: Settings,
+    app: tauri::AppHandle,
+    settings: State<'_, SettingsStore>,
+    mirrors: State<'_, MirrorRegistry>,
+) -> Result<Settings> {
+    save_settings(&app, &settings, &mirrors, new_settings)
+}
+
+fn save_settings(
+    app: &tauri::AppHandle,
+    settings: &SettingsStore,
+    mirrors: &MirrorRegistry,
+    mut new_settings: Settings,
+) -> Result<Settings> {
+    let previous = settings.get();
+    let (from, to) = (previous.image_cache_dir(), new_settings.image_cache_dir());
+    let saved = if from == to {
+        settings.update(new_settings)?
+    } else {
+        // 缓存目录变化时，把已下载和已导入的镜像一起移到新目录，失败则全部移回
+        new_settings.validate()?;
+        crate::space::move_cache(&from, &to)?;
+        let local_images = app.state::<LocalImages>();
+        let result = local_images.relocate(&from, &to).and_then(|_| settings.update(new_settings));
+        if result.is_err() {
+            let _ = crate::space::move_cache(&to, &from);
+            let _ = local_images.relocate(&to, &from);
+        }
+        result?
+    };
+    // 镜像列表变化后，旧的测速结果不再适用
+    if saved.mirrors != previous.mirrors {
+        mirrors.clear_probes();
+    }
+    let _ = app.emit("settings-changed", &saved);
+    Ok(saved)
+}
+
+#[command]
+pub fn list_mirrors(settings: State<'_, SettingsStore>) -> Vec<Mirror> {
+    settings.get().mirrors
+}
+
+#[command]
+pub fn set_mirrors(
+    list: Vec<Mirror>,
+    app: tauri::AppHandle,
+    settings: State<'_, SettingsStore>,
+    mirrors: State<'_, MirrorRegistry>,
+) -> Result<()> {
+    let new_settings = Settings { mirrors: list, ..settings.get() };
+    save_settings(&app, &settings, &mirrors, new_settings).map(drop)
+}
+
+#[command]
+pub fn get_tunables(settings: State<'_, SettingsStore>) -> TunableSettings {
+    settings.get().tunables
+}
+
+/// 按开发板 id 或设备序列号（无序列号时为 vid:pid）覆盖最大下载大小、稀疏块大小、USB 传输大小与超时
+#[command]
+pub fn set_tunables(
+    tunables: TunableSettings,
+    app: tauri::AppHandle,
+    settings: State<'_, SettingsStore>,
+    mirrors: State<'_, MirrorRegistry>,
+) -> Result<()> {
+    let new_settings = Settings { tunables, ..settings.get() };
+    save_settings(&app, &settings, &mirrors, new_settings).map(drop)
+}
+
+/// 测试所有启用镜像的延迟与吞吐量，结果按优劣排序，之后的下载优先使用最快的镜像；
+/// sample 为镜像站下某个文件的相对路径，读取其开头部分以测量吞吐量，省略时只比较延迟
+#[command]
+pub async fn probe_mirrors(
+    sample: Option<String>,
+    mirrors: State<'_, MirrorRegistry>,
+    settings: State<'_, SettingsStore>,
+) -> Result<Vec<MirrorProbe>> {
+    Ok(mirrors.probe(&settings.get().mirrors, sample.as_deref()).await)
 }
 
 #[command]
 pub async fn download_image_variant(
     variant: crate::image::ImageVariant,
-    window: tauri::Window
-) -> Result<String, String> {
+    version: Option<String>,
+    window: tauri::Window,
+    history: State<'_, HistoryStore>,
+    mirrors: State<'_, MirrorRegistry>,
+    settings: State<'_, SettingsStore>,
+) -> Result<String> {
     // 创建进度回调函数
     let progress_callback = move |filename: &str, current: u64, total: u64, progress_type: ProgressType| {
         let progress_type_str = match progress_type {
@@ -104,9 +528,169 @@ pub async fn download_image_variant(
     // 克隆变体以便可以修改它
     let mut variant_clone = variant.clone();
     
-    // 执行下载
-    match variant_clone.download_binaries(progress_callback).await {
+    // 执行下载并记录到历史
+    let mut entry = HistoryEntry::start(JobKind::Download);
+    entry.image_version = version;
+    entry.variant = Some(variant.name.clone());
+    let result = variant_clone.download_binaries(&crate::space::image_cache_dir(), &mirrors.ranked(&settings.get().mirrors), progress_callback).await;
+    for binary in &variant_clone.image_binarys {
+        if let Some(local_path) = &binary.local_path {
+            entry.binaries.push(history.binary_record(std::path::Path::new(local_path), None));
+        }
+    }
+    entry.finish(&result);
+    history.record_hashed(entry).await;
+    match result {
         Ok(_) => Ok(format!("Successfully downloaded all binaries for variant {}", variant.name)),
-        Err(e) => Err(format!("Failed to download binaries: {}", e)),
+        Err(e) => Err(e.with_context("Failed to download binaries")),
+    }
+}
+
+/// 导入本地目录、tar(.zst) 或 zip 镜像包，按开发板的规则分类后登记为可选版本
+#[command]
+pub async fn import_local_image(
+    path: String,
+    board: Option<String>,
+    window: tauri::Window,
+    local_images: State<'_, LocalImages>,
+    rules: State<'_, RuleStore>,
+    settings: State<'_, SettingsStore>,
+) -> Result<ImageVersion> {
+    let board = find_board(board, &settings)?;
+    let rules = rules.rules(board)?;
+    let version = crate::import::import_local_image(std::path::Path::new(&path), board, &rules, &crate::space::image_cache_dir(), |filename, current, total, _| {
+        let _ = window.emit("image-download-progress", DownloadProgressPayload {
+            filename: filename.to_string(),
+            current,
+            total,
+            progress_type: "extract".to_string(),
+        });
+    })
+    .await
+    .with_context(|| format!("Importing {path}"))?;
+    local_images.add(board.id, version.clone())?;
+    Ok(version)
+}
+
+/// 列出为开发板（默认为设置中的开发板）导入的本地镜像
+#[command]
+pub fn list_local_images(
+    board: Option<String>,
+    local_images: State<'_, LocalImages>,
+    settings: State<'_, SettingsStore>,
+) -> Result<Vec<ImageVersion>> {
+    Ok(local_images.list(find_board(board, &settings)?.id))
+}
+
+/// 删除为开发板导入的本地镜像及其解包出的文件
+#[command]
+pub fn remove_local_image(
+    version: String,
+    board: Option<String>,
+    local_images: State<'_, LocalImages>,
+    settings: State<'_, SettingsStore>,
+) -> Result<()> {
+    local_images.remove(&crate::space::image_cache_dir(), find_board(board, &settings)?.id, &version)
+}
+
+/// 列出已下载的镜像缓存，最旧的在前，供空间不足时清理
+#[command]
+pub fn list_image_cache() -> Vec<crate::space::CacheEntry> {
+    crate::space::cache_entries(&crate::space::image_cache_dir(), None)
+}
+
+#[command]
+pub fn remove_cached_image(path: String) -> Result<()> {
+    crate::space::remove_cache_entry(&crate::space::image_cache_dir(), std::path::Path::new(&path))
+}
+
+/// 向设备发送任意 fastboot 命令（oem、getvar、set_active 等），返回所有响应行
+#[command]
+pub async fn fastboot_command(
+    device: USBDevice,
+    cmd: String,
+    transcript: State<'_, ConsoleTranscript>,
+) -> Result<Vec<ConsoleLine>> {
+    let label = format!("{}@{}", device.product_string, device.device_address);
+    let device_info: nusb::DeviceInfo = device.try_into()?;
+    let mut fb = FastBoot::from_info(&device_info)?;
+    let lines = crate::console::run_command(&mut fb, &label, &cmd).await;
+    transcript.push(&lines);
+    Ok(lines)
+}
+
+#[command]
+pub fn get_console_transcript(transcript: State<'_, ConsoleTranscript>) -> Vec<ConsoleLine> {
+    transcript.lines()
+}
+
+#[command]
+pub fn clear_console_transcript(transcript: State<'_, ConsoleTranscript>) {
+    transcript.clear();
+}
+
+#[command]
+pub fn export_console_transcript(
+    path: String,
+    transcript: State<'_, ConsoleTranscript>,
+) -> Result<()> {
+    transcript
+        .export(std::path::Path::new(&path))
+        .context(format!("Exporting transcript to {path}"))
+}
+
+/// 在所有匹配的设备上并行执行刷写计划
+#[command]
+pub fn start_batch(
+    plan: FlashPlan,
+    filter: DeviceFilter,
+    auto_join: Option<bool>,
+    app: tauri::AppHandle,
+    batch: State<'_, BatchManager>,
+) -> Result<Vec<BatchJob>> {
+    batch.start(UsbHost::new(app), plan, filter, auto_join.unwrap_or(false))
+}
+
+#[command]
+pub fn batch_status(batch: State<'_, BatchManager>) -> Vec<BatchJob> {
+    batch.jobs()
+}
+
+#[command]
+pub fn retry_failed_batch(app: tauri::AppHandle, batch: State<'_, BatchManager>) -> Result<usize> {
+    batch.retry_failed(UsbHost::new(app))
+}
+
+#[command]
+pub fn stop_batch(batch: State<'_, BatchManager>) {
+    batch.stop();
+}
+
+#[command]
+pub fn list_history(
+    limit: Option<usize>,
+    device_serial: Option<String>,
+    history: State<'_, HistoryStore>,
+) -> Result<Vec<HistoryEntry>> {
+    let mut entries = history.list()?;
+    if let Some(serial) = device_serial {
+        entries.retain(|e| e.device_serial.as_deref() == Some(serial.as_str()));
+    }
+    if let Some(limit) = limit {
+        entries.truncate(limit);
     }
-}
\ No newline at end of file
+    Ok(entries)
+}
+
+/// 导出历史记录，未指定格式时根据文件扩展名选择 CSV 或 JSON
+#[command]
+pub fn export_history(
+    path: String,
+    format: Option<ExportFormat>,
+    history: State<'_, HistoryStore>,
+) -> Result<()> {
+    let path = std::path::Path::new(&path);
+    let entries = history.list()?;
+    let format = format.unwrap_or_else(|| ExportFormat::from_path(path));
+    crate::history::export(&entries, path, format)
+}
@@ -0,0 +1,388 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, Lz4Decoder, XzDecoder, ZstdDecoder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWriteExt, BufReader};
+
+use crate::error::{Error, Result};
+use crate::image::ProgressType;
+use crate::pipeline::CountingReader;
+
+/// How many times its compressed size an extracted image is guessed to take when the
+/// format does not record it. Only zstd frames declare their content size; this is a
+/// guess, not a bound, as images that are mostly empty filesystem space compress far
+/// better, and [`crate::space::ensure_space`] only adds a fixed margin on top.
+pub const EXTRACT_RATIO_GUESS: u64 = 3;
+
+/// The compression formats images are shipped in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Zstd,
+    Xz,
+    Gzip,
+    Bzip2,
+    Lz4,
+}
+
+impl Compression {
+    const COMPRESSED: [Compression; 5] = [
+        Compression::Zstd,
+        Compression::Xz,
+        Compression::Gzip,
+        Compression::Bzip2,
+        Compression::Lz4,
+    ];
+
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Compression::None => &[],
+            Compression::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+            Compression::Xz => &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+            Compression::Gzip => &[0x1f, 0x8b],
+            Compression::Bzip2 => b"BZh",
+            // LZ4 frame format; the legacy format written by old `lz4 -l` is not supported
+            Compression::Lz4 => &[0x04, 0x22, 0x4d, 0x18],
+        }
+    }
+
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Compression::None => &[],
+            Compression::Zstd => &[".zst", ".zstd"],
+            Compression::Xz => &[".xz"],
+            Compression::Gzip => &[".gz"],
+            Compression::Bzip2 => &[".bz2"],
+            Compression::Lz4 => &[".lz4"],
+        }
+    }
+
+    /// The format whose magic `head` starts with.
+    pub fn detect(head: &[u8]) -> Self {
+        Self::COMPRESSED
+            .into_iter()
+            .find(|format| head.starts_with(format.magic()))
+            .unwrap_or(Compression::None)
+    }
+
+    /// The format a file name claims by its extension.
+    pub fn from_name(name: &str) -> Self {
+        Self::COMPRESSED
+            .into_iter()
+            .find(|format| format.extensions().iter().any(|ext| name.ends_with(ext)))
+            .unwrap_or(Compression::None)
+    }
+
+    /// The name of the decompressed file: `root.ext4.zst` becomes `root.ext4` and
+    /// `sdcard.img.bz2` becomes `sdcard.img`.
+    pub fn output_name(name: &str) -> &str {
+        Self::from_name(name)
+            .extensions()
+            .iter()
+            .find_map(|ext| name.strip_suffix(ext))
+            .filter(|stem| !stem.is_empty())
+            .unwrap_or(name)
+    }
+
+    /// Wrap `reader` in a decoder for this format, accepting concatenated members
+    /// (`cat a.gz b.gz`, `pzstd`, `pbzip2`). `None` passes the data through.
+    pub fn decoder<'a, R>(self, reader: R) -> Box<dyn AsyncRead + Send + Unpin + 'a>
+    where
+        R: AsyncBufRead + Send + Unpin + 'a,
+    {
+        macro_rules! decoder {
+            ($decoder:ident) => {{
+                let mut decoder = $decoder::new(reader);
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            }};
+        }
+        match self {
+            Compression::None => Box::new(reader),
+            Compression::Zstd => decoder!(ZstdDecoder),
+            Compression::Xz => decoder!(XzDecoder),
+            Compression::Gzip => decoder!(GzipDecoder),
+            Compression::Bzip2 => decoder!(BzDecoder),
+            Compression::Lz4 => decoder!(Lz4Decoder),
+        }
+    }
+}
+
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Decompress a local file into `output`, detecting the format from its content.
+/// Returns the format found; for [`Compression::None`] nothing is written.
+///
+/// Progress counts against `size`, the extracted size if known, or else against the
+/// compressed bytes read. The data goes to a temporary file that only replaces `output`
+/// once complete, so readers never see a partial file.
+pub async fn decompress_file<F>(
+    input: &Path,
+    output: &Path,
+    size: Option<u64>,
+    mut progress_callback: F,
+) -> Result<Compression>
+where
+    F: FnMut(&str, u64, u64, ProgressType),
+{
+    let source = tokio::fs::File::open(input).await?;
+    let total = source.metadata().await?.len();
+    let consumed = AtomicU64::new(0);
+    let mut reader = BufReader::new(CountingReader { inner: source, count: &consumed });
+    let head = tokio::io::AsyncBufReadExt::fill_buf(&mut reader).await?;
+    let format = Compression::detect(head);
+    if format == Compression::None {
+        return Ok(format);
+    }
+    let name = input.file_name().unwrap_or_default().to_string_lossy().to_string();
+    println!("Extracting {} ({format:?}) to {}", input.display(), output.display());
+    // Unique per call, as the same file may be extracted by two jobs at once
+    let id = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
+    let output_name = output.file_name().unwrap_or_default().to_string_lossy();
+    let temp = output.with_file_name(format!("{output_name}.{}-{id}.tmp", std::process::id()));
+    let result = async {
+        let mut decoder = format.decoder(reader);
+        let mut target = tokio::fs::File::create(&temp).await?;
+        let mut extracted = 0u64;
+        let mut buffer = vec![0u8; 1024 * 1024];
+        loop {
+            let n = tokio::io::AsyncReadExt::read(&mut decoder, &mut buffer)
+                .await
+                .map_err(|e| Error::invalid_image(format!("Failed to decompress {name}: {e}")))?;
+            if n == 0 {
+                break;
+            }
+            target.write_all(&buffer[..n]).await?;
+            extracted += n as u64;
+            match size {
+                Some(size) => progress_callback(&name, extracted, size.max(extracted), ProgressType::Extract),
+                None => progress_callback(&name, consumed.load(Ordering::Relaxed), total, ProgressType::Extract),
+            }
+        }
+        target.sync_all().await?;
+        tokio::fs::rename(&temp, output).await?;
+        progress_callback(&name, extracted, extracted, ProgressType::Extract);
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    result.map(|()| format)
+}
+
+/// The format of the local file `path`, from its content.
+pub async fn detect_file(path: &Path) -> Result<Compression> {
+    let mut reader = BufReader::new(tokio::fs::File::open(path).await?);
+    Ok(Compression::detect(tokio::io::AsyncBufReadExt::fill_buf(&mut reader).await?))
+}
+
+/// The exact size of the extracted local file `path` in `format`, where the format
+/// records it: only zstd frames declare their content size.
+pub async fn content_size(path: &Path, format: Compression) -> Option<u64> {
+    if format != Compression::Zstd {
+        return None;
+    }
+    let file = path.to_path_buf();
+    let size = tokio::task::spawn_blocking(move || {
+        crate::zstd_frame::decompressed_size(&mut std::fs::File::open(file)?)
+    })
+    .await
+    .ok()?;
+    size.inspect_err(|e| eprintln!("Cannot read the content size of {}: {e}", path.display()))
+        .ok()
+        .flatten()
+}
+
+/// Disk space the extracted copy of the local file `path` in `format` will take: its
+/// [`content_size`], or [`EXTRACT_RATIO_GUESS`] times the file.
+pub async fn extracted_size(path: &Path, format: Compression) -> Result<u64> {
+    let compressed = tokio::fs::metadata(path).await?.len();
+    Ok(content_size(path, format)
+        .await
+        .unwrap_or(compressed.saturating_mul(EXTRACT_RATIO_GUESS)))
+}
+
+/// Directory in `cache_dir` for the extracted copy of `path`, named after the path, size
+/// and modification time, so that a changed file or another one of the same name gets
+/// a fresh copy while an unchanged one is extracted only once.
+fn local_copy_dir(cache_dir: &Path, path: &Path) -> Result<PathBuf> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let source = std::path::absolute(path)?;
+    let mut hasher = Sha256::new();
+    hasher.update(source.to_string_lossy().as_bytes());
+    hasher.update(metadata.len().to_le_bytes());
+    hasher.update(modified.to_le_bytes());
+    Ok(cache_dir.join("local").join(&hex::encode(hasher.finalize())[..16]))
+}
+
+/// A local image ready to flash: `path` itself, or its decompressed copy in `cache_dir`
+/// if it is compressed. A copy extracted earlier is reused.
+pub async fn local_image<F>(path: &Path, cache_dir: &Path, progress_callback: F) -> Result<PathBuf>
+where
+    F: FnMut(&str, u64, u64, ProgressType),
+{
+    if path.starts_with(cache_dir.join("local")) {
+        return Ok(path.to_path_buf());
+    }
+    let format = detect_file(path).await?;
+    if format == Compression::None {
+        return Ok(path.to_path_buf());
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let dir = local_copy_dir(cache_dir, path)?;
+    let output = dir.join(Compression::output_name(&name));
+    if output.exists() {
+        println!("Using extracted copy {}", output.display());
+        return Ok(output);
+    }
+    let size = content_size(path, format).await;
+    crate::space::ensure_space(cache_dir, &dir, extracted_size(path, format).await?)?;
+    tokio::fs::create_dir_all(&dir).await?;
+    match decompress_file(path, &output, size, progress_callback).await? {
+        Compression::None => Ok(path.to_path_buf()),
+        _ => Ok(output),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::{BzEncoder, GzipEncoder, Lz4Encoder, XzEncoder, ZstdEncoder};
+    use tokio::io::AsyncReadExt;
+
+    /// `data` compressed with `format`.
+    pub(crate) async fn compress(format: Compression, data: &[u8]) -> Vec<u8> {
+        let mut encoder: Box<dyn AsyncRead + Unpin> = match format {
+            Compression::None => Box::new(data),
+            Compression::Zstd => Box::new(ZstdEncoder::new(data)),
+            Compression::Xz => Box::new(XzEncoder::new(data)),
+            Compression::Gzip => Box::new(GzipEncoder::new(data)),
+            Compression::Bzip2 => Box::new(BzEncoder::new(data)),
+            Compression::Lz4 => Box::new(Lz4Encoder::new(data)),
+        };
+        let mut compressed = Vec::new();
+        encoder.read_to_end(&mut compressed).await.unwrap();
+        compressed
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Compression::from_name("root-lpi4a.ext4.zst"), Compression::Zstd);
+        assert_eq!(Compression::from_name("sdcard-pioneer.img.bz2"), Compression::Bzip2);
+        assert_eq!(Compression::from_name("u-boot-with-spl.bin"), Compression::None);
+        assert_eq!(Compression::output_name("root-lpi4a.ext4.zst"), "root-lpi4a.ext4");
+        assert_eq!(Compression::output_name("sdcard-pioneer.img.bz2"), "sdcard-pioneer.img");
+        assert_eq!(Compression::output_name("boot.img.xz"), "boot.img");
+        assert_eq!(Compression::output_name("u-boot.bin"), "u-boot.bin");
+        assert_eq!(Compression::output_name(".gz"), ".gz");
+    }
+
+    #[tokio::test]
+    async fn test_detect_and_decode_all_formats() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 97) as u8).collect();
+        for format in Compression::COMPRESSED {
+            let mut compressed = compress(format, &data[..50_000]).await;
+            assert_eq!(Compression::detect(&compressed), format);
+            // Concatenated members decode as one stream
+            compressed.extend(compress(format, &data[50_000..]).await);
+            let mut decoded = Vec::new();
+            format.decoder(&compressed[..]).read_to_end(&mut decoded).await.unwrap();
+            assert!(decoded == data, "{format:?} round trip");
+        }
+        assert_eq!(Compression::detect(b"\x7fELF"), Compression::None);
+    }
+
+    #[tokio::test]
+    async fn test_decompress_file() {
+        let dir = std::env::temp_dir().join("revyos-imager-decompress");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = vec![3u8; 100_000];
+        // Named like a plain image on purpose: the content decides
+        let input = dir.join("sdcard.img");
+        std::fs::write(&input, compress(Compression::Xz, &data).await).unwrap();
+        let output = dir.join("sdcard.raw");
+        let mut reports = vec![];
+        let format = decompress_file(&input, &output, None, |_, done, total, _| reports.push((done, total)))
+            .await
+            .unwrap();
+        assert_eq!(format, Compression::Xz);
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert_eq!(reports.last(), Some(&(100_000, 100_000)));
+        // Without a known size, progress follows the compressed input and never passes it
+        let compressed = std::fs::metadata(&input).unwrap().len();
+        assert!(reports[..reports.len() - 1].iter().all(|&(done, total)| total == compressed && done <= total));
+
+        // A known size is what progress counts against from the start
+        let mut reports = vec![];
+        decompress_file(&input, &output, Some(100_000), |_, done, total, _| reports.push((done, total)))
+            .await
+            .unwrap();
+        assert!(reports.iter().all(|&(_, total)| total == 100_000));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        let plain = dir.join("u-boot.bin");
+        std::fs::write(&plain, b"not compressed").unwrap();
+        let format = decompress_file(&plain, &dir.join("u-boot.out"), None, |_, _, _, _| {}).await.unwrap();
+        assert_eq!(format, Compression::None);
+        assert!(!dir.join("u-boot.out").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_local_image() {
+        let dir = std::env::temp_dir().join("revyos-imager-local-image");
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = dir.join("cache");
+        for sub in ["a", "b"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+            let data = sub.repeat(1000).into_bytes();
+            std::fs::write(dir.join(sub).join("root.ext4.zst"), compress(Compression::Zstd, &data).await).unwrap();
+        }
+        let a = local_image(&dir.join("a").join("root.ext4.zst"), &cache, |_, _, _, _| {}).await.unwrap();
+        let b = local_image(&dir.join("b").join("root.ext4.zst"), &cache, |_, _, _, _| {}).await.unwrap();
+        // Same name, different files: each gets its own copy
+        assert_ne!(a, b);
+        assert_eq!(std::fs::read(&a).unwrap(), "a".repeat(1000).into_bytes());
+        assert_eq!(std::fs::read(&b).unwrap(), "b".repeat(1000).into_bytes());
+        // Extracted once, then reused
+        let mut extracted = false;
+        let again = local_image(&dir.join("a").join("root.ext4.zst"), &cache, |_, _, _, _| extracted = true)
+            .await
+            .unwrap();
+        assert_eq!((again, extracted), (a.clone(), false));
+        assert_eq!(local_image(&a, &cache, |_, _, _, _| {}).await.unwrap(), a);
+
+        let plain = dir.join("u-boot.bin");
+        std::fs::write(&plain, b"not compressed").unwrap();
+        assert_eq!(local_image(&plain, &cache, |_, _, _, _| {}).await.unwrap(), plain);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_extracted_size() {
+        let dir = std::env::temp_dir().join("revyos-imager-extracted-size");
+        std::fs::create_dir_all(&dir).unwrap();
+        let zstd = dir.join("boot.ext4.zst");
+        std::fs::write(&zstd, crate::zstd_frame::tests::raw_frame(&[7u8; 5000])).unwrap();
+        assert_eq!(extracted_size(&zstd, Compression::Zstd).await.unwrap(), 5000);
+
+        // Nothing records the content size, so it is guessed from the file size
+        let xz = dir.join("boot.ext4.xz");
+        let compressed = compress(Compression::Xz, &[7u8; 5000]).await;
+        std::fs::write(&xz, &compressed).unwrap();
+        assert_eq!(
+            extracted_size(&xz, Compression::Xz).await.unwrap(),
+            compressed.len() as u64 * EXTRACT_RATIO_GUESS
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
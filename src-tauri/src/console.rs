@@ -0,0 +1,188 @@
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::fastboot::{FastBoot, Response, ResponseKind, Transport};
+
+/// Commands that start a data phase; the console cannot feed or drain the payload.
+const DATA_PHASE_COMMANDS: [&str; 3] = ["download", "upload", "fetch"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ConsoleLineKind {
+    /// The command as sent to the device.
+    Command,
+    Info,
+    Text,
+    Okay,
+    Fail,
+    /// A transport or protocol error raised on the host side.
+    Error,
+}
+
+impl From<ResponseKind> for ConsoleLineKind {
+    fn from(kind: ResponseKind) -> Self {
+        match kind {
+            ResponseKind::Info => ConsoleLineKind::Info,
+            ResponseKind::Text => ConsoleLineKind::Text,
+            ResponseKind::Okay => ConsoleLineKind::Okay,
+            ResponseKind::Fail => ConsoleLineKind::Fail,
+            // Data phases are rejected before sending, so a DATA reply is unexpected
+            ResponseKind::Data => ConsoleLineKind::Error,
+        }
+    }
+}
+
+/// One line of console output.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsoleLine {
+    pub timestamp: DateTime<Utc>,
+    pub device: String,
+    pub kind: ConsoleLineKind,
+    pub message: String,
+}
+
+impl ConsoleLine {
+    fn new(device: &str, kind: ConsoleLineKind, message: impl Into<String>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            device: device.to_string(),
+            kind,
+            message: message.into(),
+        }
+    }
+
+    fn from_response(device: &str, response: &Response) -> Self {
+        let message = match response.kind {
+            ResponseKind::Data => format!("unexpected DATA{}", response.message),
+            _ => response.message.clone(),
+        };
+        Self::new(device, response.kind.into(), message)
+    }
+}
+
+/// Transcript of every console command issued during this app session.
+#[derive(Default)]
+pub struct ConsoleTranscript {
+    lines: Mutex<Vec<ConsoleLine>>,
+}
+
+impl ConsoleTranscript {
+    pub fn push(&self, lines: &[ConsoleLine]) {
+        self.lines.lock().unwrap().extend_from_slice(lines);
+    }
+
+    pub fn lines(&self) -> Vec<ConsoleLine> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.lines.lock().unwrap().clear();
+    }
+
+    /// Write the transcript to `path`, as JSON if it ends with `.json` or as plain text otherwise.
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let lines = self.lines();
+        let content = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_string_pretty(&lines)?
+        } else {
+            lines.iter().map(format_line).collect::<Vec<_>>().join("\n") + "\n"
+        };
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+fn format_line(line: &ConsoleLine) -> String {
+    let tag = match line.kind {
+        ConsoleLineKind::Command => ">",
+        ConsoleLineKind::Info => "INFO",
+        ConsoleLineKind::Text => "TEXT",
+        ConsoleLineKind::Okay => "OKAY",
+        ConsoleLineKind::Fail => "FAIL",
+        ConsoleLineKind::Error => "ERROR",
+    };
+    format!(
+        "[{}] [{}] {} {}",
+        line.timestamp.format("%Y-%m-%d %H:%M:%S%.3f"),
+        line.device,
+        tag,
+        line.message
+    )
+}
+
+/// Send a raw command and return the command line followed by every response line.
+///
+/// Host-side failures (timeouts, USB errors) are recorded as an `Error` line rather
+/// than returned, so the transcript always shows what happened.
+pub async fn run_command<T: Transport>(fb: &mut FastBoot<T>, device: &str, cmd: &str) -> Vec<ConsoleLine> {
+    let cmd = cmd.trim();
+    let mut lines = vec![ConsoleLine::new(device, ConsoleLineKind::Command, cmd)];
+    let verb = cmd.split(':').next().unwrap_or_default();
+    if DATA_PHASE_COMMANDS.contains(&verb) {
+        lines.push(ConsoleLine::new(
+            device,
+            ConsoleLineKind::Error,
+            format!("'{verb}' needs a data phase and cannot be sent from the console"),
+        ));
+        return lines;
+    }
+    let mut responses = Vec::new();
+    let result = fb
+        .command(cmd, |response| responses.push(ConsoleLine::from_response(device, response)))
+        .await;
+    lines.append(&mut responses);
+    if let Err(e) = result {
+        lines.push(ConsoleLine::new(device, ConsoleLineKind::Error, e.to_string()));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastboot::sim::SimTransport;
+
+    #[tokio::test]
+    async fn test_run_command_lines() {
+        let transport = SimTransport::new().reply("oem help", &["INFOoem poweroff", "OKAY"]);
+        let mut fb = FastBoot::new(transport);
+        let lines = run_command(&mut fb, "lpi4a", " oem help ").await;
+        let kinds: Vec<_> = lines.iter().map(|l| l.kind).collect();
+        assert_eq!(kinds, vec![ConsoleLineKind::Command, ConsoleLineKind::Info, ConsoleLineKind::Okay]);
+        assert_eq!(lines[0].message, "oem help");
+        assert_eq!(lines[1].message, "oem poweroff");
+
+        // Data phase commands are refused without being sent
+        let lines = run_command(&mut fb, "lpi4a", "download:00001000").await;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].kind, ConsoleLineKind::Error);
+    }
+
+    #[tokio::test]
+    async fn test_export_transcript() {
+        let transport = SimTransport::new().reply("getvar:product", &["OKAYlight-lpi4a"]);
+        let mut fb = FastBoot::new(transport);
+        let transcript = ConsoleTranscript::default();
+        transcript.push(&run_command(&mut fb, "lpi4a", "getvar:product").await);
+        transcript.push(&run_command(&mut fb, "lpi4a", "set_active:a").await);
+
+        let dir = std::env::temp_dir().join("revyos-imager-console-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let text_path = dir.join("transcript.txt");
+        transcript.export(&text_path).unwrap();
+        let text = std::fs::read_to_string(&text_path).unwrap();
+        assert!(text.contains("> getvar:product"));
+        assert!(text.contains("OKAY light-lpi4a"));
+        assert!(text.contains("FAIL unknown command"));
+
+        let json_path = dir.join("transcript.json");
+        transcript.export(&json_path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 4);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
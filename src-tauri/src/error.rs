@@ -0,0 +1,273 @@
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize};
+
+/// What went wrong, as a stable code the frontend can switch on.
+///
+/// The serialized names are part of the IPC contract: add new kinds freely,
+/// but never rename or reuse an existing one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The selected USB device is no longer attached (or has re-enumerated).
+    DeviceNotFound,
+    /// A USB transfer or device open failed.
+    Usb,
+    /// The device answered FAIL, or spoke something that is not fastboot.
+    Fastboot,
+    Timeout,
+    /// Could not reach the mirror at all.
+    Network,
+    /// The mirror answered with a non-success HTTP status.
+    Http,
+    ChecksumMismatch,
+    /// The file is not a usable image (bad sparse header, unexpected content...).
+    InvalidImage,
+    /// A mirror listing or other remote document could not be understood.
+    Parse,
+    NotFound,
+    Io,
+    InvalidInput,
+    Internal,
+    /// Not enough free disk space; see [`ErrorDetails::InsufficientSpace`].
+    InsufficientSpace,
+}
+
+impl ErrorKind {
+    /// Whether repeating the same operation unchanged has a reasonable chance to succeed.
+    pub fn retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorKind::DeviceNotFound
+                | ErrorKind::Usb
+                | ErrorKind::Timeout
+                | ErrorKind::Network
+                | ErrorKind::Http
+                | ErrorKind::ChecksumMismatch
+        )
+    }
+}
+
+/// Machine readable data for errors the frontend can offer a fix for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum ErrorDetails {
+    InsufficientSpace {
+        /// Directory the download would be written to.
+        path: String,
+        required: u64,
+        available: u64,
+        /// Cached downloads that could be deleted to make room, oldest first.
+        removable: Vec<crate::space::CacheEntry>,
+    },
+}
+
+/// The crate-wide error type.
+///
+/// Serialized to the frontend as `{ kind, message, retryable, context, details? }`,
+/// where `context` lists what we were doing, outermost first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub retryable: bool,
+    pub context: Vec<String>,
+    pub details: Option<ErrorDetails>,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            retryable: kind.retryable(),
+            context: Vec::new(),
+            details: None,
+        }
+    }
+
+    pub fn device_not_found() -> Self {
+        Self::new(ErrorKind::DeviceNotFound, "Device not found")
+    }
+
+    pub fn usb(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Usb, message)
+    }
+
+    pub fn fastboot(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Fastboot, message)
+    }
+
+    pub fn timeout(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Timeout, message)
+    }
+
+    pub fn invalid_image(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::InvalidImage, message)
+    }
+
+    pub fn parse(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Parse, message)
+    }
+
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::InvalidInput, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Internal, message)
+    }
+
+    /// An HTTP error status; only server side errors are worth retrying.
+    pub fn http(status: u16, url: &str) -> Self {
+        let mut error = Self::new(ErrorKind::Http, format!("HTTP {status} from {url}"));
+        error.retryable = status >= 500 || status == 408 || status == 429;
+        error
+    }
+
+    pub fn insufficient_space(
+        path: &std::path::Path,
+        required: u64,
+        available: u64,
+        removable: Vec<crate::space::CacheEntry>,
+    ) -> Self {
+        let mut error = Self::new(
+            ErrorKind::InsufficientSpace,
+            format!(
+                "Not enough space in {}: {} MiB needed, {} MiB free",
+                path.display(),
+                required.div_ceil(1 << 20),
+                available >> 20
+            ),
+        );
+        error.details = Some(ErrorDetails::InsufficientSpace {
+            path: path.to_string_lossy().to_string(),
+            required,
+            available,
+            removable,
+        });
+        error
+    }
+
+    pub fn with_context(mut self, context: impl Into<String>) -> Self {
+        self.context.insert(0, context.into());
+        self
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for context in &self.context {
+            write!(f, "{context}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Serialize for Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Error", 5)?;
+        state.serialize_field("kind", &self.kind)?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("retryable", &self.retryable)?;
+        state.serialize_field("context", &self.context)?;
+        match &self.details {
+            Some(details) => state.serialize_field("details", details)?,
+            None => state.skip_field("details")?,
+        }
+        state.end()
+    }
+}
+
+/// Attach context to the error of a `Result`, like `anyhow::Context`.
+pub trait ResultExt<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| e.into().with_context(context))
+    }
+
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.into().with_context(f()))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        let kind = match e.kind() {
+            std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+            std::io::ErrorKind::TimedOut => ErrorKind::Timeout,
+            _ => ErrorKind::Io,
+        };
+        Self::new(kind, e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if let Some(status) = e.status() {
+            let url = e.url().map(|u| u.to_string()).unwrap_or_default();
+            return Self::http(status.as_u16(), &url);
+        }
+        let kind = if e.is_timeout() {
+            ErrorKind::Timeout
+        } else if e.is_decode() {
+            ErrorKind::Parse
+        } else {
+            ErrorKind::Network
+        };
+        Self::new(kind, e.to_string())
+    }
+}
+
+impl From<nusb::transfer::TransferError> for Error {
+    fn from(e: nusb::transfer::TransferError) -> Self {
+        match e {
+            nusb::transfer::TransferError::Disconnected => Self::device_not_found(),
+            e => Self::usb(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::parse(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let error = Error::http(503, "https://mirror.iscas.ac.cn/revyos/")
+            .with_context("Fetching image list");
+        let value = serde_json::to_value(&error).unwrap();
+        assert_eq!(value["kind"], "http");
+        assert_eq!(value["retryable"], true);
+        assert_eq!(value["context"][0], "Fetching image list");
+        assert_eq!(
+            value["message"],
+            "Fetching image list: HTTP 503 from https://mirror.iscas.ac.cn/revyos/"
+        );
+        assert!(!Error::http(404, "").retryable);
+    }
+
+    #[test]
+    fn test_context_order() {
+        let result: Result<()> = Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+        let error = result
+            .context("Opening boot.ext4")
+            .context("Flashing boot")
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::NotFound);
+        assert_eq!(error.context, vec!["Flashing boot", "Opening boot.ext4"]);
+    }
+}
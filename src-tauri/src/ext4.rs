@@ -0,0 +1,1782 @@
+//! Offline editing of ext4 images: just enough to add or replace small files and
+//! create directories in a cleanly unmounted filesystem, keeping metadata checksums
+//! and htree directory indexes valid.
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::error::{Error, Result, ResultExt};
+use crate::sparse::SparseWriter;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+const RESIZE_INODE: u32 = 7;
+const MAX_SYMLINKS: usize = 40;
+/// Files are read whole; anything bigger is not a configuration file.
+const MAX_READ: u64 = 64 << 20;
+/// Free blocks a new last group must have to be worth adding, as in resize2fs.
+const MIN_LAST_GROUP_FREE: u64 = 50;
+/// How much of the image file is copied per sparse chunk.
+const COPY_BLOCKS_LEN: usize = 4 << 20;
+
+const COMPAT_RESIZE_INODE: u32 = 0x10;
+const COMPAT_DIR_INDEX: u32 = 0x20;
+const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+/// sparse_super, large_file, huge_file, gdt_csum, dir_nlink, extra_isize,
+/// metadata_csum and verity. Quota is left out: its usage records would go stale.
+const RO_COMPAT_SUPPORTED: u32 = 0x1 | 0x2 | 0x8 | RO_COMPAT_GDT_CSUM | 0x20 | 0x40 | RO_COMPAT_METADATA_CSUM | 0x8000;
+const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+const BG_INODE_UNINIT: u16 = 0x1;
+const BG_BLOCK_UNINIT: u16 = 0x2;
+
+const INDEX_FL: u32 = 0x1000;
+const HUGE_FILE_FL: u32 = 0x40000;
+const EXTENTS_FL: u32 = 0x80000;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const MAX_EXTENT_LEN: u64 = 32768;
+const INODE_EXTENTS: usize = 4;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFREG: u16 = 0o100000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFLNK: u16 = 0o120000;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const DIRENT_TAIL_LEN: usize = 12;
+const DX_HASH_HALF_MD4: u8 = 1;
+const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+
+/// A group descriptor field split into low and high halves.
+struct Field {
+    lo: usize,
+    hi: usize,
+    width: usize,
+}
+
+const BG_BLOCK_BITMAP: Field = Field { lo: 0x00, hi: 0x20, width: 4 };
+const BG_INODE_BITMAP: Field = Field { lo: 0x04, hi: 0x24, width: 4 };
+const BG_INODE_TABLE: Field = Field { lo: 0x08, hi: 0x28, width: 4 };
+const BG_FREE_BLOCKS: Field = Field { lo: 0x0c, hi: 0x2c, width: 2 };
+const BG_FREE_INODES: Field = Field { lo: 0x0e, hi: 0x2e, width: 2 };
+const BG_USED_DIRS: Field = Field { lo: 0x10, hi: 0x30, width: 2 };
+const BG_BLOCK_BITMAP_CSUM: Field = Field { lo: 0x18, hi: 0x38, width: 2 };
+const BG_INODE_BITMAP_CSUM: Field = Field { lo: 0x1a, hi: 0x3a, width: 2 };
+const BG_ITABLE_UNUSED: Field = Field { lo: 0x1c, hi: 0x32, width: 2 };
+const BG_FLAGS: usize = 0x12;
+const BG_CHECKSUM: usize = 0x1e;
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn set16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32C without the final inversion, so it can be chained the way ext4 does.
+fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// The CRC16 (ANSI, reflected) used by the older gdt_csum feature.
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xa001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Pack a name into hash input words the way ext4's `str2hashbuf` does.
+fn str2hashbuf(msg: &[u8], unsigned: bool) -> [u32; 8] {
+    let len = msg.len() as u32;
+    let mut pad = len | (len << 8);
+    pad |= pad << 16;
+    let mut val = pad;
+    let mut words = [pad; 8];
+    let mut n = 0;
+    for (i, &byte) in msg.iter().take(32).enumerate() {
+        let c = if unsigned { byte as u32 } else { byte as i8 as i32 as u32 };
+        val = c.wrapping_add(val << 8);
+        if i % 4 == 3 {
+            words[n] = val;
+            n += 1;
+            val = pad;
+        }
+    }
+    if n < 8 {
+        words[n] = val;
+    }
+    words
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0x5a82_7999;
+    const K3: u32 = 0x6ed9_eba1;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+    macro_rules! round {
+        ($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a.wrapping_add($f($b, $c, $d)).wrapping_add($x).rotate_left($s)
+        };
+    }
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+    for (word, value) in buf.iter_mut().zip([a, b, c, d]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+/// The htree hash of a directory entry name.
+fn half_md4_hash(name: &[u8], seed: [u32; 4], unsigned: bool) -> u32 {
+    let mut buf = if seed == [0; 4] { [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476] } else { seed };
+    let mut rest = name;
+    while !rest.is_empty() {
+        half_md4_transform(&mut buf, &str2hashbuf(rest, unsigned));
+        rest = &rest[rest.len().min(32)..];
+    }
+    // The last value is reserved as the end-of-directory marker
+    match buf[1] & !1 {
+        0xffff_fffe => 0xffff_fffc,
+        hash => hash,
+    }
+}
+
+fn rec_len(name_len: usize) -> usize {
+    (8 + name_len + 3) & !3
+}
+
+/// Owner and permissions for files and directories that do not exist yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Attributes {
+    pub fn root(mode: u16) -> Self {
+        Self { mode, uid: 0, gid: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Checksum {
+    None,
+    /// gdt_csum: CRC16 over group descriptors only.
+    Gdt,
+    /// metadata_csum: CRC32C over everything, seeded from the UUID.
+    Metadata { seed: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Bitmap {
+    Block,
+    Inode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Extent {
+    logical: u64,
+    start: u64,
+    len: u64,
+    uninit: bool,
+}
+
+fn map_block(extents: &[Extent], logical: u64) -> Option<u64> {
+    extents
+        .iter()
+        .find(|e| (e.logical..e.logical + e.len).contains(&logical))
+        .map(|e| e.start + logical - e.logical)
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    number: u32,
+    raw: Vec<u8>,
+}
+
+impl Inode {
+    fn mode(&self) -> u16 {
+        le16(&self.raw, 0x00)
+    }
+
+    fn file_type(&self) -> u16 {
+        self.mode() & S_IFMT
+    }
+
+    fn size(&self) -> u64 {
+        le32(&self.raw, 0x04) as u64 | (le32(&self.raw, 0x6c) as u64) << 32
+    }
+
+    fn set_size(&mut self, size: u64) {
+        set32(&mut self.raw, 0x04, size as u32);
+        set32(&mut self.raw, 0x6c, (size >> 32) as u32);
+    }
+
+    fn links(&self) -> u16 {
+        le16(&self.raw, 0x1a)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        set16(&mut self.raw, 0x1a, links);
+    }
+
+    /// Allocated space in 512 byte sectors.
+    fn sectors(&self) -> u64 {
+        le32(&self.raw, 0x1c) as u64 | (le16(&self.raw, 0x74) as u64) << 32
+    }
+
+    fn set_sectors(&mut self, sectors: u64) {
+        set32(&mut self.raw, 0x1c, sectors as u32);
+        set16(&mut self.raw, 0x74, (sectors >> 32) as u16);
+    }
+
+    fn flags(&self) -> u32 {
+        le32(&self.raw, 0x20)
+    }
+
+    fn generation(&self) -> u32 {
+        le32(&self.raw, 0x64)
+    }
+
+    fn extra_isize(&self) -> usize {
+        if self.raw.len() > 128 { le16(&self.raw, 0x80) as usize } else { 0 }
+    }
+
+    fn touch(&mut self) {
+        let now = chrono::Utc::now().timestamp() as u32;
+        set32(&mut self.raw, 0x0c, now);
+        set32(&mut self.raw, 0x10, now);
+        // Nanoseconds and epoch bits of ctime and mtime
+        if self.extra_isize() >= 12 {
+            set32(&mut self.raw, 0x84, 0);
+            set32(&mut self.raw, 0x88, 0);
+        }
+    }
+}
+
+/// One entry of a directory block, as found at `offset`.
+struct Dirent {
+    offset: usize,
+    inode: u32,
+    rec_len: usize,
+    name_len: usize,
+    file_type: u8,
+}
+
+impl Dirent {
+    fn name<'a>(&self, block: &'a [u8]) -> &'a [u8] {
+        &block[self.offset + 8..self.offset + 8 + self.name_len]
+    }
+}
+
+fn dirents(block: &[u8]) -> Result<Vec<Dirent>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= block.len() {
+        let rec_len = le16(block, offset + 4) as usize;
+        let name_len = block[offset + 6] as usize;
+        if rec_len < 8 || !rec_len.is_multiple_of(4) || offset + rec_len > block.len() || 8 + name_len > rec_len {
+            return Err(Error::invalid_image(format!("Corrupt directory entry at offset {offset}")));
+        }
+        entries.push(Dirent { offset, inode: le32(block, offset), rec_len, name_len, file_type: block[offset + 7] });
+        offset += rec_len;
+    }
+    Ok(entries)
+}
+
+fn write_dirent(block: &mut [u8], at: usize, inode: u32, len: usize, name: &[u8], file_type: u8) {
+    set32(block, at, inode);
+    set16(block, at + 4, len as u16);
+    block[at + 6] = name.len() as u8;
+    block[at + 7] = file_type;
+    block[at + 8..at + 8 + name.len()].copy_from_slice(name);
+    block[at + 8 + name.len()..at + rec_len(name.len())].fill(0);
+}
+
+fn split_path(path: &str) -> Result<Vec<&str>> {
+    if !path.starts_with('/') {
+        return Err(Error::invalid_input(format!("{path} is not an absolute path")));
+    }
+    Ok(path.split('/').filter(|c| !c.is_empty() && *c != ".").collect())
+}
+
+/// An ext4 image opened for editing. Changes to allocation metadata are kept in
+/// memory until [`Ext4::flush`], so an image dropped without flushing is corrupt.
+pub struct Ext4 {
+    file: File,
+    sb: Vec<u8>,
+    gdt: Vec<u8>,
+    dirty_groups: BTreeSet<u32>,
+    bitmaps: BTreeMap<(Bitmap, u32), Vec<u8>>,
+    dirty_bitmaps: BTreeSet<(Bitmap, u32)>,
+    block_size: usize,
+    blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    inodes_per_group: u32,
+    inode_size: usize,
+    desc_size: usize,
+    groups: u32,
+    checksum: Checksum,
+    /// Blocks backed by the image file.
+    file_blocks: u64,
+    /// Whether writes go to the file. Otherwise, and always for blocks past the end
+    /// of the file, they are kept in `overlay`.
+    write_through: bool,
+    overlay: BTreeMap<u64, Vec<u8>>,
+    /// Whether the backup superblocks and descriptor tables need rewriting.
+    backups_dirty: bool,
+}
+
+impl Ext4 {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with(path, true)
+    }
+
+    /// Open an image without modifying it, e.g. one in the download cache. Changes
+    /// are kept in memory; see [`Ext4::write_sparse`].
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        Self::open_with(path, false)
+    }
+
+    fn open_with(path: &Path, writable: bool) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut sb = vec![0u8; 1024];
+        file.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        file.read_exact(&mut sb)
+            .map_err(|_| Error::invalid_image(format!("{} is too small for ext4", path.display())))?;
+        if le16(&sb, 0x38) != MAGIC {
+            return Err(Error::invalid_image(format!("{} is not an ext4 filesystem", path.display())));
+        }
+        let incompat = le32(&sb, 0x60);
+        let ro_compat = le32(&sb, 0x64);
+        if incompat & !INCOMPAT_SUPPORTED != 0 || ro_compat & !RO_COMPAT_SUPPORTED != 0 {
+            return Err(Error::invalid_image(format!(
+                "{} uses unsupported ext4 features (incompat {:#x}, ro_compat {:#x})",
+                path.display(),
+                incompat & !INCOMPAT_SUPPORTED,
+                ro_compat & !RO_COMPAT_SUPPORTED
+            )));
+        }
+        if incompat & (INCOMPAT_FILETYPE | INCOMPAT_EXTENTS) != INCOMPAT_FILETYPE | INCOMPAT_EXTENTS {
+            return Err(Error::invalid_image(format!("{} is ext2/3, not ext4", path.display())));
+        }
+        // Valid and without errors; a dirty journal would also be caught by RECOVER above
+        if le16(&sb, 0x3a) & 0x3 != 0x1 {
+            return Err(Error::invalid_image(format!("{} was not cleanly unmounted; run e2fsck first", path.display())));
+        }
+        let checksum = if ro_compat & RO_COMPAT_METADATA_CSUM != 0 {
+            if crc32c(!0, &sb[..0x3fc]) != le32(&sb, 0x3fc) {
+                return Err(Error::invalid_image(format!("{} has a bad superblock checksum", path.display())));
+            }
+            let seed = if incompat & INCOMPAT_CSUM_SEED != 0 { le32(&sb, 0x270) } else { crc32c(!0, &sb[0x68..0x78]) };
+            Checksum::Metadata { seed }
+        } else if ro_compat & RO_COMPAT_GDT_CSUM != 0 {
+            Checksum::Gdt
+        } else {
+            Checksum::None
+        };
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let block_size = 1024usize << le32(&sb, 0x18).min(6);
+        let blocks_count = le32(&sb, 0x04) as u64 | if is_64bit { (le32(&sb, 0x150) as u64) << 32 } else { 0 };
+        let first_data_block = le32(&sb, 0x14) as u64;
+        let blocks_per_group = le32(&sb, 0x20) as u64;
+        let inodes_per_group = le32(&sb, 0x28);
+        let inode_size = if le32(&sb, 0x4c) == 0 { 128 } else { le16(&sb, 0x58) as usize };
+        let desc_size = if is_64bit { le16(&sb, 0xfe) as usize } else { 32 };
+        if blocks_per_group == 0 || inodes_per_group == 0 || desc_size < 32 || inode_size < 128 {
+            return Err(Error::invalid_image(format!("{} has a corrupt superblock", path.display())));
+        }
+        let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group) as u32;
+        let mut gdt = vec![0u8; groups as usize * desc_size];
+        file.seek(SeekFrom::Start((first_data_block + 1) * block_size as u64))?;
+        file.read_exact(&mut gdt)?;
+        let file_blocks = file.metadata()?.len() / block_size as u64;
+        let fs = Self {
+            file,
+            sb,
+            gdt,
+            dirty_groups: BTreeSet::new(),
+            bitmaps: BTreeMap::new(),
+            dirty_bitmaps: BTreeSet::new(),
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            desc_size,
+            groups,
+            checksum,
+            file_blocks,
+            write_through: writable,
+            overlay: BTreeMap::new(),
+            backups_dirty: false,
+        };
+        if fs.checksum != Checksum::None && fs.group_checksum(0) != le16(fs.gd(0), BG_CHECKSUM) {
+            return Err(Error::invalid_image(format!("{} has a bad group descriptor checksum", path.display())));
+        }
+        Ok(fs)
+    }
+
+    /// The content of the file at `path`, following symlinks, or `None` if it does
+    /// not exist.
+    pub fn read_file(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        let Some(inode) = self.resolve(path, true)? else {
+            return Ok(None);
+        };
+        if inode.file_type() != S_IFREG {
+            return Err(Error::invalid_input(format!("{path} is not a regular file")));
+        }
+        self.read_data(&inode).map(Some)
+    }
+
+    /// Create `path` and any missing parents with `attrs`. Existing directories are
+    /// left as they are.
+    pub fn create_dir_all(&mut self, path: &str, attrs: Attributes) -> Result<()> {
+        let mut current = self.read_inode(ROOT_INODE)?;
+        let mut walked = String::new();
+        for name in split_path(path)? {
+            walked.push('/');
+            walked.push_str(name);
+            current = match self.lookup(&current, name.as_bytes())? {
+                Some(_) => self
+                    .resolve(&walked, true)?
+                    .ok_or_else(|| Error::invalid_input(format!("{walked} is a dangling symlink")))?,
+                None => self.mkdir(&mut current, name.as_bytes(), attrs)?,
+            };
+            if current.file_type() != S_IFDIR {
+                return Err(Error::invalid_input(format!("{walked} is not a directory")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `data` to the file at `path`. An existing file keeps its owner and mode;
+    /// a new one is created with `attrs`. The parent directory must exist.
+    pub fn write_file(&mut self, path: &str, data: &[u8], attrs: Attributes) -> Result<()> {
+        let (parent_path, name) = path
+            .rsplit_once('/')
+            .filter(|(_, name)| !name.is_empty())
+            .ok_or_else(|| Error::invalid_input(format!("{path} is not a file path")))?;
+        let parent_path = if parent_path.is_empty() { "/" } else { parent_path };
+        let mut parent = self
+            .resolve(parent_path, true)?
+            .filter(|inode| inode.file_type() == S_IFDIR)
+            .ok_or_else(|| Error::invalid_input(format!("{parent_path} is not a directory in the image")))?;
+        match self.lookup(&parent, name.as_bytes())? {
+            Some((number, _)) => {
+                let mut inode = self.read_inode(number)?;
+                if inode.file_type() != S_IFREG {
+                    return Err(Error::invalid_input(format!("{path} is not a regular file")));
+                }
+                self.set_data(&mut inode, data)?;
+                self.write_inode(&mut inode)
+            }
+            None => {
+                let number = self.allocate_inode(false, self.group_of(parent.number))?;
+                let mut inode = self.new_inode(number, S_IFREG | (attrs.mode & 0o7777), attrs);
+                self.set_data(&mut inode, data)?;
+                self.write_inode(&mut inode)?;
+                self.add_entry(&mut parent, name.as_bytes(), number, FT_REG_FILE)
+            }
+        }
+    }
+
+    /// Write back the allocation bitmaps, group descriptors and superblock.
+    pub fn flush(&mut self) -> Result<()> {
+        let dirty_bitmaps = std::mem::take(&mut self.dirty_bitmaps);
+        for (kind, group) in dirty_bitmaps {
+            let data = self.bitmaps[&(kind, group)].clone();
+            let (location, checksum_field, checksum_len) = match kind {
+                Bitmap::Block => (BG_BLOCK_BITMAP, BG_BLOCK_BITMAP_CSUM, le32(&self.sb, 0x24) as usize / 8),
+                Bitmap::Inode => (BG_INODE_BITMAP, BG_INODE_BITMAP_CSUM, self.inodes_per_group as usize / 8),
+            };
+            self.write_block(self.gd_get(group, &location), &data)?;
+            if let Checksum::Metadata { seed } = self.checksum {
+                let csum = crc32c(seed, &data[..checksum_len]);
+                // Only descriptors of 64 bytes carry the upper half
+                let csum = if self.desc_size >= 64 { csum } else { csum & 0xffff };
+                self.gd_set(group, &checksum_field, csum as u64);
+            }
+        }
+        for group in std::mem::take(&mut self.dirty_groups) {
+            if self.checksum != Checksum::None {
+                let csum = self.group_checksum(group);
+                let at = group as usize * self.desc_size + BG_CHECKSUM;
+                set16(&mut self.gdt, at, csum);
+            }
+        }
+        // Whole blocks, so unused descriptor slots do not keep stale bytes
+        let mut gdt = self.gdt.clone();
+        gdt.resize(self.desc_blocks(self.groups) as usize * self.block_size, 0);
+        self.write_block(self.first_data_block + 1, &gdt)?;
+        seal_superblock(self.checksum, &mut self.sb);
+        let sb = self.sb.clone();
+        self.write_bytes(SUPERBLOCK_OFFSET, &sb)?;
+        if std::mem::take(&mut self.backups_dirty) {
+            let backups: Vec<u32> = (1..self.groups).filter(|&g| self.has_super(g)).collect();
+            for group in backups {
+                let mut backup = sb.clone();
+                set16(&mut backup, 0x5a, group as u16);
+                seal_superblock(self.checksum, &mut backup);
+                let first = self.group_first_block(group);
+                self.write_bytes(first * self.block_size as u64, &backup)?;
+                self.write_block(first + 1, &gdt)?;
+            }
+        }
+        if self.write_through {
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Grow the filesystem to fill `size` bytes, the way an offline resize2fs does
+    /// when there are enough reserved descriptor blocks: new groups are added with
+    /// their metadata, and no data moves. Returns the new size in blocks, which is
+    /// unchanged if `size` is not larger.
+    pub fn grow(&mut self, size: u64) -> Result<u64> {
+        let compat = le32(&self.sb, 0x5c);
+        if compat & COMPAT_SPARSE_SUPER2 != 0 {
+            return Err(Error::invalid_image("Filesystems with sparse_super2 cannot be grown"));
+        }
+        if self.checksum == Checksum::None {
+            return Err(Error::invalid_image("Only filesystems with uninit_bg or metadata_csum can be grown"));
+        }
+        if self.gd_flags(self.groups - 1) & BG_BLOCK_UNINIT != 0 {
+            return Err(Error::invalid_image("The last block group is not initialized"));
+        }
+        let bs = self.block_size as u64;
+        let max_blocks = if le32(&self.sb, 0x60) & INCOMPAT_64BIT != 0 { 1 << 48 } else { u32::MAX as u64 };
+        let old_blocks = self.blocks_count;
+        let old_groups = self.groups;
+        let old_desc = self.desc_blocks(old_groups);
+        let reserved = if compat & COMPAT_RESIZE_INODE != 0 { le16(&self.sb, 0xce) as u64 } else { 0 };
+        let itable_blocks = (self.inodes_per_group as u64 * self.inode_size as u64).div_ceil(bs);
+
+        // Drop a last group too small to hold its own metadata and some data
+        let mut new_blocks = (size / bs).min(max_blocks);
+        let new_groups = loop {
+            if new_blocks <= old_blocks {
+                return Ok(old_blocks);
+            }
+            let groups = (new_blocks - self.first_data_block).div_ceil(self.blocks_per_group);
+            let last = groups as u32 - 1;
+            if last < old_groups {
+                break groups;
+            }
+            let sb_blocks = if self.has_super(last) { 1 + old_desc + reserved } else { 0 };
+            let last_len = new_blocks - self.group_first_block(last);
+            if last_len >= sb_blocks + 2 + itable_blocks + MIN_LAST_GROUP_FREE {
+                break groups;
+            }
+            new_blocks = self.group_first_block(last);
+        };
+        if new_groups * self.inodes_per_group as u64 > u32::MAX as u64 {
+            return Err(Error::invalid_input("The filesystem would have too many inodes"));
+        }
+        let new_groups = new_groups as u32;
+        let new_desc = self.desc_blocks(new_groups);
+        let extra = new_desc - old_desc;
+        if extra > reserved {
+            let max_groups = (old_desc + reserved) * bs / self.desc_size as u64;
+            return Err(Error::invalid_image(format!(
+                "The filesystem can only grow to {} blocks; resize it on the device instead",
+                self.first_data_block + max_groups * self.blocks_per_group
+            )));
+        }
+        if reserved > 0 {
+            self.update_resize_inode(old_groups, new_groups, old_desc, extra, reserved)?;
+        }
+
+        // The old last group gets the blocks up to its end
+        let last_old = old_groups - 1;
+        let old_len = self.blocks_in_group(last_old);
+        self.blocks_count = new_blocks;
+        let new_len = self.blocks_in_group(last_old);
+        if new_len > old_len {
+            let bitmap = self.bitmap(Bitmap::Block, last_old)?;
+            for bit in old_len as usize..new_len as usize {
+                bitmap[bit / 8] &= !(1 << (bit % 8));
+            }
+            self.dirty_bitmaps.insert((Bitmap::Block, last_old));
+            self.adjust_free_blocks(last_old, (new_len - old_len) as i64);
+        }
+
+        // New groups carry their bitmaps and inode table themselves; their inodes
+        // stay uninitialized so the inode tables need not be written
+        let new_reserved = reserved - extra;
+        self.gdt.resize(new_groups as usize * self.desc_size, 0);
+        self.groups = new_groups;
+        for group in old_groups..new_groups {
+            let first = self.group_first_block(group);
+            let len = self.blocks_in_group(group);
+            let block_bitmap = first + if self.has_super(group) { 1 + new_desc + new_reserved } else { 0 };
+            let used = block_bitmap + 2 + itable_blocks - first;
+            let mut bitmap = vec![0u8; self.block_size];
+            for bit in (0..used as usize).chain(len as usize..self.block_size * 8) {
+                bitmap[bit / 8] |= 1 << (bit % 8);
+            }
+            self.bitmaps.insert((Bitmap::Block, group), bitmap);
+            self.dirty_bitmaps.insert((Bitmap::Block, group));
+            self.gd_set(group, &BG_BLOCK_BITMAP, block_bitmap);
+            self.gd_set(group, &BG_INODE_BITMAP, block_bitmap + 1);
+            self.gd_set(group, &BG_INODE_TABLE, block_bitmap + 2);
+            self.gd_set(group, &BG_FREE_INODES, self.inodes_per_group as u64);
+            self.gd_set(group, &BG_ITABLE_UNUSED, self.inodes_per_group as u64);
+            set16(&mut self.gdt, group as usize * self.desc_size + BG_FLAGS, BG_INODE_UNINIT);
+            self.adjust_free_blocks(group, (len - used) as i64);
+        }
+
+        let added_groups = (new_groups - old_groups) as u64;
+        let r_blocks = le32(&self.sb, 0x08) as u64 | (le32(&self.sb, 0x154) as u64) << 32;
+        let r_blocks = (r_blocks as u128 * new_blocks as u128 / old_blocks as u128) as u64;
+        set32(&mut self.sb, 0x04, new_blocks as u32);
+        set32(&mut self.sb, 0x150, (new_blocks >> 32) as u32);
+        set32(&mut self.sb, 0x08, r_blocks as u32);
+        set32(&mut self.sb, 0x154, (r_blocks >> 32) as u32);
+        set32(&mut self.sb, 0x00, new_groups * self.inodes_per_group);
+        let free_inodes = le32(&self.sb, 0x10) as u64 + added_groups * self.inodes_per_group as u64;
+        set32(&mut self.sb, 0x10, free_inodes as u32);
+        set16(&mut self.sb, 0xce, new_reserved as u16);
+        self.backups_dirty = true;
+        println!("Grew filesystem from {old_blocks} to {new_blocks} blocks ({old_groups} to {new_groups} groups)");
+        Ok(new_blocks)
+    }
+
+    /// Write the filesystem with all changes as a sparse image; blocks past the end
+    /// of the image file that were never written are left out as DONT_CARE.
+    pub fn write_sparse(&mut self, output: &Path) -> Result<()> {
+        let file = File::create(output).with_context(|| format!("Failed to create {}", output.display()))?;
+        let mut writer = SparseWriter::new(std::io::BufWriter::new(file), self.block_size as u32)?;
+        let in_file = self.file_blocks.min(self.blocks_count);
+        let step = (COPY_BLOCKS_LEN / self.block_size) as u64;
+        let mut block = 0;
+        while block < in_file {
+            let count = step.min(in_file - block);
+            writer.raw(&self.read_blocks(block, count)?)?;
+            block += count;
+        }
+        // Consecutive overlay blocks go out as one run
+        let mut run = Vec::new();
+        for (&number, data) in self.overlay.range(in_file..self.blocks_count) {
+            if number != block + (run.len() / self.block_size) as u64 {
+                if !run.is_empty() {
+                    writer.raw(&run)?;
+                    block += (run.len() / self.block_size) as u64;
+                    run.clear();
+                }
+                writer.dont_care(number - block)?;
+                block = number;
+            }
+            run.extend_from_slice(data);
+        }
+        writer.raw(&run)?;
+        block += (run.len() / self.block_size) as u64;
+        writer.dont_care(self.blocks_count - block)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        self.read_blocks(block, 1)
+    }
+
+    fn read_blocks(&mut self, start: u64, count: u64) -> Result<Vec<u8>> {
+        if start + count > self.blocks_count {
+            return Err(Error::invalid_image(format!("Block {} is beyond the end of the filesystem", start + count - 1)));
+        }
+        let bs = self.block_size;
+        let mut data = vec![0u8; count as usize * bs];
+        let in_file = self.file_blocks.saturating_sub(start).min(count) as usize * bs;
+        if in_file > 0 {
+            self.file.seek(SeekFrom::Start(start * bs as u64))?;
+            self.file.read_exact(&mut data[..in_file])?;
+        }
+        for (&block, content) in self.overlay.range(start..start + count) {
+            let at = (block - start) as usize * bs;
+            data[at..at + bs].copy_from_slice(content);
+        }
+        Ok(data)
+    }
+
+    /// Write whole blocks starting at `block`.
+    fn write_block(&mut self, block: u64, data: &[u8]) -> Result<()> {
+        let bs = self.block_size;
+        let in_file = if self.write_through {
+            (self.file_blocks.saturating_sub(block) as usize * bs).min(data.len())
+        } else {
+            0
+        };
+        if in_file > 0 {
+            self.file.seek(SeekFrom::Start(block * bs as u64))?;
+            self.file.write_all(&data[..in_file])?;
+        }
+        for (i, chunk) in data[in_file..].chunks(bs).enumerate() {
+            let mut chunk = chunk.to_vec();
+            chunk.resize(bs, 0);
+            self.overlay.insert(block + (in_file / bs + i) as u64, chunk);
+        }
+        Ok(())
+    }
+
+    /// Write `data` at byte `offset`, within one block.
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let bs = self.block_size as u64;
+        let mut block = self.read_block(offset / bs)?;
+        let at = (offset % bs) as usize;
+        block[at..at + data.len()].copy_from_slice(data);
+        self.write_block(offset / bs, &block)
+    }
+
+    fn gd(&self, group: u32) -> &[u8] {
+        let at = group as usize * self.desc_size;
+        &self.gdt[at..at + self.desc_size]
+    }
+
+    fn gd_get(&self, group: u32, field: &Field) -> u64 {
+        let gd = self.gd(group);
+        let read = |offset| match field.width {
+            4 => le32(gd, offset) as u64,
+            _ => le16(gd, offset) as u64,
+        };
+        let hi = if self.desc_size >= 64 { read(field.hi) } else { 0 };
+        read(field.lo) | hi << (field.width * 8)
+    }
+
+    fn gd_set(&mut self, group: u32, field: &Field, value: u64) {
+        let at = group as usize * self.desc_size;
+        let wide = self.desc_size >= 64;
+        let gd = &mut self.gdt[at..at + self.desc_size];
+        let bits = field.width * 8;
+        let write = |gd: &mut [u8], offset, value: u64| match field.width {
+            4 => set32(gd, offset, value as u32),
+            _ => set16(gd, offset, value as u16),
+        };
+        write(gd, field.lo, value);
+        if wide {
+            write(gd, field.hi, value >> bits);
+        }
+        self.dirty_groups.insert(group);
+    }
+
+    fn gd_flags(&self, group: u32) -> u16 {
+        le16(self.gd(group), BG_FLAGS)
+    }
+
+    fn group_checksum(&self, group: u32) -> u16 {
+        let gd = self.gd(group);
+        match self.checksum {
+            Checksum::None => 0,
+            Checksum::Gdt => {
+                let mut crc = crc16(!0, &self.sb[0x68..0x78]);
+                crc = crc16(crc, &group.to_le_bytes());
+                crc = crc16(crc, &gd[..BG_CHECKSUM]);
+                crc16(crc, &gd[BG_CHECKSUM + 2..])
+            }
+            Checksum::Metadata { seed } => {
+                let mut crc = crc32c(seed, &group.to_le_bytes());
+                crc = crc32c(crc, &gd[..BG_CHECKSUM]);
+                crc = crc32c(crc, &[0, 0]);
+                crc32c(crc, &gd[BG_CHECKSUM + 2..]) as u16
+            }
+        }
+    }
+
+    fn group_of(&self, inode: u32) -> u32 {
+        (inode - 1) / self.inodes_per_group
+    }
+
+    fn group_first_block(&self, group: u32) -> u64 {
+        self.first_data_block + group as u64 * self.blocks_per_group
+    }
+
+    fn blocks_in_group(&self, group: u32) -> u64 {
+        (self.blocks_count - self.group_first_block(group)).min(self.blocks_per_group)
+    }
+
+    /// Blocks the descriptors of `groups` groups take.
+    fn desc_blocks(&self, groups: u32) -> u64 {
+        (groups as u64 * self.desc_size as u64).div_ceil(self.block_size as u64)
+    }
+
+    /// Whether `group` holds a superblock copy: all of them do without sparse_super,
+    /// otherwise groups 0, 1 and powers of 3, 5 and 7.
+    fn has_super(&self, group: u32) -> bool {
+        if group <= 1 || le32(&self.sb, 0x64) & RO_COMPAT_SPARSE_SUPER == 0 {
+            return true;
+        }
+        [3u64, 5, 7].into_iter().any(|base| {
+            let mut power = base;
+            while power < group as u64 {
+                power *= base;
+            }
+            power == group as u64
+        })
+    }
+
+    /// Hand `extra` reserved descriptor blocks over to the descriptor table and
+    /// reserve the remaining ones in the backup groups among the new groups too.
+    /// The resize inode maps the reserved blocks of the primary table through its
+    /// double indirect block, and their backups through the indirect blocks below.
+    fn update_resize_inode(&mut self, old_groups: u32, new_groups: u32, old_desc: u64, extra: u64, reserved: u64) -> Result<()> {
+        let mut inode = self.read_inode(RESIZE_INODE)?;
+        let dind = le32(&inode.raw, 0x28 + 13 * 4) as u64;
+        if dind == 0 {
+            return Err(Error::invalid_image("The resize inode has no reserved blocks"));
+        }
+        let mut dind_block = self.read_block(dind)?;
+        let per_block = self.block_size as u64 / 4;
+        let old_backups = (1..old_groups).filter(|&g| self.has_super(g)).count();
+        let new_backups: Vec<u32> = (old_groups..new_groups).filter(|&g| self.has_super(g)).collect();
+        for i in 0..reserved {
+            let primary = self.first_data_block + 1 + old_desc + i;
+            let slot = ((old_desc + i) % per_block) as usize * 4;
+            if le32(&dind_block, slot) as u64 != primary {
+                return Err(Error::invalid_image("The resize inode does not match the reserved descriptor blocks"));
+            }
+            if i < extra {
+                set32(&mut dind_block, slot, 0);
+                continue;
+            }
+            if new_backups.is_empty() {
+                continue;
+            }
+            let mut indirect = self.read_block(primary)?;
+            for (n, &group) in new_backups.iter().enumerate() {
+                let at = (old_backups + n) * 4;
+                if at + 4 > self.block_size {
+                    return Err(Error::invalid_image("The resize inode has no room for more backups"));
+                }
+                set32(&mut indirect, at, (primary + group as u64 * self.blocks_per_group) as u32);
+            }
+            self.write_block(primary, &indirect)?;
+        }
+        self.write_block(dind, &dind_block)?;
+        let sectors_per_block = self.block_size as u64 / 512;
+        let removed = extra * (1 + old_backups as u64);
+        let added = (reserved - extra) * new_backups.len() as u64;
+        let sectors = inode.sectors() + added * sectors_per_block;
+        inode.set_sectors(sectors - removed * sectors_per_block);
+        self.write_inode(&mut inode)
+    }
+
+    fn bitmap(&mut self, kind: Bitmap, group: u32) -> Result<&mut Vec<u8>> {
+        if !self.bitmaps.contains_key(&(kind, group)) {
+            let data = if kind == Bitmap::Inode && self.gd_flags(group) & BG_INODE_UNINIT != 0 {
+                // Never initialized: all free, with the bits past the group marked used
+                let mut data = vec![0u8; self.block_size];
+                for bit in self.inodes_per_group as usize..self.block_size * 8 {
+                    data[bit / 8] |= 1 << (bit % 8);
+                }
+                data
+            } else {
+                let field = if kind == Bitmap::Block { BG_BLOCK_BITMAP } else { BG_INODE_BITMAP };
+                self.read_block(self.gd_get(group, &field))?
+            };
+            self.bitmaps.insert((kind, group), data);
+        }
+        Ok(self.bitmaps.get_mut(&(kind, group)).unwrap())
+    }
+
+    fn adjust_free_blocks(&mut self, group: u32, delta: i64) {
+        let free = self.gd_get(group, &BG_FREE_BLOCKS);
+        self.gd_set(group, &BG_FREE_BLOCKS, free.wrapping_add_signed(delta));
+        let total = le32(&self.sb, 0x0c) as u64 | (le32(&self.sb, 0x158) as u64) << 32;
+        let total = total.wrapping_add_signed(delta);
+        set32(&mut self.sb, 0x0c, total as u32);
+        set32(&mut self.sb, 0x158, (total >> 32) as u32);
+    }
+
+    /// Allocate `count` blocks, as one run if any group has room for it, starting the
+    /// search at group `goal`. Returns `(first block, length)` runs.
+    fn allocate_blocks(&mut self, count: u64, goal: u32) -> Result<Vec<(u64, u64)>> {
+        let mut runs = Vec::new();
+        let mut left = count;
+        for contiguous in [true, false] {
+            for i in 0..self.groups {
+                if left == 0 {
+                    return Ok(runs);
+                }
+                let group = (goal + i) % self.groups;
+                // Uninitialized bitmaps would first have to be built from the layout
+                if self.gd_flags(group) & BG_BLOCK_UNINIT != 0 || self.gd_get(group, &BG_FREE_BLOCKS) == 0 {
+                    continue;
+                }
+                let size = self.blocks_in_group(group) as usize;
+                let bitmap = self.bitmap(Bitmap::Block, group)?;
+                let used = |bitmap: &[u8], bit: usize| bitmap[bit / 8] & (1 << (bit % 8)) != 0;
+                let mut taken = Vec::new();
+                let mut bit = 0;
+                while bit < size && left > 0 {
+                    if used(bitmap, bit) {
+                        bit += 1;
+                        continue;
+                    }
+                    let start = bit;
+                    while bit < size && !used(bitmap, bit) && ((bit - start) as u64) < left {
+                        bit += 1;
+                    }
+                    let len = (bit - start) as u64;
+                    if contiguous && len < left {
+                        continue;
+                    }
+                    for b in start..bit {
+                        bitmap[b / 8] |= 1 << (b % 8);
+                    }
+                    taken.push((start as u64, len));
+                    left -= len;
+                }
+                if !taken.is_empty() {
+                    self.dirty_bitmaps.insert((Bitmap::Block, group));
+                }
+                for (start, len) in taken {
+                    self.adjust_free_blocks(group, -(len as i64));
+                    runs.push((self.group_first_block(group) + start, len));
+                }
+            }
+        }
+        if left > 0 {
+            return Err(Error::invalid_image("No free blocks left in the image"));
+        }
+        Ok(runs)
+    }
+
+    fn free_blocks(&mut self, start: u64, len: u64) -> Result<()> {
+        for block in start..start + len {
+            let group = ((block - self.first_data_block) / self.blocks_per_group) as u32;
+            let bit = ((block - self.first_data_block) % self.blocks_per_group) as usize;
+            let bitmap = self.bitmap(Bitmap::Block, group)?;
+            bitmap[bit / 8] &= !(1 << (bit % 8));
+            self.dirty_bitmaps.insert((Bitmap::Block, group));
+            self.adjust_free_blocks(group, 1);
+        }
+        Ok(())
+    }
+
+    fn allocate_inode(&mut self, directory: bool, goal: u32) -> Result<u32> {
+        for i in 0..self.groups {
+            let group = (goal + i) % self.groups;
+            if self.gd_get(group, &BG_FREE_INODES) == 0 {
+                continue;
+            }
+            let inodes = self.inodes_per_group as usize;
+            let bitmap = self.bitmap(Bitmap::Inode, group)?;
+            let Some(bit) = (0..inodes).find(|&bit| bitmap[bit / 8] & (1 << (bit % 8)) == 0) else {
+                continue;
+            };
+            bitmap[bit / 8] |= 1 << (bit % 8);
+            self.dirty_bitmaps.insert((Bitmap::Inode, group));
+            let flags = self.gd_flags(group);
+            if flags & BG_INODE_UNINIT != 0 {
+                let at = group as usize * self.desc_size + BG_FLAGS;
+                set16(&mut self.gdt, at, flags & !BG_INODE_UNINIT);
+            }
+            let free = self.gd_get(group, &BG_FREE_INODES);
+            self.gd_set(group, &BG_FREE_INODES, free - 1);
+            if directory {
+                let dirs = self.gd_get(group, &BG_USED_DIRS);
+                self.gd_set(group, &BG_USED_DIRS, dirs + 1);
+            }
+            if self.checksum != Checksum::None {
+                // Inodes past the high watermark are skipped by e2fsck and the kernel
+                let unused = self.gd_get(group, &BG_ITABLE_UNUSED) as usize;
+                if bit >= inodes - unused {
+                    self.gd_set(group, &BG_ITABLE_UNUSED, (inodes - bit - 1) as u64);
+                }
+            }
+            let free = le32(&self.sb, 0x10);
+            set32(&mut self.sb, 0x10, free - 1);
+            return Ok(group * self.inodes_per_group + bit as u32 + 1);
+        }
+        Err(Error::invalid_image("No free inodes left in the image"))
+    }
+
+    fn inode_offset(&self, number: u32) -> Result<u64> {
+        if number == 0 || number > le32(&self.sb, 0x00) {
+            return Err(Error::invalid_image(format!("Inode {number} out of range")));
+        }
+        let group = self.group_of(number);
+        let index = ((number - 1) % self.inodes_per_group) as u64;
+        Ok(self.gd_get(group, &BG_INODE_TABLE) * self.block_size as u64 + index * self.inode_size as u64)
+    }
+
+    fn read_inode(&mut self, number: u32) -> Result<Inode> {
+        let offset = self.inode_offset(number)?;
+        let bs = self.block_size as u64;
+        let block = self.read_block(offset / bs)?;
+        let at = (offset % bs) as usize;
+        Ok(Inode { number, raw: block[at..at + self.inode_size].to_vec() })
+    }
+
+    fn write_inode(&mut self, inode: &mut Inode) -> Result<()> {
+        if let Checksum::Metadata { .. } = self.checksum {
+            let has_hi = inode.extra_isize() >= 4;
+            set16(&mut inode.raw, 0x7c, 0);
+            if has_hi {
+                set16(&mut inode.raw, 0x82, 0);
+            }
+            let csum = crc32c(self.inode_seed(inode), &inode.raw);
+            set16(&mut inode.raw, 0x7c, csum as u16);
+            if has_hi {
+                set16(&mut inode.raw, 0x82, (csum >> 16) as u16);
+            }
+        }
+        let offset = self.inode_offset(inode.number)?;
+        self.write_bytes(offset, &inode.raw)
+    }
+
+    fn inode_seed(&self, inode: &Inode) -> u32 {
+        let Checksum::Metadata { seed } = self.checksum else {
+            return 0;
+        };
+        crc32c(crc32c(seed, &inode.number.to_le_bytes()), &inode.generation().to_le_bytes())
+    }
+
+    fn new_inode(&self, number: u32, mode: u16, attrs: Attributes) -> Inode {
+        let mut raw = vec![0u8; self.inode_size];
+        set16(&mut raw, 0x00, mode);
+        set16(&mut raw, 0x02, attrs.uid as u16);
+        set16(&mut raw, 0x78, (attrs.uid >> 16) as u16);
+        set16(&mut raw, 0x18, attrs.gid as u16);
+        set16(&mut raw, 0x7a, (attrs.gid >> 16) as u16);
+        set16(&mut raw, 0x1a, 1);
+        let now = chrono::Utc::now();
+        for offset in [0x08, 0x0c, 0x10] {
+            set32(&mut raw, offset, now.timestamp() as u32);
+        }
+        set32(&mut raw, 0x20, EXTENTS_FL);
+        let generation = now.timestamp_subsec_nanos() ^ number.wrapping_mul(0x9e37_79b9);
+        set32(&mut raw, 0x64, generation);
+        if self.inode_size > 128 {
+            let extra = (self.inode_size - 128).min(32);
+            set16(&mut raw, 0x80, extra as u16);
+            if extra >= 24 {
+                set32(&mut raw, 0x90, now.timestamp() as u32);
+            }
+        }
+        set16(&mut raw, 0x28, EXTENT_MAGIC);
+        set16(&mut raw, 0x2c, INODE_EXTENTS as u16);
+        Inode { number, raw }
+    }
+
+    /// The data extents of `inode`, sorted, and the blocks holding the tree itself.
+    fn extent_tree(&mut self, inode: &Inode) -> Result<(Vec<Extent>, Vec<u64>)> {
+        if inode.flags() & EXTENTS_FL == 0 {
+            return Err(Error::invalid_image(format!("Inode {} uses block maps, which are not supported", inode.number)));
+        }
+        let mut extents = Vec::new();
+        let mut index = Vec::new();
+        self.walk_extents(&inode.raw[0x28..0x64], 0, &mut extents, &mut index)?;
+        extents.sort_by_key(|e| e.logical);
+        Ok((extents, index))
+    }
+
+    fn walk_extents(&mut self, node: &[u8], level: usize, extents: &mut Vec<Extent>, index: &mut Vec<u64>) -> Result<()> {
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        if le16(node, 0) != EXTENT_MAGIC || 12 + entries * 12 > node.len() || level > 5 {
+            return Err(Error::invalid_image("Corrupt extent tree"));
+        }
+        for entry in node[12..12 + entries * 12].chunks(12) {
+            if depth == 0 {
+                let len = le16(entry, 4) as u64;
+                let (len, uninit) = if len > MAX_EXTENT_LEN { (len - MAX_EXTENT_LEN, true) } else { (len, false) };
+                let start = (le16(entry, 6) as u64) << 32 | le32(entry, 8) as u64;
+                extents.push(Extent { logical: le32(entry, 0) as u64, start, len, uninit });
+            } else {
+                let child = le32(entry, 4) as u64 | (le16(entry, 8) as u64) << 32;
+                index.push(child);
+                let block = self.read_block(child)?;
+                self.walk_extents(&block, level + 1, extents, index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Point `inode` at `extents`, replacing the tree described by `old` and
+    /// `old_index`, whose data blocks the caller has dealt with.
+    fn store_extents(&mut self, inode: &mut Inode, old: &[Extent], old_index: &[u64], extents: Vec<Extent>) -> Result<()> {
+        if inode.flags() & HUGE_FILE_FL != 0 {
+            return Err(Error::invalid_image(format!("Inode {} counts blocks as huge_file", inode.number)));
+        }
+        let sectors_per_block = self.block_size as u64 / 512;
+        let old_blocks = old.iter().map(|e| e.len).sum::<u64>() + old_index.len() as u64;
+        // Whatever else is accounted to the inode, like an xattr block, stays
+        let other = inode.sectors().saturating_sub(old_blocks * sectors_per_block);
+        for &block in old_index {
+            self.free_blocks(block, 1)?;
+        }
+        let header = |node: &mut [u8], entries: usize, max: usize, depth: u16| {
+            set16(node, 0, EXTENT_MAGIC);
+            set16(node, 2, entries as u16);
+            set16(node, 4, max as u16);
+            set16(node, 6, depth);
+            set32(node, 8, 0);
+        };
+        let write_entries = |node: &mut [u8]| {
+            for (i, e) in extents.iter().enumerate() {
+                let entry = &mut node[12 + i * 12..24 + i * 12];
+                set32(entry, 0, e.logical as u32);
+                set16(entry, 4, (e.len + if e.uninit { MAX_EXTENT_LEN } else { 0 }) as u16);
+                set16(entry, 6, (e.start >> 32) as u16);
+                set32(entry, 8, e.start as u32);
+            }
+        };
+        let mut root = [0u8; 60];
+        let mut index_blocks = 0;
+        if extents.len() <= INODE_EXTENTS {
+            header(&mut root, extents.len(), INODE_EXTENTS, 0);
+            write_entries(&mut root);
+        } else {
+            let max = (self.block_size - 12) / 12;
+            if extents.len() > max {
+                return Err(Error::invalid_image(format!("Inode {} would be too fragmented", inode.number)));
+            }
+            let leaf = self.allocate_blocks(1, self.group_of(inode.number))?[0].0;
+            let mut block = vec![0u8; self.block_size];
+            header(&mut block, extents.len(), max, 0);
+            write_entries(&mut block);
+            if let Checksum::Metadata { .. } = self.checksum {
+                let tail = 12 + max * 12;
+                let csum = crc32c(self.inode_seed(inode), &block[..tail]);
+                set32(&mut block, tail, csum);
+            }
+            self.write_block(leaf, &block)?;
+            header(&mut root, 1, INODE_EXTENTS, 1);
+            set32(&mut root, 12, 0);
+            set32(&mut root, 16, leaf as u32);
+            set16(&mut root, 20, (leaf >> 32) as u16);
+            index_blocks = 1;
+        }
+        inode.raw[0x28..0x64].copy_from_slice(&root);
+        let flags = inode.flags() | EXTENTS_FL;
+        set32(&mut inode.raw, 0x20, flags);
+        let blocks = extents.iter().map(|e| e.len).sum::<u64>() + index_blocks;
+        inode.set_sectors(other + blocks * sectors_per_block);
+        Ok(())
+    }
+
+    fn read_data(&mut self, inode: &Inode) -> Result<Vec<u8>> {
+        let size = inode.size();
+        if size > MAX_READ {
+            return Err(Error::invalid_input(format!("Inode {} is too large to read ({size} bytes)", inode.number)));
+        }
+        let (extents, _) = self.extent_tree(inode)?;
+        let mut data = vec![0u8; size as usize];
+        let block_size = self.block_size as u64;
+        for extent in extents.iter().filter(|e| !e.uninit) {
+            let from = extent.logical * block_size;
+            if from >= size {
+                continue;
+            }
+            let to = ((extent.logical + extent.len) * block_size).min(size);
+            let blocks = self.read_blocks(extent.start, (to - from).div_ceil(block_size))?;
+            data[from as usize..to as usize].copy_from_slice(&blocks[..(to - from) as usize]);
+        }
+        Ok(data)
+    }
+
+    /// Replace the content of `inode` with `data`, in newly allocated blocks.
+    fn set_data(&mut self, inode: &mut Inode, data: &[u8]) -> Result<()> {
+        let (old, old_index) = self.extent_tree(inode)?;
+        for extent in &old {
+            self.free_blocks(extent.start, extent.len)?;
+        }
+        let count = data.len().div_ceil(self.block_size) as u64;
+        let runs = if count == 0 { Vec::new() } else { self.allocate_blocks(count, self.group_of(inode.number))? };
+        let mut extents = Vec::new();
+        let mut logical = 0u64;
+        for (start, len) in runs {
+            let from = (logical as usize * self.block_size).min(data.len());
+            let to = ((logical + len) as usize * self.block_size).min(data.len());
+            let mut chunk = data[from..to].to_vec();
+            chunk.resize(len as usize * self.block_size, 0);
+            self.write_block(start, &chunk)?;
+            let mut done = 0;
+            while done < len {
+                let piece = (len - done).min(MAX_EXTENT_LEN);
+                extents.push(Extent { logical: logical + done, start: start + done, len: piece, uninit: false });
+                done += piece;
+            }
+            logical += len;
+        }
+        self.store_extents(inode, &old, &old_index, extents)?;
+        inode.set_size(data.len() as u64);
+        inode.touch();
+        Ok(())
+    }
+
+    fn read_symlink(&mut self, inode: &Inode) -> Result<Vec<u8>> {
+        let size = inode.size() as usize;
+        // Short targets live in the block map area itself
+        if inode.flags() & EXTENTS_FL == 0 && size < 60 {
+            return Ok(inode.raw[0x28..0x28 + size].to_vec());
+        }
+        self.read_data(inode)
+    }
+
+    fn resolve(&mut self, path: &str, follow_last: bool) -> Result<Option<Inode>> {
+        let mut pending: VecDeque<String> = split_path(path)?.into_iter().map(String::from).collect();
+        let mut current = self.read_inode(ROOT_INODE)?;
+        let mut hops = 0;
+        while let Some(name) = pending.pop_front() {
+            if current.file_type() != S_IFDIR {
+                return Err(Error::invalid_input(format!("{path}: a parent of {name} is not a directory")));
+            }
+            let Some((number, _)) = self.lookup(&current, name.as_bytes())? else {
+                return Ok(None);
+            };
+            let inode = self.read_inode(number)?;
+            if inode.file_type() == S_IFLNK && (follow_last || !pending.is_empty()) {
+                hops += 1;
+                if hops > MAX_SYMLINKS {
+                    return Err(Error::invalid_input(format!("{path}: too many levels of symbolic links")));
+                }
+                let target = String::from_utf8_lossy(&self.read_symlink(&inode)?).to_string();
+                if target.starts_with('/') {
+                    current = self.read_inode(ROOT_INODE)?;
+                }
+                for component in target.split('/').filter(|c| !c.is_empty() && *c != ".").rev() {
+                    pending.push_front(component.to_string());
+                }
+                continue;
+            }
+            current = inode;
+        }
+        Ok(Some(current))
+    }
+
+    fn lookup(&mut self, dir: &Inode, name: &[u8]) -> Result<Option<(u32, u8)>> {
+        let (extents, _) = self.extent_tree(dir)?;
+        for logical in 0..dir.size() / self.block_size as u64 {
+            let Some(physical) = map_block(&extents, logical) else {
+                continue;
+            };
+            let block = self.read_block(physical)?;
+            // Index blocks look like empty entries, so a linear scan sees only the leaves
+            for entry in dirents(&block)? {
+                if entry.inode != 0 && entry.name(&block) == name {
+                    return Ok(Some((entry.inode, entry.file_type)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn dirent_tail_len(&self) -> usize {
+        if matches!(self.checksum, Checksum::Metadata { .. }) { DIRENT_TAIL_LEN } else { 0 }
+    }
+
+    /// Put an entry into the first gap of `block` that fits it.
+    fn insert_dirent(&self, block: &mut [u8], name: &[u8], inode: u32, file_type: u8) -> Result<bool> {
+        let needed = rec_len(name.len());
+        let end = block.len() - self.dirent_tail_len();
+        for entry in dirents(&block[..end])? {
+            let used = if entry.inode == 0 { 0 } else { rec_len(entry.name_len) };
+            if entry.rec_len - used >= needed {
+                if used > 0 {
+                    set16(block, entry.offset + 4, used as u16);
+                }
+                write_dirent(block, entry.offset + used, inode, entry.rec_len - used, name, file_type);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// A directory leaf block holding `entries`, the last one taking up the slack.
+    fn build_leaf(&self, entries: &[(Vec<u8>, u32, u8)]) -> Vec<u8> {
+        let mut block = vec![0u8; self.block_size];
+        let end = self.block_size - self.dirent_tail_len();
+        let mut at = 0;
+        for (i, (name, inode, file_type)) in entries.iter().enumerate() {
+            let len = if i + 1 == entries.len() { end - at } else { rec_len(name.len()) };
+            write_dirent(&mut block, at, *inode, len, name, *file_type);
+            at += len;
+        }
+        if entries.is_empty() {
+            set16(&mut block, 4, end as u16);
+        }
+        block
+    }
+
+    /// Fill in the checksum tail of a directory leaf block.
+    fn seal_dir_block(&self, dir: &Inode, block: &mut [u8]) {
+        if let Checksum::Metadata { .. } = self.checksum {
+            let at = block.len() - DIRENT_TAIL_LEN;
+            set32(block, at, 0);
+            set16(block, at + 4, DIRENT_TAIL_LEN as u16);
+            block[at + 6] = 0;
+            block[at + 7] = 0xde;
+            let csum = crc32c(self.inode_seed(dir), &block[..at]);
+            set32(block, at + 8, csum);
+        }
+    }
+
+    /// Fill in the checksum tail of an htree index block whose count and limit are
+    /// at `count_offset`.
+    fn seal_dx_block(&self, dir: &Inode, block: &mut [u8], count_offset: usize) {
+        if let Checksum::Metadata { .. } = self.checksum {
+            let limit = le16(block, count_offset) as usize;
+            let count = le16(block, count_offset + 2) as usize;
+            let tail = count_offset + limit * 8;
+            let mut csum = crc32c(self.inode_seed(dir), &block[..count_offset + count * 8]);
+            csum = crc32c(csum, &block[tail..tail + 4]);
+            csum = crc32c(csum, &[0; 4]);
+            set32(block, tail + 4, csum);
+        }
+    }
+
+    fn dx_hash(&self, name: &[u8], version: u8) -> Result<u32> {
+        let unsigned = match version {
+            DX_HASH_HALF_MD4 => le32(&self.sb, 0x160) & FLAGS_UNSIGNED_HASH != 0,
+            DX_HASH_HALF_MD4_UNSIGNED => true,
+            other => return Err(Error::invalid_image(format!("Directory hash version {other} is not supported"))),
+        };
+        let seed: Vec<u32> = (0..4).map(|i| le32(&self.sb, 0xec + i * 4)).collect();
+        Ok(half_md4_hash(name, seed.try_into().unwrap(), unsigned))
+    }
+
+    /// Add a block at the end of directory `dir`.
+    fn append_dir_block(&mut self, dir: &mut Inode) -> Result<(u64, u64)> {
+        let (old, index) = self.extent_tree(dir)?;
+        let mut extents = old.clone();
+        let logical = dir.size() / self.block_size as u64;
+        let physical = self.allocate_blocks(1, self.group_of(dir.number))?[0].0;
+        match extents.last_mut() {
+            Some(last)
+                if last.start + last.len == physical
+                    && last.logical + last.len == logical
+                    && !last.uninit
+                    && last.len < MAX_EXTENT_LEN =>
+            {
+                last.len += 1
+            }
+            _ => extents.push(Extent { logical, start: physical, len: 1, uninit: false }),
+        }
+        self.store_extents(dir, &old, &index, extents)?;
+        dir.set_size(dir.size() + self.block_size as u64);
+        Ok((logical, physical))
+    }
+
+    /// Link `inode` into directory `dir` as `name`, and write `dir` back.
+    fn add_entry(&mut self, dir: &mut Inode, name: &[u8], inode: u32, file_type: u8) -> Result<()> {
+        if name.is_empty() || name.len() > 255 || name.contains(&b'/') || name.contains(&0) {
+            return Err(Error::invalid_input(format!("Invalid file name {:?}", String::from_utf8_lossy(name))));
+        }
+        if dir.flags() & INDEX_FL != 0 && le32(&self.sb, 0x5c) & COMPAT_DIR_INDEX != 0 {
+            self.add_htree_entry(dir, name, inode, file_type)?;
+        } else {
+            self.add_linear_entry(dir, name, inode, file_type)?;
+        }
+        dir.touch();
+        self.write_inode(dir)
+    }
+
+    fn add_linear_entry(&mut self, dir: &mut Inode, name: &[u8], inode: u32, file_type: u8) -> Result<()> {
+        let (extents, _) = self.extent_tree(dir)?;
+        for logical in 0..dir.size() / self.block_size as u64 {
+            let Some(physical) = map_block(&extents, logical) else {
+                continue;
+            };
+            let mut block = self.read_block(physical)?;
+            if self.insert_dirent(&mut block, name, inode, file_type)? {
+                self.seal_dir_block(dir, &mut block);
+                return self.write_block(physical, &block);
+            }
+        }
+        let (_, physical) = self.append_dir_block(dir)?;
+        let mut block = self.build_leaf(&[(name.to_vec(), inode, file_type)]);
+        self.seal_dir_block(dir, &mut block);
+        self.write_block(physical, &block)
+    }
+
+    /// Insert into the leaf the hash index points at, splitting it at the median
+    /// hash like the kernel does when it is full.
+    fn add_htree_entry(&mut self, dir: &mut Inode, name: &[u8], inode: u32, file_type: u8) -> Result<()> {
+        let (extents, _) = self.extent_tree(dir)?;
+        let map = |logical: u64| {
+            map_block(&extents, logical).ok_or_else(|| Error::invalid_image(format!("Hole in directory {}", dir.number)))
+        };
+        let root_physical = map(0)?;
+        let root = self.read_block(root_physical)?;
+        let (hash_version, info_len, levels) = (root[0x1c], root[0x1d] as usize, root[0x1e] as usize);
+        let hash = self.dx_hash(name, hash_version)?;
+        // Index blocks on the way down, with the position of the entry followed
+        let mut path: Vec<(u64, Vec<u8>, usize, usize)> = Vec::new();
+        let (mut physical, mut block, mut count_offset) = (root_physical, root, 0x18 + info_len);
+        let leaf_logical = loop {
+            let count = le16(&block, count_offset + 2) as usize;
+            let position = (1..count)
+                .take_while(|&i| le32(&block, count_offset + i * 8) <= hash)
+                .last()
+                .unwrap_or(0);
+            let child = (le32(&block, count_offset + position * 8 + 4) & 0x0fff_ffff) as u64;
+            path.push((physical, block, count_offset, position));
+            if path.len() > levels {
+                break child;
+            }
+            physical = map(child)?;
+            block = self.read_block(physical)?;
+            count_offset = 8;
+        };
+        let leaf_physical = map(leaf_logical)?;
+        let mut leaf = self.read_block(leaf_physical)?;
+        if self.insert_dirent(&mut leaf, name, inode, file_type)? {
+            self.seal_dir_block(dir, &mut leaf);
+            return self.write_block(leaf_physical, &leaf);
+        }
+
+        let (parent_physical, mut parent, count_offset, position) = path.pop().unwrap();
+        let limit = le16(&parent, count_offset) as usize;
+        let count = le16(&parent, count_offset + 2) as usize;
+        if count >= limit {
+            return Err(Error::invalid_image(format!("The index of directory {} is full", dir.number)));
+        }
+        let end = self.block_size - self.dirent_tail_len();
+        let mut entries = Vec::new();
+        for entry in dirents(&leaf[..end])?.into_iter().filter(|e| e.inode != 0) {
+            let entry_name = entry.name(&leaf).to_vec();
+            entries.push((self.dx_hash(&entry_name, hash_version)?, entry_name, entry.inode, entry.file_type));
+        }
+        entries.sort_by_key(|e| e.0);
+        let split = entries.len() / 2;
+        let split_hash = entries[split].0;
+        // Equal hashes on both sides are flagged so lookups continue into the next block
+        let continued = split > 0 && entries[split - 1].0 == split_hash;
+        let upper = entries.split_off(split);
+        let strip = |entries: &[(u32, Vec<u8>, u32, u8)]| -> Vec<(Vec<u8>, u32, u8)> {
+            entries.iter().map(|(_, name, inode, file_type)| (name.clone(), *inode, *file_type)).collect()
+        };
+        let mut lower_block = self.build_leaf(&strip(&entries));
+        let mut upper_block = self.build_leaf(&strip(&upper));
+        let target = if hash >= split_hash { &mut upper_block } else { &mut lower_block };
+        if !self.insert_dirent(target, name, inode, file_type)? {
+            return Err(Error::invalid_image(format!("No room for {} after a split", String::from_utf8_lossy(name))));
+        }
+        let (new_logical, new_physical) = self.append_dir_block(dir)?;
+        self.seal_dir_block(dir, &mut lower_block);
+        self.seal_dir_block(dir, &mut upper_block);
+        self.write_block(leaf_physical, &lower_block)?;
+        self.write_block(new_physical, &upper_block)?;
+
+        let at = count_offset + (position + 1) * 8;
+        parent.copy_within(at..count_offset + count * 8, at + 8);
+        set32(&mut parent, at, split_hash | continued as u32);
+        set32(&mut parent, at + 4, new_logical as u32);
+        set16(&mut parent, count_offset + 2, (count + 1) as u16);
+        self.seal_dx_block(dir, &mut parent, count_offset);
+        self.write_block(parent_physical, &parent)
+    }
+
+    fn mkdir(&mut self, parent: &mut Inode, name: &[u8], attrs: Attributes) -> Result<Inode> {
+        let number = self.allocate_inode(true, self.group_of(parent.number))?;
+        let mut inode = self.new_inode(number, S_IFDIR | (attrs.mode & 0o7777), attrs);
+        inode.set_links(2);
+        let physical = self.allocate_blocks(1, self.group_of(number))?[0].0;
+        let mut block = self.build_leaf(&[(b".".to_vec(), number, FT_DIR), (b"..".to_vec(), parent.number, FT_DIR)]);
+        self.seal_dir_block(&inode, &mut block);
+        self.write_block(physical, &block)?;
+        self.store_extents(&mut inode, &[], &[], vec![Extent { logical: 0, start: physical, len: 1, uninit: false }])?;
+        inode.set_size(self.block_size as u64);
+        self.write_inode(&mut inode)?;
+        // With dir_nlink, a count of 1 means "too many to count"
+        if parent.links() > 1 && parent.links() < 64999 {
+            parent.set_links(parent.links() + 1);
+        }
+        self.add_entry(parent, name, number, FT_DIR)?;
+        Ok(inode)
+    }
+}
+
+fn seal_superblock(checksum: Checksum, sb: &mut [u8]) {
+    if let Checksum::Metadata { .. } = checksum {
+        let csum = crc32c(!0, &sb[..0x3fc]);
+        set32(sb, 0x3fc, csum);
+    }
+}
+
+/// Where edited copies of `source` go: next to it, with `-custom` in the name.
+pub fn copy_path(source: &Path) -> std::path::PathBuf {
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match source.extension() {
+        Some(ext) => format!("{stem}-custom.{}", ext.to_string_lossy()),
+        None => format!("{stem}-custom"),
+    };
+    source.with_file_name(name)
+}
+
+/// Copy the raw ext4 image `source` to `output`, run `edit` on the copy and flush
+/// it. The source is never modified, and a failed copy is removed.
+pub fn edit_copy(source: &Path, output: &Path, edit: impl FnOnce(&mut Ext4) -> Result<()>) -> Result<()> {
+    check_raw_ext4(source)?;
+    std::fs::copy(source, output).with_context(|| format!("Failed to copy {}", source.display()))?;
+    let result = Ext4::open(output).and_then(|mut fs| {
+        edit(&mut fs)?;
+        fs.flush()
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(output);
+    }
+    result
+}
+
+fn check_raw_ext4(source: &Path) -> Result<()> {
+    let info = crate::inspect::inspect(source)?;
+    if info.sparse {
+        return Err(Error::invalid_image("Sparse images cannot be edited; unsparse the image first"));
+    }
+    if !matches!(info.kind, crate::inspect::ImageKind::Ext4 { .. }) {
+        return Err(Error::invalid_image(format!("{} is not an ext4 image", source.display())));
+    }
+    Ok(())
+}
+
+/// Grow the ext4 image `source` to fill a partition of `size` bytes and write the
+/// result to `output` as a sparse image, in which the added space is DONT_CARE.
+/// `source` itself is left alone. Returns the new filesystem size in bytes.
+pub fn grow_image(source: &Path, output: &Path, size: u64) -> Result<u64> {
+    check_raw_ext4(source)?;
+    let result = Ext4::open_read_only(source).and_then(|mut fs| {
+        let blocks = fs.grow(size)?;
+        fs.flush()?;
+        fs.write_sparse(output)?;
+        Ok(blocks * fs.block_size as u64)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(output);
+    }
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::process::Command;
+    use tokio::io::AsyncReadExt;
+
+    /// A copy of the image `fixtures/ext4/<name>.img.zst`, made by `generate.sh` there,
+    /// that the test may change.
+    pub(crate) fn fixture(name: &str) -> std::path::PathBuf {
+        let path = format!("{}/fixtures/ext4/{name}.img.zst", env!("CARGO_MANIFEST_DIR"));
+        let compressed = std::fs::read(&path).unwrap();
+        let mut data = Vec::new();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime
+            .block_on(crate::compression::Compression::Zstd.decoder(&compressed[..]).read_to_end(&mut data))
+            .unwrap();
+        let image = std::env::temp_dir().join(format!("revyos-imager-ext4-{name}.img"));
+        std::fs::write(&image, data).unwrap();
+        image
+    }
+
+    /// Run `e2fsck -fn` and assert it finds nothing to fix. Skipped where e2fsprogs is
+    /// not installed.
+    pub(crate) fn fsck(image: &Path) {
+        let output = match Command::new("e2fsck").arg("-fn").arg(image).output() {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!("e2fsck is not installed, not checking {}", image.display());
+                return;
+            }
+            Err(e) => panic!("e2fsck: {e}"),
+        };
+        assert!(
+            output.status.success(),
+            "e2fsck: {}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// The owner of `path`.
+    pub(crate) fn owner(fs: &mut Ext4, path: &str) -> u16 {
+        le16(&fs.resolve(path, false).unwrap().unwrap().raw, 0x02)
+    }
+
+    #[test]
+    fn test_checksums_and_hash() {
+        assert_eq!(!crc32c(!0, b"123456789"), 0xe306_9283);
+        assert_eq!(crc16(0, b"123456789"), 0xbb3d);
+        // Values from e2fsprogs' `debugfs -R "dx_hash -h half_md4 -s <seed> <name>"`
+        assert_eq!(half_md4_hash(b"hostname", [0; 4], false), 0xa205_0766);
+        assert_eq!(half_md4_hash("é".as_bytes(), [0; 4], false), 0x89d4_704e);
+        assert_eq!(half_md4_hash(&[b'x'; 70], [0; 4], false), 0x43b7_ab42);
+        let seed = [0x0403_0201, 0x0807_0605, 0x0c0b_0a09, 0x100f_0e0d];
+        assert_eq!(half_md4_hash(b"hostname", seed, false), 0x8253_a4e6);
+        assert_ne!(half_md4_hash("é".as_bytes(), [0; 4], true), 0x89d4_704e);
+    }
+
+    #[test]
+    fn test_write_files() {
+        let image = fixture("write");
+        let mut fs = Ext4::open(&image).unwrap();
+        assert_eq!(fs.read_file("/etc/hostname").unwrap().unwrap(), b"revyos-lpi4a\n");
+        assert_eq!(fs.read_file("/config/hostname").unwrap().unwrap(), b"revyos-lpi4a\n");
+        assert!(fs.read_file("/etc/missing").unwrap().is_none());
+
+        fs.write_file("/etc/hostname", b"board-7\n", Attributes::root(0o644)).unwrap();
+        fs.create_dir_all("/home/debian/.ssh", Attributes { mode: 0o700, uid: 1000, gid: 1000 }).unwrap();
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs.write_file("/home/debian/.ssh/authorized_keys", &big, Attributes { mode: 0o600, uid: 1000, gid: 1000 })
+            .unwrap();
+        assert!(fs.write_file("/nope/file", b"", Attributes::root(0o644)).is_err());
+        assert_eq!(copy_path(Path::new("/cache/boot-lpi4a.ext4")), Path::new("/cache/boot-lpi4a-custom.ext4"));
+        fs.flush().unwrap();
+        drop(fs);
+        fsck(&image);
+
+        let mut fs = Ext4::open(&image).unwrap();
+        assert_eq!(fs.read_file("/etc/hostname").unwrap().unwrap(), b"board-7\n");
+        assert_eq!(fs.read_file("/home/debian/.ssh/authorized_keys").unwrap().unwrap(), big);
+        let keys = fs.resolve("/home/debian/.ssh/authorized_keys", false).unwrap().unwrap();
+        assert_eq!(keys.mode(), S_IFREG | 0o600);
+        assert_eq!(le16(&keys.raw, 0x02), 1000);
+        // Shrinking gives blocks back
+        fs.write_file("/home/debian/.ssh/authorized_keys", b"ssh-ed25519 AAAA\n", Attributes::root(0o600)).unwrap();
+        fs.flush().unwrap();
+        drop(fs);
+        fsck(&image);
+        let _ = std::fs::remove_file(image);
+    }
+
+    #[test]
+    fn test_htree_insert_and_split() {
+        // 400 files in /etc, indexed by e2fsck
+        let image = fixture("htree");
+        let mut fs = Ext4::open(&image).unwrap();
+        let etc = fs.resolve("/etc", true).unwrap().unwrap();
+        assert_ne!(etc.flags() & INDEX_FL, 0, "/etc should be indexed");
+        let blocks_before = etc.size();
+        for i in 0..300 {
+            let path = format!("/etc/added-by-imager-{i:04}.conf");
+            fs.write_file(&path, path.as_bytes(), Attributes::root(0o644)).unwrap();
+        }
+        fs.flush().unwrap();
+        let etc = fs.resolve("/etc", true).unwrap().unwrap();
+        assert!(etc.size() > blocks_before, "leaves should have been split");
+        drop(fs);
+        fsck(&image);
+
+        let mut fs = Ext4::open(&image).unwrap();
+        for i in (0..300).step_by(7) {
+            let path = format!("/etc/added-by-imager-{i:04}.conf");
+            assert_eq!(fs.read_file(&path).unwrap().unwrap(), path.as_bytes());
+        }
+        assert_eq!(fs.read_file("/etc/existing-config-file-0123.conf").unwrap().unwrap(), b"x");
+        let _ = std::fs::remove_file(image);
+    }
+
+    #[test]
+    fn test_grow() {
+        // 1K blocks: 2 groups growing to 64 need more descriptor blocks, taken from
+        // the reserved ones. 4K blocks: 1 group growing to 8 adds backup groups only
+        for (block_size, size_mb, grown_mb) in [(1024, 16, 512), (4096, 32, 1024)] {
+            let image = fixture(&format!("grow-{block_size}"));
+            let before = std::fs::read(&image).unwrap();
+            let sparse = image.with_extension("sparse");
+            let size = grow_image(&image, &sparse, grown_mb << 20).unwrap();
+            assert_eq!(size, grown_mb << 20);
+            assert_eq!(std::fs::read(&image).unwrap(), before);
+            let info = crate::inspect::inspect(&sparse).unwrap();
+            assert!(info.sparse && !info.truncated);
+            assert_eq!(info.size, grown_mb << 20);
+            assert!(std::fs::metadata(&sparse).unwrap().len() < (size_mb + 1) << 20);
+
+            let raw = image.with_extension("grown");
+            crate::sparse::unsparse(&sparse, &raw).unwrap();
+            fsck(&raw);
+            let mut fs = Ext4::open(&raw).unwrap();
+            assert_eq!(fs.blocks_count * block_size as u64, size);
+            assert_eq!(fs.read_file("/etc/hostname").unwrap().unwrap(), b"revyos-lpi4a\n");
+            // Growing again to the same size changes nothing
+            assert_eq!(fs.grow(size).unwrap(), size / block_size as u64);
+            assert!(!fs.backups_dirty);
+            drop(fs);
+            for path in [image, sparse, raw] {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
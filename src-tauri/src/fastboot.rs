@@ -0,0 +1,531 @@
+use std::future::Future;
+use std::time::Duration;
+use nusb::transfer::{Bulk, Direction, In, Out, TransferType};
+use nusb::MaybeFuture;
+use serde::Serialize;
+
+use crate::error::{Error, Result, ResultExt};
+use crate::usb::FASTBOOT_INTERFACE;
+
+/// Fastboot responses are at most 256 bytes (4 byte prefix + message).
+const MAX_RESPONSE_SIZE: usize = 256;
+/// Largest single IN transfer asked for during a data phase.
+const MAX_DATA_CHUNK: usize = 1 << 20;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Writing a large split to eMMC may take minutes without any INFO in between.
+pub const FLASH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A raw byte pipe to a fastboot device.
+///
+/// The protocol is implemented on top of this trait, so tests can swap the USB device
+/// for a simulated one.
+pub trait Transport: Send {
+    /// Send `data` as one bulk OUT transfer and hand the buffer back for reuse.
+    fn send(&mut self, data: Vec<u8>) -> impl Future<Output = Result<Vec<u8>>> + Send;
+    /// Receive one bulk IN transfer of at most `max_len` bytes.
+    fn receive(&mut self, max_len: usize) -> impl Future<Output = Result<Vec<u8>>> + Send;
+}
+
+/// Bulk endpoints of a claimed fastboot interface.
+pub struct NusbTransport {
+    ep_out: nusb::Endpoint<Bulk, Out>,
+    ep_in: nusb::Endpoint<Bulk, In>,
+}
+
+impl NusbTransport {
+    pub fn open(info: &nusb::DeviceInfo) -> Result<Self> {
+        let interface_number = info
+            .interfaces()
+            .find(|i| (i.class(), i.subclass(), i.protocol()) == FASTBOOT_INTERFACE)
+            .map(|i| i.interface_number())
+            .ok_or_else(|| Error::usb("Device has no fastboot interface"))?;
+        let device = info
+            .open()
+            .wait()
+            .map_err(|e| Error::usb(format!("Failed to open USB device: {e}")))?;
+        let interface = device
+            .claim_interface(interface_number)
+            .wait()
+            .map_err(|e| Error::usb(format!("Failed to claim fastboot interface: {e}")))?;
+
+        let config = device
+            .active_configuration()
+            .map_err(|e| Error::usb(format!("Failed to read configuration: {e}")))?;
+        let alt = config
+            .interface_alt_settings()
+            .find(|alt| alt.interface_number() == interface_number && alt.alternate_setting() == 0)
+            .ok_or_else(|| Error::usb("Fastboot interface descriptor not found"))?;
+        let mut ep_out = None;
+        let mut ep_in = None;
+        for ep in alt.endpoints() {
+            if ep.transfer_type() != TransferType::Bulk {
+                continue;
+            }
+            match ep.direction() {
+                Direction::Out => ep_out = ep_out.or(Some(ep.address())),
+                Direction::In => ep_in = ep_in.or(Some(ep.address())),
+            }
+        }
+        let ep_out = ep_out.ok_or_else(|| Error::usb("Fastboot interface has no bulk OUT endpoint"))?;
+        let ep_in = ep_in.ok_or_else(|| Error::usb("Fastboot interface has no bulk IN endpoint"))?;
+
+        Ok(Self {
+            ep_out: interface
+                .endpoint::<Bulk, Out>(ep_out)
+                .map_err(|e| Error::usb(format!("Failed to claim OUT endpoint: {e}")))?,
+            ep_in: interface
+                .endpoint::<Bulk, In>(ep_in)
+                .map_err(|e| Error::usb(format!("Failed to claim IN endpoint: {e}")))?,
+        })
+    }
+}
+
+impl Transport for NusbTransport {
+    async fn send(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+        // The buffer is moved into the transfer and comes back with its completion
+        self.ep_out.submit(data.into());
+        let completion = self.ep_out.next_complete().await;
+        completion.status.context("USB bulk OUT transfer failed")?;
+        Ok(completion.buffer.into_vec())
+    }
+
+    async fn receive(&mut self, max_len: usize) -> Result<Vec<u8>> {
+        // IN transfers must be a multiple of the max packet size
+        let packet = self.ep_in.max_packet_size();
+        let len = max_len.div_ceil(packet) * packet;
+        let buffer = self.ep_in.allocate(len);
+        self.ep_in.submit(buffer);
+        let completion = self.ep_in.next_complete().await;
+        completion.status.context("USB bulk IN transfer failed")?;
+        Ok(completion.buffer.into_vec())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ResponseKind {
+    Info,
+    Text,
+    Okay,
+    Fail,
+    Data,
+}
+
+/// A single response packet sent by the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub kind: ResponseKind,
+    pub message: String,
+}
+
+impl Response {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 4 {
+            return Err(Error::fastboot(format!("Short fastboot response ({} bytes)", bytes.len())));
+        }
+        let (prefix, payload) = bytes.split_at(4);
+        let kind = match prefix {
+            b"INFO" => ResponseKind::Info,
+            b"TEXT" => ResponseKind::Text,
+            b"OKAY" => ResponseKind::Okay,
+            b"FAIL" => ResponseKind::Fail,
+            b"DATA" => ResponseKind::Data,
+            _ => {
+                return Err(Error::fastboot(format!(
+                    "Unknown fastboot response: {:?}",
+                    String::from_utf8_lossy(bytes)
+                )))
+            }
+        };
+        Ok(Self {
+            kind,
+            message: String::from_utf8_lossy(payload).to_string(),
+        })
+    }
+
+    /// OKAY, FAIL and DATA end a command; INFO and TEXT may be followed by more packets.
+    pub fn is_final(&self) -> bool {
+        !matches!(self.kind, ResponseKind::Info | ResponseKind::Text)
+    }
+}
+
+/// The last of the responses to `cmd`, which ends the exchange.
+fn final_response<'a>(cmd: &str, responses: &'a [Response]) -> Result<&'a Response> {
+    responses.last().ok_or_else(|| Error::fastboot(format!("No response to {cmd}")))
+}
+
+/// The OKAY payload of the responses to `cmd`, or its FAIL as an error.
+fn okay_payload(cmd: &str, responses: &[Response]) -> Result<String> {
+    let last = final_response(cmd, responses)?;
+    match last.kind {
+        ResponseKind::Okay => Ok(last.message.clone()),
+        ResponseKind::Fail => Err(Error::fastboot(format!("{cmd} failed: {}", last.message))),
+        _ => Err(Error::fastboot(format!("Unexpected response to {cmd}: {:?}", last.kind))),
+    }
+}
+
+/// Fastboot client speaking the wire protocol over any [`Transport`].
+pub struct FastBoot<T: Transport> {
+    transport: T,
+    timeout: Duration,
+    flash_timeout: Duration,
+}
+
+impl FastBoot<NusbTransport> {
+    pub fn from_info(info: &nusb::DeviceInfo) -> Result<Self> {
+        Ok(Self::new(NusbTransport::open(info)?))
+    }
+}
+
+impl<T: Transport> FastBoot<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            timeout: DEFAULT_TIMEOUT,
+            flash_timeout: FLASH_TIMEOUT,
+        }
+    }
+
+    /// Replace the time allowed for each response, and for a `flash` to finish.
+    pub fn set_timeouts(&mut self, command: Duration, flash: Duration) {
+        self.timeout = command;
+        self.flash_timeout = flash;
+    }
+
+    async fn read_response(&mut self) -> Result<Response> {
+        let bytes = tokio::time::timeout(self.timeout, self.transport.receive(MAX_RESPONSE_SIZE))
+            .await
+            .map_err(|_| Error::timeout("Timed out waiting for fastboot response"))??;
+        Response::parse(&bytes)
+    }
+
+    /// Send `cmd` and collect every response packet up to the final OKAY, FAIL or DATA.
+    ///
+    /// `on_response` is called as packets arrive, so long running commands can report
+    /// INFO lines before they finish. A FAIL is returned as a response, not an error.
+    pub async fn command<F>(&mut self, cmd: &str, on_response: F) -> Result<Vec<Response>>
+    where
+        F: FnMut(&Response) + Send,
+    {
+        self.send_command(cmd).await?;
+        self.read_responses(on_response).await
+    }
+
+    /// Send `cmd` without waiting for the device to answer it.
+    pub async fn send_command(&mut self, cmd: &str) -> Result<()> {
+        if cmd.len() > 64 {
+            return Err(Error::invalid_input("Fastboot commands are limited to 64 bytes"));
+        }
+        self.transport.send(cmd.as_bytes().to_vec()).await.with_context(|| format!("Sending {cmd}"))?;
+        Ok(())
+    }
+
+    /// Collect the responses to a command sent with [`send_command`](Self::send_command).
+    pub async fn read_responses<F>(&mut self, mut on_response: F) -> Result<Vec<Response>>
+    where
+        F: FnMut(&Response) + Send,
+    {
+        let mut responses = Vec::new();
+        loop {
+            let response = self.read_response().await?;
+            on_response(&response);
+            let done = response.is_final();
+            responses.push(response);
+            if done {
+                return Ok(responses);
+            }
+        }
+    }
+
+    /// Run `cmd` and return the OKAY payload, turning FAIL into an error.
+    pub async fn execute(&mut self, cmd: &str) -> Result<String> {
+        let responses = self.command(cmd, |_| {}).await?;
+        okay_payload(cmd, &responses)
+    }
+
+    pub async fn get_var(&mut self, var: &str) -> Result<String> {
+        self.execute(&format!("getvar:{var}")).await
+    }
+
+    /// Run `cmd`, which starts a data phase, and return the length the device announced.
+    async fn data_command(&mut self, cmd: &str) -> Result<u32> {
+        let responses = self.command(cmd, |_| {}).await?;
+        let last = final_response(cmd, &responses)?;
+        match last.kind {
+            ResponseKind::Data => u32::from_str_radix(&last.message, 16)
+                .map_err(|_| Error::fastboot(format!("Bad DATA length in response to {cmd}: {}", last.message))),
+            ResponseKind::Fail => Err(Error::fastboot(format!("{cmd} failed: {}", last.message))),
+            kind => Err(Error::fastboot(format!("Unexpected response to {cmd}: {kind:?}"))),
+        }
+    }
+
+    /// Announce a download of `size` bytes. The data follows with
+    /// [`send_data`](Self::send_data), then [`finish_download`](Self::finish_download).
+    pub async fn start_download(&mut self, size: u32) -> Result<()> {
+        let cmd = format!("download:{size:08x}");
+        let accepted = self.data_command(&cmd).await?;
+        if accepted != size {
+            return Err(Error::fastboot(format!("Device accepted {accepted} bytes of a {size} byte download")));
+        }
+        Ok(())
+    }
+
+    /// Send one bulk transfer of download data, returning the buffer once it is sent.
+    pub async fn send_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+        tokio::time::timeout(self.timeout, self.transport.send(data))
+            .await
+            .map_err(|_| Error::timeout("Timed out sending download data"))?
+    }
+
+    /// Wait for the device to confirm the downloaded data.
+    pub async fn finish_download(&mut self) -> Result<()> {
+        loop {
+            let response = self.read_response().await?;
+            match response.kind {
+                ResponseKind::Okay => return Ok(()),
+                ResponseKind::Info | ResponseKind::Text => {}
+                _ => return Err(Error::fastboot(format!("Download did not complete: {}", response.message))),
+            }
+        }
+    }
+
+    /// Write the last download to `partition`.
+    pub async fn flash(&mut self, partition: &str) -> Result<()> {
+        self.send_command(&format!("flash:{partition}")).await?;
+        self.finish_flash(partition).await
+    }
+
+    /// Wait for the answer to a `flash`. After a timeout the device may still be writing,
+    /// and its late answer would be read as the reply to the next command; calling this
+    /// again waits for it once more.
+    pub async fn finish_flash(&mut self, partition: &str) -> Result<()> {
+        let timeout = std::mem::replace(&mut self.timeout, self.flash_timeout);
+        let result = self.read_responses(|_| {}).await;
+        self.timeout = timeout;
+        okay_payload(&format!("flash:{partition}"), &result?).map(drop)
+    }
+
+    /// Read `size` bytes at `offset` of `partition` with `fetch`, which not every
+    /// bootloader implements.
+    pub async fn fetch(&mut self, partition: &str, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let cmd = format!("fetch:{partition}:0x{offset:08x}:0x{size:08x}");
+        let len = self.data_command(&cmd).await? as usize;
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let want = (len - data.len()).min(MAX_DATA_CHUNK);
+            let chunk = tokio::time::timeout(self.timeout, self.transport.receive(want))
+                .await
+                .map_err(|_| Error::timeout(format!("Timed out reading data of {cmd}")))??;
+            if chunk.is_empty() {
+                return Err(Error::fastboot(format!("Device stopped sending data of {cmd}")));
+            }
+            data.extend_from_slice(&chunk[..chunk.len().min(len - data.len())]);
+        }
+        let done = self.read_response().await?;
+        match done.kind {
+            ResponseKind::Okay => Ok(data),
+            _ => Err(Error::fastboot(format!("{cmd} did not complete: {}", done.message))),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod sim {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use super::Transport;
+    use crate::error::{Error, Result};
+
+    /// A scripted fastboot device for tests.
+    ///
+    /// Each known command answers with a fixed list of response packets; unknown commands
+    /// get `FAILunknown command`. Every command received is recorded in `commands`.
+    /// With [`accept_downloads`](Self::accept_downloads), `download:` commands are
+    /// answered and their data collected in `downloads`.
+    #[derive(Default)]
+    pub struct SimTransport {
+        replies: HashMap<String, Vec<Vec<u8>>>,
+        pending: VecDeque<Vec<u8>>,
+        pub commands: Arc<Mutex<Vec<String>>>,
+        pub downloads: Arc<Mutex<Vec<Vec<u8>>>>,
+        accept_downloads: bool,
+        data_left: usize,
+        /// Per transfer overhead and bytes per second of simulated bulk transfers.
+        link: Option<(Duration, u64)>,
+        /// Data transfers that succeed before the device drops off the bus.
+        fail_after: Option<usize>,
+        transfers: usize,
+        dropped: bool,
+        /// How long the answers to a command take, and when the pending ones are due.
+        delays: HashMap<String, Duration>,
+        due: Option<tokio::time::Instant>,
+    }
+
+    impl SimTransport {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn reply(self, cmd: &str, responses: &[&str]) -> Self {
+            self.reply_bytes(cmd, responses.iter().map(|r| r.as_bytes().to_vec()).collect())
+        }
+
+        /// Like [`reply`](Self::reply), for data phases that are not text.
+        pub fn reply_bytes(mut self, cmd: &str, responses: Vec<Vec<u8>>) -> Self {
+            self.replies.insert(cmd.to_string(), responses);
+            self
+        }
+
+        /// Hold back the answers to `cmd` for `delay`, like a device busy writing.
+        pub fn delay(mut self, cmd: &str, delay: Duration) -> Self {
+            self.delays.insert(cmd.to_string(), delay);
+            self
+        }
+
+        pub fn accept_downloads(mut self) -> Self {
+            self.accept_downloads = true;
+            self
+        }
+
+        /// Make every data transfer take `overhead` plus its length at `bytes_per_sec`.
+        pub fn link(mut self, overhead: Duration, bytes_per_sec: u64) -> Self {
+            self.link = Some((overhead, bytes_per_sec));
+            self
+        }
+
+        /// Drop off the bus, like a device that reset, on the first data transfer after
+        /// `transfers` successful ones; everything after that fails too.
+        pub fn fail_after(mut self, transfers: usize) -> Self {
+            self.fail_after = Some(transfers);
+            self
+        }
+
+        /// Start out unplugged, so that even sending a command fails.
+        pub fn disconnected(mut self) -> Self {
+            self.dropped = true;
+            self
+        }
+    }
+
+    impl Transport for SimTransport {
+        async fn send(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+            if self.data_left > 0 && self.fail_after.is_some_and(|n| self.transfers >= n) {
+                self.dropped = true;
+            }
+            if self.dropped {
+                return Err(Error::usb("Simulated device disconnected"));
+            }
+            if self.data_left > 0 {
+                self.transfers += 1;
+                if data.len() > self.data_left {
+                    return Err(Error::usb("More data than announced"));
+                }
+                if let Some((overhead, bytes_per_sec)) = self.link {
+                    let wire = Duration::from_secs_f64(data.len() as f64 / bytes_per_sec as f64);
+                    tokio::time::sleep(overhead + wire).await;
+                }
+                self.downloads.lock().unwrap().last_mut().unwrap().extend_from_slice(&data);
+                self.data_left -= data.len();
+                if self.data_left == 0 {
+                    self.pending.push_back(b"OKAY".to_vec());
+                }
+                return Ok(data);
+            }
+            let cmd = String::from_utf8_lossy(&data).to_string();
+            let size = cmd.strip_prefix("download:").and_then(|size| usize::from_str_radix(size, 16).ok());
+            if let Some(size) = size.filter(|_| self.accept_downloads) {
+                self.data_left = size;
+                self.downloads.lock().unwrap().push(Vec::new());
+                self.pending.push_back(format!("DATA{size:08x}").into_bytes());
+                if size == 0 {
+                    self.pending.push_back(b"OKAY".to_vec());
+                }
+                self.commands.lock().unwrap().push(cmd);
+                return Ok(data);
+            }
+            let replies = self
+                .replies
+                .get(&cmd)
+                .cloned()
+                .unwrap_or_else(|| vec![b"FAILunknown command".to_vec()]);
+            self.pending.extend(replies);
+            if let Some(delay) = self.delays.get(&cmd) {
+                self.due = Some(tokio::time::Instant::now() + *delay);
+            }
+            self.commands.lock().unwrap().push(cmd);
+            Ok(data)
+        }
+
+        async fn receive(&mut self, _max_len: usize) -> Result<Vec<u8>> {
+            if self.dropped {
+                return Err(Error::usb("Simulated device disconnected"));
+            }
+            if let Some(due) = self.due {
+                tokio::time::sleep_until(due).await;
+                self.due = None;
+            }
+            self.pending
+                .pop_front()
+                .ok_or_else(|| Error::usb("No response queued"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sim::SimTransport;
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let response = Response::parse(b"INFOerasing userdata").unwrap();
+        assert_eq!(response.kind, ResponseKind::Info);
+        assert_eq!(response.message, "erasing userdata");
+        assert!(!response.is_final());
+
+        let response = Response::parse(b"DATA00001000").unwrap();
+        assert_eq!(response.kind, ResponseKind::Data);
+        assert!(response.is_final());
+
+        assert!(Response::parse(b"OK").is_err());
+        assert!(Response::parse(b"NOPEmessage").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_command_collects_all_responses() {
+        let transport = SimTransport::new().reply(
+            "oem version",
+            &["INFOu-boot 2020.01", "TEXTbuilt for th1520", "OKAY"],
+        );
+        let mut fb = FastBoot::new(transport);
+        let mut seen = 0;
+        let responses = fb.command("oem version", |_| seen += 1).await.unwrap();
+        assert_eq!(seen, 3);
+        let kinds: Vec<_> = responses.iter().map(|r| r.kind).collect();
+        assert_eq!(kinds, vec![ResponseKind::Info, ResponseKind::Text, ResponseKind::Okay]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_fail_is_error() {
+        let transport = SimTransport::new()
+            .reply("getvar:version", &["OKAY0.4"])
+            .reply("getvar:slot-count", &["FAILunknown variable"]);
+        let mut fb = FastBoot::new(transport);
+        assert_eq!(fb.get_var("version").await.unwrap(), "0.4");
+        let err = fb.get_var("slot-count").await.unwrap_err();
+        assert!(err.to_string().contains("unknown variable"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reads_data_phase() {
+        let transport = SimTransport::new()
+            .reply_bytes(
+                "fetch:env:0x00000000:0x00000006",
+                vec![b"DATA00000006".to_vec(), vec![0, 1, 2], vec![0xff, 4, 5], b"OKAY".to_vec()],
+            )
+            .reply("fetch:env:0x00000000:0x00000010", &["FAILunknown command"]);
+        let mut fb = FastBoot::new(transport);
+        assert_eq!(fb.fetch("env", 0, 6).await.unwrap(), vec![0, 1, 2, 0xff, 4, 5]);
+        assert!(fb.fetch("env", 0, 16).await.is_err());
+    }
+}
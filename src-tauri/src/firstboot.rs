@@ -0,0 +1,400 @@
+//! First-boot configuration written straight into a copy of the root image, so a
+//! freshly flashed board comes up with its hostname, SSH keys, Wi-Fi and password.
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::error::{Error, Result};
+use crate::ext4::{Attributes, Ext4};
+
+const CRYPT_ROUNDS: usize = 5000;
+const CRYPT_ALPHABET: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WifiNetwork {
+    pub ssid: String,
+    /// WPA passphrase or 64 hex digit key; `None` for an open network.
+    pub psk: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FirstBootProfile {
+    pub hostname: Option<String>,
+    /// The existing account that receives the keys and password.
+    #[serde(default = "default_user")]
+    pub user: String,
+    #[serde(default)]
+    pub authorized_keys: Vec<String>,
+    pub wifi: Option<WifiNetwork>,
+    pub password: Option<String>,
+}
+
+fn default_user() -> String {
+    "debian".to_string()
+}
+
+impl Default for FirstBootProfile {
+    fn default() -> Self {
+        Self { hostname: None, user: default_user(), authorized_keys: Vec::new(), wifi: None, password: None }
+    }
+}
+
+impl FirstBootProfile {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(hostname) = &self.hostname {
+            let valid_label = |label: &str| {
+                (1..=63).contains(&label.len())
+                    && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+            };
+            if hostname.len() > 253 || !hostname.split('.').all(valid_label) {
+                return Err(Error::invalid_input(format!("Invalid hostname: {hostname:?}")));
+            }
+        }
+        if self.user.is_empty() || self.user.contains([':', '/', '\n']) {
+            return Err(Error::invalid_input(format!("Invalid user name: {:?}", self.user)));
+        }
+        for key in &self.authorized_keys {
+            let key = key.trim();
+            if key.is_empty() || key.contains(['\n', '\r']) || key.split_whitespace().count() < 2 {
+                return Err(Error::invalid_input(format!("Invalid SSH public key: {key:?}")));
+            }
+        }
+        if let Some(wifi) = &self.wifi {
+            if !(1..=32).contains(&wifi.ssid.len()) || wifi.ssid.chars().any(char::is_control) {
+                return Err(Error::invalid_input(format!("Invalid Wi-Fi SSID: {:?}", wifi.ssid)));
+            }
+            if let Some(psk) = &wifi.psk {
+                let passphrase = (8..=63).contains(&psk.len()) && psk.bytes().all(|b| (0x20..0x7f).contains(&b));
+                let raw_key = psk.len() == 64 && psk.bytes().all(|b| b.is_ascii_hexdigit());
+                if !passphrase && !raw_key {
+                    return Err(Error::invalid_input("Wi-Fi passphrase must be 8 to 63 printable characters"));
+                }
+            }
+        }
+        if self.password.as_deref() == Some("") {
+            return Err(Error::invalid_input("Password must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+/// An account from /etc/passwd.
+struct Account {
+    uid: u32,
+    gid: u32,
+    home: String,
+}
+
+fn find_account(passwd: &str, user: &str) -> Option<Account> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 7 || fields[0] != user {
+            return None;
+        }
+        Some(Account { uid: fields[2].parse().ok()?, gid: fields[3].parse().ok()?, home: fields[5].to_string() })
+    })
+}
+
+fn read_text(fs: &mut Ext4, path: &str) -> Result<Option<String>> {
+    Ok(fs.read_file(path)?.map(|data| String::from_utf8_lossy(&data).into_owned()))
+}
+
+/// `/etc/hosts` with the 127.0.1.1 line pointing at `hostname`, as Debian sets it up.
+fn update_hosts(hosts: &str, hostname: &str) -> String {
+    let entry = format!("127.0.1.1\t{hostname}");
+    let mut found = false;
+    let mut lines: Vec<String> = hosts
+        .lines()
+        .map(|line| {
+            if line.split_whitespace().next() == Some("127.0.1.1") {
+                found = true;
+                entry.clone()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        lines.push(entry);
+    }
+    lines.join("\n") + "\n"
+}
+
+fn merge_authorized_keys(existing: &str, keys: &[String]) -> String {
+    let mut lines: Vec<String> = existing.lines().map(String::from).collect();
+    for key in keys {
+        let key = key.trim();
+        if !lines.iter().any(|line| line.trim() == key) {
+            lines.push(key.to_string());
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+fn nm_connection(wifi: &WifiNetwork) -> String {
+    // Stable across runs, so re-customizing replaces rather than duplicates the profile
+    let digest = Sha256::digest(format!("revyos-imager-wifi:{}", wifi.ssid));
+    let mut uuid = hex::encode(&digest[..16]);
+    uuid.replace_range(12..13, "4");
+    uuid.replace_range(16..17, &format!("{:x}", 0x8 | (digest[8] & 0x3)));
+    let uuid = format!("{}-{}-{}-{}-{}", &uuid[..8], &uuid[8..12], &uuid[12..16], &uuid[16..20], &uuid[20..]);
+    let mut config = format!(
+        "[connection]\nid={ssid}\nuuid={uuid}\ntype=wifi\nautoconnect=true\n\n\
+         [wifi]\nmode=infrastructure\nssid={ssid}\n\n",
+        ssid = wifi.ssid
+    );
+    if let Some(psk) = &wifi.psk {
+        config.push_str(&format!("[wifi-security]\nkey-mgmt=wpa-psk\npsk={psk}\n\n"));
+    }
+    config.push_str("[ipv4]\nmethod=auto\n\n[ipv6]\nmethod=auto\n");
+    config
+}
+
+fn crypt_base64(bytes: &[u8], out: &mut String) {
+    for group in bytes.chunks(3) {
+        let mut word = 0u32;
+        for (i, &b) in group.iter().enumerate() {
+            word |= (b as u32) << (16 - 8 * i);
+        }
+        for _ in 0..group.len() + 1 {
+            out.push(CRYPT_ALPHABET[(word & 0x3f) as usize] as char);
+            word >>= 6;
+        }
+    }
+}
+
+/// glibc's SHA-512 crypt (`$6$`) with the default number of rounds.
+fn sha512_crypt(password: &str, salt: &str) -> String {
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(16)];
+    let repeat = |digest: &[u8], len: usize| -> Vec<u8> { digest.iter().cycle().take(len).copied().collect() };
+
+    let b = Sha512::new().chain_update(password).chain_update(salt).chain_update(password).finalize();
+    let mut a = Sha512::new().chain_update(password).chain_update(salt);
+    a.update(repeat(&b, password.len()));
+    let mut len = password.len();
+    while len > 0 {
+        if len & 1 != 0 {
+            a.update(b);
+        } else {
+            a.update(password);
+        }
+        len >>= 1;
+    }
+    let mut a = a.finalize();
+    let mut dp = Sha512::new();
+    for _ in 0..password.len() {
+        dp.update(password);
+    }
+    let p = repeat(&dp.finalize(), password.len());
+    let mut ds = Sha512::new();
+    for _ in 0..16 + a[0] as usize {
+        ds.update(salt);
+    }
+    let s = repeat(&ds.finalize(), salt.len());
+    for round in 0..CRYPT_ROUNDS {
+        let mut c = Sha512::new();
+        if round % 2 == 1 {
+            c.update(&p);
+        } else {
+            c.update(a);
+        }
+        if round % 3 != 0 {
+            c.update(&s);
+        }
+        if round % 7 != 0 {
+            c.update(&p);
+        }
+        if round % 2 == 1 {
+            c.update(a);
+        } else {
+            c.update(&p);
+        }
+        a = c.finalize();
+    }
+
+    // The digest bytes go out in this interleaved order
+    let mut permuted = Vec::with_capacity(63);
+    for i in 0..21 {
+        permuted.extend([a[i * 22 % 63], a[(i * 22 + 21) % 63], a[(i * 22 + 42) % 63]]);
+    }
+    let mut hash = String::new();
+    crypt_base64(&permuted, &mut hash);
+    hash.push(CRYPT_ALPHABET[(a[63] & 0x3f) as usize] as char);
+    hash.push(CRYPT_ALPHABET[(a[63] >> 6) as usize] as char);
+    format!("$6${}${hash}", String::from_utf8_lossy(salt))
+}
+
+/// 16 salt characters from the OS random source.
+fn new_salt() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| Error::internal(format!("Cannot get random bytes for a password salt: {e}")))?;
+    Ok(bytes.iter().map(|b| CRYPT_ALPHABET[(b & 0x3f) as usize] as char).collect())
+}
+
+/// `/etc/shadow` with the password of `user` replaced.
+fn update_shadow(shadow: &str, user: &str, password: &str) -> Result<String> {
+    let days = chrono::Utc::now().timestamp() / 86400;
+    let salt = new_salt()?;
+    let mut found = false;
+    let lines: Vec<String> = shadow
+        .lines()
+        .map(|line| {
+            let mut fields: Vec<String> = line.split(':').map(String::from).collect();
+            if fields.len() >= 3 && fields[0] == user {
+                found = true;
+                fields[1] = sha512_crypt(password, &salt);
+                fields[2] = days.to_string();
+            }
+            fields.join(":")
+        })
+        .collect();
+    if !found {
+        return Err(Error::invalid_image(format!("User {user} has no entry in /etc/shadow")));
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Apply `profile` to an opened root filesystem.
+fn apply(fs: &mut Ext4, profile: &FirstBootProfile) -> Result<()> {
+    let root_file = Attributes::root(0o644);
+    if let Some(hostname) = &profile.hostname {
+        fs.write_file("/etc/hostname", format!("{hostname}\n").as_bytes(), root_file)?;
+        let hosts = read_text(fs, "/etc/hosts")?.unwrap_or_else(|| "127.0.0.1\tlocalhost\n".to_string());
+        fs.write_file("/etc/hosts", update_hosts(&hosts, hostname).as_bytes(), root_file)?;
+    }
+
+    if !profile.authorized_keys.is_empty() || profile.password.is_some() {
+        let passwd = read_text(fs, "/etc/passwd")?
+            .ok_or_else(|| Error::invalid_image("The image has no /etc/passwd"))?;
+        let account = find_account(&passwd, &profile.user)
+            .ok_or_else(|| Error::invalid_image(format!("User {} does not exist in the image", profile.user)))?;
+        if !profile.authorized_keys.is_empty() {
+            let owned = |mode| Attributes { mode, uid: account.uid, gid: account.gid };
+            let home = account.home.trim_end_matches('/');
+            let ssh_dir = format!("{home}/.ssh");
+            // Only the home directory itself belongs to the user, not `/home` above it
+            if let Some((parent, _)) = home.rsplit_once('/').filter(|(parent, _)| !parent.is_empty()) {
+                fs.create_dir_all(parent, Attributes::root(0o755))?;
+            }
+            fs.create_dir_all(home, owned(0o755))?;
+            fs.create_dir_all(&ssh_dir, owned(0o700))?;
+            let path = format!("{ssh_dir}/authorized_keys");
+            let existing = read_text(fs, &path)?.unwrap_or_default();
+            let keys = merge_authorized_keys(&existing, &profile.authorized_keys);
+            fs.write_file(&path, keys.as_bytes(), owned(0o600))?;
+        }
+        if let Some(password) = &profile.password {
+            let shadow = read_text(fs, "/etc/shadow")?
+                .ok_or_else(|| Error::invalid_image("The image has no /etc/shadow"))?;
+            fs.write_file("/etc/shadow", update_shadow(&shadow, &profile.user, password)?.as_bytes(), root_file)?;
+        }
+    }
+
+    if let Some(wifi) = &profile.wifi {
+        let dir = "/etc/NetworkManager/system-connections";
+        fs.create_dir_all(dir, Attributes::root(0o755))?;
+        let name: String = wifi.ssid.chars().map(|c| if c == '/' { '_' } else { c }).collect();
+        // NetworkManager ignores connection files readable by others
+        fs.write_file(&format!("{dir}/{name}.nmconnection"), nm_connection(wifi).as_bytes(), Attributes::root(0o600))?;
+    }
+    Ok(())
+}
+
+/// Write `profile` into `output`, a copy of the raw ext4 root image `source`.
+pub fn customize_image(source: &Path, output: &Path, profile: &FirstBootProfile) -> Result<()> {
+    profile.validate()?;
+    crate::ext4::edit_copy(source, output, |fs| apply(fs, profile))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4::tests::{fixture, fsck, owner};
+
+    #[test]
+    fn test_sha512_crypt() {
+        // From the glibc SHA-crypt specification
+        assert_eq!(
+            sha512_crypt("Hello world!", "saltstring"),
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1"
+        );
+        assert_eq!(new_salt().unwrap().len(), 16);
+        assert_ne!(new_salt().unwrap(), new_salt().unwrap());
+    }
+
+    #[test]
+    fn test_validate() {
+        let profile = |f: fn(&mut FirstBootProfile)| {
+            let mut profile = FirstBootProfile::default();
+            f(&mut profile);
+            profile.validate()
+        };
+        assert!(profile(|p| p.hostname = Some("lpi4a-01.lab".into())).is_ok());
+        assert!(profile(|p| p.hostname = Some("-bad".into())).is_err());
+        assert!(profile(|p| p.hostname = Some("under_score".into())).is_err());
+        assert!(profile(|p| p.authorized_keys = vec!["ssh-ed25519 AAAA me@host".into()]).is_ok());
+        assert!(profile(|p| p.authorized_keys = vec!["ssh-ed25519 AAAA\nssh-rsa BBBB".into()]).is_err());
+        assert!(profile(|p| p.wifi = Some(WifiNetwork { ssid: "lab".into(), psk: Some("short".into()) })).is_err());
+        assert!(profile(|p| p.wifi = Some(WifiNetwork { ssid: "lab".into(), psk: Some("a".repeat(64)) })).is_ok());
+        assert!(profile(|p| p.wifi = Some(WifiNetwork { ssid: "x".repeat(33), psk: None })).is_err());
+        assert!(profile(|p| p.password = Some(String::new())).is_err());
+    }
+
+    #[test]
+    fn test_hosts_and_keys() {
+        let hosts = "127.0.0.1\tlocalhost\n127.0.1.1\trevyos-lpi4a\n::1\tlocalhost\n";
+        assert_eq!(update_hosts(hosts, "lab-7"), "127.0.0.1\tlocalhost\n127.0.1.1\tlab-7\n::1\tlocalhost\n");
+        assert_eq!(update_hosts("127.0.0.1 localhost", "lab-7"), "127.0.0.1 localhost\n127.0.1.1\tlab-7\n");
+        let keys = merge_authorized_keys("ssh-rsa AAAA old\n", &["ssh-rsa AAAA old".into(), "ssh-ed25519 BBBB".into()]);
+        assert_eq!(keys, "ssh-rsa AAAA old\nssh-ed25519 BBBB\n");
+    }
+
+    #[test]
+    fn test_customize_image() {
+        // /etc holds hostname, hosts, passwd and shadow, and there is no /home
+        let image = fixture("firstboot");
+        let output = crate::ext4::copy_path(&image);
+        let profile = FirstBootProfile {
+            hostname: Some("lab-board-7".into()),
+            authorized_keys: vec!["ssh-ed25519 AAAAC3Nza me@laptop".into()],
+            wifi: Some(WifiNetwork { ssid: "Lab/5G".into(), psk: Some("correct horse".into()) }),
+            password: Some("revyos".into()),
+            ..Default::default()
+        };
+        customize_image(&image, &output, &profile).unwrap();
+        fsck(&output);
+
+        let mut fs = Ext4::open(&output).unwrap();
+        assert_eq!(read_text(&mut fs, "/etc/hostname").unwrap().unwrap(), "lab-board-7\n");
+        assert!(read_text(&mut fs, "/etc/hosts").unwrap().unwrap().contains("127.0.1.1\tlab-board-7\n"));
+        let keys = read_text(&mut fs, "/home/debian/.ssh/authorized_keys").unwrap().unwrap();
+        assert_eq!(keys, "ssh-ed25519 AAAAC3Nza me@laptop\n");
+        // The missing home directory is created for the user, `/home` above it for root
+        assert_eq!(owner(&mut fs, "/home"), 0);
+        assert_eq!(owner(&mut fs, "/home/debian"), 1000);
+        let wifi = read_text(&mut fs, "/etc/NetworkManager/system-connections/Lab_5G.nmconnection").unwrap().unwrap();
+        assert!(wifi.contains("ssid=Lab/5G\n") && wifi.contains("psk=correct horse\n"));
+        let shadow = read_text(&mut fs, "/etc/shadow").unwrap().unwrap();
+        let entry = shadow.lines().find(|line| line.starts_with("debian:")).unwrap();
+        let hash = entry.split(':').nth(1).unwrap();
+        let salt = hash.split('$').nth(2).unwrap();
+        assert_eq!(hash, sha512_crypt("revyos", salt));
+        assert!(shadow.starts_with("root:*:19000:"));
+        drop(fs);
+
+        // The source stays untouched, and a profile for a missing user leaves nothing behind
+        let mut fs = Ext4::open(&image).unwrap();
+        assert_eq!(read_text(&mut fs, "/etc/hostname").unwrap().unwrap(), "revyos-lpi4a\n");
+        drop(fs);
+        let missing = FirstBootProfile { user: "nobody".into(), password: Some("x".into()), ..Default::default() };
+        assert!(customize_image(&image, &output, &missing).is_err());
+        assert!(!output.exists());
+        let _ = std::fs::remove_file(image);
+    }
+}
@@ -1,113 +1,688 @@
+use std::future::Future;
 use std::io::SeekFrom;
-use anyhow::{bail, Context};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
-use android_sparse_image::{split::split_image, ChunkHeader, FileHeader, FileHeaderBytes, CHUNK_HEADER_BYTES_LEN};
+use tokio::sync::mpsc;
 
-pub async fn flash_raw<R>(
-    fb: &mut fastboot_protocol::nusb::NusbFastBoot,
-    target: &str,
-    mut file: R,
-    file_size: u32,
-) -> anyhow::Result<()>
+use crate::error::{Error, ErrorKind, Result, ResultExt};
+use crate::fastboot::{FastBoot, NusbTransport, Transport};
+use crate::sparse::{Segment, WINDOW_OVERHEAD};
+use crate::tunables::Tuning;
+use crate::usb::{self, USBDevice, FASTBOOT_INTERFACE};
+
+/// How long a device that reset during a transfer gets to come back.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How far the upload reads ahead of the USB transfers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadOptions {
+    /// Bytes per read and per bulk transfer.
+    pub buffer_size: usize,
+    /// Buffers in flight: filled and waiting, or being sent. One means reading and
+    /// sending take turns.
+    pub buffers: usize,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self { buffer_size: 1 << 20, buffers: 4 }
+    }
+}
+
+/// How often a failed split is sent again, and how long to wait before each retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryPolicy {
+    /// Attempts per split after the first one.
+    pub retries: u32,
+    /// Wait before the first retry; it doubles for every further one.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { retries: 3, initial_backoff_ms: 1000, max_backoff_ms: 8000 }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retry number `attempt`, counting from 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ms = self.initial_backoff_ms.saturating_mul(1 << (attempt - 1).min(16));
+        Duration::from_millis(ms.min(self.max_backoff_ms))
+    }
+}
+
+/// What [`flash_file`] reports while it prepares and sends a file.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "event", content = "data")]
+pub enum UploadProgressEvent {
+    #[serde(rename_all = "camelCase")]
+    Progress { current: u64, total: u64 },
+    /// A compressed local file is being extracted before the upload starts.
+    #[serde(rename_all = "camelCase")]
+    Extract { current: u64, total: u64 },
+    /// The ext4 image is being grown to the partition size before the upload.
+    #[serde(rename_all = "camelCase")]
+    Grow { size: u64 },
+}
+
+static NEXT_GROWN_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Opens a fresh connection to the device being flashed, which may have reset and
+/// re-enumerated after a USB error.
+pub trait Reconnect<T: Transport>: Send {
+    fn reconnect(&mut self) -> impl Future<Output = Result<FastBoot<T>>> + Send;
+}
+
+/// Finds the same board on the bus again and keeps its new address.
+///
+/// Only a board known by serial or port is looked for: among identical boards without
+/// either, any of them could answer.
+impl Reconnect<NusbTransport> for USBDevice {
+    async fn reconnect(&mut self) -> Result<FastBoot<NusbTransport>> {
+        self.ensure_identifiable()?;
+        let device = self.clone();
+        let info = usb::wait_for_device(RECONNECT_TIMEOUT, |info| {
+            device.is_same_board(info) && usb::has_interface(info, FASTBOOT_INTERFACE)
+        })
+        .await?;
+        println!("Reconnected to {} at address {}", device.product_string, info.device_address());
+        *self = info.clone().into();
+        FastBoot::from_info(&info)
+    }
+}
+
+/// Fills buffers from the pool with the segments, in order, and queues them for sending.
+struct Filler {
+    buffer: Vec<u8>,
+    buffer_size: usize,
+    free: mpsc::Receiver<Vec<u8>>,
+    full: mpsc::Sender<Result<Vec<u8>>>,
+}
+
+impl Filler {
+    /// Queue the current buffer and wait for a free one. `false` once the sending
+    /// side has stopped.
+    async fn ship(&mut self) -> bool {
+        let buffer = std::mem::take(&mut self.buffer);
+        if self.full.send(Ok(buffer)).await.is_err() {
+            return false;
+        }
+        let Some(mut next) = self.free.recv().await else {
+            return false;
+        };
+        next.clear();
+        self.buffer = next;
+        true
+    }
+
+    async fn fill<R: AsyncRead + AsyncSeek + Unpin>(&mut self, source: &mut R, segments: Vec<Segment>) -> Result<()> {
+        for segment in segments {
+            match segment {
+                Segment::Bytes(bytes) => {
+                    let mut rest = &bytes[..];
+                    while !rest.is_empty() {
+                        let n = rest.len().min(self.buffer_size - self.buffer.len());
+                        self.buffer.extend_from_slice(&rest[..n]);
+                        rest = &rest[n..];
+                        if self.buffer.len() == self.buffer_size && !self.ship().await {
+                            return Ok(());
+                        }
+                    }
+                }
+                Segment::File { offset, len } => {
+                    source.seek(SeekFrom::Start(offset)).await.context("Failed to seek input file")?;
+                    let mut left = len;
+                    while left > 0 {
+                        let n = left.min((self.buffer_size - self.buffer.len()) as u64) as usize;
+                        let start = self.buffer.len();
+                        self.buffer.resize(start + n, 0);
+                        source.read_exact(&mut self.buffer[start..]).await.context("Failed to read from file")?;
+                        left -= n as u64;
+                        if self.buffer.len() == self.buffer_size && !self.ship().await {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+        if !self.buffer.is_empty() {
+            let buffer = std::mem::take(&mut self.buffer);
+            let _ = self.full.send(Ok(buffer)).await;
+        }
+        Ok(())
+    }
+}
+
+/// Download `segments` to the device. A reader task fills up to `options.buffers`
+/// buffers from `source` while earlier ones are on the wire, so disk and USB latency
+/// overlap instead of adding up.
+pub async fn upload<T, R>(fb: &mut FastBoot<T>, source: R, segments: Vec<Segment>, options: UploadOptions) -> Result<()>
 where
-    R: AsyncRead + AsyncSeek + Unpin,
+    T: Transport,
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
 {
-    println!("Uploading raw image directly");
-    let mut sender = fb.download(file_size).await?;
-    loop {
-        let left = sender.left();
-        if left == 0 {
-            break;
-        }
-        let buf = sender.get_mut_data(left as usize).await?;
-        file.read_exact(buf)
-            .await
-            .context("Failed to read from file")?;
+    if options.buffer_size == 0 || options.buffers == 0 {
+        return Err(Error::invalid_input("Uploads need at least one non-empty buffer"));
     }
+    let size = segments.iter().map(Segment::len).sum::<u64>();
+    let size = u32::try_from(size).map_err(|_| Error::invalid_input(format!("{size} bytes are too many for one download")))?;
+    fb.start_download(size).await?;
 
-    sender.finish().await?;
-    println!("Flashing data");
-    fb.flash(target).await?;
-
-    Ok(())
+    let (full_tx, mut full_rx) = mpsc::channel(options.buffers);
+    let (free_tx, free_rx) = mpsc::channel(options.buffers);
+    for _ in 1..options.buffers {
+        free_tx.try_send(Vec::with_capacity(options.buffer_size)).expect("pool has room for every buffer");
+    }
+    let mut filler = Filler {
+        buffer: Vec::with_capacity(options.buffer_size),
+        buffer_size: options.buffer_size,
+        free: free_rx,
+        full: full_tx,
+    };
+    let reader = tokio::spawn(async move {
+        let mut source = source;
+        if let Err(e) = filler.fill(&mut source, segments).await {
+            let _ = filler.full.send(Err(e)).await;
+        }
+    });
+    // Dropping the receiving ends on error stops the reader at its next buffer
+    let result: Result<()> = async {
+        while let Some(buffer) = full_rx.recv().await {
+            let buffer = fb.send_data(buffer?).await?;
+            // The pool never holds more buffers than it was made with
+            let _ = free_tx.try_send(buffer);
+        }
+        Ok(())
+    }
+    .await;
+    drop((full_rx, free_tx));
+    reader.await.map_err(|e| Error::internal(e.to_string()))?;
+    result?;
+    fb.finish_download().await
 }
 
-pub async fn flash<F>(
-    fb: &mut fastboot_protocol::nusb::NusbFastBoot,
+/// Flash `file` to `target`, splitting it to fit the device's download buffer.
+///
+/// Images that clearly do not belong on `target` (see [`crate::inspect::fit`]) are
+/// refused unless `force` is set. A split that fails with a retryable error is sent
+/// again over a fresh connection from `device`, as `retry` allows; splits that
+/// already went through are not repeated. `tuning` may cap the download size the
+/// device reports and sets block size, transfer size and timeouts.
+#[allow(clippy::too_many_arguments)]
+pub async fn flash<T, C, F>(
+    mut fb: FastBoot<T>,
+    device: &mut C,
     target: &str,
     file: &std::path::Path,
-    mut progress_callback: F,
-) -> anyhow::Result<()>
+    force: bool,
+    retry: RetryPolicy,
+    tuning: &Tuning,
+    progress_callback: F,
+) -> Result<()>
 where
+    T: Transport,
+    C: Reconnect<T>,
     F: FnMut(u64, u64) + Send + 'static,
 {
-    let max_download = fb.get_var("max-download-size").await?;
-    let max_download = fastboot_protocol::protocol::parse_u32_hex(&max_download)
-        .with_context(|| anyhow::anyhow!("Failed to parse max download size: {max_download}"))?;
-    println!("Max download size: {max_download}");
-
-    let mut f = tokio::fs::File::open(file).await?;
-    let mut header_bytes = FileHeaderBytes::default();
-    f.read_exact(&mut header_bytes).await?;
-    let splits = match FileHeader::from_bytes(&header_bytes) {
-        Ok(header) => {
+    crate::inspect::check(file, target, force)?;
+    tuning.log();
+    tuning.validate()?;
+    fb.set_timeouts(tuning.command_timeout.value, tuning.flash_timeout.value);
+    let reported = fb.get_var("max-download-size").await?;
+    let reported = u32::from_str_radix(reported.trim().trim_start_matches("0x"), 16)
+        .map_err(|_| Error::fastboot(format!("Failed to parse max download size: {reported}")))?;
+    let max_download = match &tuning.max_download_size {
+        Some(limit) => {
+            println!("Max download size: {} ({}, device reports {reported})", limit.value, limit.source);
+            limit.value
+        }
+        None => {
+            println!("Max download size: {reported} (reported by device)");
+            reported
+        }
+    };
+
+    let path = file.to_path_buf();
+    let map = tokio::task::spawn_blocking(move || {
+        let mut f = std::fs::File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let map = crate::sparse::read_map(&mut f)?;
+        Ok::<_, Error>((map, f.metadata()?.len()))
+    })
+    .await
+    .map_err(|e| Error::internal(e.to_string()))?;
+    let splits = match map? {
+        (Some(map), _) => {
             println!("Preparing to flash android sparse image");
-            let mut chunks = vec![];
-            for _ in 0..header.chunks {
-                let mut chunk_bytes = [0; CHUNK_HEADER_BYTES_LEN];
-                f.read_exact(&mut chunk_bytes).await?;
-                let chunk = ChunkHeader::from_bytes(&chunk_bytes)?;
-
-                f.seek(SeekFrom::Current(chunk.data_size() as i64)).await?;
-                chunks.push(chunk);
-            }
-            split_image(&header, &chunks, max_download)?
-        }
-        Err(android_sparse_image::ParseError::UnknownMagic) => {
-            f.seek(SeekFrom::Start(0))
-                .await
-                .context("Seeking back to the start")?;
-            let file_size = f
-                .seek(SeekFrom::End(0))
-                .await
-                .context("Seek for determining file size")?;
-            if file_size < max_download as u64 {
-                f.seek(SeekFrom::Start(0))
-                    .await
-                    .context("Seeking back to the start")?;
-                return flash_raw(fb, target, f, file_size as u32).await;
-            }
-            android_sparse_image::split::split_raw(file_size as usize, max_download)?
+            crate::sparse::split_segments(&map, max_download as u64)
+                .with_context(|| format!("Failed to split {}", file.display()))?
+        }
+        (None, file_size) if file_size < max_download as u64 => {
+            println!("Uploading raw image directly");
+            vec![vec![Segment::File { offset: 0, len: file_size }]]
         }
-        Err(e) => bail!("Failed to parse sparse image: {e}"),
+        (None, file_size) => raw_splits(file_size, max_download, tuning.sparse_block_size.value)?,
+    };
+    flash_splits(fb, device, target, file, splits, retry, tuning, progress_callback).await
+}
+
+/// Flash a local file as picked by the user: a compressed file is extracted to
+/// `cache_dir` first, and with `grow` an ext4 image is grown offline to the size the
+/// device reports for `target`, the new space sent as DONT_CARE. The rest is [`flash`].
+#[allow(clippy::too_many_arguments)]
+pub async fn flash_file<T, C, F>(
+    mut fb: FastBoot<T>,
+    device: &mut C,
+    target: &str,
+    file: &std::path::Path,
+    force: bool,
+    grow: bool,
+    retry: RetryPolicy,
+    tuning: &Tuning,
+    cache_dir: &std::path::Path,
+    mut on_event: F,
+) -> Result<()>
+where
+    T: Transport,
+    C: Reconnect<T>,
+    F: FnMut(UploadProgressEvent) + Send + 'static,
+{
+    let source = crate::compression::local_image(file, cache_dir, |_, current, total, _| {
+        on_event(UploadProgressEvent::Extract { current, total })
+    })
+    .await?;
+    let grown = if grow {
+        let var = format!("partition-size:{target}");
+        let value = fb.get_var(&var).await?;
+        let size = u64::from_str_radix(value.trim().trim_start_matches("0x"), 16)
+            .map_err(|_| Error::fastboot(format!("Failed to parse {var}: {value}")))?;
+        on_event(UploadProgressEvent::Grow { size });
+        // One copy per call, as the same image may be flashed to several boards at once
+        let id = NEXT_GROWN_FILE.fetch_add(1, Ordering::Relaxed);
+        let output = crate::sparse::sibling_path(&source, &format!("grown-{}-{id}", std::process::id()));
+        // The grown copy is sparse, so it takes about as much as the source, not `size`
+        let dir = output.parent().unwrap_or(std::path::Path::new("."));
+        crate::space::ensure_space(cache_dir, dir, std::fs::metadata(&source)?.len())?;
+        let (from, to) = (source.clone(), output.clone());
+        tokio::task::spawn_blocking(move || crate::ext4::grow_image(&from, &to, size))
+            .await
+            .map_err(|e| Error::internal(e.to_string()))?
+            .with_context(|| format!("Growing {} to {size} bytes", source.display()))?;
+        Some(output)
+    } else {
+        None
     };
+    let path = grown.as_deref().unwrap_or(&source);
+    let result = flash(fb, device, target, path, force, retry, tuning, move |current, total| {
+        on_event(UploadProgressEvent::Progress { current, total })
+    })
+    .await;
+    if let Some(grown) = grown {
+        let _ = std::fs::remove_file(grown);
+    }
+    result
+}
+
+/// Splits of a raw image of `size` bytes that each fit in `max_download`: sparse images
+/// of `block_size` blocks carrying one stretch of the file and skipping the rest.
+pub fn raw_splits(size: u64, max_download: u32, block_size: u32) -> Result<Vec<Vec<Segment>>> {
+    let per_split = max_download.saturating_sub(WINDOW_OVERHEAD) / block_size;
+    if per_split == 0 {
+        return Err(Error::invalid_input(format!(
+            "A download of {max_download} bytes cannot hold a block of {block_size} bytes"
+        )));
+    }
+    let total = u32::try_from(size.div_ceil(block_size as u64))
+        .map_err(|_| Error::invalid_image("The image has too many blocks for a sparse file"))?;
+    let mut splits = vec![];
+    let mut start = 0;
+    while start < total {
+        let blocks = per_split.min(total - start);
+        let (before, mut after) = crate::sparse::window(block_size, total, start, blocks);
+        let offset = start as u64 * block_size as u64;
+        let len = (blocks as u64 * block_size as u64).min(size - offset);
+        // The last block is padded with zeros
+        let padding = blocks as u64 * block_size as u64 - len;
+        after.splice(0..0, std::iter::repeat_n(0, padding as usize));
+        splits.push(vec![Segment::Bytes(before), Segment::File { offset, len }, Segment::Bytes(after)]);
+        start += blocks;
+    }
+    Ok(splits)
+}
 
+/// Write the downloaded part to `target`.
+///
+/// A timeout does not mean the write failed, only that the device has not answered yet.
+/// Retrying right away would read that late answer as the reply to the next `download:`,
+/// so wait for it once more, and give up for good if it still does not come.
+async fn flash_part<T: Transport>(fb: &mut FastBoot<T>, target: &str) -> Result<()> {
+    match fb.flash(target).await {
+        Err(e) if e.kind == ErrorKind::Timeout => {
+            println!("Flashing {target} timed out, waiting for the device to finish");
+            fb.finish_flash(target).await.map_err(|mut e| {
+                // Still busy: the connection cannot be trusted to be in step any more
+                if e.kind == ErrorKind::Timeout {
+                    e.retryable = false;
+                }
+                e
+            })
+        }
+        result => result,
+    }
+}
+
+/// Download and flash each split in turn, retrying a failed one from its start.
+#[allow(clippy::too_many_arguments)]
+pub async fn flash_splits<T, C, F>(
+    mut fb: FastBoot<T>,
+    device: &mut C,
+    target: &str,
+    file: &std::path::Path,
+    splits: Vec<Vec<Segment>>,
+    retry: RetryPolicy,
+    tuning: &Tuning,
+    mut progress_callback: F,
+) -> Result<()>
+where
+    T: Transport,
+    C: Reconnect<T>,
+    F: FnMut(u64, u64) + Send + 'static,
+{
     println!("Flashing in {} parts", splits.len());
     let total_parts = splits.len() as u64;
-    for (i, split) in splits.iter().enumerate() {
-        println!("Downloading part {i}");
-        let mut sender = fb.download(split.sparse_size() as u32).await?;
-
-        sender.extend_from_slice(&split.header.to_bytes()).await?;
-        for chunk in &split.chunks {
-            sender.extend_from_slice(&chunk.header.to_bytes()).await?;
-            f.seek(SeekFrom::Start(chunk.offset as u64))
-                .await
-                .context("Failed to seek input file")?;
-            let mut left = chunk.size;
-            while left > 0 {
-                let buf = sender.get_mut_data(left).await?;
-                left -= f
-                    .read_exact(buf)
+    for (i, segments) in splits.into_iter().enumerate() {
+        let mut attempt = 0;
+        loop {
+            println!("Downloading part {i}");
+            let result = async {
+                let source = tokio::fs::File::open(file)
                     .await
-                    .context("Failed to read from file")?;
+                    .with_context(|| format!("Failed to open {}", file.display()))?;
+                upload(&mut fb, source, segments.clone(), tuning.upload_options()).await?;
+                println!("Flashing Part {i}");
+                flash_part(&mut fb, target).await
+            }
+            .await
+            .with_context(|| format!("Flashing part {i} to {target}"));
+            match result {
+                Ok(()) => break,
+                Err(e) if e.retryable && attempt < retry.retries => {
+                    attempt += 1;
+                    let wait = retry.backoff(attempt);
+                    println!("Part {i} failed: {e}; retry {attempt}/{} in {wait:?}", retry.retries);
+                    // The old connection has to go before the interface can be claimed again
+                    drop(fb);
+                    tokio::time::sleep(wait).await;
+                    fb = device.reconnect().await.with_context(|| format!("Reconnecting to retry part {i}"))?;
+                    fb.set_timeouts(tuning.command_timeout.value, tuning.flash_timeout.value);
+                }
+                Err(e) => return Err(e),
             }
         }
-        sender.finish().await?;
-        println!("Flashing Part {i}");
-        fb.flash(target).await?;
         progress_callback(i as u64 + 1, total_parts); // Update progress
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::future::Future;
+    use std::io::Cursor;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+    use tokio::io::ReadBuf;
+    use super::*;
+    use crate::fastboot::sim::SimTransport;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 253) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_upload_pipeline() {
+        let file = data(10_000);
+        let transport = SimTransport::new().accept_downloads();
+        let downloads = transport.downloads.clone();
+        let mut fb = FastBoot::new(transport);
+        let segments = vec![
+            Segment::Bytes(b"header".to_vec()),
+            Segment::File { offset: 5000, len: 4321 },
+            Segment::Bytes(vec![]),
+            Segment::File { offset: 0, len: 1000 },
+        ];
+        for (buffer_size, buffers) in [(1, 1), (1000, 3), (64, 2), (1 << 20, 4)] {
+            let options = UploadOptions { buffer_size, buffers };
+            upload(&mut fb, Cursor::new(file.clone()), segments.clone(), options).await.unwrap();
+            let mut expected = b"header".to_vec();
+            expected.extend(&file[5000..9321]);
+            expected.extend(&file[..1000]);
+            assert_eq!(downloads.lock().unwrap().last().unwrap(), &expected);
+        }
+
+        // A short file fails the upload instead of sending garbage
+        let segments = vec![Segment::File { offset: 9000, len: 2000 }];
+        let options = UploadOptions { buffer_size: 256, buffers: 2 };
+        assert!(upload(&mut fb, Cursor::new(file), segments, options).await.is_err());
+    }
+
+    /// Hands out the next scripted device on every reconnect.
+    struct SimReconnect {
+        devices: VecDeque<SimTransport>,
+        reconnects: usize,
+    }
+
+    impl Reconnect<SimTransport> for SimReconnect {
+        async fn reconnect(&mut self) -> Result<FastBoot<SimTransport>> {
+            self.reconnects += 1;
+            let transport = self.devices.pop_front().ok_or_else(Error::device_not_found)?;
+            Ok(FastBoot::new(transport))
+        }
+    }
+
+    fn device() -> SimTransport {
+        SimTransport::new().accept_downloads().reply("flash:boot", &["OKAY"])
+    }
+
+    const RETRY: RetryPolicy = RetryPolicy { retries: 2, initial_backoff_ms: 1, max_backoff_ms: 2 };
+
+    /// Three splits of a 3000 byte file, one data transfer each.
+    fn splits(file: &std::path::Path) -> Vec<Vec<Segment>> {
+        std::fs::write(file, data(3000)).unwrap();
+        (0..3)
+            .map(|i| vec![Segment::Bytes(vec![i as u8]), Segment::File { offset: i * 1000, len: 1000 }])
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_retry_resumes_failed_split() {
+        let file = std::env::temp_dir().join("revyos-imager-flash-retry");
+        let first = device().fail_after(1);
+        let first_downloads = first.downloads.clone();
+        let second = device();
+        let (downloads, commands) = (second.downloads.clone(), second.commands.clone());
+        let mut connect = SimReconnect { devices: VecDeque::from([second]), reconnects: 0 };
+        let progress = Arc::new(Mutex::new(vec![]));
+        let seen = progress.clone();
+        let tuning = Tuning::default();
+        let fb = FastBoot::new(first);
+        flash_splits(fb, &mut connect, "boot", &file, splits(&file), RETRY, &tuning, move |c, t| {
+            seen.lock().unwrap().push((c, t));
+        })
+        .await
+        .unwrap();
+        assert_eq!(connect.reconnects, 1);
+        assert_eq!(*progress.lock().unwrap(), [(1, 3), (2, 3), (3, 3)]);
+        // The failed split is sent again in full, the one before it is not
+        let expected = data(3000);
+        assert_eq!(first_downloads.lock().unwrap()[0][1..], expected[..1000]);
+        let downloads = downloads.lock().unwrap().clone();
+        assert_eq!(downloads.len(), 2);
+        assert_eq!(downloads[0][0], 1);
+        assert_eq!(downloads[0][1..], expected[1000..2000]);
+        assert_eq!(downloads[1][1..], expected[2000..]);
+        assert_eq!(commands.lock().unwrap().iter().filter(|c| *c == "flash:boot").count(), 2);
+
+        // A device that keeps dropping off uses up the retries
+        let devices = VecDeque::from([device().fail_after(0), device().fail_after(0), device()]);
+        let mut connect = SimReconnect { devices, reconnects: 0 };
+        let fb = FastBoot::new(device().fail_after(0));
+        let err = flash_splits(fb, &mut connect, "boot", &file, splits(&file), RETRY, &tuning, |_, _| {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Usb);
+        assert!(err.to_string().contains("Flashing part 0 to boot"));
+        assert_eq!(connect.reconnects, 2);
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[tokio::test]
+    async fn test_device_refusal_is_not_retried() {
+        let file = std::env::temp_dir().join("revyos-imager-flash-refused");
+        let splits = splits(&file);
+        let transport = SimTransport::new().accept_downloads().reply("flash:boot", &["FAILpartition locked"]);
+        let mut connect = SimReconnect { devices: VecDeque::from([device()]), reconnects: 0 };
+        let fb = FastBoot::new(transport);
+        let err = flash_splits(fb, &mut connect, "boot", &file, splits, RETRY, &Tuning::default(), |_, _| {})
+            .await
+            .unwrap_err();
+        assert!(!err.retryable);
+        assert_eq!(connect.reconnects, 0);
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[tokio::test]
+    async fn test_flash_timeout_waits_for_late_answer() {
+        let file = std::env::temp_dir().join("revyos-imager-flash-slow");
+        let mut tuning = Tuning::default();
+        tuning.flash_timeout.value = Duration::from_millis(100);
+        let connect_with = |transport| {
+            let mut fb = FastBoot::new(transport);
+            fb.set_timeouts(tuning.command_timeout.value, tuning.flash_timeout.value);
+            fb
+        };
+
+        // An answer that comes after the timeout is still taken as the answer to that flash
+        let transport = device().delay("flash:boot", Duration::from_millis(150));
+        let commands = transport.commands.clone();
+        let mut connect = SimReconnect { devices: VecDeque::from([device()]), reconnects: 0 };
+        let fb = connect_with(transport);
+        flash_splits(fb, &mut connect, "boot", &file, splits(&file), RETRY, &tuning, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(connect.reconnects, 0);
+        assert_eq!(commands.lock().unwrap().iter().filter(|c| *c == "flash:boot").count(), 3);
+
+        // One that does not come at all is not retried
+        let transport = device().delay("flash:boot", Duration::from_secs(60));
+        let mut connect = SimReconnect { devices: VecDeque::from([device()]), reconnects: 0 };
+        let fb = connect_with(transport);
+        let err = flash_splits(fb, &mut connect, "boot", &file, splits(&file), RETRY, &tuning, |_, _| {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Timeout);
+        assert!(!err.retryable);
+        assert_eq!(connect.reconnects, 0);
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[test]
+    fn test_raw_splits() {
+        let source = data(10_000);
+        let part = std::env::temp_dir().join("revyos-imager-raw-split");
+        let output = std::env::temp_dir().join("revyos-imager-raw-split-out");
+        // Four 1K blocks per split, the last one padded
+        let splits = raw_splits(10_000, 4096 + WINDOW_OVERHEAD, 1024).unwrap();
+        assert_eq!(splits.len(), 3);
+        let mut image = vec![0; 10_240];
+        for segments in splits {
+            let mut bytes = vec![];
+            for segment in segments {
+                match segment {
+                    Segment::Bytes(data) => bytes.extend(data),
+                    Segment::File { offset, len } => bytes.extend(&source[offset as usize..(offset + len) as usize]),
+                }
+            }
+            assert!(bytes.len() <= 4096 + WINDOW_OVERHEAD as usize);
+            std::fs::write(&part, bytes).unwrap();
+            assert_eq!(crate::sparse::unsparse(&part, &output).unwrap(), 10_240);
+            // Each split only writes its own stretch over what earlier ones left
+            let expanded = std::fs::read(&output).unwrap();
+            let map = crate::sparse::chunk_map(&part).unwrap();
+            let raw = map.chunks.iter().find(|c| c.kind == crate::sparse::ChunkKind::Raw).unwrap();
+            let range = raw.start as usize * 1024..(raw.start as usize + raw.blocks as usize) * 1024;
+            image[range.clone()].copy_from_slice(&expanded[range]);
+        }
+        assert_eq!(image[..10_000], source[..]);
+        assert!(image[10_000..].iter().all(|&b| b == 0));
+        assert!(raw_splits(10_000, 1024, 1024).is_err());
+        let _ = std::fs::remove_file(part);
+        let _ = std::fs::remove_file(output);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        let waits: Vec<_> = (1..=5).map(|attempt| policy.backoff(attempt).as_millis()).collect();
+        assert_eq!(waits, [1000, 2000, 4000, 8000, 8000]);
+    }
+
+    /// Reads like a disk that takes `delay` per request.
+    struct SlowReader {
+        inner: Cursor<Vec<u8>>,
+        delay: Duration,
+        sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+    }
+
+    impl AsyncRead for SlowReader {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            let delay = self.delay;
+            let sleep = self.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncSeek for SlowReader {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+            Pin::new(&mut self.inner).start_seek(position)
+        }
+
+        fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+            Pin::new(&mut self.inner).poll_complete(cx)
+        }
+    }
+
+    /// Upload throughput over a simulated 40 MB/s link with 1 ms per transfer, from
+    /// a disk taking 2 ms per read. Run with
+    /// `cargo test --release bench_upload -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_upload() {
+        const LEN: usize = 64 << 20;
+        let file = data(LEN);
+        println!("{:>10} {:>8} {:>10}", "buffer", "buffers", "MB/s");
+        for buffer_size in [64 << 10, 256 << 10, 1 << 20, 4 << 20] {
+            for buffers in [1, 2, 4, 8] {
+                let transport = SimTransport::new().accept_downloads().link(Duration::from_millis(1), 40_000_000);
+                let mut fb = FastBoot::new(transport);
+                let source = SlowReader { inner: Cursor::new(file.clone()), delay: Duration::from_millis(2), sleep: None };
+                let segments = vec![Segment::File { offset: 0, len: LEN as u64 }];
+                let start = Instant::now();
+                upload(&mut fb, source, segments, UploadOptions { buffer_size, buffers }).await.unwrap();
+                let rate = LEN as f64 / start.elapsed().as_secs_f64() / 1e6;
+                println!("{:>9}K {buffers:>8} {rate:>10.1}", buffer_size >> 10);
+            }
+        }
+    }
+}
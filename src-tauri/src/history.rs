@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobKind {
+    Download,
+    Flash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// A file that was downloaded or flashed as part of a job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BinaryRecord {
+    pub name: String,
+    pub path: String,
+    pub partition: Option<String>,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+}
+
+/// One download or flash job, as stored in the history file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub id: String,
+    pub kind: JobKind,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: i64,
+    pub device_serial: Option<String>,
+    pub device: Option<String>,
+    pub image_version: Option<String>,
+    /// The u-boot variant of the image, named after its u-boot binary.
+    pub variant: Option<String>,
+    pub partitions: Vec<String>,
+    pub binaries: Vec<BinaryRecord>,
+    /// Tunable values a flash job ran with, each with where it came from.
+    #[serde(default)]
+    pub tuning: Vec<String>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl HistoryEntry {
+    /// Start a new entry; call [`HistoryEntry::finish`] once the job is over.
+    pub fn start(kind: JobKind) -> Self {
+        let started_at = Utc::now();
+        Self {
+            id: format!(
+                "{}-{}",
+                started_at.timestamp_millis(),
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            ),
+            kind,
+            started_at,
+            duration_ms: 0,
+            device_serial: None,
+            device: None,
+            image_version: None,
+            variant: None,
+            partitions: Vec::new(),
+            binaries: Vec::new(),
+            tuning: Vec::new(),
+            outcome: Outcome::Success,
+            error: None,
+        }
+    }
+
+    pub fn finish<T, E: std::fmt::Display>(&mut self, result: &Result<T, E>) {
+        self.duration_ms = (Utc::now() - self.started_at).num_milliseconds();
+        match result {
+            Ok(_) => {
+                self.outcome = Outcome::Success;
+                self.error = None;
+            }
+            Err(e) => {
+                self.outcome = Outcome::Failure;
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// Append-only job history, stored as one JSON object per line.
+pub struct HistoryStore {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+    /// SHA-256 of files already hashed, keyed by path, size and mtime, so a batch
+    /// flashing the same image on many boards only hashes it once.
+    hash_cache: Mutex<HashMap<(PathBuf, u64, SystemTime), String>>,
+}
+
+impl HistoryStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_lock: Mutex::new(()),
+            hash_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, entry: &HistoryEntry) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+
+    /// Record `entry`, logging instead of failing: a full disk must not fail a flash.
+    pub fn record_or_log(&self, entry: &HistoryEntry) {
+        if let Err(e) = self.record(entry) {
+            eprintln!("Failed to record job history: {e}");
+        }
+    }
+
+    /// All entries, newest first. Lines that fail to parse are skipped.
+    pub fn list(&self) -> Result<Vec<HistoryEntry>> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries: Vec<HistoryEntry> = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    eprintln!("Skipping unreadable history line: {e}");
+                    None
+                }
+            })
+            .collect();
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Describe a local file for the history. The SHA-256 is only filled in if the file
+    /// was hashed before; [`record_hashed`](Self::record_hashed) hashes it once the job is
+    /// over, so hashing a large image never holds up the flash or counts towards its duration.
+    pub fn binary_record(&self, path: &Path, partition: Option<&str>) -> BinaryRecord {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let size = std::fs::metadata(path).ok().map(|m| m.len());
+        let sha256 = cache_key(path).and_then(|key| self.hash_cache.lock().unwrap().get(&key).cloned());
+        BinaryRecord {
+            name,
+            path: path.to_string_lossy().to_string(),
+            partition: partition.map(str::to_string),
+            size,
+            sha256,
+        }
+    }
+
+    /// Hash the binaries of a finished `entry` that have no SHA-256 yet, then record it.
+    pub async fn record_hashed(&self, mut entry: HistoryEntry) {
+        for binary in entry.binaries.iter_mut().filter(|b| b.sha256.is_none()) {
+            binary.sha256 = self.hash(Path::new(&binary.path)).await;
+        }
+        self.record_or_log(&entry);
+    }
+
+    async fn hash(&self, path: &Path) -> Option<String> {
+        let key = cache_key(path);
+        if let Some(hash) = key.as_ref().and_then(|key| self.hash_cache.lock().unwrap().get(key).cloned()) {
+            return Some(hash);
+        }
+        match sha256_file(path).await {
+            Ok(hash) => {
+                if let Some(key) = key {
+                    self.hash_cache.lock().unwrap().insert(key, hash.clone());
+                }
+                Some(hash)
+            }
+            Err(e) => {
+                eprintln!("Failed to hash {}: {e}", path.display());
+                None
+            }
+        }
+    }
+}
+
+/// Identifies a version of a file: a file that changes size or mtime is hashed again.
+fn cache_key(path: &Path) -> Option<(PathBuf, u64, SystemTime)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((path.to_path_buf(), metadata.len(), metadata.modified().ok()?))
+}
+
+pub async fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &Path) -> Self {
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+            ExportFormat::Csv
+        } else {
+            ExportFormat::Json
+        }
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Render entries as CSV, one row per job. Partitions, binaries and tuning are `;` separated.
+pub fn to_csv(entries: &[HistoryEntry]) -> String {
+    let mut out = String::from(
+        "id,kind,started_at,duration_ms,device_serial,device,image_version,variant,partitions,binaries,tuning,outcome,error\n",
+    );
+    for entry in entries {
+        let binaries = entry
+            .binaries
+            .iter()
+            .map(|b| format!("{}={}", b.name, b.sha256.as_deref().unwrap_or("")))
+            .collect::<Vec<_>>()
+            .join(";");
+        let fields = [
+            entry.id.clone(),
+            format!("{:?}", entry.kind),
+            entry.started_at.to_rfc3339(),
+            entry.duration_ms.to_string(),
+            entry.device_serial.clone().unwrap_or_default(),
+            entry.device.clone().unwrap_or_default(),
+            entry.image_version.clone().unwrap_or_default(),
+            entry.variant.clone().unwrap_or_default(),
+            entry.partitions.join(";"),
+            binaries,
+            entry.tuning.join(";"),
+            format!("{:?}", entry.outcome),
+            entry.error.clone().unwrap_or_default(),
+        ];
+        out.push_str(&fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+        out.push('\n');
+    }
+    out
+}
+
+pub fn export(entries: &[HistoryEntry], path: &Path, format: ExportFormat) -> Result<()> {
+    let content = match format {
+        ExportFormat::Csv => to_csv(entries),
+        ExportFormat::Json => serde_json::to_string_pretty(entries)?,
+    };
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("revyos-imager-history-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_record_and_list() {
+        let dir = test_dir("list");
+        let store = HistoryStore::new(dir.join("history.jsonl"));
+        assert!(store.list().unwrap().is_empty());
+
+        let image = dir.join("boot.ext4");
+        std::fs::write(&image, b"hello").unwrap();
+
+        let mut first = HistoryEntry::start(JobKind::Flash);
+        first.device_serial = Some("0123456789ABCDEF".to_string());
+        first.image_version = Some("20250323".to_string());
+        first.partitions.push("boot".to_string());
+        first.binaries.push(store.binary_record(&image, Some("boot")));
+        assert_eq!(first.binaries[0].sha256, None);
+        first.finish::<(), String>(&Ok(()));
+        store.record_hashed(first.clone()).await;
+
+        let mut second = HistoryEntry::start(JobKind::Download);
+        second.finish::<(), String>(&Err("connection reset".to_string()));
+        store.record(&second).unwrap();
+
+        let entries = store.list().unwrap();
+        let hash = Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string());
+        first.binaries[0].sha256 = hash.clone();
+        assert_eq!(entries, vec![second.clone(), first.clone()]);
+        // Known from then on, without hashing again
+        assert_eq!(store.binary_record(&image, None).sha256, hash);
+        assert_eq!(entries[0].outcome, Outcome::Failure);
+        assert_eq!(entries[0].error.as_deref(), Some("connection reset"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_csv_export() {
+        let mut entry = HistoryEntry::start(JobKind::Flash);
+        entry.partitions = vec!["boot".to_string(), "root".to_string()];
+        entry.tuning = vec!["Sparse block size: 4096 (default)".to_string()];
+        entry.finish::<(), String>(&Err("FAIL: \"partition\" not found, aborting".to_string()));
+        let csv = to_csv(&[entry]);
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().starts_with("id,kind,started_at"));
+        let row = lines.next().unwrap();
+        assert!(row.contains(",boot;root,,Sparse block size: 4096 (default),"));
+        assert!(row.ends_with(",Failure,\"FAIL: \"\"partition\"\" not found, aborting\""));
+    }
+
+    #[test]
+    fn test_export_format_from_path() {
+        assert_eq!(ExportFormat::from_path(Path::new("history.CSV")), ExportFormat::Csv);
+        assert_eq!(ExportFormat::from_path(Path::new("history.json")), ExportFormat::Json);
+    }
+}
@@ -1,97 +1,156 @@
 use scraper::{Html, Selector};
 use std::collections::HashMap;
 
-use crate::image::{ImageBinary, ImageBinaryType, ImageVariant, ImageVersion};
+use futures::StreamExt;
 
-/// This function fetches HTML content from a given URL and parses it to extract links.
-///
-/// It returns a vector of hash maps, where each map contains the name and address of a link.
-///
-/// :url: The URL to fetch the HTML content from.
-///
-/// :returns: A vector of hash maps containing the name and address of each link found in the HTML content.
-/// This function will always return the raw link list, so it may be return a parent directory link.
-///
-/// ## Example
-/// ```
-/// let url = "https://mirror.iscas.ac.cn/revyos/extra/images/";
-/// let result = fetch_and_parse(url.to_string()).await;
-/// println!("{:?}", result);
-/// ```
-pub async fn fetch_and_parse_raw(
-    url: String,
-) -> Result<Vec<HashMap<String, String>>, Box<dyn std::error::Error>> {
-    // PATCH: add a trailing slash to the URL if it doesn't have one, which make ISCAS Nginx happy
-    let url = if url.ends_with('/') {
-        url
-    } else {
-        format!("{}/", url)
-    };
-    let response = reqwest::get(url).await?.text().await?;
-    let document = Html::parse_document(&response);
+use crate::board::{Board, RuleSet};
+use crate::error::{Error, Result, ResultExt};
+use crate::http_cache::HttpCache;
+use crate::image::{ImageBinary, ImageVariant, ImageVersion};
+use crate::listing::ListingFormat;
+
+/// How many version directories of a board are fetched at the same time.
+const CATALOG_CONCURRENCY: usize = 6;
+
+/// Extract the links of a directory listing. Besides `name` and `address`, each map
+/// holds `size` (bytes) and `modified` (RFC 3339) when the server format is known.
+fn parse_links(html: &str) -> Vec<HashMap<String, String>> {
+    let document = Html::parse_document(html);
     let a_selector = Selector::parse("a").unwrap();
+    let metadata = ListingFormat::detect(&document)
+        .map(|format| format.parse(&document))
+        .unwrap_or_default();
 
     let mut links = Vec::new();
     for a in document.select(&a_selector) {
         if let Some(link) = a.value().attr("href") {
             let mut map = HashMap::new();
-            map.insert("name".to_string(), a.text().collect::<Vec<_>>().join(""));
-            map.insert("address".to_string(), link.to_string());
+            map.insert("name".to_string(), a.text().collect::<Vec<_>>().join("").trim().to_string());
+            // Caddy links entries as "./name"
+            map.insert("address".to_string(), link.trim_start_matches("./").to_string());
+            if let Some(entry) = metadata.get(link) {
+                if let Some(size) = entry.size {
+                    map.insert("size".to_string(), size.to_string());
+                }
+                if let Some(modified) = entry.modified {
+                    map.insert("modified".to_string(), modified.to_rfc3339());
+                }
+            }
             links.push(map);
         }
     }
-    Ok(links)
+    links
 }
 
-/// This function fetches HTML content from a given URL and parses it to extract links,
-/// excluding the parent directory link.
-pub async fn fetch_and_parse(
-    url: String,
-) -> Result<Vec<HashMap<String, String>>, Box<dyn std::error::Error>> {
-    // call fetch_and_parse_raw and filter out the parent directory link
-    let mut links = fetch_and_parse_raw(url).await?;
-    links.retain(|link| {
-        !link
-            .get("name").is_some_and(|name| name.contains("Parent directory") || name.contains("../"))
-    });
-    Ok(links)
+/// Fetches directory listings, either straight from the mirror or through an
+/// [`HttpCache`] that revalidates and serves the last known copy when offline.
+#[derive(Default)]
+pub struct ListingFetcher<'a> {
+    client: reqwest::Client,
+    cache: Option<&'a HttpCache>,
+    refresh: bool,
 }
 
+impl<'a> ListingFetcher<'a> {
+    /// Fetch through `cache`; with `refresh`, cached listings are refetched in full.
+    pub fn cached(cache: &'a HttpCache, refresh: bool) -> Self {
+        Self {
+            client: crate::settings::http_client(),
+            cache: Some(cache),
+            refresh,
+        }
+    }
+
+    /// This function fetches HTML content from a given URL and parses it to extract links.
+    ///
+    /// It returns a vector of hash maps, where each map contains the name and address of a link.
+    ///
+    /// :url: The URL to fetch the HTML content from.
+    ///
+    /// :returns: A vector of hash maps containing the name and address of each link found in the HTML content.
+    /// This function will always return the raw link list, so it may be return a parent directory link.
+    ///
+    /// ## Example
+    /// ```
+    /// let url = "https://mirror.iscas.ac.cn/revyos/extra/images/";
+    /// let result = ListingFetcher::default().fetch_raw(url.to_string()).await;
+    /// println!("{:?}", result);
+    /// ```
+    pub async fn fetch_raw(&self, url: String) -> Result<Vec<HashMap<String, String>>> {
+        // PATCH: add a trailing slash to the URL if it doesn't have one, which make ISCAS Nginx happy
+        let url = if url.ends_with('/') {
+            url
+        } else {
+            format!("{}/", url)
+        };
+        let text = match self.cache {
+            Some(cache) => cache.get_text(&self.client, &url, self.refresh).await,
+            None => async {
+                Ok(self.client.get(&url).send().await?.error_for_status()?.text().await?)
+            }
+            .await,
+        }
+        .with_context(|| format!("Fetching {url}"))?;
+        Ok(parse_links(&text))
+    }
 
-async fn fetch_and_parse_lpi4a_image(url: String) -> Result<ImageVersion, Box<dyn std::error::Error>> {
-    let result = fetch_and_parse(url.clone()).await?;
+    /// The links of the listing at `url`, without the parent directory link.
+    pub async fn fetch(&self, url: String) -> Result<Vec<HashMap<String, String>>> {
+        let mut links = self.fetch_raw(url).await?;
+        links.retain(|link| {
+            !link
+                .get("name").is_some_and(|name| name.contains("Parent directory") || name.contains("../"))
+        });
+        Ok(links)
+    }
+}
+
+/// Assemble the binaries listed in one version directory into an [`ImageVersion`].
+///
+/// Each binary of the board's `variant_by` type makes one variant, together with the
+/// binaries the board requires. The rest of the binaries are optional and ignored.
+/// Checksum files (`root.ext4.zst.sha256`) are attached to the binary they belong to,
+/// unless they hold a hash that cannot be verified.
+fn assemble_image_version(
+    board: &Board,
+    rules: &RuleSet,
+    url: &str,
+    links: &[HashMap<String, String>],
+) -> Result<ImageVersion> {
+    let (sidecars, links): (Vec<_>, Vec<_>) = links.iter().partition(|link| {
+        link.get("address")
+            .is_some_and(|address| crate::board::checksum_sidecar(address).is_some())
+    });
     // turn into Vec<ImageBinary>
-    let image_bin = result
+    let mut image_bin = links
         .iter()
-        .map(|link| ImageBinary::try_from_hashmap(link, &url))
+        .map(|link| ImageBinary::try_from_hashmap(link, url, rules))
         .collect::<Result<Vec<_>, _>>()?;
-    // Assemble the image binaries into a ImageVariant
-    // Pattern: Each ImageVariant must and should only contain a ROOT, a BOOT, and a UBOOT binary.
-    // The rest of the binaries are optional and can be ignored.
-    let rootfs_binary = image_bin
-        .iter()
-        .find(|link| link.binary_type == ImageBinaryType::Root)
-        .cloned()
-        .ok_or("Missing ROOT binary")?;
-    let boot_binary = image_bin
-        .iter()
-        .find(|link| link.binary_type == ImageBinaryType::Boot)
-        .cloned()
-        .ok_or("Missing BOOT binary")?;
-    let image_variants: Vec<_> = image_bin
-        .iter()
-        .filter(|link| link.binary_type == ImageBinaryType::UBoot)
-        .map(|link| {
-            ImageVariant::new(
-                link.name.clone(),
-                vec![rootfs_binary.clone(), boot_binary.clone(), link.clone()],
-            )
-        })
+    for address in sidecars.iter().filter_map(|link| link.get("address")) {
+        let Some((target, algorithm)) = crate::board::checksum_sidecar(address) else {
+            continue;
+        };
+        if crate::board::HashAlgorithm::from_name(algorithm).is_none() {
+            eprintln!("Ignoring {address} in {url}: {algorithm} checksums cannot be verified");
+            continue;
+        }
+        let target_url = format!("{}/{}", url, target);
+        if let Some(binary) = image_bin.iter_mut().find(|b| b.web_path.as_ref() == Some(&target_url)) {
+            binary.hash_type = Some(algorithm.to_string());
+            binary.checksum_url = Some(format!("{}/{}", url, address));
+        }
+    }
+    let cache_dir = crate::space::image_cache_dir();
+    let image_variants: Vec<_> = board
+        .group_variants(&image_bin)
+        .map_err(|binary_type| Error::parse(format!("Missing {binary_type:?} binary in {url}")))?
+        .into_iter()
+        .map(|(name, binaries)| ImageVariant::new(name, binaries, &cache_dir))
         .collect();
     let version = if url.ends_with('/') {
         url.split('/').nth_back(1).unwrap_or("Unknown").to_string()
     } else {
-        url.split('/').last().unwrap_or("Unknown").to_string()
+        url.split('/').next_back().unwrap_or("Unknown").to_string()
     };
     Ok(ImageVersion {
         version,
@@ -99,26 +158,49 @@ async fn fetch_and_parse_lpi4a_image(url: String) -> Result<ImageVersion, Box<dy
     })
 }
 
-pub async fn fetch_and_parse_lpi4a_image_all(url: Option<String>) -> Result<Vec<ImageVersion>, Box<dyn std::error::Error>> {
-    let url = url.unwrap_or_else(|| "https://mirror.iscas.ac.cn/revyos/extra/images/lpi4a/".to_string());
-    let result = fetch_and_parse(url.clone()).await?;
-    let mut image_versions = Vec::new();
-    for link in &result {
+async fn fetch_and_parse_image(
+    fetcher: &ListingFetcher<'_>,
+    board: &Board,
+    rules: &RuleSet,
+    url: String,
+) -> Result<ImageVersion> {
+    let result = fetcher.fetch(url.clone()).await?;
+    assemble_image_version(board, rules, &url, &result)
+}
+
+/// Fetch every image version published for `board`. `url` overrides the board's
+/// directory on the default mirror.
+///
+/// Version directories are fetched concurrently, at most [`CATALOG_CONCURRENCY`] at a
+/// time; the result keeps the order of the mirror listing.
+pub async fn fetch_image_catalog(
+    fetcher: &ListingFetcher<'_>,
+    board: &Board,
+    rules: &RuleSet,
+    url: Option<String>,
+) -> Result<Vec<ImageVersion>> {
+    let url = url.unwrap_or_else(|| board.url(crate::mirror::DEFAULT_MIRRORS[0].1));
+    let result = fetcher.fetch(url.clone()).await?;
+    let version_urls = result.iter().filter_map(|link| {
         // Construct further link using link["address"] and url
-        if let Some(address) = link.get("address") {
-            let new_url = format!("{}{}", url, address);
-            match fetch_and_parse_lpi4a_image(new_url).await {
-                Ok(image_version) => {
-                    image_versions.push(image_version);
-                }
-                Err(e) => {
-                    eprintln!("Failed to fetch and parse image version: {}", e);
-                }
+        match link.get("address") {
+            Some(address) => Some(format!("{}{}", url, address)),
+            None => {
+                eprintln!("Missing 'address' in link: {:?}", link);
+                None
             }
-        } else {
-            eprintln!("Missing 'address' in link: {:?}", link);
         }
-    }
+    });
+    let image_versions = futures::stream::iter(version_urls)
+        .map(|new_url| fetch_and_parse_image(fetcher, board, rules, new_url))
+        .buffered(CATALOG_CONCURRENCY)
+        .filter_map(|result| async move {
+            result
+                .inspect_err(|e| eprintln!("Failed to fetch and parse {} image version: {}", board.id, e))
+                .ok()
+        })
+        .collect()
+        .await;
     Ok(image_versions)
 }
 
@@ -127,11 +209,12 @@ mod tests {
     use crate::image::{ImageBinary, ImageBinaryType};
 
     use super::*;
+    use crate::board;
 
     #[tokio::test]
     async fn test_fetch_and_parse_raw() {
         let url = "https://mirror.iscas.ac.cn/revyos/extra/images/";
-        let result = fetch_and_parse_raw(url.to_string()).await;
+        let result = ListingFetcher::default().fetch_raw(url.to_string()).await;
         match result {
             Ok(links) => {
                 assert!(!links.is_empty(), "The links list should not be empty.");
@@ -154,7 +237,7 @@ mod tests {
     #[tokio::test]
     async fn test_fetch_and_parse() {
         let url = "https://mirror.iscas.ac.cn/revyos/extra/images/";
-        let result = fetch_and_parse(url.to_string()).await;
+        let result = ListingFetcher::default().fetch(url.to_string()).await;
         match result {
             Ok(links) => {
                 assert!(!links.is_empty(), "The links list should not be empty.");
@@ -174,7 +257,7 @@ mod tests {
         }
         // test without trailing slash
         let url = "https://mirror.iscas.ac.cn/revyos/extra/images";
-        let result = fetch_and_parse(url.to_string()).await;
+        let result = ListingFetcher::default().fetch(url.to_string()).await;
         match result {
             Ok(links) => {
                 assert!(!links.is_empty(), "The links list should not be empty.");
@@ -197,7 +280,7 @@ mod tests {
     #[tokio::test]
     async fn test_fetch_directory() {
         let url = "https://mirror.iscas.ac.cn/revyos/extra/images/lpi4a";
-        let result = fetch_and_parse(url.to_string()).await;
+        let result = ListingFetcher::default().fetch(url.to_string()).await;
         match result {
             Ok(links) => {
                 assert!(!links.is_empty(), "The links list should not be empty.");
@@ -229,11 +312,11 @@ mod tests {
     #[tokio::test]
     async fn test_fetch_and_parse_lpi4a_image() {
         let url = "https://fast-mirror.isrc.ac.cn/revyos/extra/images/lpi4a/20250323/".to_string();
-        let result = fetch_and_parse(url.clone()).await.unwrap();
+        let result = ListingFetcher::default().fetch(url.clone()).await.unwrap();
         println!("Result: {:?}", result);
         // turn into Vec<ImageBinary>
         let image_bin = result.iter().map(|link| {
-            ImageBinary::try_from_hashmap(link, &url).unwrap()
+            ImageBinary::try_from_hashmap(link, &url, &board::LPI4A.rules()).unwrap()
         }).collect::<Vec<_>>();
         // print image_bin
         for link in &image_bin {
@@ -260,20 +343,80 @@ mod tests {
     #[tokio::test]
     async fn test_image_version_parse() {
         let url = "https://mirror.iscas.ac.cn/revyos/extra/images/lpi4a/20250323/".to_string();
-        let image_version1 = fetch_and_parse_lpi4a_image(url.clone()).await.unwrap();
+        let image_version1 = fetch_and_parse_image(&ListingFetcher::default(), &board::LPI4A, &board::LPI4A.rules(), url.clone()).await.unwrap();
         assert_eq!(image_version1.version, "20250323".to_string());
         // url without trailing slash
         let url = "https://mirror.iscas.ac.cn/revyos/extra/images/lpi4a/20250323".to_string();
-        let image_version2 = fetch_and_parse_lpi4a_image(url.clone()).await.unwrap();
+        let image_version2 = fetch_and_parse_image(&ListingFetcher::default(), &board::LPI4A, &board::LPI4A.rules(), url.clone()).await.unwrap();
         assert_eq!(image_version2.version, "20250323".to_string());
     }
 
     #[tokio::test]
     async fn test_fetch_and_parse_lpi4a_image_all() {
-        let image_versions = fetch_and_parse_lpi4a_image_all(None).await.unwrap();
+        let board = &board::LPI4A;
+        let image_versions = fetch_image_catalog(&ListingFetcher::default(), board, &board.rules(), None).await.unwrap();
         // print image_versions
         for version in &image_versions {
             println!("Date of images: {}", version.version);
         }
     }
+
+    fn link(name: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("name".to_string(), name.to_string()),
+            ("address".to_string(), name.to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_assemble_image_version() {
+        let url = "https://mirror.iscas.ac.cn/revyos/extra/images/meles/20250123/";
+        let links = [
+            link("boot-meles-20250123.ext4.zst"),
+            link("root-meles-20250123.ext4.zst"),
+            link("u-boot-with-spl-meles.bin"),
+            link("u-boot-with-spl-meles-4g.bin"),
+            link("README.txt"),
+            link("root-meles-20250123.ext4.zst.sha256"),
+            link("boot-meles-20250123.ext4.zst.md5"),
+        ];
+        let meles = board::find("meles").unwrap();
+        let rules = meles.rules();
+        let version = assemble_image_version(meles, &rules, url, &links).unwrap();
+        assert_eq!(version.version, "20250123");
+        assert_eq!(version.image_variants.len(), 2);
+        assert_eq!(version.image_variants[1].name, "u-boot-with-spl-meles-4g.bin");
+        assert_eq!(version.image_variants[1].image_binarys.len(), 3);
+        assert_eq!(version.image_variants[1].image_binarys[2].metadata.ram.as_deref(), Some("4g"));
+        let root = &version.image_variants[0].image_binarys[0];
+        assert_eq!(root.hash_type.as_deref(), Some("sha256"));
+        assert!(root.checksum_url.as_ref().is_some_and(|u| u.ends_with("root-meles-20250123.ext4.zst.sha256")));
+        // An MD5 sum cannot be checked, so it is not attached
+        let boot = &version.image_variants[0].image_binarys[1];
+        assert_eq!((boot.hash_type.as_deref(), boot.checksum_url.as_deref()), (None, None));
+
+        // fastboot boards need root and boot, sdcard boards do not
+        assert!(assemble_image_version(meles, &rules, url, &links[1..]).is_err());
+        let pioneer = board::find("pioneer").unwrap();
+        let version =
+            assemble_image_version(pioneer, &pioneer.rules(), url, &[link("sdcard-pioneer-20250123.img.zst")]).unwrap();
+        assert_eq!(version.image_variants.len(), 1);
+        assert_eq!(version.image_variants[0].image_binarys.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_links_metadata() {
+        let url = "https://mirror.example.org/revyos/extra/images/lpi4a/20250323/";
+        for html in [
+            include_str!("../fixtures/listings/nginx.html"),
+            include_str!("../fixtures/listings/caddy.html"),
+        ] {
+            let links = parse_links(html);
+            let version = assemble_image_version(&board::LPI4A, &board::LPI4A.rules(), url, &links).unwrap();
+            let root = &version.image_variants[0].image_binarys[0];
+            assert_eq!(root.name, "root-lpi4a-20250323_154524.ext4.zst");
+            assert_eq!(root.size, Some(1_273_621_248));
+            assert!(root.modified.is_some());
+        }
+    }
 }
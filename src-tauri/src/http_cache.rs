@@ -0,0 +1,198 @@
+use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use reqwest::header::{CACHE_CONTROL, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedPage {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    fetched_at: DateTime<Utc>,
+    body: String,
+}
+
+/// On-disk cache of mirror directory listings, revalidated with ETag/If-Modified-Since.
+///
+/// When the mirror cannot be reached, the last cached copy is served instead, so the
+/// catalog still shows up offline.
+pub struct HttpCache {
+    dir: PathBuf,
+}
+
+impl HttpCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn page_path(&self, url: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", hex::encode(Sha256::digest(url.as_bytes()))))
+    }
+
+    async fn load(&self, url: &str) -> Option<CachedPage> {
+        let content = tokio::fs::read_to_string(self.page_path(url)).await.ok()?;
+        serde_json::from_str::<CachedPage>(&content)
+            .ok()
+            .filter(|page| page.url == url)
+    }
+
+    async fn store(&self, page: &CachedPage) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        // Write then rename, so a crash never leaves a truncated page behind
+        let path = self.page_path(&page.url);
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, serde_json::to_string(page)?).await?;
+        tokio::fs::rename(&temp, &path).await?;
+        Ok(())
+    }
+
+    /// Fetch `url` as text. Unless `refresh` is set, a cached copy is revalidated
+    /// instead of downloaded again.
+    pub async fn get_text(&self, client: &reqwest::Client, url: &str, refresh: bool) -> Result<String> {
+        let cached = self.load(url).await;
+        let mut request = client.get(url);
+        if let Some(page) = cached.as_ref().filter(|_| !refresh) {
+            if let Some(etag) = &page.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &page.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let response = match request.send().await {
+            Ok(response) if !response.status().is_server_error() => response,
+            result => {
+                let error = match result {
+                    Ok(response) => Error::http(response.status().as_u16(), url),
+                    Err(e) => e.into(),
+                };
+                return match cached {
+                    Some(page) => {
+                        eprintln!("{error}; using the copy of {url} cached at {}", page.fetched_at);
+                        Ok(page.body)
+                    }
+                    None => Err(error),
+                };
+            }
+        };
+        let response = match (response.status(), cached) {
+            (StatusCode::NOT_MODIFIED, Some(page)) => return Ok(page.body),
+            // Nothing to revalidate against, so whatever answered (typically a proxy)
+            // has to hand over the page itself
+            (StatusCode::NOT_MODIFIED, None) => {
+                eprintln!("{url} was not modified but is not cached, fetching it again");
+                let response = client.get(url).header(CACHE_CONTROL, "no-cache").send().await?;
+                if response.status() == StatusCode::NOT_MODIFIED {
+                    return Err(Error::http(response.status().as_u16(), url));
+                }
+                response
+            }
+            _ => response,
+        };
+        let response = response.error_for_status()?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let page = CachedPage {
+            url: url.to_string(),
+            etag,
+            last_modified,
+            fetched_at: Utc::now(),
+            body: response.text().await?,
+        };
+        if let Err(e) = self.store(&page).await {
+            eprintln!("Failed to cache {url}: {e}");
+        }
+        Ok(page.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{mock, server_url, Matcher};
+
+    const LISTING: &str = "<a href=\"20250323/\">20250323/</a>";
+
+    /// A listing at `path` that carries an ETag and answers 304 to requests that already
+    /// carry it. Returns the URL and the mocks of full and not-modified responses.
+    fn serve_with_etag(path: &str) -> (String, mockito::Mock, mockito::Mock) {
+        let full = mock("GET", path)
+            .match_header("if-none-match", Matcher::Missing)
+            .with_header("etag", "\"v1\"")
+            .with_body(LISTING);
+        let not_modified = mock("GET", path)
+            .match_header("if-none-match", "\"v1\"")
+            .with_status(304)
+            .with_header("etag", "\"v1\"");
+        (format!("{}{path}", server_url()), full, not_modified)
+    }
+
+    #[tokio::test]
+    async fn test_revalidate_refresh_and_offline() {
+        let dir = std::env::temp_dir().join("revyos-imager-http-cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = HttpCache::new(dir.clone());
+        let client = reqwest::Client::new();
+        let (url, full, not_modified) = serve_with_etag("/http-cache/lpi4a/");
+        let full = full.expect(2).create();
+        let not_modified = not_modified.expect(1).create();
+
+        let first = cache.get_text(&client, &url, false).await.unwrap();
+        let second = cache.get_text(&client, &url, false).await.unwrap();
+        assert_eq!(first, second);
+        not_modified.assert();
+
+        // A refresh downloads the page again
+        cache.get_text(&client, &url, true).await.unwrap();
+        full.assert();
+
+        // Nothing listens on port 9 of localhost, which is as offline as it gets
+        let offline = url.replace(&server_url()[7..], "127.0.0.1:9");
+        assert!(cache.get_text(&client, &offline, false).await.is_err());
+        let page = CachedPage {
+            url: offline.clone(),
+            etag: None,
+            last_modified: None,
+            fetched_at: Utc::now(),
+            body: first.clone(),
+        };
+        cache.store(&page).await.unwrap();
+        assert_eq!(cache.get_text(&client, &offline, true).await.unwrap(), first);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_without_cached_copy() {
+        let dir = std::env::temp_dir().join("revyos-imager-http-cache-304");
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = HttpCache::new(dir.clone());
+        let client = reqwest::Client::new();
+        // Like a caching proxy that answers 304 until told not to use its cache
+        let path = "/http-cache-304/lpi4a/";
+        let _not_modified = mock("GET", path).match_header("cache-control", Matcher::Missing).with_status(304).create();
+        let _full = mock("GET", path).match_header("cache-control", "no-cache").with_body(LISTING).create();
+        let url = format!("{}{path}", server_url());
+        assert_eq!(cache.get_text(&client, &url, false).await.unwrap(), LISTING);
+        assert_eq!(cache.load(&url).await.unwrap().body, LISTING);
+
+        let path = "/http-cache-stubborn/lpi4a/";
+        let _stubborn = mock("GET", path).with_status(304).create();
+        let stubborn = format!("{}{path}", server_url());
+        assert!(cache.get_text(&client, &stubborn, false).await.is_err());
+        assert!(cache.load(&stubborn).await.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,8 +1,11 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 use futures_lite::stream::StreamExt;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
-use async_compression::tokio::bufread::ZstdDecoder;
+
+use crate::board::RuleSet;
+use crate::compression::{Compression, EXTRACT_RATIO_GUESS};
+use crate::error::{Error, ErrorKind, Result};
 /// 表示进度类型的枚举，用于区分下载还是解压缩过程;
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ProgressType {
@@ -10,6 +13,9 @@ pub enum ProgressType {
     Extract,
 }
 
+/// How much of a `.zst` file to fetch to find its content size.
+const ZSTD_PROBE_BYTES: usize = 4096;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 /// ImageBinaryType represents the type of image binary.
 pub enum ImageBinaryType {
@@ -29,6 +35,24 @@ pub struct ImageBinary {
     pub binary_type: ImageBinaryType,
     pub hash_type: Option<String>, // Hash type for the binary, e.g., SHA256, MD5, etc.
     pub hash_value: Option<String>, // Hash value for the binary, e.g., SHA256, MD5, etc.
+    #[serde(default)]
+    pub size: Option<u64>, // Size on the mirror in bytes, if the listing shows it.
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>, // Modification time on the mirror, if the listing shows it.
+    #[serde(default)]
+    pub metadata: BinaryMetadata, // What the file rules read from the name.
+    #[serde(default)]
+    pub checksum_url: Option<String>, // Checksum file published next to the binary, fetched before downloading.
+}
+
+/// Details a board's file rules extract from a binary's name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BinaryMetadata {
+    /// RAM size a u-boot is built for, e.g. "16g".
+    pub ram: Option<String>,
+    pub revision: Option<String>,
+    pub built_at: Option<DateTime<Utc>>,
 }
 
 #[allow(dead_code)]
@@ -40,9 +64,9 @@ pub enum ImageBinaryHashError {
 
 impl ImageBinary {
 
-    pub fn new(name: String, web_path: Option<String>, local_path: Option<String>, binary_type: ImageBinaryType, hash_type: Option<String>, hash_value: Option<String>) -> Result<Self, &'static str> {
+    pub fn new(name: String, web_path: Option<String>, local_path: Option<String>, binary_type: ImageBinaryType, hash_type: Option<String>, hash_value: Option<String>) -> Result<Self> {
         if web_path.is_none() && local_path.is_none() {
-            return Err("Either web_path or local_path must be provided.");
+            return Err(Error::invalid_input("Either web_path or local_path must be provided."));
         }
         Ok(Self {
             name,
@@ -51,25 +75,27 @@ impl ImageBinary {
             binary_type,
             hash_type,
             hash_value,
+            size: None,
+            modified: None,
+            metadata: BinaryMetadata::default(),
+            checksum_url: None,
         })
     }
 
-    pub fn try_from_hashmap(map: &HashMap<String, String>, base_url: &String) -> Result<Self, &'static str> {
-        let web_path = map.get("address").cloned().ok_or("Web path not found")?;
-        let name = map.get("name").cloned().ok_or("Name not found")?;
+    /// Build a binary from a mirror listing link, classifying it with a board's file rules.
+    pub fn try_from_hashmap(map: &HashMap<String, String>, base_url: &str, rules: &RuleSet) -> Result<Self> {
+        let web_path = map.get("address").cloned().ok_or_else(|| Error::parse("Web path not found"))?;
+        let name = map.get("name").cloned().ok_or_else(|| Error::parse("Name not found"))?;
         // determine the binary type based on the web_path
-        let binary_type = if web_path.contains("u-boot") {
-            ImageBinaryType::UBoot
-        } else if web_path.starts_with("boot") {
-            ImageBinaryType::Boot
-        } else if web_path.contains("root") {
-            ImageBinaryType::Root
-        }else if web_path.contains("sdcard"){
-            ImageBinaryType::Sdcard
-        } else {
-            ImageBinaryType::Other(web_path.clone())
-        };
-        Self::new(name, Some(format!("{}/{}", base_url, web_path)), None, binary_type, None, None)
+        let (binary_type, metadata) = rules.classify(&web_path);
+        let mut binary = Self::new(name, Some(format!("{}/{}", base_url, web_path)), None, binary_type, None, None)?;
+        binary.metadata = metadata;
+        binary.size = map.get("size").and_then(|size| size.parse().ok());
+        binary.modified = map
+            .get("modified")
+            .and_then(|modified| DateTime::parse_from_rfc3339(modified).ok())
+            .map(|modified| modified.with_timezone(&Utc));
+        Ok(binary)
     }
 }
 
@@ -81,117 +107,177 @@ pub struct ImageVariant {
 
 impl ImageVariant {
 
-    fn get_local_path(name: &String, binary_name: &String) -> PathBuf {
-        let mut path = std::env::temp_dir();
-        path.push("revyos-imager");
+    fn get_local_path(cache_dir: &Path, name: &String, binary_name: &str) -> PathBuf {
+        let mut path = cache_dir.to_path_buf();
         path.push(name);
-        path.push(if binary_name.ends_with(".zst") {
-            binary_name.trim_end_matches(".zst").to_string()
-        } else {
-            binary_name.to_string()
-        });
+        path.push(Compression::output_name(binary_name));
         path
     }
 
-    async fn check_zst_file(file_path: &PathBuf) -> Result<bool, String> {
-        let file = tokio::fs::File::open(file_path).await.map_err(|e| e.to_string())?;
-        let mut reader = BufReader::new(file);
-        let mut buffer = [0; 4];
-        reader.read_exact(&mut buffer).await.map_err(|e| e.to_string())?;
-        Ok(buffer == [0x28, 0xb5, 0x2f, 0xfd])
+    /// Disk space downloading `binary` will take: the compressed file plus, for
+    /// compressed files, the extracted copy. Sizes that cannot be found out count as zero, and an
+    /// unknown extracted size is guessed at [`EXTRACT_RATIO_GUESS`] times the download.
+    async fn required_space(client: &reqwest::Client, binary: &ImageBinary, web_path: &str) -> u64 {
+        let compressed = match binary.size {
+            Some(size) => Some(size),
+            None => async {
+                let response = client.head(web_path).send().await?.error_for_status()?;
+                // content_length() describes the (empty) body of a HEAD response, not the file
+                Ok::<_, Error>(
+                    response
+                        .headers()
+                        .get(reqwest::header::CONTENT_LENGTH)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse().ok()),
+                )
+            }
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Could not get the size of {web_path}: {e}");
+                None
+            }),
+        }
+        .unwrap_or(0);
+        match Compression::from_name(&binary.name) {
+            Compression::None => return compressed,
+            // Only zstd declares the content size in its header
+            Compression::Zstd => {}
+            _ => return compressed + compressed * EXTRACT_RATIO_GUESS,
+        }
+        let extracted = async {
+            let response = client
+                .get(web_path)
+                .header(reqwest::header::RANGE, format!("bytes=0-{}", ZSTD_PROBE_BYTES - 1))
+                .send()
+                .await?
+                .error_for_status()?;
+            // The server may ignore the range and send the whole file, so stop reading early
+            let mut head = Vec::new();
+            let mut stream = response.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                head.extend_from_slice(&chunk?);
+                if head.len() >= ZSTD_PROBE_BYTES {
+                    break;
+                }
+            }
+            crate::zstd_frame::first_frame_content_size(&head)
+        }
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Could not read the zstd header of {web_path}: {e}");
+            None
+        })
+        .unwrap_or(compressed * EXTRACT_RATIO_GUESS);
+        compressed + extracted
+    }
+
+    /// The hash for `name` published in the sidecar at `checksum_url`, or `None` if no
+    /// mirror serves a usable one; the download then goes unverified.
+    async fn fetch_checksum(client: &reqwest::Client, checksum_url: &str, name: &str, mirrors: &[String]) -> Option<String> {
+        for url in crate::mirror::candidate_urls(checksum_url, mirrors) {
+            let content = match client.get(&url).send().await.and_then(|r| r.error_for_status()) {
+                Ok(response) => response.text().await,
+                Err(e) => Err(e),
+            };
+            match content {
+                Ok(content) => match crate::board::parse_checksum_file(&content, name) {
+                    Some(hash) => return Some(hash),
+                    None => eprintln!("No checksum for {name} in {url}"),
+                },
+                Err(e) => eprintln!("Failed to fetch checksum {url}: {e}"),
+            }
+        }
+        None
     }
 
-    /// Download all binaries in this variant.
-    pub async fn download_binaries<F>(&mut self, mut progress_callback: F) -> Result<(), String>
+    /// Download all binaries in this variant into the image cache at `cache_dir`.
+    ///
+    /// `mirrors` are the mirror roots to try, best first; a binary that fails or stalls on
+    /// one mirror is fetched again from the next.
+    pub async fn download_binaries<F>(&mut self, cache_dir: &Path, mirrors: &[String], mut progress_callback: F) -> Result<()>
     where
         F: FnMut(&str, u64, u64, ProgressType),
     {
-        let client = reqwest::Client::new();
+        let client = crate::settings::http_client();
+        let mut required = 0;
+        for binary in &self.image_binarys {
+            if let (None, Some(web_path)) = (&binary.local_path, &binary.web_path) {
+                required += Self::required_space(&client, binary, web_path).await;
+            }
+        }
+        crate::space::ensure_space(cache_dir, &cache_dir.join(&self.name), required)?;
+
         for binary in &mut self.image_binarys {
             if let Some(local_path) = &binary.local_path {
                 println!("Using local binary: {}", local_path);
                 continue;
             }
             if let Some(web_path) = &binary.web_path {
-                let file_path = ImageVariant::get_local_path(&self.name, &binary.name);
-                // first we download the file to a temp file in the same dir
-                // then we rename it to the final name
-                let temp_file_path = file_path.with_extension("tmp");
-                std::fs::create_dir_all(file_path.parent().unwrap()).map_err(|e| e.to_string())?;
-                println!("Downloading {} from {}", temp_file_path.to_string_lossy(), web_path);
-                let response = client.get(web_path).send().await.map_err(|e| e.to_string())?;
-                let mut file = tokio::fs::File::create(&temp_file_path).await.map_err(|e| e.to_string())?;
-                let content_size = response.content_length().ok_or("Failed to get content length")?;
-                let mut downloaded = 0;
-                let mut stream = response.bytes_stream();
-                while let Some(chunk) = stream.next().await {
-                    let chunk = chunk.map_err(|e| e.to_string())?;
-                    downloaded += chunk.len() as u64;
-                    file.write_all(&chunk).await.map_err(|e| e.to_string())?;
-                    progress_callback(&binary.name, downloaded, content_size, ProgressType::Download);
+                let file_path = ImageVariant::get_local_path(cache_dir, &self.name, &binary.name);
+                // 压缩数据先写入.part文件以便断点续传，同时流式解压到最终文件
+                let part_path = file_path.with_file_name(format!("{}.part", binary.name));
+                std::fs::create_dir_all(file_path.parent().unwrap())?;
+                if binary.hash_value.is_none() {
+                    if let Some(checksum_url) = &binary.checksum_url {
+                        binary.hash_value = Self::fetch_checksum(&client, checksum_url, &binary.name, mirrors).await;
+                    }
                 }
-                file.sync_all().await.map_err(|e| e.to_string())?;
-                println!("Downloaded {} to {}", binary.name, temp_file_path.display());
-                
-                // 检查是否是.zst文件，如果是则异步解压缩
-                if ImageVariant::check_zst_file(&temp_file_path).await.map_err(|e| e.to_string())? {
-                    println!("Extracting {} file", temp_file_path.to_string_lossy());
-                    
-                    // 创建不带.zst后缀的输出路径
-                    let output_path = file_path;
-                    
-                    // 异步打开zst文件进行解压缩
-                    let source = tokio::fs::File::open(&temp_file_path).await.map_err(|e| e.to_string())?;
-                    let file_size = source.metadata().await.map_err(|e| e.to_string())?.len();
-                    let reader = BufReader::new(source);
-                    
-                    // 创建目标文件
-                    let mut target = tokio::fs::File::create(&output_path).await.map_err(|e| e.to_string())?;
-                    
-                    // 使用async-compression解压缩
-                    let mut decoder = ZstdDecoder::new(reader);
-                    
-                    // 读取解码后的数据并写入目标文件
-                    const CHUNK_SIZE: usize = 1024 * 1024; // 1MB chunks
-                    let mut extracted = 0u64;
-                    let mut buffer = vec![0u8; CHUNK_SIZE];
-                    
-                    // 由于我们无法提前知道解压后的大小，我们使用压缩文件的大小的估计值
-                    // 通常，压缩比在2-5倍之间，我们选择3作为估计
-                    let estimated_total = file_size * 3;
-                    
-                    loop {
-                        let bytes_read = decoder.read(&mut buffer).await.map_err(|e| e.to_string())?;
-                        if bytes_read == 0 {
-                            break;
+                let expected = binary.hash_value.as_ref().and_then(|value| {
+                    let hash_type = binary.hash_type.as_deref().unwrap_or_default();
+                    match crate::board::HashAlgorithm::from_name(hash_type) {
+                        Some(algorithm) => Some((algorithm, value.to_lowercase())),
+                        None => {
+                            eprintln!("Cannot verify the {hash_type:?} checksum of {}, downloading it unchecked", binary.name);
+                            None
                         }
-                        target.write_all(&buffer[..bytes_read]).await.map_err(|e| e.to_string())?;
-                        extracted += bytes_read as u64;
-                        progress_callback(&binary.name, extracted, estimated_total, ProgressType::Extract);
                     }
-                    
-                    target.flush().await.map_err(|e| e.to_string())?;
-                    println!("Extracted {} to {}", binary.name, output_path.display());
-                    
-                    // 使用解压后的文件路径
-                    binary.local_path = Some(output_path.to_string_lossy().to_string());
-                } else {
-                    // Rename the temp file to the final name
-                    std::fs::rename(&temp_file_path, &file_path).map_err(|e| e.to_string())?;
-                    println!("Renamed {} to {}", temp_file_path.to_string_lossy(), file_path.to_string_lossy());
-                    binary.local_path = Some(file_path.to_string_lossy().to_string());
+                });
+                let mut result = Err(Error::invalid_input("No mirror to download from"));
+                for url in crate::mirror::candidate_urls(web_path, mirrors) {
+                    println!("Downloading {} from {}", binary.name, url);
+                    let transfer = crate::pipeline::Transfer {
+                        url: &url,
+                        part: &part_path,
+                        output: &file_path,
+                        name: &binary.name,
+                        decode: Compression::from_name(&binary.name) != Compression::None,
+                        hash: expected.as_ref().map(|(algorithm, _)| *algorithm),
+                    };
+                    result = transfer
+                        .run(&client, &mut progress_callback)
+                        .await
+                        .map_err(|e| e.with_context(format!("Downloading {url}")));
+                    match &result {
+                        Ok(_) => break,
+                        Err(e) => eprintln!("{e}, trying the next mirror"),
+                    }
                 }
+                let digests = result?;
+                if let Some((algorithm, expected)) = expected {
+                    // The published checksum may be of either the compressed or the extracted file
+                    if digests.compressed.as_ref() != Some(&expected) && digests.decompressed.as_ref() != Some(&expected) {
+                        std::fs::remove_file(&file_path)?;
+                        return Err(Error::new(
+                            ErrorKind::ChecksumMismatch,
+                            format!("{algorithm} of {} does not match {expected}", binary.name),
+                        ));
+                    }
+                }
+                println!("Downloaded {} to {}", binary.name, file_path.display());
+                binary.local_path = Some(file_path.to_string_lossy().to_string());
             } else {
-                return Err(format!("No web path or local path for binary: {}", binary.name));
+                return Err(Error::invalid_input(format!("No web path or local path for binary: {}", binary.name)));
             }
         }
         Ok(())
     }
 
-    pub fn new(name: String, image_binarys: Vec<ImageBinary>) -> Self {
+    /// A variant of `image_binarys`, pointing each at its download in the image cache at
+    /// `cache_dir` if there is one.
+    pub fn new(name: String, image_binarys: Vec<ImageBinary>, cache_dir: &Path) -> Self {
         let mut binaries = image_binarys;
         for binary in &mut binaries {
-            let local_file_path = ImageVariant::get_local_path(&name, &binary.name);
+            let local_file_path = ImageVariant::get_local_path(cache_dir, &name, &binary.name);
             binary.local_path = if local_file_path.exists() {
                  Some(local_file_path.to_string_lossy().to_string())
             } else {
@@ -218,6 +304,13 @@ mod tests {
         println!("Temp dir: {:?}", temp_dir);
     }
 
+    /// An empty image cache of the test's own.
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("revyos-imager-image-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
     #[tokio::test]
     async fn test_iscas_head_support() {
         let url = "https://fast-mirror.isrc.ac.cn/revyos/extra/images/lpi4a/20250323/boot-lpi4a-20250323_154524.ext4.zst";
@@ -228,6 +321,7 @@ mod tests {
     
     #[tokio::test]
     async fn test_download_binaries() {
+        let cache = cache_dir("download");
         use mockito::{mock, server_url};
         
         // Setup mock server
@@ -249,6 +343,10 @@ mod tests {
                     binary_type: ImageBinaryType::UBoot,
                     hash_type: None,
                     hash_value: None,
+                    size: None,
+                    modified: None,
+                    metadata: Default::default(),
+                    checksum_url: None,
                 }
             ],
         };
@@ -258,7 +356,7 @@ mod tests {
         let mut total_size = 0;
         
         // Execute the download with a simple callback that tracks progress
-        let result = variant.download_binaries(|name, progress, total, _progress_type| {
+        let result = variant.download_binaries(&cache, &[], |name, progress, total, _progress_type| {
             assert_eq!(name, "u-boot.bin");
             received_progress = progress;
             total_size = total;
@@ -284,11 +382,13 @@ mod tests {
             }
         }
         
+        let _ = std::fs::remove_dir_all(&cache);
         mock_server.assert();
     }
 
     #[tokio::test]
     async fn test_download_binaries_iscas() {
+        let cache = cache_dir("iscas");
         // Create an ImageVariant with a test binary
         let mut variant = ImageVariant {
             name: "test-variant".to_string(),
@@ -300,6 +400,10 @@ mod tests {
                     binary_type: ImageBinaryType::UBoot,
                     hash_type: None,
                     hash_value: None,
+                    size: None,
+                    modified: None,
+                    metadata: Default::default(),
+                    checksum_url: None,
                 }
             ],
         };
@@ -307,7 +411,7 @@ mod tests {
         // Progress tracking variables to verify callback
         let mut total_size = 0;
         // Execute the download with a simple callback that tracks progress
-        let result = variant.download_binaries(|_, _, total, _| {
+        let result = variant.download_binaries(&cache, &[], |_, _, total, _| {
             total_size = total;
         }).await;
         // Verify results
@@ -327,10 +431,12 @@ mod tests {
                 let _ = std::fs::remove_file(path);
             }
         }
+        let _ = std::fs::remove_dir_all(&cache);
     }
 
     #[tokio::test]
     async fn test_downloaded_binaries_detection() {
+        let cache = cache_dir("detection");
         use mockito::{mock, server_url};
         
         // Setup mock server
@@ -352,8 +458,13 @@ mod tests {
                 binary_type: ImageBinaryType::UBoot,
                 hash_type: None,
                 hash_value: None,
+                size: None,
+                modified: None,
+                metadata: Default::default(),
+                checksum_url: None,
             }
             ],
+            &cache,
         );
         
         // Progress tracking variables to verify callback
@@ -361,7 +472,7 @@ mod tests {
         let mut total_size = 0;
         
         // Execute the download with a simple callback that tracks progress
-        let result = variant.download_binaries(|name, progress, total, _progress_type| {
+        let result = variant.download_binaries(&cache, &[], |name, progress, total, _progress_type| {
             assert_eq!(name, "u-boot.bin");
             received_progress = progress;
             total_size = total;
@@ -393,11 +504,183 @@ mod tests {
                     binary_type: ImageBinaryType::UBoot,
                     hash_type: None,
                     hash_value: None,
+                    size: None,
+                    modified: None,
+                    metadata: Default::default(),
+                    checksum_url: None,
                 }
             ],
+            &cache,
         );
         assert!(new_variant.image_binarys[0].local_path.is_some(), "Local path should be set after download");
         
+        let _ = std::fs::remove_dir_all(&cache);
         mock_server.assert();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_download_fails_over_between_mirrors() {
+        let cache = cache_dir("failover");
+        use crate::mirror::mock::{serve, Behavior};
+        use std::sync::atomic::Ordering;
+
+        let data = vec![7u8; 64 * 1024];
+        let broken = serve(Behavior::Status(503)).await;
+        let stalled = serve(Behavior::Stall(data.clone())).await;
+        let good = serve(Behavior::Serve(data.clone())).await;
+        let mirrors = vec![broken.url.clone(), stalled.url.clone(), good.url.clone()];
+
+        let mut variant = ImageVariant {
+            name: "test-failover".to_string(),
+            image_binarys: vec![ImageBinary {
+                name: "u-boot-failover.bin".to_string(),
+                web_path: Some(format!("{}lpi4a/20250323/u-boot-failover.bin", broken.url)),
+                local_path: None,
+                binary_type: ImageBinaryType::UBoot,
+                hash_type: None,
+                hash_value: None,
+                size: Some(data.len() as u64),
+                modified: None,
+                metadata: Default::default(),
+                checksum_url: None,
+            }],
+        };
+        variant.download_binaries(&cache, &mirrors, |_, _, _, _| {}).await.unwrap();
+
+        let path = variant.image_binarys[0].local_path.clone().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert_eq!(broken.hits.load(Ordering::SeqCst), 1);
+        assert_eq!(stalled.hits.load(Ordering::SeqCst), 1);
+        assert_eq!(good.hits.load(Ordering::SeqCst), 1);
+        let _ = std::fs::remove_dir_all(&cache);
+    }
+
+    #[tokio::test]
+    async fn test_required_space() {
+        use crate::mirror::mock::{serve, Behavior};
+
+        // A zstd frame header declaring 1 GiB of content, as a listing would link it
+        let header = vec![0x28, 0xb5, 0x2f, 0xfd, 0xa0, 0x00, 0x00, 0x00, 0x40];
+        let mirror = serve(Behavior::Serve(header)).await;
+        let client = reqwest::Client::new();
+        let mut binary = ImageBinary::new(
+            "root.ext4.zst".to_string(),
+            Some(format!("{}root.ext4.zst", mirror.url)),
+            None,
+            ImageBinaryType::Root,
+            None,
+            None,
+        )
+        .unwrap();
+        binary.size = Some(300 << 20);
+        let web_path = binary.web_path.clone().unwrap();
+        assert_eq!(
+            ImageVariant::required_space(&client, &binary, &web_path).await,
+            (300 << 20) + (1 << 30)
+        );
+
+        // Without a content size the extracted copy is guessed at three times the download
+        let mirror = serve(Behavior::Serve(vec![0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58])).await;
+        let web_path = format!("{}root.ext4.zst", mirror.url);
+        assert_eq!(
+            ImageVariant::required_space(&client, &binary, &web_path).await,
+            4 * (300 << 20)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extract_progress_reaches_content_size() {
+        let cache = cache_dir("extract-progress");
+        use crate::mirror::mock::{serve, Behavior};
+        use crate::zstd_frame::tests::raw_frame;
+
+        // Two concatenated frames, as `zstd -T0` or `cat a.zst b.zst` produce
+        let mut data = raw_frame(&[1u8; 3000]);
+        data.extend(raw_frame(&[2u8; 5000]));
+        let mirror = serve(Behavior::Serve(data.clone())).await;
+        let mut variant = ImageVariant {
+            name: "test-extract-progress".to_string(),
+            image_binarys: vec![ImageBinary {
+                name: "boot-progress.ext4.zst".to_string(),
+                web_path: Some(format!("{}boot-progress.ext4.zst", mirror.url)),
+                local_path: None,
+                binary_type: ImageBinaryType::Boot,
+                hash_type: None,
+                hash_value: None,
+                size: Some(data.len() as u64),
+                modified: None,
+                metadata: Default::default(),
+                checksum_url: None,
+            }],
+        };
+        let mut last_extract = (0, 0);
+        variant
+            .download_binaries(&cache, &[], |_, progress, total, progress_type| {
+                if progress_type == ProgressType::Extract {
+                    assert!(progress <= total, "{progress} > {total}");
+                    last_extract = (progress, total);
+                }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(last_extract, (8000, 8000));
+        let path = variant.image_binarys[0].local_path.clone().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), 8000);
+        let _ = std::fs::remove_dir_all(&cache);
+    }
+
+    #[tokio::test]
+    async fn test_download_checks_sha256() {
+        let cache = cache_dir("checksum");
+        use crate::mirror::mock::{serve, Behavior};
+        use sha2::{Digest, Sha256, Sha512};
+
+        let data = b"u-boot with spl".to_vec();
+        let mirror = serve(Behavior::Serve(data.clone())).await;
+        let binary = |hash: String| ImageBinary {
+            name: "u-boot-checksum.bin".to_string(),
+            web_path: Some(format!("{}u-boot-checksum.bin", mirror.url)),
+            local_path: None,
+            binary_type: ImageBinaryType::UBoot,
+            hash_type: Some("SHA256".to_string()),
+            hash_value: Some(hash),
+            size: Some(data.len() as u64),
+            modified: None,
+            metadata: Default::default(),
+            checksum_url: None,
+        };
+        let mut variant = ImageVariant {
+            name: "test-checksum".to_string(),
+            image_binarys: vec![binary("00".repeat(32))],
+        };
+        let error = variant.download_binaries(&cache, &[], |_, _, _, _| {}).await.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ChecksumMismatch);
+        assert!(variant.image_binarys[0].local_path.is_none());
+
+        // A hash published in a sidecar is fetched and checked too
+        let sidecar = serve(Behavior::Serve(format!("{}  u-boot-checksum.bin\n", "11".repeat(32)).into_bytes())).await;
+        variant.image_binarys = vec![ImageBinary {
+            hash_value: None,
+            checksum_url: Some(format!("{}u-boot-checksum.bin.sha256", sidecar.url)),
+            ..binary(String::new())
+        }];
+        let error = variant.download_binaries(&cache, &[], |_, _, _, _| {}).await.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ChecksumMismatch);
+        assert_eq!(variant.image_binarys[0].hash_value, Some("11".repeat(32)));
+
+        variant.image_binarys = vec![binary(hex::encode(Sha256::digest(&data)).to_uppercase())];
+        variant.download_binaries(&cache, &[], |_, _, _, _| {}).await.unwrap();
+        let path = variant.image_binarys[0].local_path.clone().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        // SHA-512 is checked as well
+        let sha512 = |hash: String| ImageBinary { hash_type: Some("sha512".to_string()), ..binary(hash) };
+        variant.image_binarys = vec![sha512("00".repeat(64))];
+        let error = variant.download_binaries(&cache, &[], |_, _, _, _| {}).await.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ChecksumMismatch);
+        variant.image_binarys = vec![sha512(hex::encode(Sha512::digest(&data)))];
+        variant.download_binaries(&cache, &[], |_, _, _, _| {}).await.unwrap();
+        let _ = std::fs::remove_dir_all(&cache);
+    }
+}
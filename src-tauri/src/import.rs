@@ -0,0 +1,657 @@
+//! Images the user already has on disk: an unpacked build directory, or a tarball or
+//! zip archive of one. Imported images are kept next to the downloaded ones and offered
+//! like a catalog version.
+
+use std::io::Read;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::SyncIoBridge;
+
+use crate::board::{Board, HashAlgorithm, RuleSet};
+use crate::compression::Compression;
+use crate::error::{Error, ErrorKind, Result};
+use crate::image::{ImageBinary, ImageBinaryType, ImageVariant, ImageVersion, ProgressType};
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+/// The ustar magic sits at this offset of a tar header.
+const TAR_MAGIC_OFFSET: usize = 257;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bundle {
+    Directory,
+    Tar(Compression),
+    Zip,
+}
+
+impl Bundle {
+    fn detect(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(Bundle::Directory);
+        }
+        let mut head = Vec::new();
+        std::fs::File::open(path)?.take(512).read_to_end(&mut head)?;
+        if head.starts_with(ZIP_MAGIC) {
+            return Ok(Bundle::Zip);
+        }
+        match Compression::detect(&head) {
+            // A compressed file is taken for a tarball; unpacking tells whether it is one
+            Compression::None if head.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5) == Some(b"ustar") => {
+                Ok(Bundle::Tar(Compression::None))
+            }
+            Compression::None => Err(Error::invalid_input(format!(
+                "{} is not a directory, tarball or zip archive",
+                path.display()
+            ))),
+            compression => Ok(Bundle::Tar(compression)),
+        }
+    }
+}
+
+/// The bundle's name without archive extensions: `lpi4a-dev.tar.zst` gives `lpi4a-dev`.
+fn bundle_name(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let name = Compression::output_name(&name);
+    let name = [".tar", ".tgz", ".zip"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(name);
+    name.to_string()
+}
+
+/// Copy `reader` to a file in `dir` named after the last component of `entry_path`.
+/// Hidden files (`.DS_Store`, macOS `._` forks) are skipped. As directories are dropped,
+/// two entries with the same file name are an error rather than one overwriting the other.
+fn unpack_entry(mut reader: impl Read, entry_path: &Path, dir: &Path) -> Result<Option<PathBuf>> {
+    let Some(name) = entry_path.file_name().filter(|n| !n.to_string_lossy().starts_with('.')) else {
+        return Ok(None);
+    };
+    let target = dir.join(name);
+    let mut file = std::fs::File::create_new(&target).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => Error::invalid_image(format!(
+            "The archive holds more than one file named {}, last at {}",
+            name.to_string_lossy(),
+            entry_path.display()
+        )),
+        _ => e.into(),
+    })?;
+    std::io::copy(&mut reader, &mut file)?;
+    Ok(Some(target))
+}
+
+/// Unpack the regular files of a tarball into `dir`, dropping their directories.
+fn unpack_tar(reader: impl Read, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in tar::Archive::new(reader).entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let entry_path = entry.path()?.into_owned();
+        files.extend(unpack_entry(entry, &entry_path, dir)?);
+    }
+    Ok(files)
+}
+
+/// Unpack the regular files of a zip archive into `dir`, dropping their directories.
+fn unpack_zip(path: &Path, dir: &Path) -> Result<Vec<PathBuf>> {
+    let invalid = |e: zip::result::ZipError| Error::invalid_image(format!("{}: {e}", path.display()));
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?).map_err(invalid)?;
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i).map_err(invalid)?;
+        // enclosed_name() refuses absolute paths and `..`
+        let Some(entry_path) = entry.enclosed_name().filter(|_| entry.is_file()) else {
+            continue;
+        };
+        files.extend(unpack_entry(entry, &entry_path, dir)?);
+    }
+    Ok(files)
+}
+
+/// The `algorithm` hash of the file at `path`, as lowercase hex.
+async fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// The regular files directly inside `dir`.
+fn list_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// The summed size of the files in a zip archive, as its central directory records it.
+fn zip_size(path: &Path) -> Result<u64> {
+    let invalid = |e: zip::result::ZipError| Error::invalid_image(format!("{}: {e}", path.display()));
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?).map_err(invalid)?;
+    let mut size = 0u64;
+    for i in 0..archive.len() {
+        size = size.saturating_add(archive.by_index_raw(i).map_err(invalid)?.size());
+    }
+    Ok(size)
+}
+
+/// Disk space unpacking `bundle` from `path` will take.
+async fn unpacked_size(path: &Path, bundle: Bundle) -> Result<u64> {
+    match bundle {
+        Bundle::Directory => Ok(0),
+        Bundle::Tar(Compression::None) => Ok(tokio::fs::metadata(path).await?.len()),
+        Bundle::Tar(compression) => crate::compression::extracted_size(path, compression).await,
+        Bundle::Zip => {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || zip_size(&path))
+                .await
+                .map_err(|e| Error::internal(e.to_string()))?
+        }
+    }
+}
+
+static NEXT_STAGING: AtomicU64 = AtomicU64::new(0);
+
+/// Where the import of `version` for the board `board_id` keeps what it unpacked.
+fn import_dir(cache_dir: &Path, board_id: &str, version: &str) -> PathBuf {
+    cache_dir.join(format!("{board_id}-{version}"))
+}
+
+/// Build an [`ImageVersion`] from a directory, tarball (optionally compressed) or zip
+/// archive at `path`, classified with `rules`. Checksum sidecars (`*.sha256`) are
+/// checked against the file they name, as given or as extracted, and attached to it.
+///
+/// Archives are unpacked, and compressed images decompressed, into `cache_dir`; plain
+/// files of a directory are used where they are. The files are unpacked next to those
+/// of an earlier import of the same name, which is only replaced once this one succeeded.
+pub async fn import_local_image<F>(
+    path: &Path,
+    board: &Board,
+    rules: &RuleSet,
+    cache_dir: &Path,
+    progress_callback: F,
+) -> Result<ImageVersion>
+where
+    F: FnMut(&str, u64, u64, ProgressType),
+{
+    let bundle = Bundle::detect(path)?;
+    let version = format!("local-{}", bundle_name(path));
+    let dir = import_dir(cache_dir, board.id, &version);
+    // Replacing the directory would delete the files the import points at
+    if bundle == Bundle::Directory && path.starts_with(&dir) {
+        return Err(Error::invalid_input(format!(
+            "{} is inside the directory of its own earlier import",
+            path.display()
+        )));
+    }
+    crate::space::ensure_space(cache_dir, &dir, unpacked_size(path, bundle).await?)?;
+    let id = NEXT_STAGING.fetch_add(1, Ordering::Relaxed);
+    let staging = cache_dir.join(format!(".{}.{}-{id}.tmp", dir.file_name().unwrap_or_default().to_string_lossy(), std::process::id()));
+    std::fs::create_dir_all(&staging)?;
+    println!("Importing {} ({bundle:?}) as {version}", path.display());
+
+    let mut image = match unpack_bundle(path, bundle, board, rules, &staging, version, progress_callback).await {
+        Ok(image) => image,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+    if let Err(e) = replace_dir(&staging, &dir) {
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(e.into());
+    }
+    relocate(&mut image, &staging, &dir);
+    Ok(image)
+}
+
+/// Move `staging` to `dir`, replacing what is there.
+fn replace_dir(staging: &Path, dir: &Path) -> std::io::Result<()> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    std::fs::rename(staging, dir)
+}
+
+/// Point the files of `version` that are under `from` to the same place under `to`.
+fn relocate(version: &mut ImageVersion, from: &Path, to: &Path) {
+    let binaries = version
+        .image_variants
+        .iter_mut()
+        .flat_map(|variant| &mut variant.image_binarys);
+    for binary in binaries {
+        let moved = binary
+            .local_path
+            .as_deref()
+            .and_then(|path| Path::new(path).strip_prefix(from).ok())
+            .map(|rest| to.join(rest).to_string_lossy().to_string());
+        if moved.is_some() {
+            binary.local_path = moved;
+        }
+    }
+}
+
+/// Unpack `bundle` from `path` into `dir` and classify its files as `version`.
+async fn unpack_bundle<F>(
+    path: &Path,
+    bundle: Bundle,
+    board: &Board,
+    rules: &RuleSet,
+    dir: &Path,
+    version: String,
+    mut progress_callback: F,
+) -> Result<ImageVersion>
+where
+    F: FnMut(&str, u64, u64, ProgressType),
+{
+    let dir = dir.to_path_buf();
+    let unpack_dir = dir.clone();
+    let mut files = match bundle {
+        Bundle::Directory => list_dir(path)?,
+        Bundle::Tar(compression) => {
+            let file = tokio::fs::File::open(path).await?;
+            let reader = SyncIoBridge::new(compression.decoder(tokio::io::BufReader::new(file)));
+            tokio::task::spawn_blocking(move || unpack_tar(reader, &unpack_dir))
+                .await
+                .map_err(|e| Error::internal(e.to_string()))??
+        }
+        Bundle::Zip => {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || unpack_zip(&path, &unpack_dir))
+                .await
+                .map_err(|e| Error::internal(e.to_string()))??
+        }
+    };
+    files.sort();
+
+    let mut checksums = Vec::new();
+    files.retain(|file| {
+        let name = file.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let Some((target, hash_type)) = crate::board::checksum_sidecar(&name) else {
+            return true;
+        };
+        let Some(algorithm) = HashAlgorithm::from_name(hash_type) else {
+            eprintln!("Ignoring {name}: {hash_type} checksums cannot be verified");
+            return false;
+        };
+        match std::fs::read_to_string(file) {
+            Ok(content) => {
+                if let Some(hash) = crate::board::parse_checksum_file(&content, target) {
+                    checksums.push((target.to_string(), algorithm, hash));
+                }
+            }
+            Err(e) => eprintln!("Failed to read {}: {e}", file.display()),
+        }
+        false
+    });
+
+    let mut binaries = Vec::new();
+    for file in files {
+        let name = file.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let (binary_type, metadata) = rules.classify(&name);
+        if matches!(binary_type, ImageBinaryType::Other(_)) {
+            continue;
+        }
+        let output = dir.join(Compression::output_name(&name));
+        // A compressed file without a telling extension is decompressed next to itself
+        let target = if output == file { dir.join(format!("{name}.decompressed")) } else { output.clone() };
+        let format = crate::compression::detect_file(&file).await?;
+        let size = crate::compression::content_size(&file, format).await;
+        if format != Compression::None {
+            // The staging directory sits right in the image cache
+            let cache_dir = dir.parent().unwrap_or(&dir);
+            crate::space::ensure_space(cache_dir, &dir, crate::compression::extracted_size(&file, format).await?)?;
+        }
+        let checksum = checksums.iter().find(|(target, _, _)| *target == name);
+        // Hashed before extracting, which may delete the file
+        let given_hash = match checksum {
+            Some((_, algorithm, _)) => Some(hash_file(&file, *algorithm).await?),
+            None => None,
+        };
+        let found = crate::compression::decompress_file(&file, &target, size, &mut progress_callback).await?;
+        let local_path = match found {
+            Compression::None => file,
+            _ => {
+                // Only drop what we unpacked ourselves, never the user's files
+                if file.starts_with(&dir) {
+                    std::fs::remove_file(&file)?;
+                }
+                std::fs::rename(&target, &output)?;
+                output
+            }
+        };
+        if let Some((_, algorithm, expected)) = checksum {
+            let matches = given_hash.as_ref() == Some(expected)
+                || (found != Compression::None && hash_file(&local_path, *algorithm).await? == *expected);
+            if !matches {
+                return Err(Error::new(
+                    ErrorKind::ChecksumMismatch,
+                    format!("{algorithm} of {name} does not match {expected}"),
+                ));
+            }
+        }
+        let (hash_type, hash_value) = checksum
+            .map(|(_, algorithm, hash)| (Some(algorithm.name().to_string()), Some(hash.clone())))
+            .unwrap_or_default();
+        let mut binary = ImageBinary::new(
+            name,
+            None,
+            Some(local_path.to_string_lossy().to_string()),
+            binary_type,
+            hash_type,
+            hash_value,
+        )?;
+        binary.metadata = metadata;
+        binary.size = std::fs::metadata(&local_path).ok().map(|m| m.len());
+        binaries.push(binary);
+    }
+
+    let missing = |binary_type: ImageBinaryType| {
+        Error::invalid_image(format!("{} has no {binary_type:?} image for {}", path.display(), board.name))
+    };
+    let variants = board.group_variants(&binaries).map_err(missing)?;
+    if variants.is_empty() {
+        return Err(missing(board.variant_by.clone()));
+    }
+    Ok(ImageVersion {
+        version,
+        image_variants: variants
+            .into_iter()
+            .map(|(name, image_binarys)| ImageVariant { name, image_binarys })
+            .collect(),
+    })
+}
+
+/// An imported version and the board it was imported for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LocalImage {
+    board: String,
+    #[serde(flatten)]
+    version: ImageVersion,
+}
+
+/// Imported images, persisted so they stay selectable across restarts.
+pub struct LocalImages {
+    path: PathBuf,
+    images: Mutex<Vec<LocalImage>>,
+}
+
+impl LocalImages {
+    pub fn load(path: PathBuf) -> Self {
+        let images = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| {
+                serde_json::from_str(&content)
+                    .inspect_err(|e| eprintln!("Ignoring unreadable {}: {e}", path.display()))
+                    .ok()
+            })
+            .unwrap_or_default();
+        Self {
+            path,
+            images: Mutex::new(images),
+        }
+    }
+
+    fn save(&self, images: &[LocalImage]) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(images)?)?;
+        Ok(())
+    }
+
+    /// The versions imported for `board` whose files are all still there.
+    pub fn list(&self, board: &str) -> Vec<ImageVersion> {
+        self.images
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|image| image.board == board)
+            .map(|image| &image.version)
+            .filter(|version| {
+                version.image_variants.iter().all(|variant| {
+                    variant
+                        .image_binarys
+                        .iter()
+                        .all(|binary| binary.local_path.as_ref().is_some_and(|p| Path::new(p).exists()))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Add `version` for `board`, replacing an earlier import of the same name.
+    pub fn add(&self, board: &str, version: ImageVersion) -> Result<()> {
+        let mut images = self.images.lock().unwrap();
+        images.retain(|image| image.board != board || image.version.version != version.version);
+        images.push(LocalImage {
+            board: board.to_string(),
+            version,
+        });
+        self.save(&images)
+    }
+
+    /// Point files unpacked into the image cache at `from` to where it moved, `to`.
+    /// Files of imported directories stay where they are.
+    pub fn relocate(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut images = self.images.lock().unwrap();
+        for image in images.iter_mut() {
+            relocate(&mut image.version, from, to);
+        }
+        self.save(&images)
+    }
+
+    /// Forget a version imported for `board` and delete what the import unpacked into
+    /// the image cache at `cache_dir`.
+    pub fn remove(&self, cache_dir: &Path, board: &str, version: &str) -> Result<()> {
+        let mut images = self.images.lock().unwrap();
+        images.retain(|image| image.board != board || image.version.version != version);
+        self.save(&images)?;
+        let dir = import_dir(cache_dir, board, version);
+        if dir.exists() {
+            crate::space::remove_cache_entry(cache_dir, &dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::tests::compress;
+    use sha2::Digest;
+    use std::io::Write;
+
+    const FILES: &[(&str, &[u8])] = &[
+        ("u-boot-with-spl-lpi4a.bin", b"spl"),
+        ("u-boot-with-spl-lpi4a-16g.bin", b"spl 16g"),
+        ("boot-lpi4a-dev.ext4", b"boot"),
+        ("root-lpi4a-dev.ext4", b"root"),
+        ("README.md", b"notes"),
+    ];
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, format!("lpi4a-dev/{name}"), *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, data) in files {
+            writer
+                .start_file(format!("lpi4a-dev/{name}"), zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn contents(version: &ImageVersion, variant: usize) -> Vec<(ImageBinaryType, Vec<u8>)> {
+        version.image_variants[variant]
+            .image_binarys
+            .iter()
+            .map(|b| (b.binary_type.clone(), std::fs::read(b.local_path.as_ref().unwrap()).unwrap()))
+            .collect()
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("revyos-imager-import-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_import_bundles() {
+        let dir = test_dir("bundles");
+        let cache = test_dir("bundles-cache");
+        let mut compressed_root = FILES.to_vec();
+        let root = compress(Compression::Zstd, b"root").await;
+        compressed_root[3] = ("root-lpi4a-dev.ext4.zst", &root);
+        let rules = crate::board::LPI4A.rules();
+        let bundles = [
+            ("lpi4a-dev.tar", tar(FILES)),
+            ("lpi4a-dev.tar.zst", compress(Compression::Zstd, &tar(&compressed_root)).await),
+            ("lpi4a-dev.zip", zip(FILES)),
+        ];
+        let mut last = None;
+        for (name, data) in bundles {
+            let path = dir.join(name);
+            std::fs::write(&path, data).unwrap();
+            let version = import_local_image(&path, &crate::board::LPI4A, &rules, &cache, |_, _, _, _| {}).await.unwrap();
+            assert_eq!(version.version, "local-lpi4a-dev", "{name}");
+            assert_eq!(version.image_variants.len(), 2, "{name}");
+            assert_eq!(version.image_variants[1].name, "u-boot-with-spl-lpi4a.bin", "{name}");
+            assert_eq!(
+                contents(&version, 1),
+                vec![
+                    (ImageBinaryType::Root, b"root".to_vec()),
+                    (ImageBinaryType::Boot, b"boot".to_vec()),
+                    (ImageBinaryType::UBoot, b"spl".to_vec()),
+                ],
+                "{name}"
+            );
+            last = Some(version);
+        }
+
+        // Two files of one name clash once directories are dropped; the failed
+        // re-import leaves the earlier one in place
+        let mut clashing = FILES.to_vec();
+        clashing.push(("extra/boot-lpi4a-dev.ext4", b"other boot"));
+        let path = dir.join("lpi4a-dev.tar");
+        std::fs::write(&path, tar(&clashing)).unwrap();
+        let error = import_local_image(&path, &crate::board::LPI4A, &rules, &cache, |_, _, _, _| {}).await.unwrap_err();
+        assert_eq!(error.kind, crate::error::ErrorKind::InvalidImage);
+        assert!(error.message.contains("boot-lpi4a-dev.ext4"), "{}", error.message);
+        assert_eq!(contents(last.as_ref().unwrap(), 1)[1], (ImageBinaryType::Boot, b"boot".to_vec()));
+        let names: Vec<_> = std::fs::read_dir(&cache).unwrap().flatten().map(|e| e.file_name()).collect();
+        assert_eq!(names, ["lpi4a-local-lpi4a-dev"]);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&cache);
+    }
+
+    #[tokio::test]
+    async fn test_import_directory_and_validation() {
+        let dir = test_dir("directory");
+        let cache = test_dir("directory-cache");
+        for (name, data) in FILES {
+            std::fs::write(dir.join(name), data).unwrap();
+        }
+        let sha256 = hex::encode(sha2::Sha256::digest(b"spl 16g"));
+        std::fs::write(dir.join("u-boot-with-spl-lpi4a-16g.bin.sha256"), format!("{sha256}  u-boot-with-spl-lpi4a-16g.bin\n")).unwrap();
+        std::fs::write(dir.join("boot-lpi4a-dev.ext4.md5"), "0123  boot-lpi4a-dev.ext4\n").unwrap();
+        let rules = crate::board::LPI4A.rules();
+        let version = import_local_image(&dir, &crate::board::LPI4A, &rules, &cache, |_, _, _, _| {}).await.unwrap();
+        assert_eq!(version.version, "local-revyos-imager-import-directory");
+        // Plain files are used in place
+        let uboot = &version.image_variants[0].image_binarys[2];
+        assert_eq!(uboot.local_path.as_deref(), Some(dir.join("u-boot-with-spl-lpi4a-16g.bin").to_str().unwrap()));
+        assert_eq!(uboot.metadata.ram.as_deref(), Some("16g"));
+        assert_eq!(uboot.hash_type.as_deref(), Some("sha256"));
+        assert_eq!(uboot.hash_value, Some(sha256));
+        // An MD5 sum cannot be checked, so it is left out
+        assert_eq!(version.image_variants[0].image_binarys[1].hash_type, None);
+
+        std::fs::write(dir.join("u-boot-with-spl-lpi4a-16g.bin.sha256"), "00  u-boot-with-spl-lpi4a-16g.bin\n").unwrap();
+        let error = import_local_image(&dir, &crate::board::LPI4A, &rules, &cache, |_, _, _, _| {}).await.unwrap_err();
+        assert_eq!(error.kind, crate::error::ErrorKind::ChecksumMismatch);
+        std::fs::remove_file(dir.join("u-boot-with-spl-lpi4a-16g.bin.sha256")).unwrap();
+
+        std::fs::remove_file(dir.join("boot-lpi4a-dev.ext4")).unwrap();
+        let error = import_local_image(&dir, &crate::board::LPI4A, &rules, &cache, |_, _, _, _| {}).await.unwrap_err();
+        assert_eq!(error.kind, crate::error::ErrorKind::InvalidImage);
+        assert!(error.message.contains("Boot"), "{}", error.message);
+
+        let not_a_bundle = dir.join("README.md");
+        assert!(import_local_image(&not_a_bundle, &crate::board::LPI4A, &rules, &cache, |_, _, _, _| {}).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&cache);
+    }
+
+    #[tokio::test]
+    async fn test_local_images_registry() {
+        let dir = test_dir("registry");
+        let image = dir.join("root.ext4");
+        std::fs::write(&image, b"root").unwrap();
+        let version = |name: &str| ImageVersion {
+            version: name.to_string(),
+            image_variants: vec![ImageVariant {
+                name: "u-boot.bin".to_string(),
+                image_binarys: vec![ImageBinary::new(
+                    "root.ext4".to_string(),
+                    None,
+                    Some(image.to_string_lossy().to_string()),
+                    ImageBinaryType::Root,
+                    None,
+                    None,
+                )
+                .unwrap()],
+            }],
+        };
+        let images = LocalImages::load(dir.join("local_images.json"));
+        images.add("lpi4a", version("local-a")).unwrap();
+        images.add("lpi4a", version("local-b")).unwrap();
+        images.add("lpi4a", version("local-a")).unwrap();
+        images.add("other", version("local-a")).unwrap();
+        let reloaded = LocalImages::load(dir.join("local_images.json"));
+        let names: Vec<_> = reloaded.list("lpi4a").into_iter().map(|v| v.version).collect();
+        assert_eq!(names, ["local-b", "local-a"]);
+        assert_eq!(reloaded.list("other").len(), 1);
+        assert!(reloaded.list("unknown").is_empty());
+
+        reloaded.remove(&dir, "lpi4a", "local-b").unwrap();
+        assert_eq!(reloaded.list("lpi4a").len(), 1);
+        // Moving the cache takes the recorded paths along
+        let moved = dir.join("moved");
+        std::fs::create_dir_all(&moved).unwrap();
+        std::fs::rename(&image, moved.join("root.ext4")).unwrap();
+        assert!(reloaded.list("lpi4a").is_empty());
+        reloaded.relocate(&dir, &moved).unwrap();
+        let reloaded = LocalImages::load(dir.join("local_images.json"));
+        assert_eq!(reloaded.list("lpi4a").len(), 1);
+        std::fs::remove_file(moved.join("root.ext4")).unwrap();
+        assert!(reloaded.list("lpi4a").is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,481 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use serde::Serialize;
+
+use crate::compression::Compression;
+use crate::error::{Error, Result, ResultExt};
+use crate::sparse::ChunkKind;
+
+/// How much of the (unsparsed) image start is read to identify it.
+const HEAD_LEN: usize = 64 << 10;
+/// How far into a raw file to look for a U-Boot version string.
+const UBOOT_SCAN_LEN: u64 = 4 << 20;
+const SECTOR: u64 = 512;
+
+/// What an image file contains, as far as its headers tell.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ImageKind {
+    /// An ext2/3/4 filesystem; `size` is what its superblock claims.
+    #[serde(rename_all = "camelCase")]
+    Ext4 { label: String, size: u64 },
+    #[serde(rename_all = "camelCase")]
+    Fat { label: String, size: u64 },
+    /// A U-Boot or SPL binary, with the version string if one was found.
+    #[serde(rename_all = "camelCase")]
+    UBoot { version: Option<String> },
+    #[serde(rename_all = "camelCase")]
+    AndroidBoot { header_version: u32 },
+    /// A whole disk with a GUID partition table.
+    #[serde(rename_all = "camelCase")]
+    Gpt { partitions: Vec<String> },
+    /// Still compressed, so not flashable as is.
+    #[serde(rename_all = "camelCase")]
+    Compressed { format: Compression },
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageInfo {
+    pub kind: ImageKind,
+    /// Whether the file is an Android sparse image.
+    pub sparse: bool,
+    /// Size of the image once written, after unsparsing.
+    pub size: u64,
+    /// Whether the content claims more bytes than the file holds.
+    pub truncated: bool,
+}
+
+/// Whether an image belongs on a partition.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "fit", content = "reason", rename_all = "camelCase")]
+pub enum Fit {
+    Fits,
+    /// Possibly wrong; flashing goes ahead with a warning.
+    Suspicious(String),
+    /// Certainly wrong; flashing is refused unless forced.
+    Wrong(String),
+}
+
+/// What a partition is expected to hold, judged by its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Bootloader,
+    Boot,
+    Root,
+    Disk,
+}
+
+impl Role {
+    fn of(partition: &str) -> Option<Self> {
+        let partition = partition.to_ascii_lowercase();
+        match partition.as_str() {
+            "ram" | "uboot" | "u-boot" | "spl" | "fsbl" | "bootloader" | "opensbi" => Some(Role::Bootloader),
+            "boot" | "bootfs" => Some(Role::Boot),
+            "root" | "rootfs" | "system" | "userdata" => Some(Role::Root),
+            "mmc0" | "mmc1" | "emmc" | "sdcard" | "disk" | "user" => Some(Role::Disk),
+            _ => None,
+        }
+    }
+}
+
+fn u16_le(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_le(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn u32_be(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// A NUL padded string field.
+fn c_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+/// The first [`HEAD_LEN`] bytes of the image a sparse file describes, its unsparsed
+/// size, and whether the file ends before its last chunk does.
+fn sparse_head<R: Read + Seek>(file: &mut R, file_len: u64) -> Result<Option<(Vec<u8>, u64, bool)>> {
+    let Some(map) = crate::sparse::read_map(file)? else {
+        return Ok(None);
+    };
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    for chunk in &map.chunks {
+        if head.len() == HEAD_LEN {
+            break;
+        }
+        let wanted = (HEAD_LEN - head.len()).min(chunk.blocks as usize * map.header.block_size as usize);
+        match chunk.kind {
+            ChunkKind::Raw => {
+                let available = file_len.saturating_sub(chunk.offset).min(wanted as u64) as usize;
+                let start = head.len();
+                head.resize(start + available, 0);
+                file.seek(SeekFrom::Start(chunk.offset))?;
+                file.read_exact(&mut head[start..])?;
+            }
+            ChunkKind::Fill => {
+                let fill = chunk.value.unwrap_or_default().to_le_bytes();
+                head.extend(fill.iter().cycle().take(wanted));
+            }
+            ChunkKind::DontCare => head.resize(head.len() + wanted, 0),
+            ChunkKind::Crc32 => {}
+        }
+    }
+    Ok(Some((head, map.header.size(), map.truncated)))
+}
+
+/// The filesystem or disk layout `head` starts with, and the size it claims. Header
+/// fields whose values overflow are rejected as an invalid image.
+fn identify(head: &[u8]) -> Result<(ImageKind, Option<u64>)> {
+    let compression = Compression::detect(head);
+    if compression != Compression::None {
+        return Ok((ImageKind::Compressed { format: compression }, None));
+    }
+    if head.len() >= 1024 && &head[512..520] == b"EFI PART" {
+        let overflow = || Error::invalid_image("GPT header points beyond any disk");
+        let entries_at = u64_le(head, 512 + 72)
+            .checked_mul(SECTOR)
+            .and_then(|offset| usize::try_from(offset).ok())
+            .ok_or_else(overflow)?;
+        let count = u32_le(head, 512 + 80) as usize;
+        let entry_len = u32_le(head, 512 + 84) as usize;
+        let mut partitions = Vec::new();
+        let mut end = 0;
+        for i in 0..count {
+            let offset = i
+                .checked_mul(entry_len)
+                .and_then(|offset| offset.checked_add(entries_at))
+                .ok_or_else(overflow)?;
+            if entry_len < 128 || offset.saturating_add(128) > head.len() {
+                break;
+            }
+            let entry = &head[offset..offset + 128];
+            if entry[..16].iter().all(|&b| b == 0) {
+                continue;
+            }
+            let name: Vec<u16> = entry[56..128].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            let name = String::from_utf16_lossy(&name);
+            partitions.push(name.trim_end_matches('\0').to_string());
+            let last = u64_le(entry, 40);
+            end = end.max(last.checked_add(1).and_then(|n| n.checked_mul(SECTOR)).ok_or_else(overflow)?);
+        }
+        return Ok((ImageKind::Gpt { partitions }, Some(end)));
+    }
+    if head.len() >= 2048 && u16_le(head, 1024 + 0x38) == 0xef53 {
+        let superblock = &head[1024..2048];
+        let block_size = 1024u64 << u32_le(superblock, 0x18).min(16);
+        let mut blocks = u32_le(superblock, 0x04) as u64;
+        // INCOMPAT_64BIT
+        if u32_le(superblock, 0x60) & 0x80 != 0 {
+            blocks |= (u32_le(superblock, 0x150) as u64) << 32;
+        }
+        let size = blocks
+            .checked_mul(block_size)
+            .ok_or_else(|| Error::invalid_image("ext4 superblock claims more blocks than fit any disk"))?;
+        return Ok((ImageKind::Ext4 { label: c_string(&superblock[0x78..0x88]), size }, Some(size)));
+    }
+    if head.len() >= 512 && head[510..512] == [0x55, 0xaa] {
+        let sector_size = u16_le(head, 11) as u64;
+        let fat32 = u16_le(head, 22) == 0;
+        let (label_at, type_at) = if fat32 { (71, 82) } else { (43, 54) };
+        if head[type_at..type_at + 3] == *b"FAT" && sector_size != 0 {
+            let sectors = match u16_le(head, 19) {
+                0 => u32_le(head, 32) as u64,
+                sectors => sectors as u64,
+            };
+            let size = sectors * sector_size;
+            return Ok((ImageKind::Fat { label: c_string(&head[label_at..label_at + 11]), size }, Some(size)));
+        }
+    }
+    if head.starts_with(b"ANDROID!") && head.len() >= 44 {
+        return Ok((ImageKind::AndroidBoot { header_version: u32_le(head, 40) }, None));
+    }
+    // Legacy uImage header
+    if head.len() >= 64 && u32_be(head, 0) == 0x2705_1956 {
+        let size = 64 + u32_be(head, 12) as u64;
+        return Ok((ImageKind::UBoot { version: Some(c_string(&head[32..64])) }, Some(size)));
+    }
+    Ok((ImageKind::Unknown, None))
+}
+
+/// The version string of a U-Boot or SPL binary, e.g. `U-Boot SPL 2020.01-g3e5c0a4b`.
+fn find_uboot_version<R: Read>(file: R) -> Result<Option<String>> {
+    const NEEDLE: &[u8] = b"U-Boot ";
+    let mut data = Vec::new();
+    file.take(UBOOT_SCAN_LEN).read_to_end(&mut data)?;
+    let found = data.windows(NEEDLE.len()).enumerate().find_map(|(at, window)| {
+        if window != NEEDLE {
+            return None;
+        }
+        let rest = &data[at..(at + 64).min(data.len())];
+        let end = rest.iter().position(|&b| !(0x20..0x7f).contains(&b) || b == b'(').unwrap_or(rest.len());
+        let version = String::from_utf8_lossy(&rest[..end]).trim().to_string();
+        // Skip mentions like "U-Boot " in help texts: a version starts with a digit
+        let after = version.strip_prefix("U-Boot ")?;
+        let after = after.strip_prefix("SPL ").unwrap_or(after);
+        after.starts_with(|c: char| c.is_ascii_digit()).then_some(version)
+    });
+    Ok(found)
+}
+
+/// Identify the image at `path` from its headers, looking through sparse encoding.
+pub fn inspect(path: &Path) -> Result<ImageInfo> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let file_len = file.metadata()?.len();
+    let (head, size, sparse, sparse_truncated) = match sparse_head(&mut file, file_len)? {
+        Some((head, size, truncated)) => (head, size, true, truncated),
+        None => {
+            let mut head = Vec::with_capacity(HEAD_LEN);
+            file.seek(SeekFrom::Start(0))?;
+            (&mut file).take(HEAD_LEN as u64).read_to_end(&mut head)?;
+            (head, file_len, false, false)
+        }
+    };
+    let (mut kind, claimed) = identify(&head)?;
+    if kind == ImageKind::Unknown && !sparse {
+        file.seek(SeekFrom::Start(0))?;
+        if let Some(version) = find_uboot_version(&mut file)? {
+            kind = ImageKind::UBoot { version: Some(version) };
+        }
+    }
+    let truncated = sparse_truncated || claimed.is_some_and(|claimed| claimed > size);
+    Ok(ImageInfo { kind, sparse, size, truncated })
+}
+
+/// Whether `info` belongs on `partition`. Partitions not known by name always fit.
+pub fn fit(info: &ImageInfo, partition: &str) -> Fit {
+    let describe = |kind: &ImageKind| match kind {
+        ImageKind::Ext4 { label, .. } if !label.is_empty() => format!("an ext4 filesystem labelled \"{label}\""),
+        ImageKind::Ext4 { .. } => "an ext4 filesystem".to_string(),
+        ImageKind::Fat { .. } => "a FAT filesystem".to_string(),
+        ImageKind::UBoot { .. } => "a U-Boot binary".to_string(),
+        ImageKind::AndroidBoot { .. } => "an Android boot image".to_string(),
+        ImageKind::Gpt { .. } => "a whole disk image".to_string(),
+        ImageKind::Compressed { format } => format!("still {format:?} compressed"),
+        ImageKind::Unknown => "of unknown content".to_string(),
+    };
+    if info.truncated {
+        return Fit::Wrong(format!("The image is truncated: it holds fewer bytes than {} needs", describe(&info.kind)));
+    }
+    if let ImageKind::Compressed { .. } = info.kind {
+        return Fit::Wrong(format!("The image is {}", describe(&info.kind)));
+    }
+    let Some(role) = Role::of(partition) else {
+        return Fit::Fits;
+    };
+    let label = match &info.kind {
+        ImageKind::Ext4 { label, .. } | ImageKind::Fat { label, .. } => label.to_ascii_lowercase(),
+        _ => String::new(),
+    };
+    let wrong = || Fit::Wrong(format!("The image is {}, which does not belong on the {partition} partition", describe(&info.kind)));
+    let suspicious = || Fit::Suspicious(format!("The image is {}; is it meant for the {partition} partition?", describe(&info.kind)));
+    match (role, &info.kind) {
+        (Role::Bootloader, ImageKind::UBoot { .. }) => Fit::Fits,
+        (Role::Bootloader, ImageKind::Unknown) => Fit::Fits,
+        (Role::Bootloader, _) => wrong(),
+        (Role::Boot, ImageKind::Ext4 { .. } | ImageKind::Fat { .. }) if label.contains("root") => suspicious(),
+        (Role::Boot, ImageKind::Ext4 { .. } | ImageKind::Fat { .. } | ImageKind::AndroidBoot { .. }) => Fit::Fits,
+        (Role::Root, ImageKind::Ext4 { .. }) if label.contains("boot") => suspicious(),
+        (Role::Root, ImageKind::Ext4 { .. }) => Fit::Fits,
+        (Role::Root, ImageKind::Fat { .. }) => suspicious(),
+        (Role::Disk, ImageKind::Gpt { .. }) => Fit::Fits,
+        (Role::Disk, ImageKind::Ext4 { .. } | ImageKind::Fat { .. }) => suspicious(),
+        (_, ImageKind::Unknown) => suspicious(),
+        _ => wrong(),
+    }
+}
+
+/// Refuse images that certainly do not belong on `partition` unless `force` is set,
+/// and log a warning for doubtful ones.
+pub fn check(path: &Path, partition: &str, force: bool) -> Result<ImageInfo> {
+    let info = inspect(path)?;
+    println!("{} is {:?}", path.display(), info.kind);
+    match fit(&info, partition) {
+        Fit::Fits => {}
+        Fit::Suspicious(reason) => eprintln!("Warning: {reason}"),
+        Fit::Wrong(reason) if force => eprintln!("Warning: {reason}; flashing anyway as forced"),
+        Fit::Wrong(reason) => return Err(Error::invalid_image(reason)),
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse::{CHUNK_DONT_CARE, CHUNK_RAW, SPARSE_MAGIC};
+
+    fn ext4(label: &str, blocks: u32) -> Vec<u8> {
+        let mut image = vec![0u8; 4096];
+        let superblock = &mut image[1024..2048];
+        superblock[0x04..0x08].copy_from_slice(&blocks.to_le_bytes());
+        superblock[0x18..0x1c].copy_from_slice(&2u32.to_le_bytes());
+        superblock[0x38..0x3a].copy_from_slice(&0xef53u16.to_le_bytes());
+        superblock[0x78..0x78 + label.len()].copy_from_slice(label.as_bytes());
+        image
+    }
+
+    fn fat(label: &str) -> Vec<u8> {
+        let mut image = vec![0u8; 4096];
+        image[0] = 0xeb;
+        image[11..13].copy_from_slice(&512u16.to_le_bytes());
+        image[19..21].copy_from_slice(&8u16.to_le_bytes());
+        image[22..24].copy_from_slice(&1u16.to_le_bytes());
+        image[43..54].copy_from_slice(format!("{label:<11}").as_bytes());
+        image[54..62].copy_from_slice(b"FAT12   ");
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        image
+    }
+
+    fn gpt(partitions: &[(&str, u64)]) -> Vec<u8> {
+        let mut image = vec![0u8; 34 * 512];
+        image[512..520].copy_from_slice(b"EFI PART");
+        image[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        image[512 + 80..512 + 84].copy_from_slice(&128u32.to_le_bytes());
+        image[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        for (i, (name, last_lba)) in partitions.iter().enumerate() {
+            let entry = &mut image[1024 + i * 128..1024 + (i + 1) * 128];
+            entry[0] = 1;
+            entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+            for (j, c) in name.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        image
+    }
+
+    /// `image` as a sparse file: the first block raw, the rest don't care.
+    fn sparse(image: &[u8], blocks: u32) -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend(SPARSE_MAGIC.to_le_bytes());
+        for value in [1u16, 0, 28, 12] {
+            file.extend(value.to_le_bytes());
+        }
+        for value in [4096u32, blocks, 2, 0] {
+            file.extend(value.to_le_bytes());
+        }
+        file.extend(CHUNK_RAW.to_le_bytes());
+        file.extend(0u16.to_le_bytes());
+        file.extend(1u32.to_le_bytes());
+        file.extend((12 + 4096u32).to_le_bytes());
+        file.extend(&image[..4096]);
+        file.extend(CHUNK_DONT_CARE.to_le_bytes());
+        file.extend(0u16.to_le_bytes());
+        file.extend((blocks - 1).to_le_bytes());
+        file.extend(12u32.to_le_bytes());
+        file
+    }
+
+    fn inspect_bytes(name: &str, data: &[u8]) -> ImageInfo {
+        let path = std::env::temp_dir().join(format!("revyos-imager-inspect-{name}"));
+        std::fs::write(&path, data).unwrap();
+        let info = inspect(&path).unwrap();
+        let _ = std::fs::remove_file(path);
+        info
+    }
+
+    #[test]
+    fn test_identify() {
+        let mut root = ext4("root", 4);
+        root.resize(16384, 0);
+        let info = inspect_bytes("ext4", &root);
+        assert_eq!(info.kind, ImageKind::Ext4 { label: "root".to_string(), size: 16384 });
+        assert!(!info.truncated && !info.sparse);
+
+        // Claims 64 KiB but holds 4
+        assert!(inspect_bytes("ext4-short", &ext4("boot", 16)).truncated);
+
+        let info = inspect_bytes("fat", &fat("EFI"));
+        assert_eq!(info.kind, ImageKind::Fat { label: "EFI".to_string(), size: 4096 });
+
+        let mut disk = gpt(&[("uboot", 40), ("boot", 60)]);
+        disk.resize(61 * 512, 0);
+        let info = inspect_bytes("gpt", &disk);
+        assert_eq!(info.kind, ImageKind::Gpt { partitions: vec!["uboot".to_string(), "boot".to_string()] });
+        assert!(!info.truncated);
+
+        let mut boot = b"ANDROID!".to_vec();
+        boot.resize(2048, 0);
+        boot[40] = 2;
+        assert_eq!(inspect_bytes("android", &boot).kind, ImageKind::AndroidBoot { header_version: 2 });
+
+        let mut uboot = vec![0x13u8; 100_000];
+        uboot.extend(b"\0U-Boot is a bootloader\0U-Boot SPL 2020.01-g3e5c0a4b (Jan 01 2024)\0");
+        let info = inspect_bytes("uboot", &uboot);
+        assert_eq!(info.kind, ImageKind::UBoot { version: Some("U-Boot SPL 2020.01-g3e5c0a4b".to_string()) });
+
+        assert_eq!(inspect_bytes("unknown", &[0x13u8; 1000]).kind, ImageKind::Unknown);
+    }
+
+    #[test]
+    fn test_identify_rejects_overflowing_headers() {
+        let mut disk = gpt(&[("boot", 60)]);
+        disk[512 + 72..512 + 80].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(identify(&disk).unwrap_err().kind, crate::error::ErrorKind::InvalidImage);
+
+        let mut disk = gpt(&[("boot", u64::MAX)]);
+        disk.resize(64 * 512, 0);
+        assert_eq!(identify(&disk).unwrap_err().kind, crate::error::ErrorKind::InvalidImage);
+
+        let mut root = ext4("root", u32::MAX);
+        root[1024 + 0x18..1024 + 0x1c].copy_from_slice(&16u32.to_le_bytes());
+        root[1024 + 0x60] = 0x80;
+        root[1024 + 0x150..1024 + 0x154].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(identify(&root).unwrap_err().kind, crate::error::ErrorKind::InvalidImage);
+    }
+
+    #[tokio::test]
+    async fn test_identify_sparse_and_compressed() {
+        let info = inspect_bytes("sparse", &sparse(&ext4("root", 8), 8));
+        assert!(info.sparse);
+        assert_eq!(info.size, 8 * 4096);
+        assert_eq!(info.kind, ImageKind::Ext4 { label: "root".to_string(), size: 8 * 4096 });
+        assert!(!info.truncated);
+
+        let mut cut = sparse(&ext4("root", 8), 8);
+        cut.truncate(2000);
+        assert!(inspect_bytes("sparse-cut", &cut).truncated);
+
+        let compressed = crate::compression::tests::compress(Compression::Zstd, &ext4("root", 1)).await;
+        let info = inspect_bytes("zst", &compressed);
+        assert_eq!(info.kind, ImageKind::Compressed { format: Compression::Zstd });
+        assert!(matches!(fit(&info, "root"), Fit::Wrong(_)));
+    }
+
+    #[test]
+    fn test_fit() {
+        let info = |kind| ImageInfo { kind, sparse: false, size: 1 << 20, truncated: false };
+        let root = info(ImageKind::Ext4 { label: "root".to_string(), size: 1 << 20 });
+        let uboot = info(ImageKind::UBoot { version: None });
+        let disk = info(ImageKind::Gpt { partitions: vec![] });
+        assert_eq!(fit(&root, "root"), Fit::Fits);
+        assert!(matches!(fit(&root, "boot"), Fit::Suspicious(_)));
+        assert!(matches!(fit(&root, "uboot"), Fit::Wrong(_)));
+        assert!(matches!(fit(&root, "ram"), Fit::Wrong(_)));
+        assert_eq!(fit(&uboot, "ram"), Fit::Fits);
+        assert!(matches!(fit(&uboot, "root"), Fit::Wrong(_)));
+        assert!(matches!(fit(&disk, "boot"), Fit::Wrong(_)));
+        assert_eq!(fit(&disk, "mmc0"), Fit::Fits);
+        assert_eq!(fit(&disk, "vendor"), Fit::Fits);
+        assert!(matches!(fit(&info(ImageKind::Unknown), "root"), Fit::Suspicious(_)));
+        assert_eq!(fit(&info(ImageKind::Unknown), "ram"), Fit::Fits);
+        let truncated = ImageInfo { truncated: true, ..root.clone() };
+        assert!(matches!(fit(&truncated, "root"), Fit::Wrong(_)));
+        assert!(matches!(fit(&truncated, "vendor"), Fit::Wrong(_)));
+
+        let path = std::env::temp_dir().join("revyos-imager-inspect-check");
+        std::fs::write(&path, gpt(&[])).unwrap();
+        let error = check(&path, "root", false).unwrap_err();
+        assert_eq!(error.kind, crate::error::ErrorKind::InvalidImage);
+        assert!(check(&path, "root", true).is_ok());
+        let _ = std::fs::remove_file(path);
+    }
+}
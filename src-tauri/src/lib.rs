@@ -3,19 +3,93 @@ mod flash;
 mod commands;
 mod html_parser;
 mod image;
+mod fastboot;
+mod console;
+mod reboot;
+mod batch;
+mod history;
+mod error;
+mod board;
+mod mirror;
+mod http_cache;
+mod listing;
+mod space;
+mod zstd_frame;
+mod pipeline;
+mod compression;
+mod import;
+mod inspect;
+mod ext4;
+mod firstboot;
+mod bootconf;
+mod ubootenv;
+mod sparse;
+mod tunables;
+mod settings;
+
+use tauri::Manager;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_opener::init())
+        .manage(console::ConsoleTranscript::default())
+        .manage(batch::BatchManager::default())
+        .manage(mirror::MirrorRegistry::default())
+        .setup(|app| {
+            let data_dir = app.path().app_data_dir()?;
+            app.manage(history::HistoryStore::new(data_dir.join("history.jsonl")));
+            let config_dir = app.path().app_config_dir()?;
+            app.manage(settings::SettingsStore::load(config_dir.join("settings.json")));
+            app.manage(import::LocalImages::load(data_dir.join("local_images.json")));
+            app.manage(board::RuleStore::new(data_dir.join("rules")));
+            app.manage(http_cache::HttpCache::new(app.path().app_cache_dir()?.join("catalog")));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             commands::connect_to_device,
             commands::reboot_device,
             commands::flash_to_partition,
+            commands::inspect_image,
+            commands::customize_root_image,
+            commands::read_boot_config,
+            commands::write_boot_config,
+            commands::flash_uboot_env,
+            commands::read_uboot_env,
+            commands::sparse_chunk_map,
+            commands::unsparse_image,
+            commands::resparse_image,
+            commands::split_sparse_image,
+            commands::merge_sparse_images,
+            commands::sparse_logical_hash,
             commands::list_usb_devices,
             commands::fetch_lpi4a_image_versions,
-            commands::download_image_variant
+            commands::list_boards,
+            commands::fetch_image_catalog,
+            commands::list_mirrors,
+            commands::set_mirrors,
+            commands::probe_mirrors,
+            commands::get_settings,
+            commands::update_settings,
+            commands::get_tunables,
+            commands::set_tunables,
+            commands::list_image_cache,
+            commands::remove_cached_image,
+            commands::import_local_image,
+            commands::list_local_images,
+            commands::remove_local_image,
+            commands::download_image_variant,
+            commands::fastboot_command,
+            commands::get_console_transcript,
+            commands::clear_console_transcript,
+            commands::export_console_transcript,
+            commands::start_batch,
+            commands::batch_status,
+            commands::retry_failed_batch,
+            commands::stop_batch,
+            commands::list_history,
+            commands::export_history
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use scraper::{ElementRef, Html, Node, Selector};
+
+/// Size and modification time of one entry of a directory listing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub size: Option<u64>,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// The web servers whose autoindex pages we know how to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListingFormat {
+    /// `<pre>` listing with date and size after each link. Apache's fancy index
+    /// without `HTMLTable` looks the same.
+    Nginx,
+    /// Apache fancy index rendered as a table.
+    Apache,
+    /// Caddy's `file_server browse` template.
+    Caddy,
+    /// lighttpd `mod_dirlisting`.
+    Lighttpd,
+}
+
+fn selector(css: &str) -> Selector {
+    Selector::parse(css).unwrap()
+}
+
+impl ListingFormat {
+    pub fn detect(document: &Html) -> Option<Self> {
+        let has = |css: &str| document.select(&selector(css)).next().is_some();
+        if has("td[data-order], time[datetime]") {
+            Some(ListingFormat::Caddy)
+        } else if has("td.n a") {
+            Some(ListingFormat::Lighttpd)
+        } else if has("table td a") {
+            Some(ListingFormat::Apache)
+        } else if has("pre a") {
+            Some(ListingFormat::Nginx)
+        } else {
+            None
+        }
+    }
+
+    /// Metadata of each entry of the listing, keyed by the entry's href.
+    pub fn parse(self, document: &Html) -> HashMap<String, EntryMetadata> {
+        match self {
+            ListingFormat::Nginx => parse_pre(document),
+            ListingFormat::Apache => parse_table(document, "tr", "td"),
+            ListingFormat::Lighttpd => parse_table(document, "tr", "td.m, td.s"),
+            ListingFormat::Caddy => parse_caddy(document),
+        }
+    }
+}
+
+/// Parse a size as printed by autoindex pages: exact bytes ("123456"), or a
+/// human readable size with binary units ("118M", "4.0K", "1.2 GiB").
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let unit = unit.trim().trim_end_matches('B').trim_end_matches('i');
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * multiplier as f64).round() as u64)
+}
+
+/// Parse a timestamp in one of the formats autoindex pages use. Servers print local
+/// time without a zone; mirrors run on UTC, so that is what we assume.
+pub fn parse_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    const FORMATS: &[&str] = &[
+        "%d-%b-%Y %H:%M",    // nginx, classic Apache
+        "%Y-%m-%d %H:%M",    // Apache 2.4
+        "%Y-%b-%d %H:%M:%S", // lighttpd
+        "%Y-%m-%d %H:%M:%S",
+    ];
+    let text = text.trim();
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(text) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+    FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .map(|naive| naive.and_utc())
+}
+
+/// Read "date time size" from the text following each link of a `<pre>` listing.
+fn parse_pre(document: &Html) -> HashMap<String, EntryMetadata> {
+    let mut entries = HashMap::new();
+    for a in document.select(&selector("pre a")) {
+        let Some(href) = a.value().attr("href") else {
+            continue;
+        };
+        let Some(Node::Text(text)) = a.next_sibling().map(|n| n.value()) else {
+            continue;
+        };
+        let line = text.lines().next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 {
+            continue;
+        }
+        entries.insert(
+            href.to_string(),
+            EntryMetadata {
+                modified: parse_timestamp(&format!("{} {}", fields[0], fields[1])),
+                size: parse_size(fields[2]),
+            },
+        );
+    }
+    entries
+}
+
+fn cell_text(cell: ElementRef) -> String {
+    cell.text().collect::<String>().replace('\u{a0}', " ")
+}
+
+/// Read each table row whose link is followed by cells holding a date and a size.
+fn parse_table(document: &Html, row_css: &str, cell_css: &str) -> HashMap<String, EntryMetadata> {
+    let link = selector("a");
+    let cells = selector(cell_css);
+    let mut entries = HashMap::new();
+    for row in document.select(&selector(row_css)) {
+        let Some(href) = row.select(&link).find_map(|a| a.value().attr("href")) else {
+            continue;
+        };
+        let mut metadata = EntryMetadata::default();
+        for cell in row.select(&cells) {
+            let text = cell_text(cell);
+            if metadata.modified.is_none() {
+                metadata.modified = parse_timestamp(&text);
+                if metadata.modified.is_some() {
+                    continue;
+                }
+            }
+            // Only look for the size after the date, so names like "1.0" are not taken for one
+            if metadata.modified.is_some() && metadata.size.is_none() {
+                metadata.size = parse_size(&text);
+            }
+        }
+        if metadata != EntryMetadata::default() {
+            entries.insert(href.to_string(), metadata);
+        }
+    }
+    entries
+}
+
+/// Caddy puts the exact size into `data-order` and an RFC 3339 time into `<time>`.
+fn parse_caddy(document: &Html) -> HashMap<String, EntryMetadata> {
+    let link = selector("a");
+    let size = selector("td[data-order]");
+    let time = selector("time[datetime]");
+    let mut entries = HashMap::new();
+    for row in document.select(&selector("tr")) {
+        let Some(href) = row.select(&link).find_map(|a| a.value().attr("href")) else {
+            continue;
+        };
+        let metadata = EntryMetadata {
+            // Directories have a size of -1
+            size: row
+                .select(&size)
+                .find_map(|td| td.value().attr("data-order"))
+                .and_then(|order| order.parse().ok()),
+            modified: row
+                .select(&time)
+                .find_map(|t| t.value().attr("datetime"))
+                .and_then(parse_timestamp),
+        };
+        if metadata != EntryMetadata::default() {
+            entries.insert(href.to_string(), metadata);
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn parse(html: &str) -> (Option<ListingFormat>, HashMap<String, EntryMetadata>) {
+        let document = Html::parse_document(html);
+        let format = ListingFormat::detect(&document);
+        (format, format.map(|f| f.parse(&document)).unwrap_or_default())
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> Option<DateTime<Utc>> {
+        Some(Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap())
+    }
+
+    const ROOT: &str = "root-lpi4a-20250323_154524.ext4.zst";
+    const UBOOT: &str = "u-boot-with-spl-lpi4a-16g.bin";
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("123456"), Some(123456));
+        assert_eq!(parse_size("4.0K"), Some(4096));
+        assert_eq!(parse_size("118M"), Some(118 << 20));
+        assert_eq!(parse_size("1.5 GiB"), Some(3 << 29));
+        assert_eq!(parse_size("-"), None);
+    }
+
+    #[test]
+    fn test_nginx() {
+        let (format, entries) = parse(include_str!("../fixtures/listings/nginx.html"));
+        assert_eq!(format, Some(ListingFormat::Nginx));
+        assert_eq!(entries[ROOT].size, Some(1_273_621_248));
+        assert_eq!(entries[ROOT].modified, utc(2025, 3, 23, 8, 21, 0));
+        assert_eq!(entries[UBOOT].size, Some(1_003_880));
+        assert!(!entries.contains_key("../"));
+    }
+
+    #[test]
+    fn test_apache() {
+        let (format, entries) = parse(include_str!("../fixtures/listings/apache.html"));
+        assert_eq!(format, Some(ListingFormat::Apache));
+        assert_eq!(entries[ROOT].size, Some(1215 << 20));
+        assert_eq!(entries[ROOT].modified, utc(2025, 3, 23, 8, 21, 0));
+        assert_eq!(entries[UBOOT].size, Some(980 << 10));
+        assert!(!entries.contains_key("?C=N;O=D"));
+    }
+
+    #[test]
+    fn test_caddy() {
+        let (format, entries) = parse(include_str!("../fixtures/listings/caddy.html"));
+        assert_eq!(format, Some(ListingFormat::Caddy));
+        assert_eq!(entries[&format!("./{ROOT}")].size, Some(1_273_621_248));
+        assert_eq!(entries[&format!("./{ROOT}")].modified, utc(2025, 3, 23, 8, 21, 4));
+        assert_eq!(entries[&format!("./{UBOOT}")].size, Some(1_003_880));
+    }
+
+    #[test]
+    fn test_lighttpd() {
+        let (format, entries) = parse(include_str!("../fixtures/listings/lighttpd.html"));
+        assert_eq!(format, Some(ListingFormat::Lighttpd));
+        assert_eq!(entries[ROOT].size, Some((1214.6 * (1 << 20) as f64).round() as u64));
+        assert_eq!(entries[ROOT].modified, utc(2025, 3, 23, 8, 21, 4));
+        assert_eq!(entries[UBOOT].size, Some((980.4 * 1024.0f64).round() as u64));
+        assert!(entries.get("../").is_none_or(|e| e.size.is_none()));
+    }
+}
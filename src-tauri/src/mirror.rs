@@ -0,0 +1,428 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Mirrors shipped by default, as (name, url of the RevyOS image tree).
+pub const DEFAULT_MIRRORS: &[(&str, &str)] = &[
+    ("ISCAS", "https://mirror.iscas.ac.cn/revyos/extra/images/"),
+    ("ISRC fast mirror", "https://fast-mirror.isrc.ac.cn/revyos/extra/images/"),
+];
+
+/// Give up on a mirror that has not answered a probe within this time.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Read at most this much of the sample file to estimate throughput.
+const PROBE_BYTES: u64 = 1024 * 1024;
+/// Mirrors are ranked by how long they would take to deliver this much data.
+const RANK_SIZE: f64 = 64.0 * 1024.0 * 1024.0;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mirror {
+    pub name: String,
+    /// Root of the RevyOS image tree on this mirror, ending in `/`.
+    pub url: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+pub fn default_mirrors() -> Vec<Mirror> {
+    DEFAULT_MIRRORS
+        .iter()
+        .map(|(name, url)| Mirror {
+            name: name.to_string(),
+            url: url.to_string(),
+            enabled: true,
+        })
+        .collect()
+}
+
+/// The outcome of probing one mirror.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirrorProbe {
+    pub url: String,
+    /// Time until the response headers of the index page arrived.
+    pub latency_ms: Option<u64>,
+    /// Bytes per second while reading the sample file; `None` when there was none.
+    pub throughput: Option<u64>,
+    pub error: Option<Error>,
+}
+
+impl MirrorProbe {
+    /// Estimated seconds to fetch [`RANK_SIZE`] bytes, or just the round trip without a
+    /// throughput; `None` for mirrors that failed.
+    fn score(&self) -> Option<f64> {
+        if self.error.is_some() {
+            return None;
+        }
+        let latency = self.latency_ms? as f64 / 1000.0;
+        Some(match self.throughput {
+            Some(throughput) if throughput > 0 => latency + RANK_SIZE / throughput as f64,
+            _ => latency,
+        })
+    }
+}
+
+async fn measure_latency(client: &reqwest::Client, url: &str) -> Result<Duration> {
+    let start = Instant::now();
+    client.get(url).send().await?.error_for_status()?;
+    Ok(start.elapsed())
+}
+
+/// Read up to [`PROBE_BYTES`] of `url` and return the bytes per second while the body
+/// arrived. A mirror too slow to deliver all of them within [`PROBE_TIMEOUT`] is rated
+/// by what it managed.
+async fn measure_throughput(client: &reqwest::Client, url: &str) -> Result<Option<u64>> {
+    let deadline = tokio::time::Instant::now() + PROBE_TIMEOUT;
+    let request = client
+        .get(url)
+        .header(reqwest::header::RANGE, format!("bytes=0-{}", PROBE_BYTES - 1))
+        .send();
+    let response = tokio::time::timeout_at(deadline, request)
+        .await
+        .map_err(|_| Error::timeout(format!("No answer from {url} within {PROBE_TIMEOUT:?}")))??
+        .error_for_status()?;
+    let start = Instant::now();
+    let mut received = 0u64;
+    let mut stream = response.bytes_stream();
+    while received < PROBE_BYTES {
+        match tokio::time::timeout_at(deadline, stream.next()).await {
+            Ok(Some(chunk)) => received += chunk?.len() as u64,
+            Ok(None) | Err(_) => break,
+        }
+    }
+    // A local mirror may deliver it all within the timer's resolution
+    let transfer = start.elapsed().as_secs_f64().max(0.001);
+    Ok((received > 0).then(|| (received as f64 / transfer) as u64))
+}
+
+/// Measure the latency of a mirror by requesting its index page, and its throughput by
+/// reading the start of `sample`, a path relative to the mirror. A mirror lacking the
+/// sample file counts as failed.
+pub async fn probe(client: &reqwest::Client, url: &str, sample: Option<&str>) -> MirrorProbe {
+    let result = async {
+        let latency = tokio::time::timeout(PROBE_TIMEOUT, measure_latency(client, url))
+            .await
+            .unwrap_or_else(|_| Err(Error::timeout(format!("No answer from {url} within {PROBE_TIMEOUT:?}"))))?;
+        let throughput = match sample {
+            Some(sample) => measure_throughput(client, &format!("{url}{sample}")).await?,
+            None => None,
+        };
+        Ok::<_, Error>((latency, throughput))
+    }
+    .await;
+    match result {
+        Ok((latency, throughput)) => MirrorProbe {
+            url: url.to_string(),
+            latency_ms: Some(latency.as_millis() as u64),
+            throughput,
+            error: None,
+        },
+        Err(error) => MirrorProbe {
+            url: url.to_string(),
+            latency_ms: None,
+            throughput: None,
+            error: Some(error),
+        },
+    }
+}
+
+/// Order `probes` fastest first, with failed mirrors last.
+fn sort_probes(probes: &mut [MirrorProbe]) {
+    probes.sort_by(|a, b| match (a.score(), b.score()) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+}
+
+/// The same file on every mirror, starting with the best one.
+///
+/// `web_path` has to live below one of `mirrors`; otherwise it is the only candidate.
+pub fn candidate_urls(web_path: &str, mirrors: &[String]) -> Vec<String> {
+    match mirrors.iter().find_map(|mirror| web_path.strip_prefix(mirror.as_str())) {
+        Some(relative) => mirrors.iter().map(|mirror| format!("{mirror}{relative}")).collect(),
+        None => vec![web_path.to_string()],
+    }
+}
+
+/// Run `f` against each mirror in turn until one succeeds, returning the last error
+/// if none does.
+pub async fn with_failover<T, F, Fut>(mirrors: &[String], mut f: F) -> Result<T>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut last_error = Error::invalid_input("No mirror is enabled");
+    for mirror in mirrors {
+        match f(mirror.clone()).await {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                eprintln!("Mirror {mirror} failed: {e}");
+                last_error = e.with_context(format!("Mirror {mirror}"));
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Check that every mirror is an HTTP(S) URL and end each with `/`.
+pub fn normalize(mirrors: &mut [Mirror]) -> Result<()> {
+    for mirror in mirrors.iter_mut() {
+        if !mirror.url.starts_with("http://") && !mirror.url.starts_with("https://") {
+            return Err(Error::invalid_input(format!("Not an HTTP(S) URL: {}", mirror.url)));
+        }
+        if !mirror.url.ends_with('/') {
+            mirror.url.push('/');
+        }
+    }
+    if !mirrors.iter().any(|m| m.enabled) {
+        return Err(Error::invalid_input("At least one mirror has to be enabled"));
+    }
+    Ok(())
+}
+
+/// The results of the last probe of the configured mirrors.
+#[derive(Default)]
+pub struct MirrorRegistry {
+    probes: Mutex<Vec<MirrorProbe>>,
+}
+
+impl MirrorRegistry {
+    /// Forget the last probe, after the mirror list changed.
+    pub fn clear_probes(&self) {
+        self.probes.lock().unwrap().clear();
+    }
+
+    /// Probe all enabled `mirrors` concurrently, reading `sample` from each to measure
+    /// throughput; results are returned best first.
+    pub async fn probe(&self, mirrors: &[Mirror], sample: Option<&str>) -> Vec<MirrorProbe> {
+        let client = crate::settings::http_client();
+        let urls: Vec<&str> = mirrors.iter().filter(|m| m.enabled).map(|m| m.url.as_str()).collect();
+        let mut probes =
+            futures::future::join_all(urls.iter().map(|url| probe(&client, url, sample))).await;
+        sort_probes(&mut probes);
+        *self.probes.lock().unwrap() = probes.clone();
+        probes
+    }
+
+    /// URLs of the enabled `mirrors`, best first: mirrors that passed the last probe in
+    /// score order, then unprobed ones in configured order, then those that failed.
+    pub fn ranked(&self, mirrors: &[Mirror]) -> Vec<String> {
+        let probes = self.probes.lock().unwrap();
+        let mut urls: Vec<(usize, String)> = mirrors
+            .iter()
+            .filter(|m| m.enabled)
+            .map(|m| {
+                let rank = match probes.iter().position(|p| p.url == m.url) {
+                    Some(i) if probes[i].error.is_none() => i,
+                    Some(_) => usize::MAX,
+                    None => probes.len(),
+                };
+                (rank, m.url.clone())
+            })
+            .collect();
+        // Stable, so ties keep the configured order
+        urls.sort_by_key(|(rank, _)| *rank);
+        urls.into_iter().map(|(_, url)| url).collect()
+    }
+}
+
+/// Minimal HTTP servers standing in for mirrors in tests of downloads. mockito answers
+/// one request at a time under a global lock, so it cannot play a mirror that stalls
+/// halfway through a body while the download fails over to another one.
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[derive(Clone)]
+    pub(crate) enum Behavior {
+        /// Answer every request with this body.
+        Serve(Vec<u8>),
+        /// Answer with an error status.
+        Status(u16),
+        /// Promise the body, send half of it, then go quiet.
+        Stall(Vec<u8>),
+        /// Serve the body, honouring `Range: bytes=N-` and `bytes=N-M` with a 206.
+        Ranged(Vec<u8>),
+    }
+
+    pub(crate) struct MockMirror {
+        /// Base URL, ending in `/`.
+        pub url: String,
+        pub hits: Arc<AtomicUsize>,
+    }
+
+    async fn respond(socket: &mut tokio::net::TcpStream, behavior: Behavior) -> std::io::Result<()> {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            request.extend_from_slice(&buffer[..n]);
+        }
+        let request = String::from_utf8_lossy(&request).to_lowercase();
+        let range = request
+            .lines()
+            .find_map(|line| line.strip_prefix("range: bytes="))
+            .and_then(|range| {
+                let (start, end) = range.trim().split_once('-')?;
+                Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()))
+            });
+        let mut content_range = String::new();
+        let (status, body, stall) = match behavior {
+            Behavior::Serve(body) => (200, body, false),
+            Behavior::Status(status) => (status, Vec::new(), false),
+            Behavior::Stall(body) => (200, body, true),
+            Behavior::Ranged(body) => match range {
+                Some((start, end)) if start < body.len() => {
+                    let end = end.map_or(body.len() - 1, |end| end.min(body.len() - 1));
+                    content_range = format!("Content-Range: bytes {start}-{end}/{}\r\n", body.len());
+                    (206, body[start..=end].to_vec(), false)
+                }
+                Some(_) => {
+                    content_range = format!("Content-Range: bytes */{}\r\n", body.len());
+                    (416, Vec::new(), false)
+                }
+                None => (200, body, false),
+            },
+        };
+        let header = format!(
+            "HTTP/1.1 {status} Mock\r\nContent-Length: {}\r\n{content_range}Connection: close\r\n\r\n",
+            body.len()
+        );
+        socket.write_all(header.as_bytes()).await?;
+        if stall {
+            socket.write_all(&body[..body.len() / 2]).await?;
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        }
+        socket.write_all(&body).await?;
+        socket.shutdown().await
+    }
+
+    pub(crate) async fn serve(behavior: Behavior) -> MockMirror {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let behavior = behavior.clone();
+                tokio::spawn(async move {
+                    let _ = respond(&mut socket, behavior).await;
+                });
+            }
+        });
+        MockMirror { url, hits }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(url: &str) -> Mirror {
+        Mirror {
+            name: url.to_string(),
+            url: url.to_string(),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_candidate_urls() {
+        let mirrors = vec!["http://a/images/".to_string(), "http://b/revyos/".to_string()];
+        assert_eq!(
+            candidate_urls("http://b/revyos/lpi4a/20250323/boot.ext4", &mirrors),
+            vec![
+                "http://a/images/lpi4a/20250323/boot.ext4",
+                "http://b/revyos/lpi4a/20250323/boot.ext4"
+            ]
+        );
+        assert_eq!(candidate_urls("http://c/boot.ext4", &mirrors), vec!["http://c/boot.ext4"]);
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert!(normalize(&mut [mirror("ftp://example.org/")]).is_err());
+        assert!(normalize(&mut []).is_err());
+        let mut mirrors = [mirror("http://127.0.0.1:8080/revyos")];
+        normalize(&mut mirrors).unwrap();
+        assert_eq!(mirrors[0].url, "http://127.0.0.1:8080/revyos/");
+    }
+
+    fn probed(url: &str, latency_ms: Option<u64>, throughput: Option<u64>) -> MirrorProbe {
+        let error = latency_ms.is_none().then(|| Error::timeout("No answer"));
+        MirrorProbe { url: url.to_string(), latency_ms, throughput, error }
+    }
+
+    #[test]
+    fn test_ranking() {
+        let mb = 1024 * 1024;
+        let mut probes = vec![
+            probed("http://c/", None, None),
+            probed("http://b/", Some(20), Some(mb)),
+            probed("http://a/", Some(300), Some(16 * mb)),
+        ];
+        sort_probes(&mut probes);
+        let order: Vec<_> = probes.iter().map(|p| p.url.as_str()).collect();
+        // A slower round trip is made up for by a faster transfer
+        assert_eq!(order, ["http://a/", "http://b/", "http://c/"]);
+        let mut unsampled = vec![probed("http://b/", Some(300), None), probed("http://a/", Some(20), None)];
+        sort_probes(&mut unsampled);
+        assert_eq!(unsampled[0].url, "http://a/");
+
+        let registry = MirrorRegistry::default();
+        let mirrors = [mirror("http://c/"), mirror("http://d/"), mirror("http://b/"), mirror("http://a/")];
+        // Before probing, the configured order is used
+        assert_eq!(registry.ranked(&mirrors), ["http://c/", "http://d/", "http://b/", "http://a/"]);
+        // Then probed mirrors by latency, unprobed ones, and failed ones last
+        *registry.probes.lock().unwrap() = probes;
+        assert_eq!(registry.ranked(&mirrors), ["http://a/", "http://b/", "http://d/", "http://c/"]);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        use mockito::{mock, server_url};
+
+        let listing = "<a href=\"lpi4a/\">lpi4a/</a>";
+        let _broken = mock("GET", "/probe-broken/").with_status(500).create();
+        let _working = mock("GET", "/probe-working/").with_body(listing).create();
+        let broken = format!("{}/probe-broken/", server_url());
+        let working = format!("{}/probe-working/", server_url());
+        let registry = MirrorRegistry::default();
+        let probes = registry.probe(&[mirror(&broken), mirror(&working)], None).await;
+        assert_eq!(probes[0].url, working);
+        assert!(probes[0].error.is_none() && probes[0].latency_ms.is_some());
+        assert_eq!(probes[0].throughput, None);
+        assert_eq!(probes[1].error.as_ref().unwrap().kind, crate::error::ErrorKind::Http);
+
+        // Only the start of the sample is asked for
+        let sample = mock("GET", "/probe-working/lpi4a/root.ext4")
+            .match_header("range", format!("bytes=0-{}", PROBE_BYTES - 1).as_str())
+            .with_status(206)
+            .with_body(vec![7u8; PROBE_BYTES as usize])
+            .create();
+        let probes = registry.probe(&[mirror(&working)], Some("lpi4a/root.ext4")).await;
+        sample.assert();
+        assert!(probes[0].error.is_none(), "{:?}", probes[0].error);
+        assert!(probes[0].throughput.is_some_and(|t| t > 0));
+        // A mirror without the sample counts as failed
+        let probes = registry.probe(&[mirror(&working)], Some("lpi4a/missing.ext4")).await;
+        assert!(probes[0].error.is_some());
+    }
+}
@@ -0,0 +1,416 @@
+//! Streaming download: bytes from the mirror are appended to a `.part` file and, for
+//! compressed images, decoded into the final file while the download is still running.
+//!
+//! The `.part` file only exists to resume an interrupted download. Resuming replays it
+//! through a fresh decoder and then asks the mirror for the rest with a Range request.
+
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::StreamExt;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::bytes::Bytes;
+use tokio_util::io::StreamReader;
+
+use crate::board::HashAlgorithm;
+use crate::compression::Compression;
+use crate::error::{Error, ErrorKind, Result};
+use crate::image::ProgressType;
+
+/// How long a download may go without receiving any data before the mirror is abandoned.
+#[cfg(not(test))]
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(20);
+#[cfg(test)]
+pub const STALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Compressed chunks that may wait for the decoder before the download is held back.
+const PIPELINE_DEPTH: usize = 16;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Digests of both ends of the pipeline, as lowercase hex.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamDigests {
+    /// The file as served by the mirror.
+    pub compressed: Option<String>,
+    /// The file as written to disk; the same as `compressed` when nothing was decoded.
+    pub decompressed: Option<String>,
+}
+
+/// Counts the bytes read through it, so extraction can report how far into the
+/// compressed stream it is.
+pub struct CountingReader<'a, R> {
+    pub inner: R,
+    pub count: &'a AtomicU64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<'_, R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.count.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+/// One file to fetch from one mirror.
+pub struct Transfer<'a> {
+    pub url: &'a str,
+    /// Where the compressed bytes are kept until the download completes.
+    pub part: &'a Path,
+    pub output: &'a Path,
+    /// Name reported to the progress callback.
+    pub name: &'a str,
+    /// Decompress the stream instead of storing it as is. The format is detected from
+    /// the first bytes.
+    pub decode: bool,
+    /// Compute [`StreamDigests`] with this algorithm on the way.
+    pub hash: Option<HashAlgorithm>,
+}
+
+/// Parse the first byte position out of `Content-Range: bytes start-end/total`.
+fn content_range_start(value: &str) -> Option<u64> {
+    value.strip_prefix("bytes ")?.split('-').next()?.parse().ok()
+}
+
+/// Parse the total out of `Content-Range: bytes */total`, sent with a 416.
+fn content_range_total(value: &str) -> Option<u64> {
+    value.rsplit('/').next()?.parse().ok()
+}
+
+impl Transfer<'_> {
+    fn stalled(&self) -> Error {
+        Error::timeout(format!("No data from {} for {}s", self.url, STALL_TIMEOUT.as_secs_f32()))
+    }
+
+    /// Ask the mirror for whatever the `.part` file is missing. Returns how many bytes
+    /// of the `.part` file to keep, and the response carrying the rest, if anything is left.
+    async fn request(&self, client: &reqwest::Client) -> Result<(u64, Option<reqwest::Response>)> {
+        let resume_from = tokio::fs::metadata(self.part).await.map(|m| m.len()).unwrap_or(0);
+        let mut request = client.get(self.url);
+        if resume_from > 0 {
+            request = request.header(RANGE, format!("bytes={resume_from}-"));
+        }
+        let response = tokio::time::timeout(STALL_TIMEOUT, request.send())
+            .await
+            .map_err(|_| self.stalled())??;
+        let content_range = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT if resume_from > 0 => {
+                if content_range.as_deref().and_then(content_range_start) != Some(resume_from) {
+                    return Err(Error::new(
+                        ErrorKind::Http,
+                        format!("{} answered with an unexpected range {content_range:?}", self.url),
+                    ));
+                }
+                println!("Resuming {} at {resume_from} bytes", self.name);
+                Ok((resume_from, Some(response)))
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE if resume_from > 0 => {
+                if content_range.as_deref().and_then(content_range_total) == Some(resume_from) {
+                    return Ok((resume_from, None));
+                }
+                // The file changed or shrank on the mirror; the next attempt starts over
+                tokio::fs::remove_file(self.part).await?;
+                Err(Error::http(416, self.url))
+            }
+            _ => Ok((0, Some(response.error_for_status()?))),
+        }
+    }
+
+    /// Fetch the file, resuming from the `.part` file if there is one.
+    pub async fn run<F>(&self, client: &reqwest::Client, progress_callback: &mut F) -> Result<StreamDigests>
+    where
+        F: FnMut(&str, u64, u64, ProgressType),
+    {
+        let (resume_from, response) = self.request(client).await?;
+        let total = match &response {
+            Some(response) => {
+                resume_from
+                    + response.content_length().ok_or_else(|| {
+                        Error::new(ErrorKind::Http, format!("No content length for {}", self.url))
+                    })?
+            }
+            None => resume_from,
+        };
+        let mut part = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(resume_from > 0)
+            .truncate(resume_from == 0)
+            .write(true)
+            .open(self.part)
+            .await?;
+
+        // Both halves of the pipeline report progress and share what is known about the
+        // size, from within the same task; the locks are never held across an await
+        let progress_callback = Mutex::new(progress_callback);
+        let report = |done, total, progress_type| {
+            (progress_callback.lock().unwrap())(self.name, done, total, progress_type)
+        };
+        // Decompressed size as far as we know it: the first zstd frame's declared size
+        // while downloading, the sum over all frames once the download is complete
+        let extract_total = Mutex::new(None);
+        let consumed = AtomicU64::new(0);
+        let (sender, receiver) = mpsc::channel::<std::io::Result<Bytes>>(PIPELINE_DEPTH);
+
+        let fetch = async {
+            let mut hasher = self.hash.map(HashAlgorithm::hasher);
+            let replay = resume_from > 0 && (self.decode || self.hash.is_some());
+            let mut downloaded = if replay { 0 } else { resume_from };
+            let mut format = Compression::None;
+            let mut forward = |chunk: Bytes| {
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&chunk);
+                }
+                if downloaded == 0 && self.decode {
+                    format = Compression::detect(&chunk);
+                    if let Ok(Some(size)) = crate::zstd_frame::first_frame_content_size(&chunk) {
+                        *extract_total.lock().unwrap() = Some(size);
+                    }
+                }
+                downloaded += chunk.len() as u64;
+                report(downloaded, total, ProgressType::Download);
+                chunk
+            };
+
+            if replay {
+                let mut replay = tokio::fs::File::open(self.part).await?.take(resume_from);
+                let mut buffer = vec![0u8; CHUNK_SIZE];
+                loop {
+                    let n = replay.read(&mut buffer).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    let chunk = forward(Bytes::copy_from_slice(&buffer[..n]));
+                    // A closed channel means the decoder failed; its error is the one to report
+                    if self.decode && sender.send(Ok(chunk)).await.is_err() {
+                        return Ok(None);
+                    }
+                }
+            }
+
+            if let Some(response) = response {
+                let mut stream = response.bytes_stream();
+                while let Some(chunk) = tokio::time::timeout(STALL_TIMEOUT, stream.next())
+                    .await
+                    .map_err(|_| self.stalled())?
+                {
+                    let chunk = chunk?;
+                    part.write_all(&chunk).await?;
+                    let chunk = forward(chunk);
+                    if self.decode && sender.send(Ok(chunk)).await.is_err() {
+                        return Ok(None);
+                    }
+                }
+            }
+            part.sync_all().await?;
+            drop(sender);
+
+            if format == Compression::Zstd {
+                let part_path = self.part.to_path_buf();
+                let size = tokio::task::spawn_blocking(move || {
+                    crate::zstd_frame::decompressed_size(&mut std::fs::File::open(part_path)?)
+                })
+                .await
+                .map_err(|e| Error::internal(e.to_string()))?;
+                match size {
+                    Ok(Some(size)) => *extract_total.lock().unwrap() = Some(size),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Cannot read the content size of {}: {e}", self.name),
+                }
+            }
+            Ok::<_, Error>(hasher.map(|h| hex::encode(h.finalize())))
+        };
+
+        let decode = async {
+            if !self.decode {
+                return Ok(None);
+            }
+            let stream = Box::pin(futures::stream::unfold(receiver, |mut receiver| async move {
+                receiver.recv().await.map(|chunk| (chunk, receiver))
+            }));
+            let mut reader = BufReader::new(CountingReader { inner: StreamReader::new(stream), count: &consumed });
+            let format = Compression::detect(reader.fill_buf().await?);
+            if format == Compression::None && Compression::from_name(self.name) != Compression::None {
+                eprintln!("{} is not compressed after all, storing it as is", self.name);
+            }
+            let mut decoder = format.decoder(reader);
+            let mut target = tokio::fs::File::create(self.output).await?;
+            let mut hasher = self.hash.map(HashAlgorithm::hasher);
+            let mut extracted = 0u64;
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            loop {
+                let n = decoder
+                    .read(&mut buffer)
+                    .await
+                    .map_err(|e| Error::invalid_image(format!("Failed to decompress {}: {e}", self.name)))?;
+                if n == 0 {
+                    break;
+                }
+                target.write_all(&buffer[..n]).await?;
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&buffer[..n]);
+                }
+                extracted += n as u64;
+                let size = *extract_total.lock().unwrap();
+                match size {
+                    Some(size) => report(extracted, size.max(extracted), ProgressType::Extract),
+                    None => report(consumed.load(Ordering::Relaxed), total, ProgressType::Extract),
+                }
+            }
+            target.sync_all().await?;
+            report(extracted, extracted, ProgressType::Extract);
+            Ok::<_, Error>(hasher.map(|h| hex::encode(h.finalize())))
+        };
+
+        let (fetched, decoded) = tokio::join!(fetch, decode);
+        let compressed = fetched?;
+        let decompressed = decoded?;
+        if self.decode {
+            tokio::fs::remove_file(self.part).await?;
+            Ok(StreamDigests { compressed, decompressed })
+        } else {
+            tokio::fs::rename(self.part, self.output).await?;
+            Ok(StreamDigests { decompressed: compressed.clone(), compressed })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mirror::mock::{serve, Behavior};
+    use crate::zstd_frame::tests::raw_frame;
+    use sha2::{Digest, Sha256, Sha512};
+
+    fn sha256(data: &[u8]) -> Option<String> {
+        Some(hex::encode(Sha256::digest(data)))
+    }
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(content_range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(content_range_total("bytes */200"), Some(200));
+        assert_eq!(content_range_start("items 1-2/3"), None);
+    }
+
+    #[tokio::test]
+    async fn test_resume_replays_part_through_decoder() {
+        let plain: Vec<u8> = (0..60_000u32).map(|i| (i % 251) as u8).collect();
+        let mut compressed = raw_frame(&plain[..20_000]);
+        compressed.extend(raw_frame(&plain[20_000..]));
+        let stalled = serve(Behavior::Stall(compressed.clone())).await;
+        let ranged = serve(Behavior::Ranged(compressed.clone())).await;
+
+        let dir = std::env::temp_dir().join("revyos-imager-pipeline");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (part, output) = (dir.join("root.ext4.zst.part"), dir.join("root.ext4"));
+        let client = reqwest::Client::new();
+        let transfer = |url| Transfer {
+            url,
+            part: &part,
+            output: &output,
+            name: "root.ext4.zst",
+            decode: true,
+            hash: Some(HashAlgorithm::Sha256),
+        };
+
+        let url = format!("{}root.ext4.zst", stalled.url);
+        let error = transfer(&url).run(&client, &mut |_, _, _, _| {}).await.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Timeout);
+        assert_eq!(std::fs::metadata(&part).unwrap().len(), compressed.len() as u64 / 2);
+
+        let url = format!("{}root.ext4.zst", ranged.url);
+        let mut last = Vec::new();
+        let digests = transfer(&url)
+            .run(&client, &mut |_, done, total, progress_type| {
+                assert!(done <= total, "{done} > {total}");
+                last.push((progress_type, done, total));
+            })
+            .await
+            .unwrap();
+        assert_eq!(ranged.hits.load(Ordering::SeqCst), 1);
+        assert_eq!(std::fs::read(&output).unwrap(), plain);
+        assert!(!part.exists());
+        assert_eq!(digests.compressed, sha256(&compressed));
+        assert_eq!(digests.decompressed, sha256(&plain));
+        let total = compressed.len() as u64;
+        assert!(last.contains(&(ProgressType::Download, total, total)));
+        assert_eq!(last.last(), Some(&(ProgressType::Extract, 60_000, 60_000)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_plain_file_and_ignored_range() {
+        let data = vec![9u8; 10_000];
+        let mirror = serve(Behavior::Serve(data.clone())).await;
+        let dir = std::env::temp_dir().join("revyos-imager-pipeline-plain");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (part, output) = (dir.join("u-boot.bin.part"), dir.join("u-boot.bin"));
+        // A stale partial download, which the mirror's full answer replaces
+        std::fs::write(&part, [1u8; 300]).unwrap();
+        let url = format!("{}u-boot.bin", mirror.url);
+        let digests = Transfer {
+            url: &url,
+            part: &part,
+            output: &output,
+            name: "u-boot.bin",
+            decode: false,
+            hash: Some(HashAlgorithm::Sha512),
+        }
+        .run(&reqwest::Client::new(), &mut |_, _, _, _| {})
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert!(!part.exists());
+        assert_eq!(digests.compressed, Some(hex::encode(Sha512::digest(&data))));
+        assert_eq!(digests.decompressed, digests.compressed);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_decodes_other_formats() {
+        use crate::compression::tests::compress;
+
+        let plain = vec![5u8; 300_000];
+        let dir = std::env::temp_dir().join("revyos-imager-pipeline-formats");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (format, name) in [(Compression::Gzip, "sdcard.img.gz"), (Compression::Bzip2, "sdcard.img.bz2")] {
+            let mirror = serve(Behavior::Serve(compress(format, &plain).await)).await;
+            let url = format!("{}{name}", mirror.url);
+            let (part, output) = (dir.join(format!("{name}.part")), dir.join(Compression::output_name(name)));
+            let mut last = None;
+            Transfer { url: &url, part: &part, output: &output, name, decode: true, hash: None }
+                .run(&reqwest::Client::new(), &mut |_, done, total, progress_type| last = Some((progress_type, done, total)))
+                .await
+                .unwrap();
+            assert!(std::fs::read(&output).unwrap() == plain, "{format:?}");
+            assert_eq!(last, Some((ProgressType::Extract, 300_000, 300_000)));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pipeline_is_send() {
+        // Tauri runs async commands on a multi-threaded runtime
+        fn assert_send<T: Send>(_: T) {}
+        let client = reqwest::Client::new();
+        let path = Path::new("unused");
+        let transfer = Transfer { url: "", part: path, output: path, name: "", decode: true, hash: Some(HashAlgorithm::Sha256) };
+        assert_send(transfer.run(&client, &mut |_, _, _, _| {}));
+        let mut variant = crate::image::ImageVariant { name: String::new(), image_binarys: Vec::new() };
+        assert_send(variant.download_binaries(std::path::Path::new("."), &[], |_, _, _, _| {}));
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::fastboot::{FastBoot, ResponseKind, Transport};
+use crate::usb::{self, USBDevice, ADB_INTERFACE, FASTBOOT_INTERFACE};
+
+/// How long the old device gets to drop off the bus before we call the reboot a failure.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Where the device should go after a reboot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RebootTarget {
+    /// Boot the installed system.
+    System,
+    /// Restart into the bootloader's fastboot.
+    Bootloader,
+    /// Restart into userspace fastboot (fastbootd).
+    Fastbootd,
+    Recovery,
+    PowerOff,
+}
+
+impl RebootTarget {
+    pub fn command(self) -> &'static str {
+        match self {
+            RebootTarget::System => "reboot",
+            RebootTarget::Bootloader => "reboot-bootloader",
+            RebootTarget::Fastbootd => "reboot-fastboot",
+            RebootTarget::Recovery => "reboot-recovery",
+            RebootTarget::PowerOff => "oem poweroff",
+        }
+    }
+}
+
+/// Send the reboot command for `target`.
+///
+/// Some bootloaders reset the USB controller before their OKAY reaches the host, so a
+/// transport error while waiting for the answer counts as success. A FAIL, or failing
+/// to send the command at all, does not.
+pub async fn reboot<T: Transport>(fb: &mut FastBoot<T>, target: RebootTarget) -> Result<()> {
+    let cmd = target.command();
+    fb.send_command(cmd).await?;
+    match fb.read_responses(|_| {}).await {
+        Ok(responses) => match responses.last() {
+            Some(r) if r.kind == ResponseKind::Okay => Ok(()),
+            Some(r) if r.kind == ResponseKind::Fail => Err(Error::fastboot(format!("{cmd} failed: {}", r.message))),
+            other => Err(Error::fastboot(format!("Unexpected response to {cmd}: {other:?}"))),
+        },
+        Err(e) => {
+            println!("No response to {cmd}, assuming the device already reset: {e}");
+            Ok(())
+        }
+    }
+}
+
+/// Whether a fastboot device is running fastbootd rather than the bootloader.
+pub async fn is_userspace<T: Transport>(fb: &mut FastBoot<T>) -> bool {
+    // Bootloaders that predate fastbootd do not know the variable at all
+    fb.get_var("is-userspace").await.is_ok_and(|v| v == "yes")
+}
+
+/// Wait until `device` has rebooted into `target`.
+///
+/// Returns the re-enumerated device for targets that come back over USB, `None` for
+/// targets that leave the bus (system boot, power off). Boards that cannot be told apart
+/// from identical ones are refused, as any of them could be taken for `device`.
+pub async fn wait_for_target(
+    device: &USBDevice,
+    target: RebootTarget,
+    timeout: Duration,
+) -> Result<Option<USBDevice>> {
+    device.ensure_identifiable()?;
+    let same_address = |info: &nusb::DeviceInfo| {
+        info.vendor_id() == device.vendor_id
+            && info.product_id() == device.product_id
+            && info.device_address() == device.device_address
+    };
+    match target {
+        RebootTarget::System | RebootTarget::PowerOff => {
+            usb::wait_for_removal(timeout, |info| {
+                device.is_same_board(info) && usb::has_interface(info, FASTBOOT_INTERFACE)
+            })
+            .await?;
+            Ok(None)
+        }
+        RebootTarget::Recovery => {
+            usb::wait_for_removal(DISCONNECT_TIMEOUT, same_address).await?;
+            let info = usb::wait_for_device(timeout, |info| {
+                device.is_same_board(info) && usb::has_interface(info, ADB_INTERFACE)
+            })
+            .await?;
+            Ok(Some(info.into()))
+        }
+        RebootTarget::Bootloader | RebootTarget::Fastbootd => {
+            usb::wait_for_removal(DISCONNECT_TIMEOUT, same_address).await?;
+            let info = usb::wait_for_device(timeout, |info| {
+                device.is_same_board(info) && usb::has_interface(info, FASTBOOT_INTERFACE)
+            })
+            .await?;
+            let mut fb = FastBoot::from_info(&info)?;
+            let userspace = is_userspace(&mut fb).await;
+            if userspace != (target == RebootTarget::Fastbootd) {
+                let actual = if userspace { "fastbootd" } else { "bootloader" };
+                return Err(Error::fastboot(format!(
+                    "Device came back in {actual} instead of {target:?}"
+                )));
+            }
+            Ok(Some(info.into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastboot::sim::SimTransport;
+
+    #[tokio::test]
+    async fn test_reboot_sends_target_command() {
+        let transport = SimTransport::new().reply("reboot-fastboot", &["OKAY"]);
+        let commands = transport.commands.clone();
+        let mut fb = FastBoot::new(transport);
+        reboot(&mut fb, RebootTarget::Fastbootd).await.unwrap();
+        assert_eq!(*commands.lock().unwrap(), vec!["reboot-fastboot".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_reboot_errors() {
+        // `oem poweroff` is not known to the simulated device, so it answers FAIL
+        let mut fb = FastBoot::new(SimTransport::new());
+        assert!(reboot(&mut fb, RebootTarget::PowerOff).await.is_err());
+
+        // No reply at all is treated as the device resetting before it could answer
+        let mut fb = FastBoot::new(SimTransport::new().reply("reboot", &[]));
+        assert!(reboot(&mut fb, RebootTarget::System).await.is_ok());
+
+        // But a command that never left the host is an error
+        let mut fb = FastBoot::new(SimTransport::new().reply("reboot", &["OKAY"]).disconnected());
+        assert!(reboot(&mut fb, RebootTarget::System).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_refuses_unidentifiable_board() {
+        let device = USBDevice {
+            vendor_id: 0x2345,
+            product_id: 0x7654,
+            product_string: "USB download gadget".to_string(),
+            device_address: 1,
+            serial_number: None,
+            port_path: None,
+        };
+        let Err(error) = wait_for_target(&device, RebootTarget::Bootloader, Duration::from_secs(1)).await else {
+            panic!("an unidentifiable board must be refused");
+        };
+        assert_eq!(error.kind, crate::error::ErrorKind::Usb);
+        assert!(!error.retryable);
+    }
+
+    #[tokio::test]
+    async fn test_is_userspace() {
+        let mut fb = FastBoot::new(SimTransport::new().reply("getvar:is-userspace", &["OKAYyes"]));
+        assert!(is_userspace(&mut fb).await);
+        let mut fb = FastBoot::new(SimTransport::new());
+        assert!(!is_userspace(&mut fb).await);
+    }
+}
@@ -0,0 +1,260 @@
+//! Application settings, stored as one versioned JSON file in the platform config dir.
+//!
+//! Commands read and replace them through [`SettingsStore`]; code that has no access to
+//! Tauri state, such as the image cache and HTTP clients, reads the loaded store's
+//! settings through [`current`].
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{Error, Result, ResultExt};
+use crate::mirror::{self, Mirror};
+use crate::tunables::TunableSettings;
+
+/// Version of the settings file this build writes.
+pub const SETTINGS_VERSION: u32 = 1;
+
+/// Proxy schemes reqwest is built to speak.
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    pub version: u32,
+    /// Where downloaded images are kept; the system temp dir if unset.
+    pub cache_dir: Option<PathBuf>,
+    /// Proxy for all HTTP(S) requests, such as `http://127.0.0.1:7890` or `socks5://host:1080`.
+    /// With `socks5h://` host names are resolved by the proxy.
+    pub proxy: Option<String>,
+    /// Board picked when a request does not name one.
+    pub default_board: Option<String>,
+    pub mirrors: Vec<Mirror>,
+    pub tunables: TunableSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            cache_dir: None,
+            proxy: None,
+            default_board: None,
+            mirrors: mirror::default_mirrors(),
+            tunables: TunableSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Check every field, normalising mirror URLs and blank strings on the way.
+    pub fn validate(&mut self) -> Result<()> {
+        if self.version != SETTINGS_VERSION {
+            return Err(Error::invalid_input(format!(
+                "Settings version {} does not match {SETTINGS_VERSION}",
+                self.version
+            )));
+        }
+        if let Some(dir) = &self.cache_dir {
+            if !dir.is_absolute() {
+                return Err(Error::invalid_input(format!("Cache directory {} is not absolute", dir.display())));
+            }
+        }
+        self.proxy = self.proxy.take().map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+        if let Some(proxy) = &self.proxy {
+            let url = reqwest::Url::parse(proxy).map_err(|e| Error::invalid_input(format!("Invalid proxy {proxy}: {e}")))?;
+            if !PROXY_SCHEMES.contains(&url.scheme()) {
+                return Err(Error::invalid_input(format!(
+                    "Unsupported proxy {proxy}, use one of {}",
+                    PROXY_SCHEMES.join(", ")
+                )));
+            }
+        }
+        self.default_board = self.default_board.take().filter(|id| !id.is_empty());
+        if let Some(id) = &self.default_board {
+            if crate::board::find(id).is_none() {
+                return Err(Error::invalid_input(format!("Unknown board {id}")));
+            }
+        }
+        mirror::normalize(&mut self.mirrors)?;
+        self.tunables.validate()
+    }
+
+    /// Where downloaded and imported images are kept.
+    pub fn image_cache_dir(&self) -> PathBuf {
+        self.cache_dir.clone().unwrap_or_else(|| std::env::temp_dir().join("revyos-imager"))
+    }
+
+    /// The board a request without one is for, the LicheePi 4A unless configured.
+    pub fn board(&self) -> &'static crate::board::Board {
+        let id = self.default_board.as_deref().unwrap_or(crate::board::LPI4A.id);
+        crate::board::find(id)
+            .or_else(|| crate::board::find(crate::board::LPI4A.id))
+            .expect("LPI4A is a known board")
+    }
+}
+
+/// The settings of the store opened with [`SettingsStore::load`].
+static CURRENT: OnceLock<Arc<RwLock<Settings>>> = OnceLock::new();
+
+/// The settings in effect: those of the loaded [`SettingsStore`], or the defaults before
+/// it is loaded.
+pub fn current() -> Settings {
+    CURRENT.get().map(|settings| settings.read().unwrap().clone()).unwrap_or_default()
+}
+
+/// An HTTP client that goes through the configured proxy.
+pub fn http_client() -> reqwest::Client {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = current().proxy {
+        match reqwest::Proxy::all(&proxy) {
+            Ok(proxy) => builder = builder.proxy(proxy),
+            Err(e) => eprintln!("Ignoring invalid proxy {proxy}: {e}"),
+        }
+    }
+    builder.build().unwrap_or_default()
+}
+
+/// The settings file and the settings loaded from it.
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: Arc<RwLock<Settings>>,
+    /// Set when the file is from a newer version, which saving would clobber.
+    read_only: bool,
+}
+
+impl SettingsStore {
+    /// Load `path` as the settings in effect for [`current`].
+    ///
+    /// A file that cannot be read falls back to the defaults.
+    pub fn load(path: PathBuf) -> Self {
+        let store = Self::open(path);
+        if CURRENT.set(store.settings.clone()).is_err() {
+            eprintln!("Settings are already loaded, ignoring {}", store.path.display());
+        }
+        store
+    }
+
+    fn open(path: PathBuf) -> Self {
+        let mut read_only = false;
+        let settings = Self::read(&path).unwrap_or_else(|e| {
+            eprintln!("Ignoring settings {}: {e}", path.display());
+            read_only = stored_version(&path).is_some_and(|version| version > SETTINGS_VERSION as u64);
+            Settings::default()
+        });
+        Self { path, settings: Arc::new(RwLock::new(settings)), read_only }
+    }
+
+    /// The settings in `path`, or the defaults if it does not exist yet.
+    fn read(path: &Path) -> Result<Settings> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Settings::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut settings: Settings = serde_json::from_str(&content)?;
+        if settings.version > SETTINGS_VERSION {
+            return Err(Error::invalid_input(format!(
+                "Settings version {} is newer than this build supports ({SETTINGS_VERSION})",
+                settings.version
+            )));
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn get(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Validate and persist `settings`, returning them as stored.
+    pub fn update(&self, mut settings: Settings) -> Result<Settings> {
+        if self.read_only {
+            return Err(Error::invalid_input(format!(
+                "{} was written by a newer version and is left untouched",
+                self.path.display()
+            )));
+        }
+        settings.validate()?;
+        let mut current = self.settings.write().unwrap();
+        write(&self.path, &settings)?;
+        *current = settings.clone();
+        Ok(settings)
+    }
+}
+
+fn stored_version(path: &Path) -> Option<u64> {
+    let value: Value = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    value.get("version")?.as_u64()
+}
+
+fn write(path: &Path, settings: &Settings) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Write a sibling first so a crash never leaves a truncated settings file
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_string_pretty(settings)?)
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+    std::fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunables::Overrides;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("revyos-imager-settings-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_update_validates() {
+        let dir = test_dir("update");
+        let path = dir.join("settings.json");
+        let store = SettingsStore::open(path.clone());
+        assert_eq!(store.get(), Settings::default());
+
+        let mut settings = store.get();
+        settings.proxy = Some(" http://127.0.0.1:7890 ".to_string());
+        settings.default_board = Some("meles".to_string());
+        let overrides = Overrides { flash_timeout_ms: Some(600_000), ..Default::default() };
+        settings.tunables.devices.insert("ABC".to_string(), overrides);
+        let saved = store.update(settings).unwrap();
+        assert_eq!(saved.proxy.as_deref(), Some("http://127.0.0.1:7890"));
+        let socks = Settings { proxy: Some("socks5://127.0.0.1:1080".to_string()), ..saved.clone() };
+        assert_eq!(store.update(socks).unwrap().proxy.as_deref(), Some("socks5://127.0.0.1:1080"));
+        let saved = store.update(saved).unwrap();
+        assert_eq!(saved.board().id, "meles");
+        assert_eq!(SettingsStore::open(path.clone()).get(), saved);
+
+        for broken in [
+            Settings { default_board: Some("nope".to_string()), ..saved.clone() },
+            Settings { proxy: Some("not a url".to_string()), ..saved.clone() },
+            Settings { proxy: Some("ftp://127.0.0.1:21".to_string()), ..saved.clone() },
+            Settings { cache_dir: Some(PathBuf::from("relative")), ..saved.clone() },
+            Settings { mirrors: vec![], ..saved.clone() },
+            Settings { version: 0, ..saved.clone() },
+        ] {
+            assert!(store.update(broken).is_err());
+        }
+        assert_eq!(store.get(), saved);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_newer_version_is_left_alone() {
+        let dir = test_dir("newer");
+        let path = dir.join("settings.json");
+        let newer = format!(r#"{{"version": {}, "proxy": "http://proxy:3128"}}"#, SETTINGS_VERSION + 1);
+        std::fs::write(&path, &newer).unwrap();
+        let store = SettingsStore::open(path.clone());
+        assert_eq!(store.get(), Settings::default());
+        assert!(store.update(Settings::default()).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), newer);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,189 @@
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::error::{Error, Result, ResultExt};
+
+/// Keep this much free on top of the estimate, for rounding and filesystem overhead.
+const SPACE_MARGIN: u64 = 64 << 20;
+
+/// Where downloaded images are kept, one directory per variant: the configured cache
+/// directory, or one in the system temp dir.
+pub fn image_cache_dir() -> PathBuf {
+    crate::settings::current().image_cache_dir()
+}
+
+/// A downloaded variant that could be deleted to free space.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheEntry {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// The variant directories in the image cache at `root`, oldest first, leaving out `keep`.
+pub fn cache_entries(root: &Path, keep: Option<&Path>) -> Vec<CacheEntry> {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<CacheEntry> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .filter(|entry| keep != Some(entry.path().as_path()))
+        .map(|entry| CacheEntry {
+            path: entry.path().to_string_lossy().to_string(),
+            size: dir_size(&entry.path()),
+            modified: entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .map(DateTime::<Utc>::from),
+        })
+        .filter(|entry| entry.size > 0)
+        .collect();
+    entries.sort_by_key(|entry| entry.modified);
+    entries
+}
+
+/// Delete one entry of the image cache at `root`. Refuses paths outside of it.
+pub fn remove_cache_entry(root: &Path, path: &Path) -> Result<()> {
+    let root = root.canonicalize()?;
+    let path = path.canonicalize()?;
+    if path.parent() != Some(root.as_path()) {
+        return Err(Error::invalid_input(format!(
+            "{} is not in the image cache",
+            path.display()
+        )));
+    }
+    std::fs::remove_dir_all(&path)?;
+    Ok(())
+}
+
+/// Move the image cache from `from` to `to` when the cache directory changes, so that
+/// downloads and imports stay usable and can still be cleaned up.
+///
+/// `to` is created and must be empty and writable: cleaning up the cache deletes the
+/// directories in it. Entries are renamed, not copied; if one cannot be, such as on
+/// another filesystem, the ones already moved are moved back.
+pub fn move_cache(from: &Path, to: &Path) -> Result<()> {
+    if from.starts_with(to) || to.starts_with(from) {
+        return Err(Error::invalid_input(format!(
+            "Cannot move the image cache from {} to {}",
+            from.display(),
+            to.display()
+        )));
+    }
+    std::fs::create_dir_all(to).with_context(|| format!("Failed to create {}", to.display()))?;
+    if std::fs::read_dir(to)?.next().is_some() {
+        return Err(Error::invalid_input(format!("Cache directory {} is not empty", to.display())));
+    }
+    let probe = to.join(".write-test");
+    std::fs::write(&probe, b"").with_context(|| format!("Cache directory {} is not writable", to.display()))?;
+    std::fs::remove_file(&probe)?;
+
+    let Ok(entries) = std::fs::read_dir(from) else {
+        return Ok(());
+    };
+    let mut moved: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for entry in entries.flatten() {
+        let target = to.join(entry.file_name());
+        if let Err(e) = std::fs::rename(entry.path(), &target) {
+            for (source, target) in moved.into_iter().rev() {
+                let _ = std::fs::rename(target, source);
+            }
+            return Err(Error::from(e).with_context(format!(
+                "Moving {} to {}",
+                entry.path().display(),
+                to.display()
+            )));
+        }
+        moved.push((entry.path(), target));
+    }
+    Ok(())
+}
+
+/// Free space of the filesystem `dir` is on, or would be on once created.
+pub fn available_space(dir: &Path) -> Result<u64> {
+    let existing = dir
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or(dir);
+    Ok(fs4::available_space(existing)?)
+}
+
+/// Fail with an [`Error::insufficient_space`] if `dir` has less than `required` bytes
+/// free. The error suggests entries of the image cache at `root` other than `dir` itself
+/// to remove.
+pub fn ensure_space(root: &Path, dir: &Path, required: u64) -> Result<()> {
+    let available = available_space(dir)?;
+    let required = required + SPACE_MARGIN;
+    if available >= required {
+        return Ok(());
+    }
+    Err(Error::insufficient_space(dir, required, available, cache_entries(root, Some(dir))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_space() {
+        let root = std::env::temp_dir().join("revyos-imager-space-check");
+        let _ = std::fs::remove_dir_all(&root);
+        let dir = root.join("20250323");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("boot.ext4"), vec![0u8; 4096]).unwrap();
+
+        ensure_space(&root, &dir, 0).unwrap();
+        let error = ensure_space(&root, &dir.join("not-created-yet"), u64::MAX / 2).unwrap_err();
+        assert_eq!(error.kind, crate::error::ErrorKind::InsufficientSpace);
+        let Some(crate::error::ErrorDetails::InsufficientSpace { removable, .. }) = error.details else {
+            panic!("missing details");
+        };
+        let entry = removable
+            .iter()
+            .find(|e| e.path == dir.to_string_lossy())
+            .expect("cache entry listed");
+        assert_eq!(entry.size, 4096);
+
+        remove_cache_entry(&root, &dir).unwrap();
+        assert!(!dir.exists());
+        assert!(remove_cache_entry(&root, &std::env::temp_dir()).is_err());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_move_cache() {
+        let root = std::env::temp_dir().join("revyos-imager-move-cache");
+        let _ = std::fs::remove_dir_all(&root);
+        let (from, to) = (root.join("old"), root.join("new"));
+        std::fs::create_dir_all(from.join("20250323")).unwrap();
+        std::fs::write(from.join("20250323").join("boot.ext4"), b"boot").unwrap();
+
+        move_cache(&from, &to).unwrap();
+        assert_eq!(std::fs::read(to.join("20250323").join("boot.ext4")).unwrap(), b"boot");
+        assert_eq!(std::fs::read_dir(&from).unwrap().count(), 0);
+        // Back again, but never into a directory that holds something or into itself
+        assert!(move_cache(&from, &to).is_err());
+        assert!(move_cache(&to, &to.join("nested")).is_err());
+        move_cache(&to, &from).unwrap();
+        assert!(from.join("20250323").join("boot.ext4").exists());
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
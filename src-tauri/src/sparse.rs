@@ -0,0 +1,788 @@
+//! Android sparse images, the format fastboot transfers: a file header followed by
+//! chunks that each hold raw blocks, a fill pattern, or just skip blocks.
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result, ResultExt};
+
+pub const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+pub const CHUNK_RAW: u16 = 0xcac1;
+pub const CHUNK_FILL: u16 = 0xcac2;
+pub const CHUNK_DONT_CARE: u16 = 0xcac3;
+pub const CHUNK_CRC32: u16 = 0xcac4;
+const FILE_HEADER_LEN: u16 = 28;
+const CHUNK_HEADER_LEN: u16 = 12;
+/// Raw data per chunk, so that readers need not hold huge chunks at once.
+const MAX_RAW_CHUNK: usize = 4 << 20;
+/// Block size of the sparse images RevyOS ships and fastboot expects by default.
+pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SparseHeader {
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub header_len: u16,
+    pub chunk_header_len: u16,
+    pub block_size: u32,
+    /// Blocks of the expanded image.
+    pub blocks: u32,
+    pub chunks: u32,
+    pub checksum: u32,
+}
+
+impl SparseHeader {
+    /// Bytes of the expanded image.
+    pub fn size(&self) -> u64 {
+        self.blocks as u64 * self.block_size as u64
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(FILE_HEADER_LEN as usize);
+        header.extend(SPARSE_MAGIC.to_le_bytes());
+        for value in [self.major_version, self.minor_version, self.header_len, self.chunk_header_len] {
+            header.extend(value.to_le_bytes());
+        }
+        for value in [self.block_size, self.blocks, self.chunks, self.checksum] {
+            header.extend(value.to_le_bytes());
+        }
+        header
+    }
+}
+
+fn chunk_header(kind: u16, blocks: u32, data_len: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(CHUNK_HEADER_LEN as usize);
+    header.extend(kind.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    header.extend(blocks.to_le_bytes());
+    header.extend((CHUNK_HEADER_LEN as u32 + data_len as u32).to_le_bytes());
+    header
+}
+
+/// A sparse image of `total` blocks that carries raw data for `blocks` blocks from
+/// block `start` and skips the rest, as the bytes that go before and after that data.
+pub fn window(block_size: u32, total: u32, start: u32, blocks: u32) -> (Vec<u8>, Vec<u8>) {
+    let end = start + blocks;
+    let chunks = 1 + (start > 0) as u32 + (end < total) as u32;
+    let header = SparseHeader {
+        major_version: 1,
+        minor_version: 0,
+        header_len: FILE_HEADER_LEN,
+        chunk_header_len: CHUNK_HEADER_LEN,
+        block_size,
+        blocks: total,
+        chunks,
+        checksum: 0,
+    };
+    let mut before = header.to_bytes();
+    if start > 0 {
+        before.extend(chunk_header(CHUNK_DONT_CARE, start, 0));
+    }
+    before.extend(chunk_header(CHUNK_RAW, blocks, blocks as usize * block_size as usize));
+    let after = if end < total { chunk_header(CHUNK_DONT_CARE, total - end, 0) } else { vec![] };
+    (before, after)
+}
+
+/// Bytes a [`window`] adds around its data at most.
+pub const WINDOW_OVERHEAD: u32 = FILE_HEADER_LEN as u32 + 3 * CHUNK_HEADER_LEN as u32;
+
+/// A piece of a sparse file laid out for writing or sending without copying its data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    /// Bytes made up on the fly, such as the headers of a split.
+    Bytes(Vec<u8>),
+    /// A range of the source image file.
+    File { offset: u64, len: u64 },
+}
+
+impl Segment {
+    pub fn len(&self) -> u64 {
+        match self {
+            Segment::Bytes(bytes) => bytes.len() as u64,
+            Segment::File { len, .. } => *len,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChunkKind {
+    Raw,
+    Fill,
+    DontCare,
+    Crc32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chunk {
+    pub kind: ChunkKind,
+    /// First block of the expanded image the chunk covers.
+    pub start: u64,
+    pub blocks: u32,
+    /// Where the chunk data, after its header, starts in the file.
+    pub offset: u64,
+    pub data_len: u32,
+    /// The pattern of a FILL chunk or the checksum of a CRC32 chunk.
+    pub value: Option<u32>,
+}
+
+/// Everything a sparse file holds, without the data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SparseMap {
+    pub header: SparseHeader,
+    pub chunks: Vec<Chunk>,
+    /// Whether the file ends before its last chunk does. The chunks then stop at
+    /// the last one whose header is complete.
+    pub truncated: bool,
+}
+
+/// The chunk map of `file`, or `None` if it is not a sparse image.
+pub fn read_map<R: Read + Seek>(file: &mut R) -> Result<Option<SparseMap>> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let mut data = [0u8; FILE_HEADER_LEN as usize];
+    if file_len < data.len() as u64 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut data)?;
+    if le32(&data, 0) != SPARSE_MAGIC {
+        return Ok(None);
+    }
+    let header = SparseHeader {
+        major_version: le16(&data, 4),
+        minor_version: le16(&data, 6),
+        header_len: le16(&data, 8),
+        chunk_header_len: le16(&data, 10),
+        block_size: le32(&data, 12),
+        blocks: le32(&data, 16),
+        chunks: le32(&data, 20),
+        checksum: le32(&data, 24),
+    };
+    if header.major_version != 1
+        || header.header_len < FILE_HEADER_LEN
+        || header.chunk_header_len < CHUNK_HEADER_LEN
+        || header.block_size == 0
+        || !header.block_size.is_multiple_of(4)
+    {
+        return Err(Error::invalid_image("Unsupported sparse image header"));
+    }
+    let mut chunks = Vec::new();
+    let mut position = header.header_len as u64;
+    let mut start = 0;
+    for _ in 0..header.chunks {
+        if position + header.chunk_header_len as u64 > file_len {
+            break;
+        }
+        let mut data = [0u8; CHUNK_HEADER_LEN as usize];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut data)?;
+        let blocks = le32(&data, 4);
+        let total_len = le32(&data, 8);
+        let offset = position + header.chunk_header_len as u64;
+        let data_len = total_len.checked_sub(header.chunk_header_len as u32);
+        let (kind, expected_len) = match le16(&data, 0) {
+            CHUNK_RAW => (ChunkKind::Raw, blocks as u64 * header.block_size as u64),
+            CHUNK_FILL => (ChunkKind::Fill, 4),
+            CHUNK_DONT_CARE => (ChunkKind::DontCare, 0),
+            CHUNK_CRC32 => (ChunkKind::Crc32, 4),
+            other => return Err(Error::invalid_image(format!("Unknown sparse chunk type {other:#x}"))),
+        };
+        let Some(data_len) = data_len.filter(|&len| len as u64 == expected_len) else {
+            return Err(Error::invalid_image(format!("Sparse chunk at {position:#x} has a bad size")));
+        };
+        let value = if expected_len == 4 && offset + 4 <= file_len {
+            let mut value = [0u8; 4];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut value)?;
+            Some(u32::from_le_bytes(value))
+        } else {
+            None
+        };
+        chunks.push(Chunk { kind, start, blocks, offset, data_len, value });
+        start += blocks as u64;
+        position = offset + data_len as u64;
+    }
+    let truncated = chunks.len() < header.chunks as usize || position > file_len;
+    if !truncated && start != header.blocks as u64 {
+        return Err(Error::invalid_image(format!(
+            "The sparse chunks cover {start} blocks but the header claims {}",
+            header.blocks
+        )));
+    }
+    Ok(Some(SparseMap { header, chunks, truncated }))
+}
+
+/// The chunk map of the sparse image at `path`.
+pub fn chunk_map(path: &Path) -> Result<SparseMap> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    read_map(&mut file)?.ok_or_else(|| Error::invalid_image(format!("{} is not a sparse image", path.display())))
+}
+
+/// A complete sparse image, opened for reading its chunk data.
+fn open_complete(path: &Path) -> Result<(BufReader<File>, SparseMap)> {
+    let map = chunk_map(path)?;
+    if map.truncated {
+        return Err(Error::invalid_image(format!("{} is truncated", path.display())));
+    }
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok((BufReader::new(file), map))
+}
+
+/// Write the image `map` describes to `out`, in block order. `skip` is called for
+/// the bytes of DONT_CARE chunks.
+fn expand<R: Read + Seek, W: Write>(
+    map: &SparseMap,
+    input: &mut R,
+    out: &mut W,
+    mut skip: impl FnMut(&mut W, u64) -> std::io::Result<()>,
+) -> Result<()> {
+    let block_size = map.header.block_size as u64;
+    for chunk in &map.chunks {
+        let len = chunk.blocks as u64 * block_size;
+        match chunk.kind {
+            ChunkKind::Raw => {
+                input.seek(SeekFrom::Start(chunk.offset))?;
+                std::io::copy(&mut input.take(len), out)?;
+            }
+            ChunkKind::Fill => {
+                let pattern = chunk.value.unwrap_or_default().to_le_bytes();
+                let piece: Vec<u8> = pattern.iter().copied().cycle().take(MAX_RAW_CHUNK.min(len as usize)).collect();
+                let mut left = len;
+                while left > 0 {
+                    let n = left.min(piece.len() as u64);
+                    out.write_all(&piece[..n as usize])?;
+                    left -= n;
+                }
+            }
+            ChunkKind::DontCare => skip(out, len)?,
+            ChunkKind::Crc32 => {}
+        }
+    }
+    Ok(())
+}
+
+fn write_zeros<W: Write>(out: &mut W, mut len: u64) -> std::io::Result<()> {
+    let zeros = [0u8; 64 << 10];
+    while len > 0 {
+        let n = len.min(zeros.len() as u64);
+        out.write_all(&zeros[..n as usize])?;
+        len -= n;
+    }
+    Ok(())
+}
+
+/// `<stem>-<suffix>.img` next to `source`, where converted images go by default.
+pub fn sibling_path(source: &Path, suffix: &str) -> PathBuf {
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    source.with_file_name(format!("{stem}-{suffix}.img"))
+}
+
+/// Write `output` through a temporary sibling that replaces it only once `write`
+/// succeeds. A failure leaves no partial file behind, and an output that is also one
+/// of the inputs is read in full before it is replaced.
+fn write_atomically<T>(output: &Path, write: impl FnOnce(File) -> Result<T>) -> Result<T> {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    let temp = output.with_file_name(format!("{name}.tmp"));
+    let file = File::create(&temp).with_context(|| format!("Failed to create {}", temp.display()))?;
+    let result = write(file).and_then(|value| {
+        std::fs::rename(&temp, output).with_context(|| format!("Failed to replace {}", output.display()))?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Expand the sparse image `source` into the raw image `output`. DONT_CARE
+/// chunks become holes. Returns the size of the raw image.
+pub fn unsparse(source: &Path, output: &Path) -> Result<u64> {
+    let (mut input, map) = open_complete(source)?;
+    write_atomically(output, |file| {
+        file.set_len(map.header.size())?;
+        let mut out = BufWriter::new(file);
+        expand(&map, &mut input, &mut out, |out, len| out.seek(SeekFrom::Current(len as i64)).map(drop))?;
+        out.flush()?;
+        Ok(map.header.size())
+    })
+}
+
+/// Convert the raw image `source` into a sparse image with `block_size` blocks.
+/// Blocks repeating one 32 bit word, zeros included, become FILL chunks the way
+/// img2simg writes them; a partial last block is padded with zeros.
+pub fn resparse(source: &Path, output: &Path, block_size: u32) -> Result<SparseHeader> {
+    let file = File::open(source).with_context(|| format!("Failed to open {}", source.display()))?;
+    let len = file.metadata()?.len();
+    let mut input = BufReader::new(file);
+    write_atomically(output, |out| {
+        let mut writer = SparseWriter::new(BufWriter::new(out), block_size)?;
+        let bs = block_size as usize;
+        let mut block = vec![0u8; bs];
+        let mut run = Vec::new();
+        let mut fill: Option<(u32, u64)> = None;
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(bs as u64) as usize;
+            block[n..].fill(0);
+            input.read_exact(&mut block[..n])?;
+            left -= n as u64;
+            let pattern = le32(&block, 0);
+            if block.chunks(4).all(|word| word == &block[..4]) {
+                writer.raw(&std::mem::take(&mut run))?;
+                match &mut fill {
+                    Some((current, blocks)) if *current == pattern => *blocks += 1,
+                    _ => {
+                        if let Some((current, blocks)) = fill.replace((pattern, 1)) {
+                            writer.fill(current, blocks)?;
+                        }
+                    }
+                }
+            } else {
+                if let Some((current, blocks)) = fill.take() {
+                    writer.fill(current, blocks)?;
+                }
+                run.extend_from_slice(&block);
+                if run.len() >= MAX_RAW_CHUNK {
+                    writer.raw(&std::mem::take(&mut run))?;
+                }
+            }
+        }
+        writer.raw(&run)?;
+        if let Some((current, blocks)) = fill {
+            writer.fill(current, blocks)?;
+        }
+        let header = writer.header()?;
+        writer.finish()?;
+        Ok(header)
+    })
+}
+
+/// A run of blocks one output of [`split`] carries.
+struct Piece {
+    kind: ChunkKind,
+    start: u64,
+    blocks: u64,
+    /// Data offset in the source for RAW, the pattern for FILL.
+    offset: u64,
+    value: u32,
+}
+
+/// Group the RAW and FILL chunks of `map` into parts of at most `max_size` bytes once
+/// written as sparse files.
+fn plan_split(map: &SparseMap, max_size: u64) -> Result<Vec<Vec<Piece>>> {
+    let bs = map.header.block_size as u64;
+    let chunk_header = CHUNK_HEADER_LEN as u64;
+    // The file header and DONT_CARE chunks before and after the part's data
+    let overhead = FILE_HEADER_LEN as u64 + 2 * chunk_header;
+    if max_size < overhead + chunk_header + bs {
+        return Err(Error::invalid_input(format!("{max_size} bytes cannot hold a sparse part with a block of {bs} bytes")));
+    }
+    // The length field of a chunk is 32 bits
+    let max_raw_blocks = (u32::MAX as u64 - chunk_header) / bs;
+    let mut parts: Vec<Vec<Piece>> = vec![Vec::new()];
+    let mut size = overhead;
+    for chunk in map.chunks.iter().filter(|c| matches!(c.kind, ChunkKind::Raw | ChunkKind::Fill)) {
+        let (mut start, mut blocks, mut offset) = (chunk.start, chunk.blocks as u64, chunk.offset);
+        while blocks > 0 {
+            let current = parts.last_mut().unwrap();
+            let gap = current.last().is_some_and(|p| p.start + p.blocks != start);
+            let room = max_size.saturating_sub(size + if gap { chunk_header } else { 0 } + chunk_header);
+            let take = match chunk.kind {
+                ChunkKind::Raw => (room / bs).min(blocks).min(max_raw_blocks),
+                _ if room >= 4 => blocks,
+                _ => 0,
+            };
+            if take == 0 {
+                parts.push(Vec::new());
+                size = overhead;
+                continue;
+            }
+            let data = if chunk.kind == ChunkKind::Raw { take * bs } else { 4 };
+            size += if gap { chunk_header } else { 0 } + chunk_header + data;
+            let value = chunk.value.unwrap_or_default();
+            current.push(Piece { kind: chunk.kind, start, blocks: take, offset, value });
+            start += take;
+            offset += take * bs;
+            blocks -= take;
+        }
+    }
+    Ok(parts)
+}
+
+/// One part of a split laid out as a sparse file: the pieces, with the rest of the
+/// image as DONT_CARE.
+fn part_segments(map: &SparseMap, pieces: &[Piece]) -> Vec<Segment> {
+    let bs = map.header.block_size as u64;
+    let mut segments = Vec::new();
+    let mut bytes = Vec::new();
+    let mut chunks = 0;
+    let mut at = 0;
+    for piece in pieces.iter().map(Some).chain([None]) {
+        let (start, blocks) = piece.map_or((map.header.blocks as u64, 0), |p| (p.start, p.blocks));
+        if start > at {
+            bytes.extend(chunk_header(CHUNK_DONT_CARE, (start - at) as u32, 0));
+            chunks += 1;
+        }
+        at = start + blocks;
+        let Some(piece) = piece else {
+            break;
+        };
+        chunks += 1;
+        if piece.kind == ChunkKind::Raw {
+            let len = piece.blocks * bs;
+            bytes.extend(chunk_header(CHUNK_RAW, piece.blocks as u32, len as usize));
+            segments.push(Segment::Bytes(std::mem::take(&mut bytes)));
+            segments.push(Segment::File { offset: piece.offset, len });
+        } else {
+            bytes.extend(chunk_header(CHUNK_FILL, piece.blocks as u32, 4));
+            bytes.extend(piece.value.to_le_bytes());
+        }
+    }
+    if !bytes.is_empty() {
+        segments.push(Segment::Bytes(bytes));
+    }
+    let header = SparseHeader {
+        major_version: 1,
+        minor_version: 0,
+        header_len: FILE_HEADER_LEN,
+        chunk_header_len: CHUNK_HEADER_LEN,
+        block_size: map.header.block_size,
+        blocks: map.header.blocks,
+        chunks,
+        checksum: 0,
+    };
+    segments.insert(0, Segment::Bytes(header.to_bytes()));
+    segments
+}
+
+/// The parts [`split`] writes, laid out for sending straight from the source file.
+pub fn split_segments(map: &SparseMap, max_size: u64) -> Result<Vec<Vec<Segment>>> {
+    if map.truncated {
+        return Err(Error::invalid_image("The sparse image is truncated"));
+    }
+    let parts = plan_split(map, max_size)?;
+    Ok(parts.iter().map(|pieces| part_segments(map, pieces)).collect())
+}
+
+/// Split the sparse image `source` into sparse files of at most `max_size` bytes,
+/// the way fastboot splits for `max-download-size`. Every part describes the whole
+/// image with the blocks of the other parts as DONT_CARE, so flashing the parts in
+/// order writes what `source` does. Parts are named `<stem>-part<N>.img` in
+/// `output_dir`; existing files of those names are left alone and fail the split.
+pub fn split(source: &Path, max_size: u64, output_dir: &Path) -> Result<Vec<PathBuf>> {
+    let (mut input, map) = open_complete(source)?;
+    let parts = split_segments(&map, max_size)?;
+    std::fs::create_dir_all(output_dir)?;
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    let paths: Vec<PathBuf> = (1..=parts.len()).map(|i| output_dir.join(format!("{stem}-part{i}.img"))).collect();
+    if let Some(existing) = paths.iter().find(|path| path.exists()) {
+        return Err(Error::invalid_input(format!("{} already exists", existing.display())));
+    }
+    let mut written = Vec::new();
+    let result = paths.iter().zip(parts).try_for_each(|(path, segments)| {
+        write_atomically(path, |file| {
+            let mut out = BufWriter::new(file);
+            for segment in segments {
+                match segment {
+                    Segment::Bytes(bytes) => out.write_all(&bytes)?,
+                    Segment::File { offset, len } => {
+                        input.seek(SeekFrom::Start(offset))?;
+                        if std::io::copy(&mut (&mut input).take(len), &mut out)? != len {
+                            return Err(Error::invalid_image("The input ended inside a sparse chunk"));
+                        }
+                    }
+                }
+            }
+            Ok(out.flush()?)
+        })?;
+        written.push(path);
+        Ok(())
+    });
+    if result.is_err() {
+        for path in written {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    result.map(|()| paths)
+}
+
+/// Combine sparse images describing disjoint parts of one image, such as the
+/// output of [`split`], into a single sparse image.
+pub fn merge(parts: &[PathBuf], output: &Path) -> Result<SparseHeader> {
+    let mut inputs = Vec::new();
+    for path in parts {
+        inputs.push(open_complete(path)?);
+    }
+    let Some((_, first)) = inputs.first() else {
+        return Err(Error::invalid_input("No sparse images to merge"));
+    };
+    let (block_size, blocks) = (first.header.block_size, first.header.blocks);
+    if let Some((i, _)) = inputs.iter().enumerate().find(|(_, (_, m))| (m.header.block_size, m.header.blocks) != (block_size, blocks)) {
+        return Err(Error::invalid_input(format!("{} does not describe the same image as {}", parts[i].display(), parts[0].display())));
+    }
+    let mut chunks: Vec<(usize, Chunk)> = inputs
+        .iter()
+        .enumerate()
+        .flat_map(|(i, (_, map))| map.chunks.iter().filter(|c| matches!(c.kind, ChunkKind::Raw | ChunkKind::Fill)).map(move |c| (i, *c)))
+        .collect();
+    chunks.sort_by_key(|(_, c)| c.start);
+    write_atomically(output, |file| {
+        let mut writer = SparseWriter::new(BufWriter::new(file), block_size)?;
+        let mut at = 0;
+        for (i, chunk) in &chunks {
+            if chunk.start < at {
+                return Err(Error::invalid_input(format!(
+                    "{} overlaps another image at block {}",
+                    parts[*i].display(),
+                    chunk.start
+                )));
+            }
+            writer.dont_care(chunk.start - at)?;
+            if chunk.kind == ChunkKind::Raw {
+                let input = &mut inputs[*i].0;
+                input.seek(SeekFrom::Start(chunk.offset))?;
+                writer.raw_from(input, chunk.blocks as u64)?;
+            } else {
+                writer.fill(chunk.value.unwrap_or_default(), chunk.blocks as u64)?;
+            }
+            at = chunk.start + chunk.blocks as u64;
+        }
+        writer.dont_care(blocks as u64 - at)?;
+        let header = writer.header()?;
+        writer.finish()?;
+        Ok(header)
+    })
+}
+
+/// The SHA-256 of the image `path` expands to, as hex. DONT_CARE blocks count as
+/// zeros, so a sparse image made from a raw one hashes like it. Raw files hash as
+/// they are.
+pub fn logical_hash(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    match read_map(&mut file)? {
+        Some(map) if map.truncated => return Err(Error::invalid_image(format!("{} is truncated", path.display()))),
+        Some(map) => expand(&map, &mut BufReader::new(file), &mut hasher, write_zeros)?,
+        None => {
+            file.seek(SeekFrom::Start(0))?;
+            std::io::copy(&mut BufReader::new(file), &mut hasher)?;
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Writes a sparse image chunk by chunk; the header is filled in by
+/// [`SparseWriter::finish`] once the totals are known.
+pub struct SparseWriter<W: Write + Seek> {
+    out: W,
+    block_size: u32,
+    blocks: u64,
+    chunks: u32,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    pub fn new(mut out: W, block_size: u32) -> Result<Self> {
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(Error::invalid_input(format!("Invalid sparse block size {block_size}")));
+        }
+        out.write_all(&[0u8; FILE_HEADER_LEN as usize])?;
+        Ok(Self { out, block_size, blocks: 0, chunks: 0 })
+    }
+
+    fn chunk_header(&mut self, kind: u16, blocks: u32, data_len: usize) -> Result<()> {
+        self.out.write_all(&chunk_header(kind, blocks, data_len))?;
+        self.blocks += blocks as u64;
+        self.chunks += 1;
+        Ok(())
+    }
+
+    /// Append whole blocks of data.
+    pub fn raw(&mut self, data: &[u8]) -> Result<()> {
+        if !data.len().is_multiple_of(self.block_size as usize) {
+            return Err(Error::internal("Sparse raw data must be whole blocks"));
+        }
+        for chunk in data.chunks(MAX_RAW_CHUNK) {
+            self.chunk_header(CHUNK_RAW, (chunk.len() / self.block_size as usize) as u32, chunk.len())?;
+            self.out.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Append `blocks` blocks read from `input`, as one chunk.
+    pub fn raw_from<R: Read>(&mut self, input: &mut R, blocks: u64) -> Result<()> {
+        let len = blocks * self.block_size as u64;
+        if len > (u32::MAX - CHUNK_HEADER_LEN as u32) as u64 {
+            return Err(Error::invalid_input("Too much data for one sparse chunk"));
+        }
+        self.chunk_header(CHUNK_RAW, blocks as u32, len as usize)?;
+        let copied = std::io::copy(&mut input.take(len), &mut self.out)?;
+        if copied != len {
+            return Err(Error::invalid_image("The input ended inside a sparse chunk"));
+        }
+        Ok(())
+    }
+
+    /// Append `blocks` blocks that repeat `pattern`.
+    pub fn fill(&mut self, pattern: u32, mut blocks: u64) -> Result<()> {
+        while blocks > 0 {
+            let count = blocks.min(u32::MAX as u64);
+            self.chunk_header(CHUNK_FILL, count as u32, 4)?;
+            self.out.write_all(&pattern.to_le_bytes())?;
+            blocks -= count;
+        }
+        Ok(())
+    }
+
+    /// Skip `blocks` blocks, leaving whatever the target holds there.
+    pub fn dont_care(&mut self, mut blocks: u64) -> Result<()> {
+        while blocks > 0 {
+            let count = blocks.min(u32::MAX as u64);
+            self.chunk_header(CHUNK_DONT_CARE, count as u32, 0)?;
+            blocks -= count;
+        }
+        Ok(())
+    }
+
+    /// The file header for what has been written so far.
+    pub fn header(&self) -> Result<SparseHeader> {
+        let blocks = u32::try_from(self.blocks)
+            .map_err(|_| Error::invalid_input("The image has too many blocks for a sparse file"))?;
+        Ok(SparseHeader {
+            major_version: 1,
+            minor_version: 0,
+            header_len: FILE_HEADER_LEN,
+            chunk_header_len: CHUNK_HEADER_LEN,
+            block_size: self.block_size,
+            blocks,
+            chunks: self.chunks,
+            checksum: 0,
+        })
+    }
+
+    /// Write the file header and return the output.
+    pub fn finish(mut self) -> Result<W> {
+        let header = self.header()?.to_bytes();
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("revyos-imager-sparse-{name}"))
+    }
+
+    /// 40 blocks of 1 KiB: data, zeros, a repeated word, more data, and a short tail.
+    fn raw_image() -> Vec<u8> {
+        let mut image: Vec<u8> = (0..10 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+        image.resize(image.len() + 12 * 1024, 0);
+        image.extend(0xdead_beefu32.to_le_bytes().repeat(5 * 256));
+        image.extend((0..12 * 1024u32).map(|i| (i % 13) as u8));
+        image.extend([1, 2, 3]);
+        image
+    }
+
+    #[test]
+    fn test_resparse_and_unsparse() {
+        let (raw, sparse, back) = (temp("raw"), temp("sparse"), temp("back"));
+        let mut image = raw_image();
+        std::fs::write(&raw, &image).unwrap();
+        let header = resparse(&raw, &sparse, 1024).unwrap();
+        assert_eq!((header.block_size, header.blocks), (1024, 40));
+
+        let map = chunk_map(&sparse).unwrap();
+        assert_eq!(map.header, header);
+        assert!(!map.truncated);
+        let kinds: Vec<_> = map.chunks.iter().map(|c| (c.kind, c.start, c.blocks, c.value)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (ChunkKind::Raw, 0, 10, None),
+                (ChunkKind::Fill, 10, 12, Some(0)),
+                (ChunkKind::Fill, 22, 5, Some(0xdead_beef)),
+                (ChunkKind::Raw, 27, 13, None),
+            ]
+        );
+
+        assert_eq!(unsparse(&sparse, &back).unwrap(), 40 * 1024);
+        image.resize(40 * 1024, 0);
+        assert_eq!(std::fs::read(&back).unwrap(), image);
+        assert_eq!(logical_hash(&sparse).unwrap(), logical_hash(&back).unwrap());
+        assert_eq!(logical_hash(&back).unwrap(), hex::encode(Sha256::digest(&image)));
+
+        assert!(chunk_map(&raw).is_err());
+        let mut cut = std::fs::read(&sparse).unwrap();
+        cut.truncate(5000);
+        std::fs::write(&sparse, &cut).unwrap();
+        assert!(chunk_map(&sparse).unwrap().truncated);
+        assert!(unsparse(&sparse, &temp("cut")).is_err());
+        for path in [raw, sparse, back] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let (raw, sparse, merged) = (temp("split-raw"), temp("split-sparse"), temp("merged"));
+        let dir = temp("parts");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::write(&raw, raw_image()).unwrap();
+        resparse(&raw, &sparse, 1024).unwrap();
+
+        let max_size = 4 * 1024;
+        let parts = split(&sparse, max_size, &dir).unwrap();
+        assert_eq!(parts.len(), 8);
+        assert!(parts[0].ends_with("revyos-imager-sparse-split-sparse-part1.img"));
+        for part in &parts {
+            assert!(std::fs::metadata(part).unwrap().len() <= max_size);
+            assert_eq!(chunk_map(part).unwrap().header.blocks, 40);
+        }
+        assert!(split(&sparse, 1024, &dir).is_err());
+        // The laid out segments are what the part files hold
+        let segments = split_segments(&chunk_map(&sparse).unwrap(), max_size).unwrap();
+        let lens: Vec<u64> = segments.iter().map(|s| s.iter().map(Segment::len).sum()).collect();
+        let sizes: Vec<u64> = parts.iter().map(|p| std::fs::metadata(p).unwrap().len()).collect();
+        assert_eq!(lens, sizes);
+        // Existing parts are not overwritten
+        std::fs::write(&parts[7], b"mine").unwrap();
+        std::fs::remove_file(&parts[0]).unwrap();
+        assert!(split(&sparse, max_size, &dir).is_err());
+        assert!(!parts[0].exists());
+        assert_eq!(std::fs::read(&parts[7]).unwrap(), b"mine");
+        let _ = std::fs::remove_dir_all(&dir);
+        let parts = split(&sparse, max_size, &dir).unwrap();
+
+        merge(&parts, &merged).unwrap();
+        assert_eq!(logical_hash(&merged).unwrap(), logical_hash(&sparse).unwrap());
+        // A failed merge into one of its inputs leaves that input as it was
+        let before = std::fs::read(&merged).unwrap();
+        assert!(merge(&[sparse.clone(), merged.clone()], &merged).is_err());
+        assert_eq!(std::fs::read(&merged).unwrap(), before);
+        // Merging into a part reads it in full before replacing it
+        merge(&parts, &parts[0]).unwrap();
+        assert_eq!(logical_hash(&parts[0]).unwrap(), logical_hash(&sparse).unwrap());
+        for path in [raw, sparse, merged] {
+            let _ = std::fs::remove_file(path);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,274 @@
+//! Per-board and per-device overrides for how images are sent to a fastboot device.
+//!
+//! Some bootloaders misreport `max-download-size`, and some hosts or hubs only cope with
+//! smaller transfers. A device override wins over a board override, which wins over the
+//! built-in default; every resolved value remembers where it came from for the job log.
+//! The overrides are part of the [settings](crate::settings::Settings).
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::fastboot::{DEFAULT_TIMEOUT, FLASH_TIMEOUT};
+use crate::flash::UploadOptions;
+use crate::sparse::{DEFAULT_BLOCK_SIZE, WINDOW_OVERHEAD};
+use crate::usb::USBDevice;
+
+/// Largest USB transfer we allow, to keep the read-ahead buffers bounded.
+const MAX_TRANSFER_SIZE: usize = 64 << 20;
+
+/// Values that replace the defaults; unset ones fall through to the next level.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Overrides {
+    /// Used instead of the `max-download-size` the bootloader reports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_download_size: Option<u32>,
+    /// Block size of the sparse images raw images are split into.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sparse_block_size: Option<u32>,
+    /// Bytes per USB bulk transfer of download data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_size: Option<usize>,
+    /// Time allowed for each response to a command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_timeout_ms: Option<u64>,
+    /// Time allowed for writing one split to the partition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flash_timeout_ms: Option<u64>,
+}
+
+impl Overrides {
+    fn validate(&self) -> Result<()> {
+        if let Some(size) = self.sparse_block_size {
+            if size == 0 || !size.is_multiple_of(4) {
+                return Err(Error::invalid_input(format!("Invalid sparse block size {size}")));
+            }
+        }
+        if let Some(size) = self.transfer_size {
+            if size == 0 || size > MAX_TRANSFER_SIZE {
+                return Err(Error::invalid_input(format!(
+                    "Transfer size must be between 1 and {MAX_TRANSFER_SIZE} bytes"
+                )));
+            }
+        }
+        if self.command_timeout_ms == Some(0) || self.flash_timeout_ms == Some(0) {
+            return Err(Error::invalid_input("Timeouts must be longer than 0 ms"));
+        }
+        Ok(())
+    }
+}
+
+/// All overrides, keyed by board id and by [`device_key`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunableSettings {
+    #[serde(default)]
+    pub boards: BTreeMap<String, Overrides>,
+    #[serde(default)]
+    pub devices: BTreeMap<String, Overrides>,
+}
+
+/// Key for device overrides: the serial number, or `vid:pid` for boards without one.
+pub fn device_key(device: &USBDevice) -> String {
+    match &device.serial_number {
+        Some(serial) => serial.clone(),
+        None => format!("{:04x}:{:04x}", device.vendor_id, device.product_id),
+    }
+}
+
+/// Where a tunable value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    Board(String),
+    Device(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::Board(id) => write!(f, "override for board {id}"),
+            Source::Device(key) => write!(f, "override for device {key}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tuned<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// The values a flash job runs with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tuning {
+    /// `None` trusts what the device reports.
+    pub max_download_size: Option<Tuned<u32>>,
+    pub sparse_block_size: Tuned<u32>,
+    pub transfer_size: Tuned<usize>,
+    pub command_timeout: Tuned<Duration>,
+    pub flash_timeout: Tuned<Duration>,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        TunableSettings::default().resolve(None, None)
+    }
+}
+
+impl Tuning {
+    pub fn upload_options(&self) -> UploadOptions {
+        UploadOptions { buffer_size: self.transfer_size.value, ..UploadOptions::default() }
+    }
+
+    /// Every value with its source, one line each, for the job log and history.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if let Some(size) = &self.max_download_size {
+            lines.push(format!("Max download size limit: {} ({})", size.value, size.source));
+        }
+        lines.push(format!("Sparse block size: {} ({})", self.sparse_block_size.value, self.sparse_block_size.source));
+        lines.push(format!("USB transfer size: {} ({})", self.transfer_size.value, self.transfer_size.source));
+        lines.push(format!("Command timeout: {:?} ({})", self.command_timeout.value, self.command_timeout.source));
+        lines.push(format!("Flash timeout: {:?} ({})", self.flash_timeout.value, self.flash_timeout.source));
+        lines
+    }
+
+    pub fn log(&self) {
+        for line in self.describe() {
+            println!("{line}");
+        }
+    }
+
+    /// Check the values against each other. They may come from different levels, so a
+    /// board limit can be too small for a device's block size even though each level
+    /// is fine on its own.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(size) = &self.max_download_size {
+            let block_size = &self.sparse_block_size;
+            if size.value < block_size.value.saturating_add(WINDOW_OVERHEAD) {
+                return Err(Error::invalid_input(format!(
+                    "Max download size {} ({}) cannot hold a single block of {} bytes ({})",
+                    size.value, size.source, block_size.value, block_size.source
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The value of the first level, most specific first, that sets one.
+fn pick<T>(levels: &[(Source, &Overrides)], get: impl Fn(&Overrides) -> Option<T>) -> Option<Tuned<T>> {
+    levels
+        .iter()
+        .find_map(|(source, overrides)| get(overrides).map(|value| Tuned { value, source: source.clone() }))
+}
+
+fn or_default<T>(tuned: Option<Tuned<T>>, default: T) -> Tuned<T> {
+    tuned.unwrap_or(Tuned { value: default, source: Source::Default })
+}
+
+impl TunableSettings {
+    pub fn validate(&self) -> Result<()> {
+        for (id, overrides) in &self.boards {
+            if crate::board::find(id).is_none() {
+                return Err(Error::invalid_input(format!("Unknown board {id}")));
+            }
+            overrides.validate()?;
+        }
+        for overrides in self.devices.values() {
+            overrides.validate()?;
+        }
+        // Any device may be flashed with any board's image, so check every combination
+        let boards = self.boards.keys().map(|id| Some(id.as_str())).chain([None]);
+        for board in boards {
+            for key in self.devices.keys().map(|key| Some(key.as_str())).chain([None]) {
+                self.resolve_key(board, key).validate()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The values for a job flashing `device`, an image for `board`.
+    pub fn resolve(&self, board: Option<&str>, device: Option<&USBDevice>) -> Tuning {
+        self.resolve_key(board, device.map(device_key).as_deref())
+    }
+
+    fn resolve_key(&self, board: Option<&str>, device_key: Option<&str>) -> Tuning {
+        let mut levels = vec![];
+        if let Some((key, overrides)) = device_key.and_then(|key| self.devices.get_key_value(key)) {
+            levels.push((Source::Device(key.clone()), overrides));
+        }
+        if let Some((id, overrides)) = board.and_then(|id| self.boards.get_key_value(id)) {
+            levels.push((Source::Board(id.clone()), overrides));
+        }
+        let millis = |ms| Duration::from_millis(ms);
+        Tuning {
+            max_download_size: pick(&levels, |o| o.max_download_size),
+            sparse_block_size: or_default(pick(&levels, |o| o.sparse_block_size), DEFAULT_BLOCK_SIZE),
+            transfer_size: or_default(pick(&levels, |o| o.transfer_size), UploadOptions::default().buffer_size),
+            command_timeout: or_default(pick(&levels, |o| o.command_timeout_ms.map(millis)), DEFAULT_TIMEOUT),
+            flash_timeout: or_default(pick(&levels, |o| o.flash_timeout_ms.map(millis)), FLASH_TIMEOUT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(serial: Option<&str>) -> USBDevice {
+        USBDevice {
+            vendor_id: 0x2345,
+            product_id: 0x7654,
+            product_string: "USB download gadget".to_string(),
+            device_address: 3,
+            serial_number: serial.map(str::to_string),
+            port_path: None,
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut settings = TunableSettings::default();
+        settings.boards.insert(
+            "lpi4a".to_string(),
+            Overrides { max_download_size: Some(1 << 20), transfer_size: Some(1 << 16), ..Default::default() },
+        );
+        settings
+            .devices
+            .insert("2345:7654".to_string(), Overrides { transfer_size: Some(4096), ..Default::default() });
+        settings.validate().unwrap();
+
+        let tuning = settings.resolve(Some("lpi4a"), Some(&device(None)));
+        let max_download = tuning.max_download_size.unwrap();
+        assert_eq!((max_download.value, max_download.source), (1 << 20, Source::Board("lpi4a".to_string())));
+        assert_eq!(tuning.transfer_size.value, 4096);
+        assert_eq!(tuning.transfer_size.source, Source::Device("2345:7654".to_string()));
+        assert_eq!(tuning.flash_timeout, Tuned { value: FLASH_TIMEOUT, source: Source::Default });
+
+        // Another board, and a device known by its serial, get none of it
+        let tuning = settings.resolve(Some("meles"), Some(&device(Some("ABC"))));
+        assert_eq!(tuning, Tuning::default());
+        assert_eq!(tuning.max_download_size, None);
+
+        settings.boards.insert("nope".to_string(), Overrides::default());
+        assert!(settings.validate().is_err());
+        settings.boards.remove("nope");
+        settings.devices.insert("ABC".to_string(), Overrides { sparse_block_size: Some(510), ..Default::default() });
+        assert!(settings.validate().is_err());
+
+        // Fine on each level, but the board limit cannot hold one of the device's blocks
+        let block_size = Overrides { sparse_block_size: Some(1 << 20), ..Default::default() };
+        settings.devices.insert("ABC".to_string(), block_size);
+        let err = settings.validate().unwrap_err();
+        assert!(err.message.contains("override for board lpi4a"));
+        assert!(err.message.contains("override for device ABC"));
+        settings.boards.get_mut("lpi4a").unwrap().max_download_size = Some(2 << 20);
+        settings.validate().unwrap();
+        assert!(settings.resolve(Some("lpi4a"), Some(&device(Some("ABC")))).describe()[0].contains("(override for board lpi4a)"));
+    }
+}
@@ -0,0 +1,208 @@
+//! U-Boot environment images, as `mkenvimage` writes them and U-Boot stores them:
+//! a CRC32 of the data area, an optional flag byte for redundant environments, then
+//! `name=value` strings separated by NULs and terminated by an empty one.
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// CONFIG_ENV_SIZE of the RevyOS U-Boot builds.
+pub const DEFAULT_ENV_SIZE: usize = 0x20000;
+/// Flag of the copy written as the active one of a redundant pair.
+const ACTIVE_FLAG: u8 = 1;
+
+/// How the environment is stored: the size of one copy, and whether two copies with
+/// flag bytes follow each other (CONFIG_SYS_REDUNDAND_ENVIRONMENT).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvLayout {
+    #[serde(default = "default_size")]
+    pub size: usize,
+    #[serde(default)]
+    pub redundant: bool,
+}
+
+fn default_size() -> usize {
+    DEFAULT_ENV_SIZE
+}
+
+impl Default for EnvLayout {
+    fn default() -> Self {
+        Self { size: DEFAULT_ENV_SIZE, redundant: false }
+    }
+}
+
+impl EnvLayout {
+    fn header_len(&self) -> usize {
+        if self.redundant { 5 } else { 4 }
+    }
+
+    /// Bytes the whole image takes on the partition.
+    pub fn image_len(&self) -> usize {
+        if self.redundant { self.size * 2 } else { self.size }
+    }
+}
+
+/// A decoded environment and which copy it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecodedEnv {
+    pub vars: BTreeMap<String, String>,
+    /// 0 or 1; always 0 without redundancy.
+    pub copy: usize,
+    pub flags: Option<u8>,
+}
+
+fn validate(vars: &BTreeMap<String, String>) -> Result<()> {
+    for (name, value) in vars {
+        if name.is_empty() || name.contains(['=', '\0']) || name.contains(char::is_whitespace) {
+            return Err(Error::invalid_input(format!("Invalid environment variable name: {name:?}")));
+        }
+        if value.contains('\0') {
+            return Err(Error::invalid_input(format!("Environment variable {name} contains a NUL byte")));
+        }
+    }
+    Ok(())
+}
+
+fn build_copy(vars: &BTreeMap<String, String>, layout: &EnvLayout, flags: u8) -> Result<Vec<u8>> {
+    let header = layout.header_len();
+    let mut data = Vec::new();
+    // Sorted by name, like `saveenv` exports them
+    for (name, value) in vars {
+        data.extend_from_slice(name.as_bytes());
+        data.push(b'=');
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+    }
+    data.push(0);
+    if header + data.len() > layout.size {
+        return Err(Error::invalid_input(format!(
+            "The environment needs {} bytes but only {} fit",
+            data.len(),
+            layout.size - header
+        )));
+    }
+    data.resize(layout.size - header, 0);
+    let mut copy = crc32fast::hash(&data).to_le_bytes().to_vec();
+    if layout.redundant {
+        copy.push(flags);
+    }
+    copy.extend(data);
+    Ok(copy)
+}
+
+/// An environment image holding `vars`. A redundant image carries both copies, the
+/// first one marked active.
+pub fn build(vars: &BTreeMap<String, String>, layout: &EnvLayout) -> Result<Vec<u8>> {
+    validate(vars)?;
+    if layout.size <= layout.header_len() + 1 {
+        return Err(Error::invalid_input(format!("Environment size {} is too small", layout.size)));
+    }
+    let mut image = build_copy(vars, layout, ACTIVE_FLAG)?;
+    if layout.redundant {
+        image.extend(build_copy(vars, layout, ACTIVE_FLAG.wrapping_sub(1))?);
+    }
+    Ok(image)
+}
+
+/// The variables of one copy, or `None` if its CRC does not match.
+fn parse_copy(copy: &[u8], layout: &EnvLayout) -> Option<(BTreeMap<String, String>, Option<u8>)> {
+    let header = layout.header_len();
+    let crc = u32::from_le_bytes(copy[..4].try_into().unwrap());
+    let data = &copy[header..];
+    if crc32fast::hash(data) != crc {
+        return None;
+    }
+    let mut vars = BTreeMap::new();
+    for entry in data.split(|&b| b == 0).take_while(|entry| !entry.is_empty()) {
+        let entry = String::from_utf8_lossy(entry);
+        if let Some((name, value)) = entry.split_once('=') {
+            vars.insert(name.to_string(), value.to_string());
+        }
+    }
+    Some((vars, layout.redundant.then(|| copy[4])))
+}
+
+/// Decode an environment image. Of two valid redundant copies the newer one wins,
+/// judged by its flag counter the way U-Boot does.
+pub fn parse(image: &[u8], layout: &EnvLayout) -> Result<DecodedEnv> {
+    if image.len() < layout.size || layout.size <= layout.header_len() {
+        return Err(Error::invalid_image(format!(
+            "The environment image has {} bytes, expected at least {}",
+            image.len(),
+            layout.size
+        )));
+    }
+    let first = parse_copy(&image[..layout.size], layout);
+    let second = if layout.redundant && image.len() >= layout.size * 2 {
+        parse_copy(&image[layout.size..layout.size * 2], layout)
+    } else {
+        None
+    };
+    let (copy, (vars, flags)) = match (first, second) {
+        (Some(a), Some(b)) => {
+            let (fa, fb) = (a.1.unwrap_or_default(), b.1.unwrap_or_default());
+            // The counter wraps from 255 to 0
+            let second_newer = fb == fa.wrapping_add(1) || (fb > fa && !(fa == 0 && fb == 255));
+            if second_newer { (1, b) } else { (0, a) }
+        }
+        (Some(a), None) => (0, a),
+        (None, Some(b)) => (1, b),
+        (None, None) => return Err(Error::invalid_image("No environment copy has a valid CRC")),
+    };
+    Ok(DecodedEnv { vars, copy, flags })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("bootcmd".to_string(), "run bootcmd_load; bootslave; sysboot mmc ${mmcdev}:2 any $boot_conf_addr_r /extlinux/extlinux.conf".to_string()),
+            ("bootargs".to_string(), "console=ttyS0,115200 loglevel=8".to_string()),
+            ("ethaddr".to_string(), "48:da:35:60:0e:01".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_build_and_parse() {
+        let layout = EnvLayout { size: 0x4000, redundant: false };
+        let image = build(&vars(), &layout).unwrap();
+        assert_eq!(image.len(), 0x4000);
+        assert_eq!(u32::from_le_bytes(image[..4].try_into().unwrap()), crc32fast::hash(&image[4..]));
+        assert!(image[4..].starts_with(b"bootargs=console=ttyS0,115200 loglevel=8\0bootcmd="));
+        let decoded = parse(&image, &layout).unwrap();
+        assert_eq!(decoded.vars, vars());
+        assert_eq!(decoded.flags, None);
+
+        let mut corrupt = image.clone();
+        corrupt[100] ^= 1;
+        assert!(parse(&corrupt, &layout).is_err());
+        assert!(build(&vars(), &EnvLayout { size: 64, redundant: false }).is_err());
+        let bad = BTreeMap::from([("boot cmd".to_string(), String::new())]);
+        assert!(build(&bad, &layout).is_err());
+    }
+
+    #[test]
+    fn test_redundant_copies() {
+        let layout = EnvLayout { size: 0x2000, redundant: true };
+        let image = build(&vars(), &layout).unwrap();
+        assert_eq!(image.len(), layout.image_len());
+        assert_eq!((image[4], image[0x2000 + 4]), (1, 0));
+        let decoded = parse(&image, &layout).unwrap();
+        assert_eq!((decoded.copy, decoded.flags), (0, Some(1)));
+
+        // A newer second copy wins, also across the counter wrapping
+        let newer = BTreeMap::from([("bootdelay".to_string(), "3".to_string())]);
+        let mut image = build_copy(&vars(), &layout, 255).unwrap();
+        image.extend(build_copy(&newer, &layout, 0).unwrap());
+        let decoded = parse(&image, &layout).unwrap();
+        assert_eq!((decoded.copy, decoded.vars.clone()), (1, newer.clone()));
+
+        // A broken copy falls back to the other one
+        image[0x2000 + 10] ^= 0xff;
+        assert_eq!(parse(&image, &layout).unwrap().vars, vars());
+    }
+}
@@ -1,12 +1,24 @@
+use std::time::Duration;
 use nusb::MaybeFuture;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::error::{Error, ErrorKind, Result};
+
+/// Interface class/subclass/protocol triples used to tell device modes apart.
+pub const FASTBOOT_INTERFACE: (u8, u8, u8) = (0xff, 0x42, 0x03);
+pub const ADB_INTERFACE: (u8, u8, u8) = (0xff, 0x42, 0x01);
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct USBDevice {
     pub vendor_id: u16,
     pub product_id: u16,
     pub product_string: String,
     pub device_address: u8,
+    #[serde(default)]
+    pub serial_number: Option<String>,
+    /// Bus and hub ports the board is plugged into, like `1-2.4`.
+    #[serde(default)]
+    pub port_path: Option<String>,
 }
 
 impl From<nusb::DeviceInfo> for USBDevice {
@@ -16,30 +28,129 @@ impl From<nusb::DeviceInfo> for USBDevice {
             product_id: device.product_id(),
             product_string: device.product_string().unwrap_or("Unknown").to_string(),
             device_address: device.device_address(),
+            serial_number: device.serial_number().map(str::to_string),
+            port_path: port_path(&device),
         }
     }
 }
 
+fn port_path(info: &nusb::DeviceInfo) -> Option<String> {
+    let ports = info.port_chain();
+    if ports.is_empty() {
+        return None;
+    }
+    let ports: Vec<String> = ports.iter().map(u8::to_string).collect();
+    Some(format!("{}-{}", info.bus_id(), ports.join(".")))
+}
+
 impl TryFrom<USBDevice> for nusb::DeviceInfo {
-    type Error = String;
+    type Error = Error;
 
-    fn try_from(device: USBDevice) -> Result<Self, Self::Error> {
-        let mut devices = nusb::list_devices().wait().map_err(|e| e.to_string())?;
+    fn try_from(device: USBDevice) -> Result<Self> {
+        let mut devices = enumerate()?;
         devices
             .find(|dev| {
                 dev.vendor_id() == device.vendor_id
                     && dev.product_id() == device.product_id
                     && dev.device_address() == device.device_address
             })
-            .ok_or_else(|| "Device not found".to_string())
+            .ok_or_else(Error::device_not_found)
+    }
+}
+
+impl USBDevice {
+    /// Whether `info` is this board, possibly re-enumerated under a new address.
+    ///
+    /// The serial number survives a reboot while the bus address does not; boards without
+    /// a serial are matched by the port they are plugged into. With neither known nothing
+    /// matches, since vendor and product id alone cannot tell identical boards apart.
+    pub fn is_same_board(&self, info: &nusb::DeviceInfo) -> bool {
+        match (&self.serial_number, &self.port_path) {
+            (Some(serial), _) => info.serial_number() == Some(serial.as_str()),
+            (None, Some(path)) => port_path(info).as_ref() == Some(path),
+            (None, None) => false,
+        }
+    }
+
+    /// Whether [`is_same_board`](Self::is_same_board) can find this board again.
+    pub fn is_identifiable(&self) -> bool {
+        self.serial_number.is_some() || self.port_path.is_some()
+    }
+
+    /// Fail, without retrying, for a board that cannot be told apart from identical ones
+    /// once it re-enumerates: any of them could be taken for it.
+    pub fn ensure_identifiable(&self) -> Result<()> {
+        if self.is_identifiable() {
+            return Ok(());
+        }
+        let mut error = Error::usb(format!(
+            "{} has no serial number or port path to find it again by",
+            self.product_string
+        ));
+        error.retryable = false;
+        Err(error)
     }
 }
 
-pub fn list_devices() -> Result<Vec<USBDevice>, String> {
+/// Whether the device exposes an interface with the given class/subclass/protocol.
+pub fn has_interface(info: &nusb::DeviceInfo, kind: (u8, u8, u8)) -> bool {
+    info.interfaces()
+        .any(|i| (i.class(), i.subclass(), i.protocol()) == kind)
+}
+
+fn enumerate() -> Result<impl Iterator<Item = nusb::DeviceInfo>> {
+    nusb::list_devices()
+        .wait()
+        .map_err(|e| Error::usb(format!("Failed to list USB devices: {e}")))
+}
+
+pub fn list_devices() -> Result<Vec<USBDevice>> {
     let mut devices: Vec<USBDevice> = Vec::new();
-    for dev in nusb::list_devices().wait().unwrap() {
+    for dev in enumerate()? {
         devices.push(dev.into());
     }
     devices.sort_by(|a, b| a.product_string.cmp(&b.product_string));
     Ok(devices)
 }
+
+/// Poll the bus until `predicate` matches a device, or give up after `timeout`.
+pub async fn wait_for_device<P>(timeout: Duration, mut predicate: P) -> Result<nusb::DeviceInfo>
+where
+    P: FnMut(&nusb::DeviceInfo) -> bool,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let found = enumerate()?.find(|dev| predicate(dev));
+        if let Some(info) = found {
+            return Ok(info);
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(Error::new(
+                ErrorKind::DeviceNotFound,
+                format!("Timed out after {}s waiting for device", timeout.as_secs()),
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Poll the bus until no device matches `predicate`, or give up after `timeout`.
+pub async fn wait_for_removal<P>(timeout: Duration, mut predicate: P) -> Result<()>
+where
+    P: FnMut(&nusb::DeviceInfo) -> bool,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let present = enumerate()?.any(|dev| predicate(&dev));
+        if !present {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(Error::timeout(format!(
+                "Device still present after {}s",
+                timeout.as_secs()
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
@@ -0,0 +1,272 @@
+//! Just enough of the zstd frame format (RFC 8878, section 3.1) to read a frame's
+//! declared content size without decompressing anything.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::error::{Error, Result};
+
+pub const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+/// Skippable frames use any magic from `0x184D2A50` to `0x184D2A5F`.
+const SKIPPABLE_MAGIC_MASK: u32 = 0xFFFF_FFF0;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+/// Ends the seek table of the seekable format, which lives in a skippable frame at the
+/// end of the file.
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+/// The longest possible frame header: magic, descriptor, window, dictionary id, content size.
+const MAX_FRAME_HEADER_SIZE: usize = 4 + 1 + 1 + 4 + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameHeader {
+    Zstd {
+        /// Bytes taken by magic number and frame header.
+        header_size: usize,
+        /// Frame_Content_Size, when the encoder wrote it.
+        content_size: Option<u64>,
+        /// Whether the last block is followed by a 4 byte checksum.
+        has_checksum: bool,
+    },
+    /// User data the decoder skips, e.g. a seek table.
+    Skippable {
+        /// Size of the user data following the 8 byte header.
+        size: u32,
+    },
+}
+
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+/// Parse the frame header at the start of `data`.
+pub fn parse_frame_header(data: &[u8]) -> Result<FrameHeader> {
+    let truncated = || Error::invalid_image("Truncated zstd frame header");
+    let magic = read_le(data.get(..4).ok_or_else(truncated)?) as u32;
+    if magic & SKIPPABLE_MAGIC_MASK == SKIPPABLE_MAGIC {
+        let size = read_le(data.get(4..8).ok_or_else(truncated)?) as u32;
+        return Ok(FrameHeader::Skippable { size });
+    }
+    if magic != ZSTD_MAGIC {
+        return Err(Error::invalid_image(format!("Not a zstd frame (magic {magic:#010x})")));
+    }
+    let descriptor = *data.get(4).ok_or_else(truncated)?;
+    let content_size_flag = descriptor >> 6;
+    let single_segment = descriptor & 0x20 != 0;
+    let dictionary_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let window_size = if single_segment { 0 } else { 1 };
+    let content_size_size = match content_size_flag {
+        0 if single_segment => 1,
+        0 => 0,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let offset = 5 + window_size + dictionary_id_size;
+    let header_size = offset + content_size_size;
+    let field = data.get(offset..header_size).ok_or_else(truncated)?;
+    let content_size = match content_size_size {
+        0 => None,
+        // The 2 byte form is stored with an offset of 256
+        2 => Some(read_le(field) + 256),
+        _ => Some(read_le(field)),
+    };
+    Ok(FrameHeader::Zstd {
+        header_size,
+        content_size,
+        has_checksum: descriptor & 0x04 != 0,
+    })
+}
+
+/// The content size declared by the first zstd frame of `data`, skipping leading
+/// skippable frames that fit in `data`.
+pub fn first_frame_content_size(mut data: &[u8]) -> Result<Option<u64>> {
+    loop {
+        match parse_frame_header(data)? {
+            FrameHeader::Zstd { content_size, .. } => return Ok(content_size),
+            FrameHeader::Skippable { size } => {
+                data = data
+                    .get(8 + size as usize..)
+                    .ok_or_else(|| Error::invalid_image("Skippable frame exceeds the data read"))?;
+            }
+        }
+    }
+}
+
+/// The decompressed size recorded in a seekable format seek table at the end of `file`.
+fn seek_table_size<R: Read + Seek>(file: &mut R) -> Result<Option<u64>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 17 {
+        return Ok(None);
+    }
+    let mut footer = [0u8; 9];
+    file.seek(SeekFrom::End(-9))?;
+    file.read_exact(&mut footer)?;
+    if read_le(&footer[5..9]) as u32 != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+    let frames = read_le(&footer[..4]);
+    let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+    let table_size = frames * entry_size;
+    if table_size + 17 > len {
+        return Err(Error::invalid_image("zstd seek table is larger than the file"));
+    }
+    let mut table = vec![0u8; table_size as usize];
+    file.seek(SeekFrom::End(-9 - table_size as i64))?;
+    file.read_exact(&mut table)?;
+    Ok(Some(
+        table
+            .chunks_exact(entry_size as usize)
+            .map(|entry| read_le(&entry[4..8]))
+            .sum(),
+    ))
+}
+
+/// Skip over the blocks of a frame whose header ends at the current position.
+fn skip_blocks<R: Read + Seek>(file: &mut R, has_checksum: bool) -> Result<()> {
+    loop {
+        let mut header = [0u8; 3];
+        file.read_exact(&mut header)?;
+        let header = read_le(&header);
+        let last = header & 1 != 0;
+        let size = match (header >> 1) & 0x03 {
+            // RLE blocks store one byte, repeated Block_Size times
+            1 => 1,
+            3 => return Err(Error::invalid_image("Reserved zstd block type")),
+            _ => header >> 3,
+        };
+        file.seek(SeekFrom::Current(size as i64))?;
+        if last {
+            break;
+        }
+    }
+    if has_checksum {
+        file.seek(SeekFrom::Current(4))?;
+    }
+    Ok(())
+}
+
+/// The total decompressed size of a zstd file, or `None` if a frame does not declare it.
+///
+/// Uses the seek table of the seekable format when there is one; otherwise walks all
+/// frames, reading only frame and block headers.
+pub fn decompressed_size<R: Read + Seek>(file: &mut R) -> Result<Option<u64>> {
+    if let Some(size) = seek_table_size(file)? {
+        return Ok(Some(size));
+    }
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut position = file.seek(SeekFrom::Start(0))?;
+    let mut total = 0;
+    while position < len {
+        let mut header = [0u8; MAX_FRAME_HEADER_SIZE];
+        let available = (len - position).min(MAX_FRAME_HEADER_SIZE as u64) as usize;
+        file.read_exact(&mut header[..available])?;
+        match parse_frame_header(&header[..available])? {
+            FrameHeader::Skippable { size } => {
+                position = file.seek(SeekFrom::Start(position + 8 + size as u64))?;
+            }
+            FrameHeader::Zstd { content_size: None, .. } => return Ok(None),
+            FrameHeader::Zstd {
+                header_size,
+                content_size: Some(size),
+                has_checksum,
+            } => {
+                total += size;
+                file.seek(SeekFrom::Start(position + header_size as u64))?;
+                skip_blocks(file, has_checksum)?;
+                position = file.stream_position()?;
+            }
+        }
+    }
+    Ok(Some(total))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_frame_header() {
+        // single segment, 1 byte content size of 5: what `zstd` writes for "hello"
+        let frame = [0x28, 0xb5, 0x2f, 0xfd, 0x20, 0x05, 0x29, 0x00, 0x00];
+        assert_eq!(
+            parse_frame_header(&frame).unwrap(),
+            FrameHeader::Zstd { header_size: 6, content_size: Some(5), has_checksum: false }
+        );
+
+        // window descriptor, 2 byte dictionary id, 2 byte content size (+256)
+        let frame = [0x28, 0xb5, 0x2f, 0xfd, 0x42, 0x58, 0x34, 0x12, 0x00, 0x01];
+        assert_eq!(
+            parse_frame_header(&frame).unwrap(),
+            FrameHeader::Zstd { header_size: 10, content_size: Some(256 + 256), has_checksum: false }
+        );
+
+        // streaming compression leaves the size out
+        let frame = [0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58];
+        assert_eq!(
+            parse_frame_header(&frame).unwrap(),
+            FrameHeader::Zstd { header_size: 6, content_size: None, has_checksum: true }
+        );
+
+        assert!(parse_frame_header(&[0x28, 0xb5, 0x2f, 0xfd, 0x80, 0x58]).is_err());
+        assert!(parse_frame_header(b"not zstd").is_err());
+    }
+
+    #[test]
+    fn test_skippable_frames() {
+        let mut data = vec![0x5e, 0x2a, 0x4d, 0x18, 0x03, 0x00, 0x00, 0x00, 1, 2, 3];
+        data.extend_from_slice(&[0x28, 0xb5, 0x2f, 0xfd, 0xa0, 0x00, 0x00, 0x00, 0x40]);
+        assert_eq!(first_frame_content_size(&data).unwrap(), Some(0x4000_0000));
+        assert!(first_frame_content_size(&data[..9]).is_err());
+    }
+
+    /// A single segment frame holding `data` in one raw block, with a 4 byte content size.
+    pub(crate) fn raw_frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd, 0xa0];
+        frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&((data.len() as u32) << 3 | 1).to_le_bytes()[..3]);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn test_decompressed_size_walks_frames() {
+        let mut data = raw_frame(b"hello");
+        data.extend_from_slice(&[0x50, 0x2a, 0x4d, 0x18, 0x02, 0x00, 0x00, 0x00, 0xaa, 0xbb]);
+        // A frame with an RLE block of 1000 bytes, then a raw last block
+        data.extend_from_slice(&[0x28, 0xb5, 0x2f, 0xfd, 0x60, 0xeb, 0x02]);
+        data.extend_from_slice(&(1000u32 << 3 | 0x02).to_le_bytes()[..3]);
+        data.push(b'x');
+        data.extend_from_slice(&(3u32 << 3 | 1).to_le_bytes()[..3]);
+        data.extend_from_slice(b"end");
+        assert_eq!(
+            decompressed_size(&mut std::io::Cursor::new(&data)).unwrap(),
+            Some(5 + 1003)
+        );
+
+        // One frame without a content size makes the total unknown
+        data.extend_from_slice(&[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x58, 0x01, 0x00, 0x00]);
+        assert_eq!(decompressed_size(&mut std::io::Cursor::new(&data)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decompressed_size_from_seek_table() {
+        // The seek table wins even when the frames themselves do not declare a size
+        let mut data = vec![0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x58, 0x01, 0x00, 0x00];
+        let entries: [(u32, u32); 2] = [(9, 4096), (9, 1 << 20)];
+        let table_size = entries.len() as u32 * 8 + 9;
+        data.extend_from_slice(&[0x5e, 0x2a, 0x4d, 0x18]);
+        data.extend_from_slice(&table_size.to_le_bytes());
+        for (compressed, decompressed) in entries {
+            data.extend_from_slice(&compressed.to_le_bytes());
+            data.extend_from_slice(&decompressed.to_le_bytes());
+        }
+        data.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        data.push(0);
+        data.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        assert_eq!(
+            decompressed_size(&mut std::io::Cursor::new(&data)).unwrap(),
+            Some(4096 + (1 << 20))
+        );
+    }
+}